@@ -0,0 +1,405 @@
+//! Obtaining & renewing certificates with ACME, e.g. from Let's Encrypt.
+//!
+//! If `ACME_DOMAINS` is set, the proxy serves HTTPS with a certificate for those domains, which it obtains
+//! from the ACME directory at `ACME_DIRECTORY_URL` - Let's Encrypt by default. Domains are validated with
+//! HTTP-01 challenges, answered on `ACME_HTTP_PORT` (port 80 unless behind a port mapping); everything else
+//! that arrives there is redirected to HTTPS.
+//!
+//! The account key, certificate and its key are kept in `ACME_DIR`, so restarts don't order new certificates.
+//! Certificates are renewed once they expire within [`RENEW_BEFORE_DAYS`], and picked up by the listener
+//! like any other changed certificate (see [`tls`](crate::tls)).
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use hyper::header::{HeaderValue, ACCEPT, CONTENT_TYPE, HOST, LOCATION};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, HeaderMap, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNumContext;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509Req, X509};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use crate::pool::{self, UpstreamConnector};
+
+lazy_static! {
+    /// Domains to obtain a certificate for. Read from the `ACME_DOMAINS` env variable, `None` if it's empty.
+    pub static ref ACME_DOMAINS: Option<Vec<String>> = Some(env::var("ACME_DOMAINS").unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|domain| !domain.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>())
+        .filter(|domains| !domains.is_empty());
+
+    /// The ACME directory certificates are ordered from. Read from the `ACME_DIRECTORY_URL` env variable.
+    pub static ref ACME_DIRECTORY_URL: String = env::var("ACME_DIRECTORY_URL")
+        .unwrap_or(String::from("https://acme-v02.api.letsencrypt.org/directory"));
+
+    /// Where keys & certificates are kept. Read from the `ACME_DIR` env variable.
+    pub static ref ACME_DIR: PathBuf = PathBuf::from(env::var("ACME_DIR").unwrap_or(String::from("acme")));
+
+    /// Contact address of the ACME account. Read from the `ACME_EMAIL` env variable.
+    pub static ref ACME_EMAIL: Option<String> = env::var("ACME_EMAIL").ok().filter(|email| !email.is_empty());
+
+    /// The port HTTP-01 challenges are answered at. Read from the `ACME_HTTP_PORT` env variable.
+    pub static ref ACME_HTTP_PORT: u16 = env::var("ACME_HTTP_PORT").unwrap_or(String::from("80"))
+        .parse::<u16>().expect("Expected ACME_HTTP_PORT env var to contain a number");
+
+    /// Key authorizations of pending challenges, by token.
+    static ref CHALLENGES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// How many days before it expires a certificate is renewed.
+pub const RENEW_BEFORE_DAYS: i32 = 30;
+
+/// How often the certificate is checked for renewal.
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How often pending authorizations & orders are polled, and how many times.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 30;
+
+const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Returns the file the certificate is kept in.
+pub fn cert_path(dir: &Path) -> PathBuf {
+    dir.join("cert.pem")
+}
+
+/// Returns the file the certificate's private key is kept in.
+pub fn key_path(dir: &Path) -> PathBuf {
+    dir.join("key.pem")
+}
+
+/// Encodes bytes as unpadded base64url, as JWS does.
+pub fn base64url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut encoded = String::with_capacity(bytes.len() / 3 * 4 + 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Returns whether the PEM certificate expires within `days`, or can't be read.
+pub fn expires_within(cert: &[u8], days: i32) -> bool {
+    let remaining = X509::from_pem(cert).ok()
+        .and_then(|cert| Asn1Time::days_from_now(0).ok()?.diff(cert.not_after()).ok());
+    match remaining {
+        Some(remaining) => remaining.days < days,
+        None => true,
+    }
+}
+
+/// Answers a request to the challenge port: with the key authorization of a pending challenge, or with a
+/// redirect to HTTPS.
+pub fn challenge_response(req: &Request<Body>) -> Response<Body> {
+    if let Some(token) = req.uri().path().strip_prefix(CHALLENGE_PREFIX) {
+        return match CHALLENGES.lock().unwrap().get(token) {
+            Some(key_authorization) => {
+                let mut response = Response::new(Body::from(key_authorization.clone()));
+                response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+                response
+            }
+            None => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap(),
+        };
+    }
+    let host = req.headers().get(HOST).and_then(|host| host.to_str().ok()).map(|host| host.split(':').next().unwrap_or(host));
+    let location = host.and_then(|host| HeaderValue::from_str(&format!("https://{}{}", host, req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/"))).ok());
+    match location {
+        Some(location) => Response::builder().status(StatusCode::MOVED_PERMANENTLY).header(LOCATION, location).body(Body::empty()).unwrap(),
+        None => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap(),
+    }
+}
+
+/// Answers HTTP-01 challenges at `addr`, forever.
+pub async fn serve_challenges(addr: SocketAddr) -> Result<(), hyper::Error> {
+    let service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move { Ok::<_, Infallible>(challenge_response(&req)) }))
+    });
+    Server::try_bind(&addr)?.serve(service).await
+}
+
+fn openssl_error(err: openssl::error::ErrorStack) -> String {
+    err.to_string()
+}
+
+/// Reads the account key from `path`, or generates & saves a new one.
+fn account_key(path: &Path) -> Result<EcKey<Private>, String> {
+    if let Ok(pem) = fs::read(path) {
+        return EcKey::private_key_from_pem(&pem).map_err(|e| format!("could not read account key {}: {}", path.display(), e));
+    }
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(openssl_error)?;
+    let key = EcKey::generate(&group).map_err(openssl_error)?;
+    fs::write(path, key.private_key_to_pem().map_err(openssl_error)?).map_err(|e| format!("could not save account key: {}", e))?;
+    Ok(key)
+}
+
+/// Returns the JWK of a P-256 key, with its members in the order of RFC 7638 thumbprints.
+fn jwk(key: &EcKey<Private>) -> Result<Value, String> {
+    let mut ctx = BigNumContext::new().map_err(openssl_error)?;
+    let mut x = openssl::bn::BigNum::new().map_err(openssl_error)?;
+    let mut y = openssl::bn::BigNum::new().map_err(openssl_error)?;
+    key.public_key().affine_coordinates_gfp(key.group(), &mut x, &mut y, &mut ctx).map_err(openssl_error)?;
+    Ok(json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": base64url(&x.to_vec_padded(32).map_err(openssl_error)?),
+        "y": base64url(&y.to_vec_padded(32).map_err(openssl_error)?),
+    }))
+}
+
+/// Returns the key authorization of a challenge token.
+pub fn key_authorization(token: &str, jwk: &Value) -> String {
+    format!("{}.{}", token, base64url(&Sha256::digest(jwk.to_string().as_bytes())))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// A response of the ACME server.
+struct AcmeResponse {
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl AcmeResponse {
+    fn json(&self) -> Result<Value, String> {
+        serde_json::from_slice(&self.body).map_err(|e| format!("unexpected answer from the ACME server: {}", e))
+    }
+
+    fn location(&self) -> Result<String, String> {
+        self.headers.get(LOCATION).and_then(|location| location.to_str().ok()).map(String::from)
+            .ok_or_else(|| String::from("the ACME server sent no location"))
+    }
+}
+
+/// An account with an ACME server.
+struct AcmeAccount {
+    client: Client<UpstreamConnector>,
+    directory: Directory,
+    key: EcKey<Private>,
+    jwk: Value,
+    /// Url of the account, once it's registered.
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeAccount {
+    async fn open(directory_url: &str, key: EcKey<Private>) -> Result<Self, String> {
+        let client = pool::standalone_client()?;
+        let response = client.get(directory_url.parse().map_err(|_| format!("invalid ACME directory url `{}`", directory_url))?).await
+            .map_err(|e| format!("could not reach the ACME server: {}", e))?;
+        let body = hyper::body::to_bytes(response.into_body()).await.map_err(|e| format!("could not read from the ACME server: {}", e))?;
+        let directory = serde_json::from_slice(&body).map_err(|e| format!("unexpected ACME directory: {}", e))?;
+        let jwk = jwk(&key)?;
+        let mut account = AcmeAccount { client, directory, key, jwk, kid: None, nonce: None };
+
+        let mut registration = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = ACME_EMAIL.as_ref() {
+            registration["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let url = account.directory.new_account.clone();
+        let response = account.post(&url, Some(&registration)).await?;
+        account.kid = Some(response.location()?);
+        Ok(account)
+    }
+
+    async fn nonce(&mut self) -> Result<String, String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let req = Request::head(&self.directory.new_nonce).body(Body::empty()).map_err(|e| e.to_string())?;
+        let response = self.client.request(req).await.map_err(|e| format!("could not reach the ACME server: {}", e))?;
+        response.headers().get("replay-nonce").and_then(|nonce| nonce.to_str().ok()).map(String::from)
+            .ok_or_else(|| String::from("the ACME server sent no nonce"))
+    }
+
+    /// Signs the body of a request to `url` with the account key.
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<Value, String> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = base64url(protected.to_string().as_bytes());
+        // An empty payload makes a POST-as-GET
+        let payload = payload.map(|payload| base64url(payload.to_string().as_bytes())).unwrap_or_default();
+        let signature = EcdsaSig::sign(&Sha256::digest(format!("{}.{}", protected, payload).as_bytes()), &self.key).map_err(openssl_error)?;
+        let mut signature_bytes = signature.r().to_vec_padded(32).map_err(openssl_error)?;
+        signature_bytes.extend(signature.s().to_vec_padded(32).map_err(openssl_error)?);
+        Ok(json!({ "protected": protected, "payload": payload, "signature": base64url(&signature_bytes) }))
+    }
+
+    /// Sends a signed request, retrying once if the server rejects the nonce.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<AcmeResponse, String> {
+        for attempt in 0..2 {
+            let nonce = self.nonce().await?;
+            let body = self.sign(url, &nonce, payload)?;
+            let req = Request::builder().method(Method::POST).uri(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .header(ACCEPT, "application/json, application/pem-certificate-chain")
+                .body(Body::from(body.to_string())).map_err(|e| e.to_string())?;
+            let response = self.client.request(req).await.map_err(|e| format!("could not reach the ACME server: {}", e))?;
+            let status = response.status();
+            let headers = response.headers().clone();
+            self.nonce = headers.get("replay-nonce").and_then(|nonce| nonce.to_str().ok()).map(String::from);
+            let body = hyper::body::to_bytes(response.into_body()).await.map_err(|e| format!("could not read from the ACME server: {}", e))?.to_vec();
+            if status.is_success() {
+                return Ok(AcmeResponse { headers, body });
+            }
+            let problem: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            if attempt == 0 && problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                continue;
+            }
+            return Err(format!("the ACME server answered {} with {}: {}", url, status.as_u16(), problem["detail"].as_str().unwrap_or_default()));
+        }
+        unreachable!()
+    }
+
+    /// Polls `url` until its status is no longer pending or processing, returning the final object.
+    async fn poll(&mut self, url: &str) -> Result<Value, String> {
+        for _ in 0..POLL_ATTEMPTS {
+            let object = self.post(url, None).await?.json()?;
+            match object["status"].as_str() {
+                Some("pending") | Some("processing") => tokio::time::sleep(POLL_INTERVAL).await,
+                _ => return Ok(object),
+            }
+        }
+        Err(format!("{} is still pending", url))
+    }
+
+    /// Completes the HTTP-01 challenge of an authorization.
+    async fn authorize(&mut self, url: &str) -> Result<(), String> {
+        let authorization = self.post(url, None).await?.json()?;
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+        let challenge = authorization["challenges"].as_array().into_iter().flatten()
+            .find(|challenge| challenge["type"] == "http-01")
+            .ok_or_else(|| format!("{} offers no http-01 challenge", authorization["identifier"]["value"]))?;
+        let token = challenge["token"].as_str().ok_or_else(|| String::from("the challenge has no token"))?.to_string();
+        let challenge_url = challenge["url"].as_str().ok_or_else(|| String::from("the challenge has no url"))?.to_string();
+
+        CHALLENGES.lock().unwrap().insert(token.clone(), key_authorization(&token, &self.jwk));
+        let result = async {
+            self.post(&challenge_url, Some(&json!({}))).await?;
+            self.poll(url).await
+        }.await;
+        CHALLENGES.lock().unwrap().remove(&token);
+        match result?["status"].as_str() {
+            Some("valid") => Ok(()),
+            status => Err(format!("{} could not be validated: {}", authorization["identifier"]["value"], status.unwrap_or("unknown status"))),
+        }
+    }
+}
+
+/// Builds a CSR for the domains, returning it as DER along with its new private key.
+fn csr(domains: &[String]) -> Result<(Vec<u8>, PKey<Private>), String> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(openssl_error)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group).map_err(openssl_error)?).map_err(openssl_error)?;
+    let mut name = X509NameBuilder::new().map_err(openssl_error)?;
+    name.append_entry_by_text("CN", &domains[0]).map_err(openssl_error)?;
+    let mut req = X509Req::builder().map_err(openssl_error)?;
+    req.set_subject_name(&name.build()).map_err(openssl_error)?;
+    req.set_pubkey(&key).map_err(openssl_error)?;
+    let mut san = SubjectAlternativeName::new();
+    for domain in domains {
+        san.dns(domain);
+    }
+    let mut extensions = Stack::new().map_err(openssl_error)?;
+    extensions.push(san.build(&req.x509v3_context(None)).map_err(openssl_error)?).map_err(openssl_error)?;
+    req.add_extensions(&extensions).map_err(openssl_error)?;
+    req.sign(&key, MessageDigest::sha256()).map_err(openssl_error)?;
+    Ok((req.build().to_der().map_err(openssl_error)?, key))
+}
+
+/// Orders a certificate for the domains from the ACME directory, and saves it & its key in `dir`.
+pub async fn obtain_certificate(directory_url: &str, domains: &[String], dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+    let mut account = AcmeAccount::open(directory_url, account_key(&dir.join("account.pem"))?).await?;
+
+    let identifiers: Vec<Value> = domains.iter().map(|domain| json!({ "type": "dns", "value": domain })).collect();
+    let url = account.directory.new_order.clone();
+    let response = account.post(&url, Some(&json!({ "identifiers": identifiers }))).await?;
+    let order_url = response.location()?;
+    let order = response.json()?;
+    for authorization in order["authorizations"].as_array().into_iter().flatten() {
+        account.authorize(authorization.as_str().unwrap_or_default()).await?;
+    }
+
+    let (csr, key) = csr(domains)?;
+    let finalize = order["finalize"].as_str().ok_or_else(|| String::from("the order has no finalize url"))?;
+    account.post(finalize, Some(&json!({ "csr": base64url(&csr) }))).await?;
+    let order = account.poll(&order_url).await?;
+    let certificate = match (order["status"].as_str(), order["certificate"].as_str()) {
+        (Some("valid"), Some(certificate)) => certificate.to_string(),
+        (status, _) => return Err(format!("the order ended up {}", status.unwrap_or("without a status"))),
+    };
+    let chain = account.post(&certificate, None).await?.body;
+
+    // The key goes first, a certificate without its key would fail to load
+    write_atomically(&key_path(dir), &key.private_key_to_pem_pkcs8().map_err(openssl_error)?)?;
+    write_atomically(&cert_path(dir), &chain)?;
+    Ok(())
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let partial = path.with_extension("part");
+    fs::write(&partial, contents).and_then(|_| fs::rename(&partial, path))
+        .map_err(|e| format!("could not save {}: {}", path.display(), e))
+}
+
+/// Obtains a certificate for [`ACME_DOMAINS`] unless `ACME_DIR` holds one that doesn't need renewal yet.
+/// Does nothing if ACME isn't enabled.
+pub async fn ensure_certificate() -> Result<(), String> {
+    let domains = match ACME_DOMAINS.as_ref() {
+        Some(domains) => domains,
+        None => return Ok(()),
+    };
+    let current = fs::read(cert_path(&ACME_DIR)).unwrap_or_default();
+    if !expires_within(&current, RENEW_BEFORE_DAYS) {
+        return Ok(());
+    }
+    println!("<-> Obtaining a certificate for {} from {}", domains.join(", "), *ACME_DIRECTORY_URL);
+    obtain_certificate(&ACME_DIRECTORY_URL, domains, &ACME_DIR).await?;
+    println!("<-> Obtained a certificate for {}", domains.join(", "));
+    Ok(())
+}
+
+/// Keeps renewing the certificate before it expires, forever. Returns immediately if ACME isn't enabled.
+pub async fn renew_certificates() {
+    if ACME_DOMAINS.is_none() {
+        return;
+    }
+    let mut interval = tokio::time::interval(RENEW_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = ensure_certificate().await {
+            eprintln!("<!> Could not renew the certificate: {}", e);
+        }
+    }
+}
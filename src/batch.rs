@@ -0,0 +1,176 @@
+//! Splitting of oversized batch lookups.
+//!
+//! The CF api caps how many ids a single `POST /v1/mods`, `/v1/mods/files` or `/v1/fingerprints` lookup may
+//! carry. If `BATCH_CHUNK_SIZE` is set, lookups with more ids are split into lookups of at most that many ids,
+//! sent to the CF api concurrently, and their responses merged into one - clients send batches of any size.
+//! Every lookup after the first uses up another request of the client's rate limit.
+//!
+//! Responses are merged field by field: arrays are concatenated, objects merged, and flags (like whether
+//! Curseforge's fingerprint cache is built) only stay `true` if they are in every response. If any of the
+//! lookups fails, the client receives that lookup's response and the other lookups are cancelled. Nothing is split with `STRICT_PASSTHROUGH`.
+
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use serde_json::{Map, Value};
+use crate::config::ProxyConfig;
+use crate::{classify, error_response, request_cf, request_id, upstreams, ProxyError};
+
+lazy_static! {
+    /// How many ids a lookup sent to the CF api carries at most. Read from the `BATCH_CHUNK_SIZE` env variable,
+    /// lookups aren't split if `0`.
+    pub static ref BATCH_CHUNK_SIZE: usize = env::var("BATCH_CHUNK_SIZE").unwrap_or(String::from("0"))
+        .parse::<usize>().expect("Expected BATCH_CHUNK_SIZE env var to contain a number");
+}
+
+/// Returns the field of the request body that holds the ids for batch lookup routes, `None` for other paths.
+pub fn batch_field(path: &str) -> Option<&'static str> {
+    match classify::classify(path).template.as_ref() {
+        "/v1/mods" => Some("modIds"),
+        "/v1/mods/files" => Some("fileIds"),
+        "/v1/fingerprints" | "/v1/fingerprints/{gameId}" => Some("fingerprints"),
+        _ => None,
+    }
+}
+
+/// Splits a lookup body into bodies of at most `chunk_size` ids in `field`, keeping the other fields. Returns
+/// `None` if the body doesn't need to be split.
+pub fn split_body(body: &[u8], field: &str, chunk_size: usize) -> Option<Vec<Vec<u8>>> {
+    let lookup = serde_json::from_slice::<Map<String, Value>>(body).ok()?;
+    let ids = lookup.get(field)?.as_array()?;
+    if chunk_size == 0 || ids.len() <= chunk_size {
+        return None;
+    }
+    ids.chunks(chunk_size)
+        .map(|ids| {
+            let mut chunk = lookup.clone();
+            chunk.insert(field.to_string(), Value::Array(ids.to_vec()));
+            serde_json::to_vec(&chunk).ok()
+        })
+        .collect()
+}
+
+/// Returns how many lookups a lookup body is split into, `1` if it isn't split.
+pub fn chunk_count(body: &[u8], field: &str, chunk_size: usize) -> usize {
+    let ids = serde_json::from_slice::<Map<String, Value>>(body).ok()
+        .and_then(|lookup| Some(lookup.get(field)?.as_array()?.len()))
+        .unwrap_or(0);
+    match chunk_size {
+        0 => 1,
+        chunk_size => ids.div_ceil(chunk_size).max(1),
+    }
+}
+
+/// Takes another request off the client's rate limit budget with `charge` for every lookup after the first the
+/// request is split into by [`request_cf_in_chunks`]. Returns the request, and how long to wait once the budget
+/// is used up.
+pub(crate) async fn charge_chunks(req: Request<Body>, charge: &(dyn Fn() -> Result<(), Duration> + Sync), config: &ProxyConfig) -> (Request<Body>, Result<(), Duration>) {
+    let field = match batch_field(req.uri().path()) {
+        Some(field) if *BATCH_CHUNK_SIZE > 0 && !config.strict_passthrough() && upstreams::route_for(req.uri().path(), config).is_none() => field,
+        _ => return (req, Ok(())),
+    };
+    // The body was read into memory when its size was checked, so this can't fail
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+    let charged = (1..chunk_count(&body, field, *BATCH_CHUNK_SIZE)).try_for_each(|_| charge());
+    (Request::from_parts(parts, Body::from(body)), charged)
+}
+
+/// Merges the bodies of the responses to split lookups, see the [module docs](self). Returns `None` if one of
+/// them isn't JSON.
+pub fn merge_bodies(bodies: &[Bytes]) -> Option<Vec<u8>> {
+    let mut merged: Option<Value> = None;
+    for body in bodies {
+        let value = serde_json::from_slice::<Value>(body).ok()?;
+        merged = Some(match merged {
+            Some(merged) => merge(merged, value),
+            None => value,
+        });
+    }
+    serde_json::to_vec(&merged?).ok()
+}
+
+fn merge(into: Value, from: Value) -> Value {
+    match (into, from) {
+        (Value::Array(mut into), Value::Array(from)) => {
+            into.extend(from);
+            Value::Array(into)
+        }
+        (Value::Object(mut into), Value::Object(from)) => {
+            for (key, value) in from {
+                let merged = match into.remove(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => value,
+                };
+                into.insert(key, merged);
+            }
+            Value::Object(into)
+        }
+        (Value::Bool(into), Value::Bool(from)) => Value::Bool(into && from),
+        (into, _) => into,
+    }
+}
+
+/// Sends the request to the CF api, split into several lookups if it carries more than [`BATCH_CHUNK_SIZE`] ids.
+pub(crate) async fn request_cf_in_chunks(req: Request<Body>, config: &Arc<ProxyConfig>) -> Result<Response<Body>, ProxyError> {
+    let field = match batch_field(req.uri().path()) {
+        Some(field) => field,
+        None => return request_cf(req, config).await,
+    };
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let chunks = match split_body(&body, field, *BATCH_CHUNK_SIZE) {
+        Some(chunks) => chunks,
+        None => return request_cf(Request::from_parts(parts, Body::from(body)), config).await,
+    };
+    println!("{}<-> {} split into {} lookups of up to {} ids", request_id::prefix(), parts.uri.path(), chunks.len(), *BATCH_CHUNK_SIZE);
+
+    let requests: Vec<_> = chunks.into_iter()
+        .map(|chunk| {
+            let mut chunk_req = Request::new(Body::empty());
+            *chunk_req.method_mut() = parts.method.clone();
+            *chunk_req.uri_mut() = parts.uri.clone();
+            *chunk_req.version_mut() = parts.version;
+            *chunk_req.headers_mut() = parts.headers.clone();
+            chunk_req.headers_mut().insert(CONTENT_LENGTH, chunk.len().into());
+            // Compressed responses couldn't be merged
+            chunk_req.headers_mut().remove(ACCEPT_ENCODING);
+            *chunk_req.body_mut() = Body::from(chunk);
+            let config = config.clone();
+            request_id::spawn(async move { request_cf(chunk_req, &config).await })
+        })
+        .collect();
+    let mut responses = Vec::with_capacity(requests.len());
+    let mut requests = requests.into_iter();
+    while let Some(request) = requests.next() {
+        match request.await.map_err(ProxyError::ChunkFailed).and_then(|result| result) {
+            Ok(response) if response.status().is_success() => responses.push(response),
+            failed => {
+                // The client only receives this lookup's response, the others would be wasted
+                requests.for_each(|request| request.abort());
+                return failed;
+            }
+        }
+    }
+
+    let mut bodies = Vec::with_capacity(responses.len());
+    let mut merged_parts = None;
+    for response in responses {
+        let (parts, body) = response.into_parts();
+        bodies.push(hyper::body::to_bytes(body).await?);
+        merged_parts.get_or_insert(parts);
+    }
+    match (merged_parts, merge_bodies(&bodies)) {
+        (Some(mut parts), Some(merged)) => {
+            parts.status = StatusCode::OK;
+            parts.headers.insert(CONTENT_LENGTH, merged.len().into());
+            parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+            Ok(Response::from_parts(parts, Body::from(merged)))
+        }
+        _ => Ok(error_response(StatusCode::BAD_GATEWAY, "Curseforge sent an invalid response or closed the connection")),
+    }
+}
@@ -0,0 +1,120 @@
+//! An allowlist of bearer tokens clients have to present.
+//!
+//! If `BEARER_TOKENS_FILE` is set, every request needs an `Authorization: Bearer <token>` header with one
+//! of the tokens listed in that file, one per line. The file is watched for changes, so tokens can be added
+//! and revoked without restarting the proxy.
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use hyper::header::AUTHORIZATION;
+use hyper::HeaderMap;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// The allowlist read from the file in the `BEARER_TOKENS_FILE` env variable.
+    /// `None` if the variable is unset, in which case no bearer token is required.
+    pub static ref BEARER_ALLOWLIST: Option<BearerAllowlist> = env::var("BEARER_TOKENS_FILE").ok()
+        .filter(|path| !path.is_empty())
+        .map(|path| BearerAllowlist::load(PathBuf::from(path)).expect("Expected BEARER_TOKENS_FILE to point to a readable file"));
+}
+
+/// How often the allowlist file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Parses the contents of an allowlist file. Empty lines and lines starting with `#` are ignored.
+pub fn parse_tokens(contents: &str) -> HashSet<String> {
+    contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Returns the token of an `Authorization: Bearer <token>` header, if the headers contain one.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// The set of accepted bearer tokens, backed by a file.
+#[derive(Debug)]
+pub struct BearerAllowlist {
+    path: PathBuf,
+    tokens: RwLock<HashSet<String>>,
+    /// Modification time of the file when it was last read.
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl BearerAllowlist {
+    /// Reads the allowlist from the given file.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let allowlist = BearerAllowlist { path, tokens: RwLock::new(HashSet::new()), modified: Mutex::new(None) };
+        allowlist.reload()?;
+        Ok(allowlist)
+    }
+
+    /// Returns the file the allowlist is read from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether the token is on the allowlist.
+    pub fn contains(&self, token: &str) -> bool {
+        self.tokens.read().unwrap().contains(token)
+    }
+
+    /// Returns whether the headers contain a bearer token that is on the allowlist.
+    pub fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        bearer_token(headers).map(|token| self.contains(token)).unwrap_or(false)
+    }
+
+    /// Re-reads the file if it changed since it was last read. Returns whether it was re-read.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let modified = fs::metadata(&self.path)?.modified().ok();
+        if modified.is_some() && modified == *self.modified.lock().unwrap() {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Re-reads the file.
+    pub fn reload(&self) -> io::Result<()> {
+        let modified = fs::metadata(&self.path)?.modified().ok();
+        let tokens = parse_tokens(&fs::read_to_string(&self.path)?);
+        *self.tokens.write().unwrap() = tokens;
+        *self.modified.lock().unwrap() = modified;
+        Ok(())
+    }
+}
+
+/// Keeps reloading [`BEARER_ALLOWLIST`] whenever its file changes, forever.
+///
+/// Returns immediately if `BEARER_TOKENS_FILE` is not set. If the file can't be read, the previously
+/// loaded tokens stay in effect.
+pub async fn watch_allowlist() {
+    let allowlist = match BEARER_ALLOWLIST.as_ref() {
+        Some(allowlist) => allowlist,
+        None => return,
+    };
+
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        match allowlist.reload_if_changed() {
+            Ok(true) => println!("<-> Reloaded bearer tokens from {}", allowlist.path().display()),
+            Ok(false) => {}
+            Err(e) => eprintln!("<!> Could not reload bearer tokens from {}: {}", allowlist.path().display(), e),
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Limits on the size of request bodies.
+//!
+//! Bodies are read into memory before requests are forwarded, and rejected with `413` as soon as they grow
+//! past `MAX_REQUEST_BODY_BYTES` - a client can't stream an arbitrarily large body through the proxy. The
+//! lookups of the CF api that take a body (mod ids, fingerprints) stay well below the default limit.
+
+use std::env;
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Body, Request};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// How large request bodies may be, in bytes. Read from the `MAX_REQUEST_BODY_BYTES` env variable.
+    pub static ref MAX_REQUEST_BODY_BYTES: usize = env::var("MAX_REQUEST_BODY_BYTES").unwrap_or(String::from("1048576"))
+        .parse::<usize>().expect("Expected MAX_REQUEST_BODY_BYTES env var to contain a number");
+}
+
+/// Why a request body was not accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyError {
+    /// The body is larger than allowed.
+    TooLarge,
+    /// The body could not be read.
+    Unreadable,
+}
+
+/// Reads the request's body into memory, failing as soon as it's known to be larger than `max_bytes`.
+pub async fn read_limited(req: Request<Body>, max_bytes: usize) -> Result<Request<Body>, BodyError> {
+    let declared = req.headers().get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    if declared.map(|length| length > max_bytes as u64).unwrap_or(false) {
+        return Err(BodyError::TooLarge);
+    }
+
+    let (parts, mut body) = req.into_parts();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| BodyError::Unreadable)?;
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(BodyError::TooLarge);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Request::from_parts(parts, Body::from(bytes)))
+}
@@ -0,0 +1,114 @@
+//! A circuit breaker in front of the CF api.
+//!
+//! When Curseforge is down, every request would otherwise wait for a connect error or the upstream timeout.
+//! After `CIRCUIT_BREAKER_THRESHOLD` consecutive failures (errors, timeouts or `5xx` responses), the breaker
+//! opens: requests fail fast with `503` for `CIRCUIT_BREAKER_OPEN_SECS`. After that, it half-opens and lets a
+//! single probe request through - if it succeeds, the breaker closes again, otherwise it stays open for
+//! another period.
+
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// The breaker of this process, configured from the `CIRCUIT_BREAKER_*` env variables.
+    pub static ref BREAKER: CircuitBreaker = CircuitBreaker::new(
+        env::var("CIRCUIT_BREAKER_THRESHOLD").unwrap_or(String::from("5"))
+            .parse::<u32>().expect("Expected CIRCUIT_BREAKER_THRESHOLD env var to contain a number"),
+        Duration::from_secs(env::var("CIRCUIT_BREAKER_OPEN_SECS").unwrap_or(String::from("30"))
+            .parse::<u64>().expect("Expected CIRCUIT_BREAKER_OPEN_SECS env var to contain a number")),
+    );
+}
+
+/// The state of a breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Requests go through, counting consecutive failures.
+    Closed { failures: u32 },
+    /// Requests fail fast until the given time.
+    Open { until: Instant },
+    /// A probe request went through at the given time, and no other request does until it finished.
+    HalfOpen { probe_since: Instant },
+}
+
+/// Trips after a number of consecutive failures, see the [module docs](self).
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    open_for: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    /// Creates a closed breaker tripping after `threshold` consecutive failures, `0` never trips.
+    pub fn new(threshold: u32, open_for: Duration) -> Self {
+        CircuitBreaker { threshold, open_for, state: Mutex::new(BreakerState::Closed { failures: 0 }) }
+    }
+
+    /// Returns the state of the breaker.
+    pub fn state(&self) -> BreakerState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Returns whether a request would go through now, or how long until the breaker half-opens if it wouldn't.
+    /// Unlike [`allow`](Self::allow), doesn't let a probe request through.
+    pub fn check(&self) -> Result<(), Duration> {
+        self.check_at(Instant::now())
+    }
+
+    /// Returns whether a request would go through at the given time, or how long until the breaker half-opens
+    /// if it wouldn't.
+    pub fn check_at(&self, now: Instant) -> Result<(), Duration> {
+        self.wait_in(*self.state.lock().unwrap(), now)
+    }
+
+    /// Returns whether a request may go through now, or how long until the breaker half-opens if it doesn't.
+    pub fn allow(&self) -> Result<(), Duration> {
+        self.allow_at(Instant::now())
+    }
+
+    /// Returns whether a request may go through at the given time, or how long until the breaker half-opens
+    /// if it doesn't.
+    pub fn allow_at(&self, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        self.wait_in(*state, now)?;
+        if !matches!(*state, BreakerState::Closed { .. }) {
+            *state = BreakerState::HalfOpen { probe_since: now };
+        }
+        Ok(())
+    }
+
+    /// Returns how long requests have to wait in the state at the given time, if they can't go through.
+    fn wait_in(&self, state: BreakerState, now: Instant) -> Result<(), Duration> {
+        match state {
+            BreakerState::Closed { .. } => Ok(()),
+            BreakerState::Open { until } if now < until => Err(until - now),
+            // A probe that never reported back doesn't block the breaker forever
+            BreakerState::HalfOpen { probe_since } if now < probe_since + self.open_for => Err(probe_since + self.open_for - now),
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => Ok(()),
+        }
+    }
+
+    /// Records the outcome of a request that was let through.
+    pub fn record(&self, success: bool) {
+        self.record_at(Instant::now(), success)
+    }
+
+    /// Records the outcome of a request that was let through, finishing at the given time.
+    pub fn record_at(&self, now: Instant, success: bool) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        *state = match (*state, success) {
+            (_, true) => BreakerState::Closed { failures: 0 },
+            (BreakerState::Closed { failures }, false) if failures + 1 < self.threshold => BreakerState::Closed { failures: failures + 1 },
+            (BreakerState::Closed { .. } | BreakerState::HalfOpen { .. }, false) => {
+                println!("<!> Circuit breaker opened, failing requests to the CF api for {}s", self.open_for.as_secs());
+                BreakerState::Open { until: now + self.open_for }
+            }
+            (open @ BreakerState::Open { .. }, false) => open,
+        };
+    }
+}
@@ -0,0 +1,402 @@
+//! An in-memory cache for responses of the CF api.
+//!
+//! If `CACHE_TTL_SECS` is set, successful responses to `GET` requests are kept for that many seconds and
+//! served to later requests for the same path & query without asking the CF api again. At most
+//! `CACHE_MAX_ENTRIES` responses are kept - when the cache is full, the least recently used ones are
+//! evicted. With `CACHE_COMPRESSION` set, they're kept compressed (see [`compression`](crate::compression)).
+//!
+//! Responses are only served to clients that can use them: requests whose responses may be cached ask Curseforge
+//! for gzip or unencoded bodies only, which the cache can serve to any client, and responses with a `Vary` header
+//! are only served to requests with the same values for the headers it names.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, VARY};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use crate::metrics::MetricsSink;
+use crate::compression::{self, Coding};
+use crate::profile;
+
+lazy_static! {
+    /// How long responses are cached. Read from the `CACHE_TTL_SECS` env variable, caching is disabled if `0`.
+    pub static ref CACHE_TTL: Duration = Duration::from_secs(
+        profile::env_or("CACHE_TTL_SECS", "0")
+            .parse::<u64>().expect("Expected CACHE_TTL_SECS env var to contain a number")
+    );
+
+    /// How many responses are cached at most. Read from the `CACHE_MAX_ENTRIES` env variable.
+    pub static ref CACHE_MAX_ENTRIES: usize = profile::env_or("CACHE_MAX_ENTRIES", "10000")
+        .parse::<usize>().expect("Expected CACHE_MAX_ENTRIES env var to contain a number");
+
+    /// The response cache of this process.
+    pub static ref CACHE: Arc<ResponseCache> = Arc::new(
+        ResponseCache::new(*CACHE_TTL, *CACHE_MAX_ENTRIES).with_compression(*compression::CACHE_COMPRESSION)
+    );
+}
+
+/// Returns the key a request is cached under, or `None` if responses to it can't be cached.
+pub fn cache_key(req: &Request<Body>) -> Option<String> {
+    if req.method() != Method::GET {
+        return None;
+    }
+    Some(req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/").to_string())
+}
+
+/// Returns whether a response may be cached.
+pub fn is_cacheable(status: StatusCode, headers: &HeaderMap) -> bool {
+    let forbidden = headers.get_all(CACHE_CONTROL).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| matches!(directive.trim().to_ascii_lowercase().as_str(), "no-store" | "private" | "no-cache"));
+    status == StatusCode::OK && !forbidden && !vary(headers).any(|name| name == "*")
+}
+
+/// Rewrites the `Accept-Encoding` of a request whose response may be cached, so Curseforge only sends a body the
+/// cache can serve to every client: compressed with gzip if the client accepts that, as the cache decompresses it
+/// for clients that don't, and unencoded otherwise.
+pub fn normalize_accept_encoding(headers: &mut HeaderMap) {
+    let encoding = match compression::accepts_gzip(headers) {
+        true => "gzip",
+        false => "identity",
+    };
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(encoding));
+}
+
+/// Returns the lowercase header names listed in the `Vary` headers.
+fn vary(headers: &HeaderMap) -> impl Iterator<Item=String> + '_ {
+    headers.get_all(VARY).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+}
+
+/// A cached response.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// The request headers named in `Vary`, except `Accept-Encoding`, with the values the response was sent for.
+    selecting_headers: Vec<(HeaderName, Option<HeaderValue>)>,
+    stored_at: Instant,
+}
+
+impl CachedResponse {
+    /// Creates a cached response from its parts.
+    pub fn new(status: StatusCode, headers: HeaderMap, body: Bytes) -> Self {
+        CachedResponse { status, headers, body, selecting_headers: Vec::new(), stored_at: Instant::now() }
+    }
+
+    /// Returns an estimate of the memory the response takes up, in bytes: its body & headers, plus bookkeeping.
+    pub fn estimated_size(&self) -> usize {
+        let headers = self.headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum::<usize>();
+        let selecting_headers = self.selecting_headers.iter()
+            .map(|(name, value)| name.as_str().len() + value.as_ref().map(HeaderValue::len).unwrap_or(0))
+            .sum::<usize>();
+        std::mem::size_of::<Self>() + self.body.len() + headers + selecting_headers
+    }
+
+    /// Records the values of the headers named in `Vary` in `request_headers`, which the response was sent for,
+    /// so it's only served to requests with the same values.
+    pub fn varying_on(mut self, request_headers: &HeaderMap) -> Self {
+        // Accept-Encoding is normalized instead, see normalize_accept_encoding
+        self.selecting_headers = vary(&self.headers)
+            .filter(|name| name != "accept-encoding")
+            .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+            .map(|name| {
+                let value = request_headers.get(&name).cloned();
+                (name, value)
+            })
+            .collect();
+        self
+    }
+
+    /// Returns whether the response may be served to the client that sent `request_headers`: its body must be
+    /// in an encoding the client accepts or the cache can decode, and the headers named in `Vary` must match.
+    pub fn matches(&self, request_headers: &HeaderMap) -> bool {
+        let encodings = self.headers.get_all(CONTENT_ENCODING).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|coding| coding.trim())
+            .filter(|coding| !coding.is_empty())
+            .collect::<Vec<_>>();
+        let decodable = self.coding().is_some()
+            || encodings.iter().all(|coding| compression::accepts_encoding(request_headers, coding));
+        decodable && self.selecting_headers.iter().all(|(name, value)| request_headers.get(name) == value.as_ref())
+    }
+
+    /// Builds a response to send to a client.
+    pub fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+
+    /// Builds a response to send to the client that sent `request_headers`: a compressed body is recompressed
+    /// with another coding if the client doesn't accept the one it's in, or decompressed if it accepts none.
+    pub fn to_response_for(&self, request_headers: &HeaderMap) -> Response<Body> {
+        let coding = match self.coding() {
+            Some(coding) => coding,
+            None => return self.to_response(),
+        };
+        let mut response = self.to_response();
+        let headers = response.headers_mut();
+        let varies = headers.get_all(VARY).iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.to_ascii_lowercase().contains("accept-encoding"));
+        if !varies {
+            headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+        }
+        if compression::accepts_encoding(request_headers, coding.name()) {
+            return response;
+        }
+        // Serve the body as it is if it doesn't decompress, as the proxy would without a cache
+        if let Ok(body) = coding.decompress(&self.body, MAX_DECOMPRESSED_BYTES) {
+            let headers = response.headers_mut();
+            headers.remove(CONTENT_LENGTH);
+            *response.body_mut() = match compression::preferred_coding(request_headers) {
+                Some(other) => {
+                    headers.insert(CONTENT_ENCODING, HeaderValue::from_static(other.name()));
+                    Body::from(other.compress(&body))
+                }
+                None => {
+                    headers.remove(CONTENT_ENCODING);
+                    Body::from(body)
+                }
+            };
+        }
+        response
+    }
+
+    /// Returns the coding the body is compressed with, if it's one the cache can decompress - compressed either by
+    /// Curseforge or by the cache.
+    pub fn coding(&self) -> Option<Coding> {
+        let mut encodings = self.headers.get_all(CONTENT_ENCODING).iter();
+        match (encodings.next().and_then(|value| value.to_str().ok()), encodings.next()) {
+            (Some(encoding), None) => Coding::from_name(encoding),
+            _ => None,
+        }
+    }
+
+    /// Compresses the body with the coding, unless it's encoded already, too small or wouldn't get any smaller.
+    pub fn compressed(mut self, coding: Coding) -> Self {
+        if self.headers.contains_key(CONTENT_ENCODING) || self.body.len() < compression::MIN_COMPRESS_BYTES {
+            return self;
+        }
+        let compressed = coding.compress(&self.body);
+        if compressed.len() < self.body.len() {
+            self.body = compressed.into();
+            self.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(coding.name()));
+            self.headers.remove(CONTENT_LENGTH);
+        }
+        self
+    }
+}
+
+/// Cached bodies that decompress to more than this are served compressed.
+const MAX_DECOMPRESSED_BYTES: usize = 256 * 1024 * 1024;
+
+/// Cache statistics.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    /// Responses currently cached, including expired ones that were not evicted yet.
+    pub entries: usize,
+    /// Lookups that found a fresh response.
+    pub hits: u64,
+    /// Lookups that found no fresh response.
+    pub misses: u64,
+    /// Lookups that found an expired response, and returned it (see [`Cache::get_stale`]).
+    pub stale_hits: u64,
+    /// Responses evicted to make room for new ones, expired or not.
+    pub evictions: u64,
+    /// Estimate of the memory taken up by the cached responses, in bytes.
+    pub bytes: usize,
+}
+
+impl CacheStats {
+    /// Reports the statistics to the sink: lookups & evictions as how much they increased since `last`, which is
+    /// updated to these statistics.
+    pub fn report_to(&self, sink: &dyn MetricsSink, last: &mut CacheStats) {
+        sink.count("cache.hits", self.hits.saturating_sub(last.hits), &[]);
+        sink.count("cache.misses", self.misses.saturating_sub(last.misses), &[]);
+        sink.count("cache.stale_hits", self.stale_hits.saturating_sub(last.stale_hits), &[]);
+        sink.count("cache.evictions", self.evictions.saturating_sub(last.evictions), &[]);
+        sink.gauge("cache.entries", self.entries as u64, &[]);
+        sink.gauge("cache.bytes", self.bytes as u64, &[]);
+        *last = *self;
+    }
+}
+
+/// A cache for responses of the CF api, by cache key (see [`cache_key`]).
+pub trait Cache: Send + Sync {
+    /// Returns whether responses are cached at all.
+    fn is_enabled(&self) -> bool;
+
+    /// Returns the fresh response cached under the key, if there is one.
+    fn get(&self, key: &str) -> Option<CachedResponse>;
+
+    /// Returns the response cached under the key even if it expired, as long as it wasn't evicted yet.
+    fn get_stale(&self, key: &str) -> Option<CachedResponse>;
+
+    /// Returns whether a fresh response is cached under the key, without counting it as a lookup.
+    fn contains(&self, key: &str) -> bool;
+
+    /// Caches a response under the key.
+    fn insert(&self, key: String, response: CachedResponse);
+
+    /// Returns statistics about the cache.
+    fn stats(&self) -> CacheStats;
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    response: CachedResponse,
+    /// When the entry was last used, see [`CacheState::uses`].
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    /// The keys of the entries by when they were last used, least recently used first.
+    recency: BTreeMap<u64, String>,
+    /// How often entries were inserted or used, counting up.
+    uses: u64,
+    hits: u64,
+    misses: u64,
+    stale_hits: u64,
+    evictions: u64,
+    /// Estimated size of the entries, see [`CachedResponse::estimated_size`].
+    bytes: usize,
+}
+
+impl CacheState {
+    /// Returns the response cached under the key, marking it as the most recently used.
+    fn touch(&mut self, key: &str) -> Option<CachedResponse> {
+        let entry = self.entries.get_mut(key)?;
+        self.uses += 1;
+        if let Some(key) = self.recency.remove(&entry.used) {
+            self.recency.insert(self.uses, key);
+        }
+        entry.used = self.uses;
+        Some(entry.response.clone())
+    }
+
+    fn insert(&mut self, key: String, response: CachedResponse) {
+        self.remove(&key);
+        self.uses += 1;
+        self.bytes += key.len() + response.estimated_size();
+        self.recency.insert(self.uses, key.clone());
+        self.entries.insert(key, CacheEntry { response, used: self.uses });
+    }
+
+    fn remove(&mut self, key: &str) -> Option<CachedResponse> {
+        let removed = self.entries.remove(key)?;
+        self.recency.remove(&removed.used);
+        self.bytes -= key.len() + removed.response.estimated_size();
+        Some(removed.response)
+    }
+
+    /// Removes the least recently used entry.
+    fn evict(&mut self) -> Option<CachedResponse> {
+        let (_, key) = self.recency.pop_first()?;
+        self.evictions += 1;
+        self.remove(&key)
+    }
+}
+
+/// The cache the proxy uses by default: responses expire after a TTL, and the least recently used are evicted when full.
+#[derive(Debug)]
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    compression: Option<Coding>,
+    state: Mutex<CacheState>,
+}
+
+impl ResponseCache {
+    /// Creates a cache keeping at most `max_entries` responses for `ttl` each.
+    /// A cache with a `ttl` of zero is disabled.
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        ResponseCache { ttl, max_entries, compression: None, state: Mutex::new(CacheState::default()) }
+    }
+
+    /// Makes the cache keep bodies compressed with the coding, if one is given.
+    pub fn with_compression(mut self, compression: Option<Coding>) -> Self {
+        self.compression = compression;
+        self
+    }
+}
+
+impl Cache for ResponseCache {
+    fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero() && self.max_entries > 0
+    }
+
+    fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut state = self.state.lock().unwrap();
+        let fresh = state.entries.get(key).map(|entry| entry.response.stored_at.elapsed() < self.ttl).unwrap_or(false);
+        match fresh {
+            true => {
+                state.hits += 1;
+                state.touch(key)
+            }
+            false => {
+                state.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn get_stale(&self, key: &str) -> Option<CachedResponse> {
+        let mut state = self.state.lock().unwrap();
+        let cached = state.touch(key);
+        match &cached {
+            Some(cached) if cached.stored_at.elapsed() < self.ttl => state.hits += 1,
+            Some(_) => state.stale_hits += 1,
+            None => state.misses += 1,
+        }
+        cached
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state.entries.get(key).map(|entry| entry.response.stored_at.elapsed() < self.ttl).unwrap_or(false)
+    }
+
+    /// Evicts the least recently used responses if the cache is full.
+    fn insert(&self, key: String, response: CachedResponse) {
+        if !self.is_enabled() {
+            return;
+        }
+        let response = match self.compression {
+            Some(coding) => response.compressed(coding),
+            None => response,
+        };
+        let mut state = self.state.lock().unwrap();
+        while state.entries.len() >= self.max_entries && !state.entries.contains_key(&key) {
+            if state.evict().is_none() {
+                break;
+            }
+        }
+        state.insert(key, response);
+    }
+
+    fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            entries: state.entries.len(),
+            hits: state.hits,
+            misses: state.misses,
+            stale_hits: state.stale_hits,
+            evictions: state.evictions,
+            bytes: state.bytes,
+        }
+    }
+}
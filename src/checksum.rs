@@ -0,0 +1,200 @@
+//! SHA-256 checksums of proxied response bodies.
+//!
+//! If `CHECKSUM_TRAILER` is enabled, the proxy hashes every response body while streaming it to the client
+//! and sends the hex-encoded hash in the `x-checksum-sha256` trailer, so clients can detect truncated
+//! responses. The hash is logged as well.
+//!
+//! Trailers can only be delivered over HTTP/2 - HTTP/1 clients don't receive them, but the logged hash can
+//! still be used to investigate reports of corrupted responses.
+//!
+//! Files downloaded through the proxy (see [`crate::downloads`]) are checked against the SHA-1 hash the CF
+//! api reports for them, with [`with_sha1_check`]. SHA-1 is long broken for signatures, but still catches
+//! truncated & corrupted downloads.
+
+use std::env;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue, TRAILER};
+use hyper::{Body, HeaderMap, Response};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+
+lazy_static! {
+    /// Whether response bodies are hashed. Read from the `CHECKSUM_TRAILER` env variable.
+    pub static ref CHECKSUM_TRAILER: bool = env::var("CHECKSUM_TRAILER").unwrap_or(String::from("false"))
+        .parse::<bool>().expect("Expected CHECKSUM_TRAILER env var to be either true or false");
+}
+
+/// Name of the trailer carrying the checksum.
+pub const CHECKSUM_HEADER: &str = "x-checksum-sha256";
+
+/// Makes the response stream its body through a hasher, and sends the hash as a trailer once the body is done.
+///
+/// `on_complete` is called with the hex-encoded hash once the whole body was streamed. If the body can't be
+/// read or the client goes away, the body is aborted and `on_complete` is not called.
+pub fn with_checksum_trailer<F>(response: Response<Body>, on_complete: F) -> Response<Body>
+    where F: FnOnce(&str) + Send + 'static
+{
+    let (mut parts, mut body) = response.into_parts();
+    parts.headers.insert(TRAILER, HeaderValue::from_static(CHECKSUM_HEADER));
+
+    let (mut sender, checked_body) = Body::channel();
+    tokio::spawn(async move {
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => return sender.abort(),
+            };
+            hasher.update(&chunk);
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+
+        // Pass on upstream trailers along with the checksum
+        let mut trailers = body.trailers().await.ok().flatten().unwrap_or_else(HeaderMap::new);
+        let checksum = to_hex(&hasher.finalize());
+        trailers.insert(HeaderName::from_static(CHECKSUM_HEADER), HeaderValue::from_str(&checksum).unwrap());
+        on_complete(&checksum);
+        sender.send_trailers(trailers).await.ok();
+    });
+
+    Response::from_parts(parts, checked_body)
+}
+
+/// Hex-encodes a hash.
+pub fn to_hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Makes the response check its body against the hex-encoded SHA-1 hash while streaming it.
+///
+/// The last chunk is held back until the hash is known: if it doesn't match, the body is aborted instead of
+/// completed, so the client never receives a corrupted file in full. `on_mismatch` is called with the actual
+/// hash. Bodies that can't be read are aborted as well.
+pub fn with_sha1_check<F>(response: Response<Body>, expected: &str, on_mismatch: F) -> Response<Body>
+    where F: FnOnce(&str) + Send + 'static
+{
+    let (parts, mut body) = response.into_parts();
+    let expected = expected.to_ascii_lowercase();
+
+    let (mut sender, checked_body) = Body::channel();
+    tokio::spawn(async move {
+        let mut hasher = Sha1::new();
+        let mut held_back = None;
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => return sender.abort(),
+            };
+            hasher.update(&chunk);
+            if let Some(previous) = held_back.replace(chunk) {
+                if sender.send_data(previous).await.is_err() {
+                    return;
+                }
+            }
+        }
+
+        let actual = to_hex(&hasher.finalize());
+        if actual != expected {
+            on_mismatch(&actual);
+            return sender.abort();
+        }
+        if let Some(last) = held_back {
+            sender.send_data(last).await.ok();
+        }
+    });
+
+    Response::from_parts(parts, checked_body)
+}
+
+/// A SHA-1 hasher.
+#[derive(Debug, Clone)]
+pub struct Sha1 {
+    state: [u32; 5],
+    /// Bytes that don't fill a block yet.
+    pending: Vec<u8>,
+    length: u64,
+}
+
+impl Default for Sha1 {
+    fn default() -> Self {
+        Sha1 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0],
+            pending: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+}
+
+impl Sha1 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hashes the data.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if !self.pending.is_empty() {
+            let taken = data.len().min(64 - self.pending.len());
+            self.pending.extend_from_slice(&data[..taken]);
+            data = &data[taken..];
+            if self.pending.len() < 64 {
+                return;
+            }
+            let block = std::mem::take(&mut self.pending);
+            self.compress(&block);
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block);
+        }
+        self.pending.extend_from_slice(blocks.remainder());
+    }
+
+    /// Returns the hash of all data.
+    pub fn finalize(mut self) -> [u8; 20] {
+        let bits = self.length.wrapping_mul(8);
+        let mut padding = vec![0x80];
+        padding.resize(1 + (119 - self.pending.len()) % 64, 0);
+        padding.extend_from_slice(&bits.to_be_bytes());
+        let length = self.length;
+        self.update(&padding);
+        self.length = length;
+
+        let mut hash = [0; 20];
+        for (bytes, word) in hash.chunks_exact_mut(4).zip(self.state.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        hash
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
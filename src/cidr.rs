@@ -0,0 +1,68 @@
+//! IP address ranges in CIDR notation.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A range of IP addresses, like `10.0.0.0/8` or `fd00::/8`. A plain address is a range of one address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+/// Converts IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`, as seen by dual stack listeners) to IPv4.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => IpAddr::V4(v6.to_ipv4().unwrap()),
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+fn bits(ip: IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(v4) => (u32::from(v4) as u128, 32),
+        IpAddr::V6(v6) => (u128::from(v6), 128),
+    }
+}
+
+impl Cidr {
+    /// Returns whether the address is in the range.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (network, width) = bits(self.network);
+        let (ip, ip_width) = bits(canonical(*ip));
+        if width != ip_width {
+            return false;
+        }
+        let host_bits = (width - self.prefix) as u32;
+        network.checked_shr(host_bits).unwrap_or(0) == ip.checked_shr(host_bits).unwrap_or(0)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = match s.trim().split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let network = canonical(ip.parse::<IpAddr>().map_err(|_| format!("`{}`: invalid IP address", s))?);
+        let width = bits(network).1;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|prefix| *prefix <= width)
+                .ok_or_else(|| format!("`{}`: invalid prefix length", s))?,
+            None => width,
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
@@ -0,0 +1,72 @@
+//! Classification of requests by the route of the CF api they are for.
+//!
+//! Every request is labeled with a normalized route template like `/v1/mods/{id}/files/{fileId}`, so logs,
+//! metrics and per-route policies refer to routes the same way instead of each matching raw paths.
+
+use std::borrow::Cow;
+
+/// Templates of the routes of the CF api. `{name}` segments match any single path segment.
+pub const ROUTE_TEMPLATES: &[&str] = &[
+    "/v1/games",
+    "/v1/games/{id}",
+    "/v1/games/{id}/versions",
+    "/v1/games/{id}/version-types",
+    "/v2/games/{id}/versions",
+    "/v1/categories",
+    "/v1/mods/search",
+    "/v1/mods/featured",
+    "/v1/mods/files",
+    "/v1/mods",
+    "/v1/mods/{id}",
+    "/v1/mods/{id}/description",
+    "/v1/mods/{id}/files",
+    "/v1/mods/{id}/files/{fileId}",
+    "/v1/mods/{id}/files/{fileId}/changelog",
+    "/v1/mods/{id}/files/{fileId}/download-url",
+    "/v1/fingerprints",
+    "/v1/fingerprints/{gameId}",
+    "/v1/fingerprints/fuzzy",
+    "/v1/fingerprints/fuzzy/{gameId}",
+    "/v1/minecraft/version",
+    "/v1/minecraft/version/{version}",
+    "/v1/minecraft/modloader",
+    "/v1/minecraft/modloader/{name}",
+];
+
+/// The route a request is for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// The route's template. For paths no template matches, the path with numeric segments replaced by `{id}`.
+    pub template: Cow<'static, str>,
+    /// Whether the path matched one of the [`ROUTE_TEMPLATES`].
+    pub known: bool,
+}
+
+fn matches(template: &str, path: &str) -> bool {
+    let mut segments = path.trim_end_matches('/').split('/');
+    let all_match = template.split('/')
+        .all(|expected| match segments.next() {
+            Some(segment) if expected.starts_with('{') => !segment.is_empty(),
+            Some(segment) => segment == expected,
+            None => false,
+        });
+    all_match && segments.next().is_none()
+}
+
+/// Classifies a request by its path.
+///
+/// Templates without placeholders win over ones with, so `/v1/mods/search` isn't mistaken for a mod id.
+pub fn classify(path: &str) -> Route {
+    let template = ROUTE_TEMPLATES.iter().find(|template| !template.contains('{') && matches(template, path))
+        .or_else(|| ROUTE_TEMPLATES.iter().find(|template| matches(template, path)));
+    match template {
+        Some(template) => Route { template: Cow::Borrowed(template), known: true },
+        None => Route {
+            template: Cow::Owned(path.split('/')
+                .map(|segment| if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) { "{id}" } else { segment })
+                .collect::<Vec<_>>()
+                .join("/")),
+            known: false,
+        },
+    }
+}
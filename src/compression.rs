@@ -0,0 +1,149 @@
+//! Compression of response bodies with gzip, brotli or zstd, for the cache.
+//!
+//! With `CACHE_COMPRESSION` set to one of `gzip`, `br` or `zstd`, cached bodies are kept compressed -
+//! Curseforge's JSON shrinks to a fraction of its size. They're sent compressed to clients whose
+//! `Accept-Encoding` allows it, saving egress too, recompressed for clients that only accept another of the
+//! three, and decompressed for all others. Bodies Curseforge already sent gzip compressed are handled the same way.
+
+use std::env;
+use std::io::{Read, Write};
+use hyper::header::ACCEPT_ENCODING;
+use hyper::HeaderMap;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// How cached bodies are compressed, if at all. Read from the `CACHE_COMPRESSION` env variable.
+    pub static ref CACHE_COMPRESSION: Option<Coding> = match env::var("CACHE_COMPRESSION").unwrap_or(String::from("none")).as_str() {
+        "none" => None,
+        coding => Some(Coding::from_name(coding).expect("Expected CACHE_COMPRESSION env var to be one of none, gzip, br or zstd")),
+    };
+}
+
+/// A content coding the proxy can compress & decompress bodies with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Coding {
+    /// The codings in the order they're preferred in when recompressing a body: the smallest output first.
+    pub const PREFERRED: [Coding; 3] = [Coding::Brotli, Coding::Zstd, Coding::Gzip];
+
+    /// Returns the coding with the name used in `Content-Encoding`, if it's one the proxy supports.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "br" => Some(Coding::Brotli),
+            "zstd" => Some(Coding::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the coding, as used in `Content-Encoding`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Coding::Gzip => "gzip",
+            Coding::Brotli => "br",
+            Coding::Zstd => "zstd",
+        }
+    }
+
+    /// Compresses `data` with the coding.
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Coding::Gzip => gzip(data),
+            Coding::Brotli => {
+                let mut out = Vec::new();
+                let mut writer = brotli::CompressorWriter::new(&mut out, 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                writer.write_all(data).expect("Expected writing to memory not to fail");
+                drop(writer);
+                out
+            }
+            Coding::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).expect("Expected zstd compression in memory not to fail"),
+        }
+    }
+
+    /// Decompresses `data`, failing if it's invalid or decompresses to more than `max_len` bytes.
+    pub fn decompress(self, data: &[u8], max_len: usize) -> Result<Vec<u8>, String> {
+        match self {
+            Coding::Gzip => gunzip(data, max_len),
+            Coding::Brotli => read_limited(brotli::Decompressor::new(data, 4096), max_len),
+            Coding::Zstd => read_limited(zstd::stream::read::Decoder::new(data).map_err(|err| err.to_string())?, max_len),
+        }
+    }
+}
+
+/// How hard brotli tries, from 0 to 11. Higher levels take much longer for little gain on JSON.
+const BROTLI_QUALITY: u32 = 5;
+/// The log2 of brotli's window size.
+const BROTLI_WINDOW: u32 = 22;
+/// How hard zstd tries, from 1 to 22.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Bodies smaller than this aren't worth compressing.
+pub const MIN_COMPRESS_BYTES: usize = 256;
+
+/// Returns the coding of [`Coding::PREFERRED`] the client that sent `headers` accepts, if any.
+pub fn preferred_coding(headers: &HeaderMap) -> Option<Coding> {
+    Coding::PREFERRED.into_iter().find(|coding| accepts_encoding(headers, coding.name()))
+}
+
+/// Returns whether the client that sent `headers` accepts gzip compressed bodies.
+pub fn accepts_gzip(headers: &HeaderMap) -> bool {
+    accepts_encoding(headers, "gzip")
+}
+
+/// Returns whether the client that sent `headers` accepts bodies with the content coding, going by its
+/// `Accept-Encoding`. Unencoded bodies are always accepted.
+pub fn accepts_encoding(headers: &HeaderMap, coding: &str) -> bool {
+    let coding = match coding.trim().to_ascii_lowercase() {
+        coding if coding == "x-gzip" => String::from("gzip"),
+        coding => coding,
+    };
+    if coding == "identity" {
+        return true;
+    }
+    let mut explicit = None;
+    let mut any = None;
+    for entry in headers.get_all(ACCEPT_ENCODING).iter().filter_map(|value| value.to_str().ok()).flat_map(|value| value.split(',')) {
+        let mut params = entry.split(';');
+        let name = match params.next().unwrap_or_default().trim().to_ascii_lowercase() {
+            name if name == "x-gzip" => String::from("gzip"),
+            name => name,
+        };
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q=").or_else(|| param.trim().strip_prefix("Q=")))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name == coding {
+            explicit = Some(quality);
+        } else if name == "*" {
+            any = Some(quality);
+        }
+    }
+    explicit.or(any).map(|quality| quality > 0.0).unwrap_or(false)
+}
+
+/// Compresses `data` into a gzip stream.
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(data).expect("Expected writing to memory not to fail");
+    encoder.finish().expect("Expected writing to memory not to fail")
+}
+
+/// Decompresses a gzip stream, failing if it's invalid or decompresses to more than `max_len` bytes.
+pub fn gunzip(data: &[u8], max_len: usize) -> Result<Vec<u8>, String> {
+    read_limited(flate2::read::GzDecoder::new(data), max_len)
+}
+
+/// Reads everything from a decoder, failing if it's invalid or decodes to more than `max_len` bytes.
+fn read_limited(decoder: impl Read, max_len: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    decoder.take(max_len.saturating_add(1) as u64).read_to_end(&mut out).map_err(|err| err.to_string())?;
+    match out.len() > max_len {
+        true => Err(format!("decompresses to more than {} bytes", max_len)),
+        false => Ok(out),
+    }
+}
@@ -0,0 +1,127 @@
+//! A limit on the requests the proxy handles at once.
+//!
+//! Once `MAX_IN_FLIGHT_REQUESTS` requests are being handled, further requests are shed with a fast `503`
+//! instead of queueing up behind requests the proxy can't serve in time anyways. The number of handled &
+//! shed requests is part of the [metrics](crate::metrics).
+//!
+//! Calls to the CF api can be limited adaptively too, with `ADAPTIVE_CONCURRENCY_MAX`: the limit grows by about
+//! one per round of calls that answer faster than `SLO_LATENCY_MS`, and shrinks by a tenth whenever a call is
+//! slower or times out (AIMD). When Curseforge slows down, the proxy sheds calls it couldn't get answered in
+//! time anyways, instead of piling them up.
+
+use std::env;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use lazy_static::lazy_static;
+use crate::slo;
+
+lazy_static! {
+    /// The limit of this process. Read from the `MAX_IN_FLIGHT_REQUESTS` env variable, `0` means no limit.
+    pub static ref IN_FLIGHT_LIMIT: ConcurrencyLimit = ConcurrencyLimit::new(
+        env::var("MAX_IN_FLIGHT_REQUESTS").unwrap_or(String::from("0"))
+            .parse::<usize>().expect("Expected MAX_IN_FLIGHT_REQUESTS env var to contain a number")
+    );
+
+    /// The adaptive limit of calls to the CF api. Read from the `ADAPTIVE_CONCURRENCY_MAX` env variable,
+    /// `None` if it's `0`.
+    pub static ref UPSTREAM_CONCURRENCY: Option<AdaptiveLimit> = match env::var("ADAPTIVE_CONCURRENCY_MAX").unwrap_or(String::from("0"))
+        .parse::<usize>().expect("Expected ADAPTIVE_CONCURRENCY_MAX env var to contain a number")
+    {
+        0 => None,
+        max => Some(AdaptiveLimit::new(max, slo::SLO.config().latency_threshold)),
+    };
+}
+
+/// Factor the adaptive limit shrinks by when a call is too slow.
+const BACKOFF: f64 = 0.9;
+
+/// A counting semaphore that never waits: a permit is either available right away, or not at all.
+#[derive(Debug, Default)]
+pub struct ConcurrencyLimit {
+    /// Permits available in total, `0` for unlimited.
+    limit: AtomicUsize,
+    in_flight: AtomicUsize,
+}
+
+impl ConcurrencyLimit {
+    /// Creates a limit of `limit` permits, `0` for unlimited.
+    pub fn new(limit: usize) -> Self {
+        ConcurrencyLimit { limit: AtomicUsize::new(limit), in_flight: AtomicUsize::new(0) }
+    }
+
+    /// Returns the number of permits, `0` if unlimited.
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of permits that are taken.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Changes the number of permits. Permits taken beyond the new limit stay valid until they're dropped.
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    /// Takes a permit, which is given back when dropped. Returns `None` if all permits are taken.
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        let limit = self.limit();
+        let taken = self.in_flight.fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_flight| {
+            match limit == 0 || in_flight < limit {
+                true => Some(in_flight + 1),
+                false => None,
+            }
+        });
+        taken.ok().map(|_| Permit(self))
+    }
+}
+
+/// A permit of a [`ConcurrencyLimit`].
+#[derive(Debug)]
+pub struct Permit<'a>(&'a ConcurrencyLimit);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A [`ConcurrencyLimit`] between 1 and a maximum, tuned by the latency of the calls it lets through.
+#[derive(Debug)]
+pub struct AdaptiveLimit {
+    permits: ConcurrencyLimit,
+    max: usize,
+    /// Calls slower than this shrink the limit.
+    target: Duration,
+    /// The limit, with the fractions of additive increases.
+    limit: Mutex<f64>,
+}
+
+impl AdaptiveLimit {
+    /// Creates a limit starting at `max`.
+    pub fn new(max: usize, target: Duration) -> Self {
+        AdaptiveLimit { permits: ConcurrencyLimit::new(max), max, target, limit: Mutex::new(max as f64) }
+    }
+
+    /// Returns the current limit.
+    pub fn limit(&self) -> usize {
+        self.permits.limit()
+    }
+
+    /// Takes a permit for a call, see [`ConcurrencyLimit::try_acquire`].
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        self.permits.try_acquire()
+    }
+
+    /// Tunes the limit by the latency of a call. `timed_out` calls shrink it, like slow ones.
+    pub fn record(&self, latency: Duration, timed_out: bool) {
+        let mut limit = self.limit.lock().unwrap();
+        *limit = match timed_out || latency > self.target {
+            true => (*limit * BACKOFF).max(1.0),
+            false => (*limit + 1.0 / *limit).min(self.max as f64),
+        };
+        self.permits.set_limit(*limit as usize);
+    }
+}
@@ -0,0 +1,174 @@
+//! How a proxy handles requests: where it sends them to the CF api, with which keys, which paths it forwards
+//! under which base path, how clients are rate limited and how long responses are cached.
+//!
+//! Everything is read from the environment by default (`CF_API_URL`, `CF_API_KEYS` / `CF_API_KEY` - see
+//! [`keys`] -, `STRICT_PASSTHROUGH`, `ALLOWED_PATHS`, `BASE_PATH`, `REQUIRE_TOKEN`, `REQ_LIMIT_PER_HOUR`,
+//! `ANONYMOUS_REQ_LIMIT_PER_HOUR`, `RATE_LIMIT_MAX_WAIT_SECS`, `CACHE_TTL_SECS` and `CACHE_MAX_ENTRIES`).
+//! Applications embedding the proxy can configure each of them per proxy instead, through
+//! [`ProxyState::with_config`](crate::server::ProxyState::with_config) - e.g. to run two proxies with different
+//! keys or limits in one process. Proxies with different keys should not share a response cache, see
+//! [`ProxyState::with_cache`](crate::server::ProxyState::with_cache). All other settings are read from the
+//! environment once per process, and shared by every proxy in it.
+
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+use lazy_static::lazy_static;
+use crate::keys::{self, KeyPool};
+use crate::{cache, limiter, routes, server, Upstream, CF_API, STRICT_PASSTHROUGH};
+
+lazy_static! {
+    /// The configuration read from the environment.
+    pub(crate) static ref ENV_CONFIG: Arc<ProxyConfig> = Arc::new(ProxyConfig::from_env());
+}
+
+/// How a proxy handles requests, see the [module docs](self). Settings that aren't set are read from the
+/// environment.
+#[derive(Debug, Default)]
+pub struct ProxyConfig {
+    /// The CF api, the one in `CF_API_URL` if unset.
+    cf_api: Option<Upstream>,
+    /// The CF api keys, the process' [`keys::KEY_POOL`] if unset.
+    keys: Option<KeyPool>,
+    strict_passthrough: Option<bool>,
+    allowed_paths: Option<Vec<String>>,
+    base_path: Option<String>,
+    require_token: Option<bool>,
+    req_limit_per_hour: Option<NonZeroU32>,
+    anonymous_req_limit_per_hour: Option<Option<NonZeroU32>>,
+    rate_limit_max_wait: Option<Option<Duration>>,
+    cache: Option<(Duration, usize)>,
+}
+
+impl ProxyConfig {
+    /// Creates the configuration read from the environment, like the binary uses.
+    pub fn from_env() -> Self {
+        ProxyConfig::default()
+    }
+
+    /// Sends requests to the CF api at `url`, instead of the one in `CF_API_URL`.
+    pub fn with_api_url(self, url: &str) -> Result<Self, String> {
+        Ok(ProxyConfig { cf_api: Some(Upstream::try_parse(url)?), ..self })
+    }
+
+    /// Uses `keys` for requests to the CF api, instead of the keys in `CF_API_KEYS` / `CF_API_KEY`. They're
+    /// rotated & sidelined like those (see [`keys`]), but not reloaded on `SIGHUP`.
+    pub fn with_api_keys<'a>(self, keys: impl IntoIterator<Item = &'a str>) -> Result<Self, String> {
+        let keys = KeyPool::new(keys, *keys::KEY_ROTATION, *keys::KEY_SIDELINE)
+            .ok_or_else(|| String::from("expected at least one CF api key, all of them valid header values"))?;
+        Ok(ProxyConfig { keys: Some(keys), ..self })
+    }
+
+    /// Passes requests & responses through unchanged or not, instead of following `STRICT_PASSTHROUGH`.
+    pub fn with_strict_passthrough(self, strict: bool) -> Self {
+        ProxyConfig { strict_passthrough: Some(strict), ..self }
+    }
+
+    /// Only forwards paths matching one of the globs to the CF api, instead of those in `ALLOWED_PATHS`.
+    pub fn with_allowed_paths<'a>(self, globs: impl IntoIterator<Item = &'a str>) -> Self {
+        let globs = globs.into_iter().map(str::trim).filter(|glob| !glob.is_empty()).map(String::from).collect();
+        ProxyConfig { allowed_paths: Some(globs), ..self }
+    }
+
+    /// Mounts the proxy under `path`, instead of the `BASE_PATH`.
+    pub fn with_base_path(self, path: &str) -> Self {
+        ProxyConfig { base_path: Some(routes::normalize_base_path(path)), ..self }
+    }
+
+    /// Rejects requests without a proxy token or not, instead of following `REQUIRE_TOKEN`.
+    pub fn with_require_token(self, require: bool) -> Self {
+        ProxyConfig { require_token: Some(require), ..self }
+    }
+
+    /// Allows `limit` requests per hour per IP, instead of `REQ_LIMIT_PER_HOUR`.
+    pub fn with_req_limit_per_hour(self, limit: NonZeroU32) -> Self {
+        ProxyConfig { req_limit_per_hour: Some(limit), ..self }
+    }
+
+    /// Allows `limit` unsigned requests per hour per IP if request signing is enabled, instead of
+    /// `ANONYMOUS_REQ_LIMIT_PER_HOUR`. Unsigned requests are rejected with `None`.
+    pub fn with_anonymous_req_limit_per_hour(self, limit: Option<NonZeroU32>) -> Self {
+        ProxyConfig { anonymous_req_limit_per_hour: Some(limit), ..self }
+    }
+
+    /// Lets requests wait at most `wait` for a rate limiter, instead of `RATE_LIMIT_MAX_WAIT_SECS`. Requests
+    /// wait as long as needed with `None`.
+    pub fn with_rate_limit_max_wait(self, wait: Option<Duration>) -> Self {
+        ProxyConfig { rate_limit_max_wait: Some(wait), ..self }
+    }
+
+    /// Caches up to `max_entries` responses for `ttl`, instead of following `CACHE_TTL_SECS` &
+    /// `CACHE_MAX_ENTRIES`. Caching is disabled with a `ttl` of `0`.
+    pub fn with_cache(self, ttl: Duration, max_entries: usize) -> Self {
+        ProxyConfig { cache: Some((ttl, max_entries)), ..self }
+    }
+
+    /// Returns the CF api.
+    pub(crate) fn cf_api(&self) -> &Upstream {
+        self.cf_api.as_ref().unwrap_or_else(|| &CF_API)
+    }
+
+    /// Returns the CF api keys.
+    pub fn key_pool(&self) -> &KeyPool {
+        // The process' keys are only read if they're used, they may not be configured otherwise
+        self.keys.as_ref().unwrap_or_else(|| &keys::KEY_POOL)
+    }
+
+    /// Returns whether requests & responses are passed through unchanged, apart from the host & api key.
+    pub fn strict_passthrough(&self) -> bool {
+        self.strict_passthrough.unwrap_or_else(|| *STRICT_PASSTHROUGH)
+    }
+
+    /// Returns the globs of the paths that are forwarded to the CF api.
+    pub fn allowed_paths(&self) -> &[String] {
+        self.allowed_paths.as_deref().unwrap_or_else(|| &routes::ALLOWED_PATHS)
+    }
+
+    /// Returns the path the proxy is mounted under, without a trailing `/`.
+    pub fn base_path(&self) -> &str {
+        self.base_path.as_deref().unwrap_or_else(|| &routes::BASE_PATH)
+    }
+
+    /// Returns whether requests without a proxy token are rejected.
+    pub fn require_token(&self) -> bool {
+        self.require_token.unwrap_or_else(|| *server::REQUIRE_TOKEN)
+    }
+
+    /// Returns how many requests per hour are allowed per IP.
+    pub fn req_limit_per_hour(&self) -> u32 {
+        self.req_limit_per_hour.map_or_else(|| *server::REQ_LIMIT_PER_HOUR, NonZeroU32::get)
+    }
+
+    /// Returns how many unsigned requests per hour are allowed per IP, if request signing is enabled.
+    pub fn anonymous_req_limit_per_hour(&self) -> Option<u32> {
+        match self.anonymous_req_limit_per_hour {
+            Some(limit) => limit.map(NonZeroU32::get),
+            None => *server::ANONYMOUS_REQ_LIMIT_PER_HOUR,
+        }
+    }
+
+    /// Returns how long a request may wait for a rate limiter, `None` if as long as needed.
+    pub fn rate_limit_max_wait(&self) -> Option<Duration> {
+        self.rate_limit_max_wait.unwrap_or_else(|| *limiter::RATE_LIMIT_MAX_WAIT)
+    }
+
+    /// Returns how long responses are cached, caching is disabled if `0`.
+    pub fn cache_ttl(&self) -> Duration {
+        self.cache.map_or_else(|| *cache::CACHE_TTL, |(ttl, _)| ttl)
+    }
+
+    /// Returns how many responses are cached at most.
+    pub fn cache_max_entries(&self) -> usize {
+        self.cache.map_or_else(|| *cache::CACHE_MAX_ENTRIES, |(_, max_entries)| max_entries)
+    }
+
+    /// Returns whether the rate limits per IP are set here, rather than read from the environment.
+    pub(crate) fn sets_rate_limits(&self) -> bool {
+        self.req_limit_per_hour.is_some() || self.anonymous_req_limit_per_hour.is_some()
+    }
+
+    /// Returns whether the cache is set up here, rather than read from the environment.
+    pub(crate) fn sets_cache(&self) -> bool {
+        self.cache.is_some()
+    }
+}
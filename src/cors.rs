@@ -0,0 +1,107 @@
+//! CORS headers, so browser-based tools can call the proxy.
+//!
+//! CORS is enabled by listing the origins that may call the proxy in `CORS_ALLOWED_ORIGINS` (comma separated,
+//! `*` for any origin). Responses to requests from those origins carry `Access-Control-*` headers allowing the
+//! methods in `CORS_ALLOWED_METHODS` and the request headers in `CORS_ALLOWED_HEADERS`. Nothing is added with
+//! `STRICT_PASSTHROUGH`.
+//!
+//! Preflight requests (`OPTIONS` with an `Access-Control-Request-Method` header) are answered by the proxy
+//! itself, before any authentication - browsers send them without credentials, and Curseforge rejects them.
+
+use std::env;
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use crate::{signing, tokens};
+
+lazy_static! {
+    /// The policy of this process, read from the `CORS_*` env variables. `None` if no origin is allowed.
+    pub static ref CORS_POLICY: Option<CorsPolicy> = {
+        let origins = list_from_env("CORS_ALLOWED_ORIGINS", "");
+        let methods = list_from_env("CORS_ALLOWED_METHODS", "GET, HEAD, POST, OPTIONS");
+        let default_headers = format!("content-type, authorization, {}, {}, {}",
+            tokens::TOKEN_HEADER.as_str(), signing::TIMESTAMP_HEADER, signing::SIGNATURE_HEADER);
+        let headers = list_from_env("CORS_ALLOWED_HEADERS", &default_headers);
+        let max_age = env::var("CORS_MAX_AGE_SECS").unwrap_or(String::from("600"))
+            .parse::<u64>().expect("Expected CORS_MAX_AGE_SECS env var to contain a number");
+        match origins.is_empty() {
+            true => None,
+            false => Some(CorsPolicy::new(origins, &methods, &headers)
+                .expect("Expected CORS_ALLOWED_METHODS & CORS_ALLOWED_HEADERS env vars to contain valid header values")
+                .with_max_age(max_age)),
+        }
+    };
+}
+
+/// Returns whether the request is a CORS preflight.
+pub fn is_preflight(req: &Request<Body>) -> bool {
+    req.method() == Method::OPTIONS && req.headers().contains_key(ORIGIN) && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+fn list_from_env(var: &str, default: &str) -> Vec<String> {
+    env::var(var).unwrap_or(String::from(default))
+        .split(',')
+        .map(|entry| entry.trim().to_string())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+/// Which origins may call the proxy, and with which methods & headers.
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    /// Allowed origins, `*` allows any.
+    origins: Vec<String>,
+    methods: HeaderValue,
+    headers: HeaderValue,
+    /// How long browsers may cache preflight responses, in seconds.
+    max_age: u64,
+}
+
+impl CorsPolicy {
+    /// Creates a policy. Fails if the methods or headers don't make a valid header value.
+    pub fn new(origins: Vec<String>, methods: &[String], headers: &[String]) -> Result<Self, String> {
+        let to_value = |list: &[String]| HeaderValue::from_str(&list.join(", ")).map_err(|e| e.to_string());
+        Ok(CorsPolicy { origins, methods: to_value(methods)?, headers: to_value(headers)?, max_age: 600 })
+    }
+
+    /// Lets browsers cache preflight responses for `secs` seconds.
+    pub fn with_max_age(self, secs: u64) -> Self {
+        CorsPolicy { max_age: secs, ..self }
+    }
+
+    /// Returns whether requests from the origin are allowed.
+    pub fn allows(&self, origin: &HeaderValue) -> bool {
+        self.origins.iter().any(|allowed| allowed == "*" || origin.as_bytes() == allowed.as_bytes())
+    }
+
+    /// Adds the CORS headers to a response to a request from `origin`, if the origin is allowed.
+    pub fn apply(&self, origin: &HeaderValue, headers: &mut HeaderMap) {
+        if !self.allows(origin) {
+            return;
+        }
+        match self.origins.iter().any(|allowed| allowed == "*") {
+            true => headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*")),
+            false => {
+                // The response depends on the origin, caches must not hand it to other origins
+                headers.append(VARY, HeaderValue::from_static("origin"));
+                headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone())
+            }
+        };
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, self.methods.clone());
+        headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, self.headers.clone());
+    }
+
+    /// Answers a preflight request. Preflights from origins that aren't allowed are answered without CORS
+    /// headers, so the browser blocks the actual request.
+    pub fn preflight_response(&self, req: &Request<Body>) -> Response<Body> {
+        let mut response = Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap();
+        if let Some(origin) = req.headers().get(ORIGIN).filter(|origin| self.allows(origin)) {
+            self.apply(origin, response.headers_mut());
+            response.headers_mut().insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(self.max_age));
+        }
+        response
+    }
+}
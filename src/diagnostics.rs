@@ -0,0 +1,419 @@
+//! Diagnostic reports about the running proxy.
+//!
+//! Sending `SIGUSR1` to the process dumps a report to the log, which helps debugging on hosts where
+//! nothing but the logs is reachable. `GET /_status` answers with the runtime statistics as JSON, for curl &
+//! dashboards. On graceful shutdown, a summary of the run is logged as a single JSON line.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::time::Instant;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use crate::breaker::{BreakerState, BREAKER};
+use crate::cache::CacheStats;
+use crate::concurrency::{IN_FLIGHT_LIMIT, UPSTREAM_CONCURRENCY};
+use crate::metrics::{RouteLatency, RunStats, METRICS};
+use crate::server::ProxyState;
+use crate::slo::{SloReport, SLO};
+
+lazy_static! {
+    /// When the proxy was started. Initialized on first access, so the server should access it on startup.
+    pub static ref STARTED_AT: Instant = Instant::now();
+}
+
+/// All environment variables the proxy is configured with.
+pub const CONFIG_VARS: &[&str] = &[
+    "ACCESS_RULES",
+    "ACME_DIR",
+    "ACME_DIRECTORY_URL",
+    "ACME_DOMAINS",
+    "ACME_EMAIL",
+    "ACME_HTTP_PORT",
+    "ADAPTIVE_CONCURRENCY_MAX",
+    "ALL_PROXY",
+    "ALLOWED_PATHS",
+    "ANONYMOUS_REQ_LIMIT_PER_HOUR",
+    "AWS_ACCESS_KEY_ID",
+    "AWS_DEFAULT_REGION",
+    "AWS_ENDPOINT_URL",
+    "AWS_REGION",
+    "AWS_SECRET_ACCESS_KEY",
+    "AWS_SESSION_TOKEN",
+    "BACKGROUND_REQ_LIMIT_PER_HOUR",
+    "BASE_PATH",
+    "BATCH_CHUNK_SIZE",
+    "BEARER_TOKENS_FILE",
+    "CACHE_COMPRESSION",
+    "CACHE_MAX_ENTRIES",
+    "CACHE_TTL_SECS",
+    "CF_API_KEY",
+    "CF_API_KEY_FILE",
+    "CF_API_KEYS",
+    "CF_API_KEYS_FILE",
+    "CF_API_URL",
+    "CHECKSUM_TRAILER",
+    "CIRCUIT_BREAKER_OPEN_SECS",
+    "CIRCUIT_BREAKER_THRESHOLD",
+    "CORS_ALLOWED_HEADERS",
+    "CORS_ALLOWED_METHODS",
+    "CORS_ALLOWED_ORIGINS",
+    "CORS_MAX_AGE_SECS",
+    "DNS_CACHE_SECS",
+    "DNS_OVERRIDES",
+    "DNS_SERVERS",
+    "DOWNLOAD_HOSTS",
+    "DOWNLOAD_MIRROR_DIR",
+    "DOWNLOAD_MIRROR_S3_BUCKET",
+    "DOWNLOAD_MIRROR_S3_ENDPOINT",
+    "DOWNLOAD_PROXY",
+    "DOWNLOAD_URL_BASE",
+    "DOWNLOAD_VERIFY_CHECKSUMS",
+    "EXTRA_REQUEST_HEADERS",
+    "EXTRA_RESPONSE_HEADERS",
+    "FALLBACK_API_URL",
+    "FALLBACK_SEND_API_KEY",
+    "FORWARD_CLIENT_HEADERS",
+    "GRAPHQL",
+    "HEADER_READ_TIMEOUT_SECS",
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "IDLE_TIMEOUT_SECS",
+    "KEY_ROTATION",
+    "KEY_SIDELINE_SECS",
+    "LEGACY_API",
+    "LIMITS_PROFILE",
+    "LISTEN",
+    "LISTEN_FDS",
+    "LISTEN_PID",
+    "LISTEN_SOCKET_MODE",
+    "LOG_BODY_BYTES",
+    "MAX_CONNECTIONS_PER_IP",
+    "MAX_IN_FLIGHT_REQUESTS",
+    "MAX_REQUEST_BODY_BYTES",
+    "MAX_REQUESTS_PER_CONNECTION",
+    "MAX_STREAMS_PER_CONNECTION",
+    "METRICS_SNAPSHOT_FILE",
+    "METRICS_SNAPSHOT_INTERVAL_SECS",
+    "MODRINTH_API_URL",
+    "MODRINTH_PROXY",
+    "NO_PROXY",
+    "OTEL_BSP_MAX_QUEUE_SIZE",
+    "OTEL_BSP_SCHEDULE_DELAY",
+    "OTEL_EXPORTER_OTLP_ENDPOINT",
+    "OTEL_EXPORTER_OTLP_HEADERS",
+    "OTEL_EXPORTER_OTLP_PROTOCOL",
+    "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    "OTEL_EXPORTER_OTLP_TRACES_HEADERS",
+    "OTEL_EXPORTER_OTLP_TRACES_PROTOCOL",
+    "OTEL_SDK_DISABLED",
+    "OTEL_SERVICE_NAME",
+    "OTEL_TRACES_EXPORTER",
+    "OTEL_TRACES_SAMPLER",
+    "OTEL_TRACES_SAMPLER_ARG",
+    "PAGINATE_MAX_RESULTS",
+    "PORT",
+    "PREFETCH_NEXT_PAGE",
+    "PROXY_PROTOCOL",
+    "RATE_LIMIT_MAX_WAIT_SECS",
+    "RATE_LIMIT_MAX_WAITERS",
+    "RATE_LIMIT_MAX_WAITERS_PER_CLIENT",
+    "RATE_LIMIT_TIERS",
+    "REAL_IP_HEADER",
+    "REQ_LIMIT_PER_HOUR",
+    "REQUEST_ID_HEADER",
+    "REQUIRE_TOKEN",
+    "RESPONSE_HEADERS_KEEP",
+    "RESPONSE_HEADERS_STRIP",
+    "RETRY_BACKOFF_MS",
+    "REUSE_PORT",
+    "SENTRY_DSN",
+    "SENTRY_ENVIRONMENT",
+    "SENTRY_RELEASE",
+    "SIGNATURE_MAX_AGE_SECS",
+    "SIGNING_SECRET",
+    "SIGNING_SECRET_FILE",
+    "SLO_AVAILABILITY_TARGET",
+    "SLO_BURN_WINDOW_SECS",
+    "SLO_DEGRADE_BELOW",
+    "SLO_DEGRADED_MODE",
+    "SLO_LATENCY_MS",
+    "SLO_LATENCY_TARGET",
+    "SLO_WINDOW_SECS",
+    "STARTUP_KEY_CHECK",
+    "STATSD_FLAVOR",
+    "STATSD_HOST",
+    "STATSD_INTERVAL_SECS",
+    "STATSD_PORT",
+    "STATSD_PREFIX",
+    "STATSD_TAGS",
+    "STRICT_PASSTHROUGH",
+    "TCP_KEEPALIVE_SECS",
+    "TLS_CERT_FILE",
+    "TLS_CLIENT_CA_FILE",
+    "TLS_KEY_FILE",
+    "TOKEN_HEADER",
+    "TOKEN_STORE_FILE",
+    "TOKEN_TIERS",
+    "TRUSTED_PROXIES",
+    "UPSTREAM_H2C",
+    "UPSTREAM_HTTP2",
+    "UPSTREAM_HTTP2_KEEPALIVE_SECS",
+    "UPSTREAM_IP_FAMILY",
+    "UPSTREAM_POOL_IDLE_TIMEOUT_SECS",
+    "UPSTREAM_POOL_MAX_IDLE",
+    "UPSTREAM_PROXIES",
+    "UPSTREAM_REQ_LIMIT_PER_SEC",
+    "UPSTREAM_RETRIES",
+    "UPSTREAM_ROUTES",
+    "UPSTREAM_TIMEOUT_SECS",
+    "UPSTREAM_USER_AGENT",
+    "VAULT_ADDR",
+    "VAULT_NAMESPACE",
+    "VAULT_TOKEN",
+    "VIA_HEADER",
+];
+
+/// Returns a hash over the values of all [`CONFIG_VARS`].
+///
+/// Two processes with the same hash run with the same configuration, without the report revealing any of it.
+pub fn config_hash() -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for var in CONFIG_VARS {
+        var.hash(&mut hasher);
+        env::var(var).ok().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// A snapshot of the state of the running proxy.
+#[derive(Debug, Clone)]
+pub struct DiagnosticReport {
+    /// Open client connections.
+    pub active_connections: u64,
+    /// Calls to the CF api that are waiting for a response.
+    pub upstream_calls_in_flight: u64,
+    /// Requests being handled, and how many may be handled at once (`0` for no limit).
+    pub requests_in_flight: (usize, usize),
+    /// The adaptive limit of calls to the CF api, if enabled.
+    pub upstream_concurrency_limit: Option<usize>,
+    /// IP addresses the rate limiter currently keeps state for.
+    pub rate_limiter_keys: usize,
+    /// Requests rejected by the rate limiter of clients, and by the proxy's rate limit of calls to the CF api.
+    pub rate_limited_requests: (u64, u64),
+    /// Statistics of the response cache.
+    pub cache: CacheStats,
+    /// CF api keys that are not sidelined.
+    pub healthy_api_keys: usize,
+    /// CF api keys in the pool.
+    pub api_keys: usize,
+    /// Requests received since the counters started.
+    pub total_requests: u64,
+    /// Requests made against the CF api key today.
+    pub quota_used_today: u64,
+    /// State of the SLOs.
+    pub slo: SloReport,
+    /// Latencies of requests per route & status class.
+    pub latencies: Vec<RouteLatency>,
+    /// See [`config_hash`].
+    pub config_hash: u64,
+}
+
+impl DiagnosticReport {
+    /// Collects a report about the proxy with `state`.
+    pub fn collect(state: &ProxyState) -> Self {
+        let snapshot = METRICS.snapshot();
+        let run = METRICS.run_stats();
+        let key_pool = state.config().key_pool();
+        DiagnosticReport {
+            active_connections: METRICS.active_connections(),
+            upstream_calls_in_flight: METRICS.upstream_calls_in_flight(),
+            requests_in_flight: (IN_FLIGHT_LIMIT.in_flight(), IN_FLIGHT_LIMIT.limit()),
+            upstream_concurrency_limit: UPSTREAM_CONCURRENCY.as_ref().map(|adaptive| adaptive.limit()),
+            rate_limiter_keys: state.rate_limiter_keys(),
+            rate_limited_requests: (run.rate_limited_requests, run.upstream_throttled),
+            cache: state.cache_stats(),
+            healthy_api_keys: key_pool.healthy(),
+            api_keys: key_pool.len(),
+            total_requests: snapshot.total_requests,
+            quota_used_today: snapshot.quota.used,
+            slo: SLO.report(),
+            latencies: METRICS.latencies(),
+            config_hash: config_hash(),
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "<-> Diagnostic report")?;
+        writeln!(f, "<->   active connections:       {}", self.active_connections)?;
+        writeln!(f, "<->   in-flight upstream calls: {}", self.upstream_calls_in_flight)?;
+        writeln!(f, "<->   in-flight requests:       {}/{}", self.requests_in_flight.0, self.requests_in_flight.1)?;
+        if let Some(limit) = self.upstream_concurrency_limit {
+            writeln!(f, "<->   upstream concurrency:     {}", limit)?;
+        }
+        writeln!(f, "<->   rate limiter keys:        {}", self.rate_limiter_keys)?;
+        writeln!(f, "<->   rate limited requests:    {} by client limits, {} by the upstream limit", self.rate_limited_requests.0, self.rate_limited_requests.1)?;
+        writeln!(f, "<->   cache:                    {} entries (~{} KiB), {} hits, {} stale hits, {} misses, {} evictions",
+            self.cache.entries, self.cache.bytes / 1024, self.cache.hits, self.cache.stale_hits, self.cache.misses, self.cache.evictions)?;
+        writeln!(f, "<->   healthy CF api keys:      {}/{}", self.healthy_api_keys, self.api_keys)?;
+        writeln!(f, "<->   total requests:           {}", self.total_requests)?;
+        writeln!(f, "<->   quota used today:         {}", self.quota_used_today)?;
+        writeln!(f, "<->   availability:             {:.4} (budget left {:.2}, burn rate {:.2})",
+            self.slo.availability, self.slo.availability_budget_remaining, self.slo.availability_burn_rate)?;
+        writeln!(f, "<->   latency:                  {:.4} (budget left {:.2}, burn rate {:.2})",
+            self.slo.latency, self.slo.latency_budget_remaining, self.slo.latency_burn_rate)?;
+        writeln!(f, "<->   degraded:                 {}", self.slo.degraded)?;
+        for latency in &self.latencies {
+            let histogram = &latency.histogram;
+            writeln!(f, "<->   latency of {} {}: {} requests, p50 {}ms, p99 {}ms, max {}ms", latency.route, latency.status, histogram.count,
+                histogram.quantile_ms(0.5).unwrap_or(0), histogram.quantile_ms(0.99).unwrap_or(0), histogram.max_ms)?;
+        }
+        write!(f, "<->   config hash:              {:016x}", self.config_hash)
+    }
+}
+
+/// What's being handled right now.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct InFlightStatus {
+    /// Open client connections.
+    pub connections: u64,
+    /// Requests being handled.
+    pub requests: usize,
+    /// How many requests may be handled at once, `0` for no limit.
+    pub request_limit: usize,
+    /// Calls to the CF api that are waiting for a response.
+    pub upstream_calls: u64,
+}
+
+/// State of the rate limiters.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RateLimiterStatus {
+    /// IP addresses & client certificates the rate limiter keeps state for.
+    pub keys: usize,
+    /// Requests of this run rejected because the client hit its rate limit.
+    pub rate_limited_requests: u64,
+    /// Requests of this run not forwarded because of the proxy's rate limit of calls to the CF api.
+    pub upstream_throttled: u64,
+}
+
+/// Health of the CF api, as seen by the proxy.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamStatus {
+    /// State of the circuit breaker: `closed`, `open` or `half-open`.
+    pub circuit_breaker: &'static str,
+    /// CF api keys that are not sidelined.
+    pub healthy_api_keys: usize,
+    /// CF api keys in the pool.
+    pub api_keys: usize,
+    /// The adaptive limit of calls to the CF api, if enabled.
+    pub concurrency_limit: Option<usize>,
+    /// Requests made against the CF api key today, including previous runs if metrics are persisted.
+    pub quota_used_today: u64,
+    /// Requests forwarded to other upstreams in this run, by their host (see [`upstreams`](crate::upstreams)).
+    pub routed_requests: HashMap<String, u64>,
+    /// Share of proxied requests that succeeded, over the SLO window.
+    pub availability: f64,
+    /// Whether the proxy answers from the cache to save its error budget.
+    pub degraded: bool,
+}
+
+/// What `GET /_status` answers with.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusReport {
+    /// How long the proxy runs for, in seconds.
+    pub uptime_secs: u64,
+    /// Requests received since the counters started, including previous runs if metrics are persisted.
+    pub total_requests: u64,
+    /// Counters of this run.
+    pub run: RunStats,
+    pub in_flight: InFlightStatus,
+    pub cache: CacheStats,
+    pub rate_limiter: RateLimiterStatus,
+    pub upstream: UpstreamStatus,
+    /// Latencies of requests of this run per route & status class.
+    pub latencies: Vec<RouteLatency>,
+}
+
+impl StatusReport {
+    /// Collects the statistics of the proxy with `state`.
+    pub fn collect(state: &ProxyState) -> Self {
+        let snapshot = METRICS.snapshot();
+        let run = METRICS.run_stats();
+        let slo = SLO.report();
+        let key_pool = state.config().key_pool();
+        StatusReport {
+            uptime_secs: STARTED_AT.elapsed().as_secs(),
+            total_requests: snapshot.total_requests,
+            run,
+            in_flight: InFlightStatus {
+                connections: METRICS.active_connections(),
+                requests: IN_FLIGHT_LIMIT.in_flight(),
+                request_limit: IN_FLIGHT_LIMIT.limit(),
+                upstream_calls: METRICS.upstream_calls_in_flight(),
+            },
+            cache: state.cache_stats(),
+            rate_limiter: RateLimiterStatus {
+                keys: state.rate_limiter_keys(),
+                rate_limited_requests: run.rate_limited_requests,
+                upstream_throttled: run.upstream_throttled,
+            },
+            upstream: UpstreamStatus {
+                circuit_breaker: match BREAKER.state() {
+                    BreakerState::Closed { .. } => "closed",
+                    BreakerState::Open { .. } => "open",
+                    BreakerState::HalfOpen { .. } => "half-open",
+                },
+                healthy_api_keys: key_pool.healthy(),
+                api_keys: key_pool.len(),
+                concurrency_limit: UPSTREAM_CONCURRENCY.as_ref().map(|adaptive| adaptive.limit()),
+                quota_used_today: snapshot.quota.used,
+                routed_requests: METRICS.routed_requests(),
+                availability: slo.availability,
+                degraded: slo.degraded,
+            },
+            latencies: METRICS.latencies(),
+        }
+    }
+}
+
+/// A summary of a run of the proxy, logged on shutdown.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShutdownReport {
+    /// Always `"shutdown"`, to tell this event apart from other log lines.
+    pub event: &'static str,
+    /// How long the proxy ran for, in seconds.
+    pub uptime_secs: u64,
+    /// Counters of this run.
+    #[serde(flatten)]
+    pub run: RunStats,
+    /// Requests made against the CF api key today, including previous runs if metrics are persisted.
+    pub quota_used_today: u64,
+    /// Requests forwarded to other upstreams in this run, by their host (see [`upstreams`](crate::upstreams)).
+    pub routed_requests: HashMap<String, u64>,
+    /// See [`config_hash`].
+    pub config_hash: String,
+}
+
+impl ShutdownReport {
+    /// Collects the report for the current run.
+    pub fn collect() -> Self {
+        ShutdownReport {
+            event: "shutdown",
+            uptime_secs: STARTED_AT.elapsed().as_secs(),
+            run: METRICS.run_stats(),
+            quota_used_today: METRICS.snapshot().quota.used,
+            routed_requests: METRICS.routed_requests(),
+            config_hash: format!("{:016x}", config_hash()),
+        }
+    }
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).map_err(|_| fmt::Error)?)
+    }
+}
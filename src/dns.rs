@@ -0,0 +1,203 @@
+//! Resolving the hosts of upstreams.
+//!
+//! By default, hosts are resolved by the system's resolver, and the results cached for `DNS_CACHE_SECS`. With
+//! `DNS_SERVERS`, the proxy asks those servers itself instead (with hickory's resolver, over UDP & over TCP for
+//! answers too large for UDP), and caches the answers for as long as their TTL says. `DNS_OVERRIDES` pins hosts to fixed addresses, like `/etc/hosts` does, without asking anyone.
+//!
+//! Upstreams are connected to over IPv6 & IPv4 alike by default, falling back to the other if connecting over
+//! one takes too long (happy eyeballs). On hosts with broken IPv6 (or IPv4) egress, `UPSTREAM_IP_FAMILY` limits
+//! connections to the family that works, so they don't wait for the fallback.
+
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+use hyper::client::connect::dns::Name;
+use hyper::service::Service;
+use lazy_static::lazy_static;
+use tokio::net::lookup_host;
+
+/// How long a DNS server has to answer a query, before the next one is asked.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    /// The DNS servers hosts are resolved with, instead of the system's resolver. Read from the `DNS_SERVERS`
+    /// env variable, as a comma separated list of `<ip>` or `<ip>:<port>`.
+    pub static ref DNS_SERVERS: Vec<SocketAddr> = env::var("DNS_SERVERS").unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|server| !server.is_empty())
+        .map(|server| server.parse::<SocketAddr>()
+            .or_else(|_| server.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+            .expect("Expected DNS_SERVERS env var to contain IP addresses, optionally with a port"))
+        .collect();
+
+    /// How long the system resolver's results are cached. Read from the `DNS_CACHE_SECS` env variable.
+    pub static ref DNS_CACHE_TTL: Duration = Duration::from_secs(env::var("DNS_CACHE_SECS").unwrap_or(String::from("0"))
+        .parse::<u64>().expect("Expected DNS_CACHE_SECS env var to contain a number"));
+
+    /// Hosts resolved to fixed addresses. Read from the `DNS_OVERRIDES` env variable, as a comma separated list
+    /// of `<host>=<ip>` - a host may be listed more than once.
+    pub static ref DNS_OVERRIDES: HashMap<String, Vec<IpAddr>> = {
+        let mut overrides = HashMap::<String, Vec<IpAddr>>::new();
+        for entry in env::var("DNS_OVERRIDES").unwrap_or_default().split(',').filter(|entry| !entry.trim().is_empty()) {
+            let (host, ip) = entry.split_once('=')
+                .and_then(|(host, ip)| Some((host.trim().to_ascii_lowercase(), ip.trim().parse::<IpAddr>().ok()?)))
+                .expect("Expected DNS_OVERRIDES env var to contain entries of <host>=<ip>");
+            overrides.entry(host).or_default().push(ip);
+        }
+        overrides
+    };
+
+    /// Which addresses upstreams are connected to. Read from the `UPSTREAM_IP_FAMILY` env variable.
+    pub static ref UPSTREAM_IP_FAMILY: IpFamily = env::var("UPSTREAM_IP_FAMILY").unwrap_or(String::from("any"))
+        .parse::<IpFamily>().expect("Expected UPSTREAM_IP_FAMILY env var to be either any, ipv4 or ipv6");
+
+    /// Asks the [`DNS_SERVERS`], if any are configured.
+    static ref SERVERS_RESOLVER: Option<TokioAsyncResolver> = (!DNS_SERVERS.is_empty()).then(servers_resolver);
+
+    /// Resolved hosts, with when their addresses expire.
+    static ref CACHE: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>> = Mutex::new(HashMap::new());
+}
+
+/// Which addresses are connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+    /// IPv6 & IPv4 addresses, with happy eyeballs.
+    Any,
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn includes(&self, ip: &IpAddr) -> bool {
+        match self {
+            IpFamily::Any => true,
+            IpFamily::V4 => ip.is_ipv4(),
+            IpFamily::V6 => ip.is_ipv6(),
+        }
+    }
+}
+
+impl std::str::FromStr for IpFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(IpFamily::Any),
+            "ipv4" => Ok(IpFamily::V4),
+            "ipv6" => Ok(IpFamily::V6),
+            other => Err(format!("unknown IP family `{}`", other)),
+        }
+    }
+}
+
+/// Resolves `host` to the addresses of the [`UPSTREAM_IP_FAMILY`] it has - from [`DNS_OVERRIDES`], the cache,
+/// [`DNS_SERVERS`] or the system's resolver, in that order.
+pub async fn resolve(host: &str) -> io::Result<Vec<IpAddr>> {
+    let ips = resolve_any(host).await?
+        .into_iter()
+        .filter(|ip| UPSTREAM_IP_FAMILY.includes(ip))
+        .collect::<Vec<_>>();
+    if ips.is_empty() {
+        let family = if *UPSTREAM_IP_FAMILY == IpFamily::V4 { "IPv4" } else { "IPv6" };
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no {} addresses", host, family)));
+    }
+    Ok(ips)
+}
+
+async fn resolve_any(host: &str) -> io::Result<Vec<IpAddr>> {
+    let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.').to_ascii_lowercase();
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+    if let Some(ips) = DNS_OVERRIDES.get(&host) {
+        return Ok(ips.clone());
+    }
+    if let Some((ips, expires)) = CACHE.lock().unwrap().get(&host) {
+        if *expires > Instant::now() {
+            return Ok(ips.clone());
+        }
+    }
+
+    let (ips, ttl) = match SERVERS_RESOLVER.as_ref() {
+        Some(resolver) => query_servers(resolver, &host).await?,
+        None => {
+            let ips = lookup_host((host.as_str(), 0)).await?.map(|addr| addr.ip()).collect::<Vec<_>>();
+            (ips, *DNS_CACHE_TTL)
+        }
+    };
+    if ips.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", host)));
+    }
+    if ttl > Duration::ZERO {
+        let mut cache = CACHE.lock().unwrap();
+        let now = Instant::now();
+        cache.retain(|_, (_, expires)| *expires > now);
+        cache.insert(host, (ips.clone(), now + ttl));
+    }
+    Ok(ips)
+}
+
+/// Builds the resolver asking the [`DNS_SERVERS`], in turn. Answers aren't cached by the resolver, [`resolve`]
+/// caches them along with those of the system's resolver.
+fn servers_resolver() -> TokioAsyncResolver {
+    let mut servers = NameServerConfigGroup::new();
+    for server in DNS_SERVERS.iter() {
+        servers.merge(NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true));
+    }
+    let mut options = ResolverOpts::default();
+    options.cache_size = 0;
+    options.timeout = QUERY_TIMEOUT;
+    options.use_hosts_file = false;
+    // Only ask for the records of the family that's connected to
+    options.ip_strategy = match *UPSTREAM_IP_FAMILY {
+        IpFamily::Any => LookupIpStrategy::Ipv4AndIpv6,
+        IpFamily::V4 => LookupIpStrategy::Ipv4Only,
+        IpFamily::V6 => LookupIpStrategy::Ipv6Only,
+    };
+    TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, Vec::new(), servers), options)
+}
+
+/// Asks the [`DNS_SERVERS`] for the addresses of `host`. Returns them with how long they may be cached.
+async fn query_servers(resolver: &TokioAsyncResolver, host: &str) -> io::Result<(Vec<IpAddr>, Duration)> {
+    match resolver.lookup_ip(format!("{}.", host)).await {
+        Ok(lookup) => {
+            let ttl = lookup.valid_until().saturating_duration_since(Instant::now());
+            Ok((lookup.iter().collect(), ttl))
+        }
+        // The host doesn't exist, or has no addresses
+        Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok((Vec::new(), Duration::ZERO)),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+/// Resolves hosts for the upstream client, with [`resolve`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Resolver;
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        Box::pin(async move {
+            // The client sets the port
+            let ips = resolve(name.as_str()).await?;
+            Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect::<Vec<_>>().into_iter())
+        })
+    }
+}
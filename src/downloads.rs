@@ -0,0 +1,282 @@
+//! Routing file downloads through the proxy.
+//!
+//! Files in CF api responses carry a `downloadUrl` on Curseforge's CDN. If `DOWNLOAD_URL_BASE` is set, these
+//! are rewritten to `<DOWNLOAD_URL_BASE>/<modId>/<fileId>`, so clients download through the proxy's host
+//! instead. Rewriting needs the whole JSON document, so successful JSON responses are read into memory before
+//! they are sent on - these are bounded by the CF api's page sizes. Compressed responses are left alone.
+//!
+//! If `DOWNLOAD_PROXY` is enabled, the proxy serves these urls: `GET /download/{modId}/{fileId}` looks up the
+//! file's download url at the CF api (through the cache, like any other request) and streams the file from
+//! the CDN to the client. Only download urls on the `DOWNLOAD_HOSTS` are followed, so a misbehaving upstream
+//! can't make the proxy fetch arbitrary urls. Requests for downloads are authenticated & rate limited like
+//! requests for the CF api.
+//!
+//! Unless `DOWNLOAD_VERIFY_CHECKSUMS` is disabled, complete downloads are checked against the file's SHA-1
+//! hash reported by the CF api, and aborted if they don't match (see [`checksum::with_sha1_check`]) - the
+//! client sees a failed download rather than a corrupted file. Files that don't match never reach the mirror.
+
+use std::env;
+use std::net::IpAddr;
+use std::sync::Arc;
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode, Uri};
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use crate::cache::Cache;
+use crate::config::ProxyConfig;
+use crate::mirror;
+use crate::{checksum, error_response, finish_response, forwarding, gateway_error, proxy_request_with_cache, request_id, response_headers, send_upstream, ProxyError};
+
+lazy_static! {
+    /// Where files are downloaded through the proxy. Read from the `DOWNLOAD_URL_BASE` env variable, download
+    /// urls aren't rewritten if it's empty.
+    pub static ref DOWNLOAD_URL_BASE: Option<String> = env::var("DOWNLOAD_URL_BASE").ok()
+        .map(|base| base.trim().trim_end_matches('/').to_string())
+        .filter(|base| !base.is_empty());
+
+    /// Whether files are downloaded through `/download/{modId}/{fileId}`. Read from the `DOWNLOAD_PROXY` env variable.
+    pub static ref DOWNLOAD_PROXY: bool = env::var("DOWNLOAD_PROXY").unwrap_or(String::from("false"))
+        .parse::<bool>().expect("Expected DOWNLOAD_PROXY env var to be either true or false");
+
+    /// Hosts files are downloaded from. Read from the `DOWNLOAD_HOSTS` env variable, as a comma separated list.
+    pub static ref DOWNLOAD_HOSTS: Vec<String> = env::var("DOWNLOAD_HOSTS").unwrap_or(String::from("edge.forgecdn.net, mediafilez.forgecdn.net"))
+        .split(',')
+        .map(|host| host.trim().to_ascii_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect();
+
+    /// Whether downloads are checked against the hash reported by the CF api. Read from the
+    /// `DOWNLOAD_VERIFY_CHECKSUMS` env variable.
+    pub static ref DOWNLOAD_VERIFY_CHECKSUMS: bool = env::var("DOWNLOAD_VERIFY_CHECKSUMS").unwrap_or(String::from("true"))
+        .parse::<bool>().expect("Expected DOWNLOAD_VERIFY_CHECKSUMS env var to be either true or false");
+}
+
+/// Request headers that are passed on to the CDN, so clients can resume & revalidate downloads.
+const FORWARDED_HEADERS: &[&str] = &["range", "if-range", "if-none-match", "if-modified-since"];
+
+/// How many redirects of the CDN are followed.
+const MAX_REDIRECTS: usize = 5;
+
+/// A file of a mod.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileRef {
+    pub mod_id: u64,
+    pub file_id: u64,
+}
+
+/// Returns the file a `/download/{modId}/{fileId}` path is for, or `None` if it's not a download path.
+pub fn parse_download_path(path: &str) -> Option<FileRef> {
+    let mut segments = path.strip_prefix("/download/")?.trim_end_matches('/').split('/');
+    let file = FileRef {
+        mod_id: segments.next()?.parse().ok()?,
+        file_id: segments.next()?.parse().ok()?,
+    };
+    match segments.next() {
+        Some(_) => None,
+        None => Some(file),
+    }
+}
+
+/// Returns whether files may be downloaded from the url.
+pub fn is_download_host(uri: &Uri) -> bool {
+    uri.host().map(|host| DOWNLOAD_HOSTS.iter().any(|allowed| host.eq_ignore_ascii_case(allowed))).unwrap_or(false)
+}
+
+/// Rewrites the `downloadUrl`s of files in the JSON document to point at `base`, returning `None` if the body
+/// isn't JSON or has no download urls.
+///
+/// A file is any object with a `downloadUrl` along with numeric `id` & `modId` fields, wherever it's nested -
+/// mods list their latest files, fingerprint matches the matched file.
+pub fn rewrite_download_urls(body: &[u8], base: &str) -> Option<Vec<u8>> {
+    let mut document = serde_json::from_slice::<Value>(body).ok()?;
+    match rewrite_value(&mut document, base) {
+        0 => None,
+        _ => serde_json::to_vec(&document).ok(),
+    }
+}
+
+fn rewrite_value(value: &mut Value, base: &str) -> usize {
+    match value {
+        Value::Array(values) => values.iter_mut().map(|value| rewrite_value(value, base)).sum(),
+        Value::Object(object) => {
+            let file = (object.get("modId").and_then(Value::as_u64), object.get("id").and_then(Value::as_u64));
+            let rewritten = match (file, object.get_mut("downloadUrl")) {
+                // Files that can't be downloaded from the api have a `null` url, which stays as it is
+                ((Some(mod_id), Some(file_id)), Some(url @ Value::String(_))) => {
+                    *url = Value::String(format!("{}/{}/{}", base, mod_id, file_id));
+                    1
+                }
+                _ => 0,
+            };
+            rewritten + object.values_mut().map(|value| rewrite_value(value, base)).sum::<usize>()
+        }
+        _ => 0,
+    }
+}
+
+fn is_uncompressed_json(headers: &HeaderMap) -> bool {
+    let is_json = headers.get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(|content_type| content_type.trim_start().to_ascii_lowercase().starts_with("application/json"))
+        .unwrap_or(false);
+    let is_compressed = headers.get(CONTENT_ENCODING)
+        .map(|encoding| encoding.as_bytes() != b"identity")
+        .unwrap_or(false);
+    is_json && !is_compressed
+}
+
+/// Rewrites the download urls of a successful JSON response to point at `base`, other responses are returned
+/// unchanged.
+pub async fn rewrite_response(response: Response<Body>, base: &str) -> Result<Response<Body>, hyper::Error> {
+    if !response.status().is_success() || !is_uncompressed_json(response.headers()) {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let body = match rewrite_download_urls(&body, base) {
+        Some(rewritten) => {
+            parts.headers.insert(CONTENT_LENGTH, rewritten.len().into());
+            Bytes::from(rewritten)
+        }
+        None => body,
+    };
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+#[derive(Deserialize)]
+struct Data<T> {
+    data: T,
+}
+
+#[derive(Deserialize)]
+struct FileHash {
+    value: String,
+    algo: u8,
+}
+
+#[derive(Deserialize)]
+struct FileDetails {
+    #[serde(default)]
+    hashes: Vec<FileHash>,
+}
+
+/// Id of the SHA-1 algorithm in the hashes of files.
+const SHA1_ALGO: u8 = 1;
+
+/// Looks up a path of the CF api, answering with its response if it's not successful.
+async fn lookup<T: DeserializeOwned>(path: String, remote_addr: &IpAddr, cache: &Arc<dyn Cache>, config: &Arc<ProxyConfig>) -> Result<T, Response<Body>> {
+    let lookup = Request::get(path).body(Body::empty()).unwrap();
+    let response = proxy_request_with_cache(lookup, remote_addr, cache, config).await.unwrap_or_else(ProxyError::into_response);
+    if !response.status().is_success() {
+        return Err(response);
+    }
+    match hyper::body::to_bytes(response.into_body()).await.ok().and_then(|body| serde_json::from_slice::<Data<T>>(&body).ok()) {
+        Some(body) => Ok(body.data),
+        None => Err(error_response(StatusCode::BAD_GATEWAY, "Curseforge sent an invalid response or closed the connection")),
+    }
+}
+
+/// Looks up the file's download url at the CF api and streams the file from the CDN, see the [module docs](self).
+///
+/// `req` is the client's request, for the method & the [`FORWARDED_HEADERS`]. Lookups are cached in `cache`, and
+/// made at the CF api in `config`.
+/// Files are served from & stored in the [`mirror`] if it's enabled.
+pub async fn download_file(req: Request<Body>, file: FileRef, remote_addr: &IpAddr, cache: &Arc<dyn Cache>, config: &Arc<ProxyConfig>) -> Response<Body> {
+    // The file's hash is needed to check the download & to find it in the mirror
+    let sha1 = match *DOWNLOAD_VERIFY_CHECKSUMS || mirror::MIRROR.is_some() {
+        true => {
+            let path = format!("/v1/mods/{}/files/{}", file.mod_id, file.file_id);
+            match lookup::<FileDetails>(path, remote_addr, cache, config).await {
+                Ok(details) => details.hashes.into_iter().find(|hash| hash.algo == SHA1_ALGO).map(|hash| hash.value),
+                Err(response) => return response,
+            }
+        }
+        false => None,
+    };
+    let mirrored = mirror::MIRROR.as_ref()
+        .zip(sha1.as_deref().and_then(|sha1| mirror::key(file.file_id, sha1)));
+    if let Some((mirror, key)) = &mirrored {
+        if let Some(response) = mirror.open(key).await {
+            println!("[{}{}] <-> {} => {} (mirrored)", remote_addr, request_id::tag(), req.uri().path(), response.status().as_str());
+            return finish_response(response, remote_addr, req.uri(), config);
+        }
+    }
+
+    let path = format!("/v1/mods/{}/files/{}/download-url", file.mod_id, file.file_id);
+    let mut uri = match lookup::<Option<String>>(path, remote_addr, cache, config).await {
+        Ok(Some(url)) => match url.replace(' ', "%20").parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(_) => return error_response(StatusCode::BAD_GATEWAY, "Curseforge sent an invalid download url"),
+        },
+        Ok(None) => return error_response(StatusCode::NOT_FOUND, "File is not available for download"),
+        Err(response) => return response,
+    };
+
+    let mut redirects = 0;
+    loop {
+        if !is_download_host(&uri) {
+            println!("[{}{}] <!> Not downloading from {}, host isn't allowed", remote_addr, request_id::tag(), uri);
+            return error_response(StatusCode::BAD_GATEWAY, "Curseforge sent a download url on an unknown host");
+        }
+        let mut cdn_req = Request::new(Body::empty());
+        *cdn_req.method_mut() = req.method().clone();
+        *cdn_req.uri_mut() = uri.clone();
+        for name in FORWARDED_HEADERS {
+            if let Some(value) = req.headers().get(*name) {
+                cdn_req.headers_mut().insert(HeaderName::from_static(name), value.clone());
+            }
+        }
+
+        let mut response = match send_upstream(cdn_req, config).await {
+            Ok(response) => response,
+            Err(ProxyError::Upstream(err)) => {
+                eprintln!("[{}{}] <!> Download from {} failed: {:#?}", remote_addr, request_id::tag(), uri, err);
+                let (status, message) = gateway_error(&err);
+                return error_response(status, message);
+            }
+            Err(_) => return error_response(StatusCode::GATEWAY_TIMEOUT, "Curseforge did not answer in time"),
+        };
+        let location = response.headers().get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| location.replace(' ', "%20").parse::<Uri>().ok());
+        match location {
+            Some(location) if response.status().is_redirection() && redirects < MAX_REDIRECTS => {
+                uri = resolve_redirect(&uri, location);
+                redirects += 1;
+            }
+            _ => {
+                println!("[{}{}] <-> {} => {} (from {})", remote_addr, request_id::tag(), req.uri().path(), response.status().as_str(), uri);
+                if !config.strict_passthrough() {
+                    forwarding::strip_hop_by_hop(response.headers_mut());
+                    response_headers::RESPONSE_HEADER_POLICY.apply(response.headers_mut());
+                }
+                // Only complete files can be checked & go to the mirror, not ranges of them
+                let complete = response.status() == StatusCode::OK && req.method() == Method::GET;
+                if let (Some(sha1), true, true) = (&sha1, complete, *DOWNLOAD_VERIFY_CHECKSUMS) {
+                    let (remote_addr, tag, path) = (*remote_addr, request_id::tag(), req.uri().path().to_string());
+                    response = checksum::with_sha1_check(response, sha1, move |actual| {
+                        println!("[{}{}] <!> {} aborted, got sha1 {} from the CDN", remote_addr, tag, path, actual);
+                    });
+                }
+                if let (Some((mirror, key)), true) = (&mirrored, complete) {
+                    response = mirror.store(key, response);
+                }
+                return finish_response(response, remote_addr, req.uri(), config);
+            }
+        }
+    }
+}
+
+/// Resolves a `Location` relative to the url that redirected to it.
+fn resolve_redirect(from: &Uri, location: Uri) -> Uri {
+    if location.authority().is_some() {
+        return location;
+    }
+    let mut parts = location.into_parts();
+    parts.scheme = from.scheme().cloned();
+    parts.authority = from.authority().cloned();
+    Uri::from_parts(parts).unwrap_or_else(|_| from.clone())
+}
@@ -0,0 +1,134 @@
+//! Transformations of the headers of forwarded requests & responses.
+//!
+//! Hop-by-hop headers (RFC 7230, section 6.1) describe a single connection, like `Connection` or
+//! `Transfer-Encoding`, and must not be forwarded by a proxy. They are removed from requests before they go
+//! to the CF api and from responses before they go back to the client - along with every header the
+//! `Connection` header names.
+//!
+//! Credentials & identifying headers of the client (see [`CLIENT_HEADERS`]) are removed from requests too:
+//! Curseforge only gets to see the proxy's api key, not the client's cookies or addresses. Headers that must
+//! reach Curseforge anyways can be listed in `FORWARD_CLIENT_HEADERS`.
+//!
+//! Operators can add headers of their own: `EXTRA_REQUEST_HEADERS` to every request to Curseforge (like a
+//! partner header), `EXTRA_RESPONSE_HEADERS` to every response (like a `Cache-Control` override). Both are
+//! lists of `<name>: <value>`, one per line, and replace headers of the same name.
+//!
+//! Responses are stamped with a `Via` header (`VIA_HEADER`, `1.1 cfproxy/<version>` by default), so clients
+//! and caches can tell they came through the proxy.
+//!
+//! Headers are forwarded as they are with `STRICT_PASSTHROUGH`.
+
+use std::env;
+use hyper::header::{HeaderName, HeaderValue, CONNECTION, VIA};
+use hyper::HeaderMap;
+use lazy_static::lazy_static;
+use crate::REAL_IP_HEADER;
+
+lazy_static! {
+    /// Client headers removed from requests: [`CLIENT_HEADERS`] and the `REAL_IP_HEADER`, apart from the ones
+    /// in the `FORWARD_CLIENT_HEADERS` env variable.
+    pub static ref SCRUBBED_HEADERS: Vec<HeaderName> = {
+        let forwarded: Vec<HeaderName> = env::var("FORWARD_CLIENT_HEADERS").unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| name.parse::<HeaderName>().expect("Expected FORWARD_CLIENT_HEADERS env var to contain a comma separated list of header names"))
+            .collect();
+        CLIENT_HEADERS.iter()
+            .map(|name| HeaderName::from_static(name))
+            .chain(std::iter::once(REAL_IP_HEADER.clone()))
+            .filter(|name| !forwarded.contains(name))
+            .collect()
+    };
+
+    /// Headers added to requests to the CF api. Read from the `EXTRA_REQUEST_HEADERS` env variable.
+    pub static ref EXTRA_REQUEST_HEADERS: HeaderMap = parse_headers(&env::var("EXTRA_REQUEST_HEADERS").unwrap_or_default())
+        .expect("Expected EXTRA_REQUEST_HEADERS env var to contain lines of `<name>: <value>`");
+
+    /// Headers added to responses. Read from the `EXTRA_RESPONSE_HEADERS` env variable.
+    pub static ref EXTRA_RESPONSE_HEADERS: HeaderMap = parse_headers(&env::var("EXTRA_RESPONSE_HEADERS").unwrap_or_default())
+        .expect("Expected EXTRA_RESPONSE_HEADERS env var to contain lines of `<name>: <value>`");
+
+    /// The `Via` entry added to responses. Read from the `VIA_HEADER` env variable, `None` if it's empty.
+    pub static ref VIA_HEADER: Option<HeaderValue> = match env::var("VIA_HEADER") {
+        Ok(via) if via.trim().is_empty() => None,
+        Ok(via) => Some(via.trim().parse::<HeaderValue>().expect("Expected VIA_HEADER env var to contain a valid header value")),
+        Err(_) => Some(HeaderValue::from_static(concat!("1.1 cfproxy/", env!("CARGO_PKG_VERSION")))),
+    };
+}
+
+/// Parses headers given as lines of `<name>: <value>`. Empty lines are skipped.
+pub fn parse_headers(lines: &str) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+    for line in lines.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or_else(|| format!("`{}`: expected `<name>: <value>`", line))?;
+        let name = name.trim().parse::<HeaderName>().map_err(|e| format!("`{}`: {}", line, e))?;
+        let value = value.trim().parse::<HeaderValue>().map_err(|e| format!("`{}`: {}", line, e))?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+/// Replaces headers with the `extra` ones.
+pub fn add_headers(headers: &mut HeaderMap, extra: &HeaderMap) {
+    for name in extra.keys() {
+        headers.remove(name);
+        for value in extra.get_all(name) {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+}
+
+/// Headers carrying credentials or the identity of the client.
+pub const CLIENT_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "x-api-key",
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-real-ip",
+];
+
+/// Headers that only apply to a single connection.
+pub const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "http2-settings",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Removes hop-by-hop headers, and the headers listed in `Connection`.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers.get_all(CONNECTION).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| name.trim().parse::<HeaderName>().ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+/// Removes the [`SCRUBBED_HEADERS`].
+pub fn scrub_client_headers(headers: &mut HeaderMap) {
+    for name in SCRUBBED_HEADERS.iter() {
+        headers.remove(name);
+    }
+}
+
+/// Adds the [`VIA_HEADER`] after the entries of proxies the response went through before.
+pub fn stamp_via(headers: &mut HeaderMap) {
+    if let Some(via) = VIA_HEADER.as_ref() {
+        headers.append(VIA, via.clone());
+    }
+}
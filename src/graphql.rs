@@ -0,0 +1,483 @@
+//! A GraphQL facade over the CF api, answered at `POST /graphql`.
+//!
+//! If `GRAPHQL` is enabled, clients can send queries like
+//!
+//! ```graphql
+//! query($id: Int) {
+//!   mod(id: $id) { name latestFiles { id fileName } }
+//!   categories(gameId: 432) { id name }
+//! }
+//! ```
+//!
+//! and receive only the fields they selected. Each top-level field is resolved with a call to the CF api,
+//! through the cache like any other request - all of them concurrently, and several `mod` fields with a
+//! single batch lookup. The types & fields are those of the CF api's responses, a field the response doesn't
+//! have resolves to `null`. Top-level fields:
+//!
+//! | Field | CF api call |
+//! |---|---|
+//! | `mod(id)` | `GET /v1/mods/{id}` |
+//! | `mods(ids)` | `POST /v1/mods` |
+//! | `file(modId, fileId)` | `GET /v1/mods/{modId}/files/{fileId}` |
+//! | `files(modId, ...)` | `GET /v1/mods/{modId}/files` |
+//! | `search(gameId, ...)` | `GET /v1/mods/search` |
+//! | `categories(gameId, ...)` | `GET /v1/categories` |
+//! | `games` | `GET /v1/games` |
+//!
+//! Arguments other than the ids are passed on as query parameters. Only queries are supported: no
+//! mutations, fragments, directives or introspection. `/graphql` is forwarded like any other path with
+//! `STRICT_PASSTHROUGH`.
+
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::sync::Arc;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use crate::cache::Cache;
+use crate::config::ProxyConfig;
+use crate::{error_response, finish_response, proxy_request_with_cache, request_id, ProxyError};
+
+lazy_static! {
+    /// Whether `POST /graphql` is answered. Read from the `GRAPHQL` env variable.
+    pub static ref GRAPHQL: bool = env::var("GRAPHQL").unwrap_or(String::from("false"))
+        .parse::<bool>().expect("Expected GRAPHQL env var to be either true or false");
+}
+
+/// Path of the GraphQL endpoint.
+pub const GRAPHQL_PATH: &str = "/graphql";
+
+/// How many top-level fields a query may have, each costs a call to the CF api.
+pub const MAX_ROOT_FIELDS: usize = 10;
+
+/// How many mods a `mods` field may look up.
+pub const MAX_MOD_IDS: usize = 50;
+
+/// How deeply selections & values of a query may be nested.
+pub const MAX_DEPTH: usize = 32;
+
+/// A field of a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub alias: Option<String>,
+    pub name: String,
+    pub arguments: Vec<(String, Value)>,
+    /// Selected fields of the field's value, empty for scalars.
+    pub selection: Vec<Field>,
+}
+
+impl Field {
+    /// Returns the key of the field in the response.
+    pub fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Returns the value of an argument.
+    pub fn argument(&self, name: &str) -> Option<&Value> {
+        self.arguments.iter().find(|(argument, _)| argument == name).map(|(_, value)| value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punct(char),
+    Name(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() || c == ',' => {
+                chars.next();
+            }
+            '#' => {
+                while chars.next().map(|c| c != '\n').unwrap_or(false) {}
+            }
+            '{' | '}' | '(' | ')' | '[' | ']' | ':' | '$' | '!' | '=' => {
+                tokens.push(Token::Punct(c));
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            Some(escaped) => text.push(escaped),
+                            None => return Err(String::from("unterminated string")),
+                        },
+                        Some(c) => text.push(c),
+                        None => return Err(String::from("unterminated string")),
+                    }
+                }
+                tokens.push(Token::Str(text));
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+                    number.push(c);
+                    chars.next();
+                }
+                tokens.push(match number.parse::<i64>() {
+                    Ok(int) => Token::Int(int),
+                    Err(_) => Token::Float(number.parse::<f64>().map_err(|_| format!("invalid number `{}`", number))?),
+                });
+            }
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek().filter(|c| **c == '_' || c.is_ascii_alphanumeric()) {
+                    name.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Name(name));
+            }
+            c => return Err(format!("unexpected character `{}`", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    variables: &'a Map<String, Value>,
+    /// How many selections & values the parser is inside of.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.tokens.get(self.position).cloned().ok_or_else(|| String::from("unexpected end of query"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, punct: char) -> bool {
+        match self.peek() {
+            Some(Token::Punct(c)) if *c == punct => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, punct: char) -> Result<(), String> {
+        match self.eat(punct) {
+            true => Ok(()),
+            false => Err(format!("expected `{}`", punct)),
+        }
+    }
+
+    /// Parses something nested in what's being parsed, unless that's nested too deeply already.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(String::from("query is nested too deeply"));
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Token::Name(name) => Ok(name),
+            token => Err(format!("expected a name, found {:?}", token)),
+        }
+    }
+
+    fn document(&mut self) -> Result<Vec<Field>, String> {
+        if let Some(Token::Name(keyword)) = self.peek().cloned() {
+            if keyword != "query" {
+                return Err(format!("`{}` operations aren't supported", keyword));
+            }
+            self.position += 1;
+            if let Some(Token::Name(_)) = self.peek() {
+                self.position += 1;
+            }
+            if self.eat('(') {
+                self.variable_definitions()?;
+            }
+        }
+        let fields = self.nested(Self::selection_set)?;
+        match self.peek() {
+            None => Ok(fields),
+            Some(_) => Err(String::from("only a single operation is supported")),
+        }
+    }
+
+    /// Skips the variable definitions, their values come from the request's `variables`.
+    fn variable_definitions(&mut self) -> Result<(), String> {
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next()? {
+                Token::Punct('(') => depth += 1,
+                Token::Punct(')') => depth -= 1,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Field>, String> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        while !self.eat('}') {
+            fields.push(self.field()?);
+        }
+        Ok(fields)
+    }
+
+    fn field(&mut self) -> Result<Field, String> {
+        let mut name = self.name()?;
+        let mut alias = None;
+        if self.eat(':') {
+            alias = Some(name);
+            name = self.name()?;
+        }
+        let mut arguments = Vec::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                let argument = self.name()?;
+                self.expect(':')?;
+                arguments.push((argument, self.nested(Self::value)?));
+            }
+        }
+        let selection = match self.peek() {
+            Some(Token::Punct('{')) => self.nested(Self::selection_set)?,
+            _ => Vec::new(),
+        };
+        Ok(Field { alias, name, arguments, selection })
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        Ok(match self.next()? {
+            Token::Punct('$') => {
+                let name = self.name()?;
+                self.variables.get(&name).cloned().unwrap_or(Value::Null)
+            }
+            Token::Punct('[') => {
+                let mut values = Vec::new();
+                while !self.eat(']') {
+                    values.push(self.nested(Self::value)?);
+                }
+                Value::Array(values)
+            }
+            Token::Punct('{') => {
+                let mut object = Map::new();
+                while !self.eat('}') {
+                    let name = self.name()?;
+                    self.expect(':')?;
+                    object.insert(name, self.nested(Self::value)?);
+                }
+                Value::Object(object)
+            }
+            Token::Int(int) => json!(int),
+            Token::Float(float) => json!(float),
+            Token::Str(text) => Value::String(text),
+            Token::Name(name) => match name.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                // Enum values
+                _ => Value::String(name),
+            },
+            token => return Err(format!("expected a value, found {:?}", token)),
+        })
+    }
+}
+
+/// Parses a query into its top-level fields, taking the values of variables from `variables`.
+pub fn parse_query(query: &str, variables: &Map<String, Value>) -> Result<Vec<Field>, String> {
+    Parser { tokens: tokenize(query)?, position: 0, variables, depth: 0 }.document()
+}
+
+/// Picks the selected fields from a value of the CF api.
+pub fn project(value: &Value, selection: &[Field]) -> Value {
+    if selection.is_empty() {
+        return value.clone();
+    }
+    match value {
+        Value::Array(values) => Value::Array(values.iter().map(|value| project(value, selection)).collect()),
+        Value::Object(object) => Value::Object(selection.iter()
+            .map(|field| (field.key().to_string(), object.get(&field.name).map(|value| project(value, &field.selection)).unwrap_or(Value::Null)))
+            .collect()),
+        value => value.clone(),
+    }
+}
+
+fn encode_query_value(value: &Value) -> String {
+    let text = match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    };
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Builds the query string of the arguments, leaving out the `skipped` ones.
+fn query_string(field: &Field, skipped: &[&str]) -> String {
+    let params: Vec<String> = field.arguments.iter()
+        .filter(|(name, value)| !skipped.contains(&name.as_str()) && !value.is_null())
+        .map(|(name, value)| format!("{}={}", name, encode_query_value(value)))
+        .collect();
+    match params.is_empty() {
+        true => String::new(),
+        false => format!("?{}", params.join("&")),
+    }
+}
+
+fn id_argument(field: &Field, name: &str) -> Result<u64, String> {
+    field.argument(name).and_then(Value::as_u64).ok_or_else(|| format!("`{}` needs an integer `{}` argument", field.name, name))
+}
+
+/// The CF api call resolving a top-level field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    Get(String),
+    /// A batch lookup of mods.
+    Mods(Vec<u64>),
+}
+
+/// Returns the call resolving a top-level field.
+pub fn call_for(field: &Field) -> Result<Call, String> {
+    Ok(match field.name.as_str() {
+        "mod" => Call::Get(format!("/v1/mods/{}", id_argument(field, "id")?)),
+        "mods" => {
+            let ids = field.argument("ids").and_then(Value::as_array).ok_or_else(|| String::from("`mods` needs an `ids` argument"))?;
+            if ids.len() > MAX_MOD_IDS {
+                return Err(format!("`mods` may look up at most {} ids", MAX_MOD_IDS));
+            }
+            Call::Mods(ids.iter().map(|id| id.as_u64().ok_or_else(|| String::from("`ids` have to be integers"))).collect::<Result<_, _>>()?)
+        }
+        "file" => Call::Get(format!("/v1/mods/{}/files/{}", id_argument(field, "modId")?, id_argument(field, "fileId")?)),
+        "files" => Call::Get(format!("/v1/mods/{}/files{}", id_argument(field, "modId")?, query_string(field, &["modId"]))),
+        "search" => Call::Get(format!("/v1/mods/search{}", query_string(field, &[]))),
+        "categories" => Call::Get(format!("/v1/categories{}", query_string(field, &[]))),
+        "games" => Call::Get(format!("/v1/games{}", query_string(field, &[]))),
+        name => return Err(format!("unknown field `{}`", name)),
+    })
+}
+
+#[derive(Deserialize)]
+struct GraphqlRequest {
+    query: String,
+    #[serde(default)]
+    variables: Option<Map<String, Value>>,
+}
+
+/// Makes a call to the CF api, returning the `data` of its response.
+async fn resolve(call: Call, remote_addr: IpAddr, cache: Arc<dyn Cache>, config: Arc<ProxyConfig>) -> Result<Value, String> {
+    let req = match call {
+        Call::Get(path) => Request::get(path).body(Body::empty()).unwrap(),
+        Call::Mods(ids) => Request::post("/v1/mods")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "modIds": ids }).to_string())).unwrap(),
+    };
+    let response = proxy_request_with_cache(req, &remote_addr, &cache, &config).await.unwrap_or_else(ProxyError::into_response);
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.map_err(|_| String::from("Curseforge closed the connection"))?;
+    if !status.is_success() {
+        return Err(format!("Curseforge answered with {}", status.as_str()));
+    }
+    serde_json::from_slice::<Value>(&body).ok()
+        .map(|mut body| body["data"].take())
+        .ok_or_else(|| String::from("Curseforge sent an invalid response"))
+}
+
+/// Answers a GraphQL request, see the [module docs](self). Calls to the CF api are cached in `cache`.
+pub async fn handle_graphql(req: Request<Body>, remote_addr: &IpAddr, cache: &Arc<dyn Cache>, config: &Arc<ProxyConfig>) -> Response<Body> {
+    let uri = req.uri().clone();
+    let request = match hyper::body::to_bytes(req.into_body()).await.ok().and_then(|body| serde_json::from_slice::<GraphqlRequest>(&body).ok()) {
+        Some(request) => request,
+        None => return error_response(StatusCode::BAD_REQUEST, "Expected a JSON body with a `query`"),
+    };
+    let fields = match parse_query(&request.query, &request.variables.unwrap_or_default()) {
+        Ok(fields) if fields.len() > MAX_ROOT_FIELDS => return graphql_response(Value::Null, vec![format!("at most {} top-level fields are allowed", MAX_ROOT_FIELDS)], remote_addr, &uri, config),
+        Ok(fields) => fields,
+        Err(err) => return graphql_response(Value::Null, vec![err], remote_addr, &uri, config),
+    };
+
+    // Several mods are looked up with a single call
+    let mod_ids: Vec<u64> = fields.iter().filter(|field| field.name == "mod").filter_map(|field| id_argument(field, "id").ok()).collect();
+    let batched = mod_ids.len() > 1;
+    let mut calls: Vec<Result<Call, String>> = fields.iter()
+        .map(|field| match (batched, field.name.as_str()) {
+            (true, "mod") => Err(String::new()),
+            _ => call_for(field),
+        })
+        .collect();
+    if batched {
+        calls.push(Ok(Call::Mods(mod_ids)));
+    }
+    let handles: Vec<_> = calls.into_iter()
+        .map(|call| call.map(|call| request_id::spawn(resolve(call, *remote_addr, cache.clone(), config.clone()))))
+        .collect();
+    let mut results = Vec::with_capacity(handles.len());
+    for handle in handles {
+        results.push(match handle {
+            Ok(handle) => handle.await.unwrap_or_else(|_| Err(String::from("resolving the field failed"))),
+            Err(err) => Err(err),
+        });
+    }
+    let batch = match batched {
+        true => results.pop(),
+        false => None,
+    };
+    let batched_mods: HashMap<u64, Value> = match &batch {
+        Some(Ok(Value::Array(mods))) => mods.iter().filter_map(|m| Some((m.get("id")?.as_u64()?, m.clone()))).collect(),
+        _ => HashMap::new(),
+    };
+
+    let mut data = Map::new();
+    let mut errors = Vec::new();
+    for (field, result) in fields.iter().zip(results) {
+        let result = match (batched, field.name.as_str()) {
+            (true, "mod") => match &batch {
+                Some(Err(err)) => Err(err.clone()),
+                _ => Ok(id_argument(field, "id").ok().and_then(|id| batched_mods.get(&id).cloned()).unwrap_or(Value::Null)),
+            },
+            _ => result,
+        };
+        match result {
+            Ok(value) => {
+                data.insert(field.key().to_string(), project(&value, &field.selection));
+            }
+            Err(err) => {
+                errors.push(format!("{}: {}", field.key(), err));
+                data.insert(field.key().to_string(), Value::Null);
+            }
+        }
+    }
+    graphql_response(Value::Object(data), errors, remote_addr, &uri, config)
+}
+
+fn graphql_response(data: Value, errors: Vec<String>, remote_addr: &IpAddr, uri: &hyper::Uri, config: &ProxyConfig) -> Response<Body> {
+    let mut body = json!({ "data": data });
+    if !errors.is_empty() {
+        body["errors"] = Value::Array(errors.into_iter().map(|message| json!({ "message": message })).collect());
+    }
+    let mut response = Response::new(Body::from(body.to_string()));
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+    finish_response(response, remote_addr, uri, config)
+}
@@ -0,0 +1,68 @@
+//! Guidance for clients on how often to poll the proxy, answered at `GET /_hints`.
+//!
+//! Hints are derived from the running configuration and the proxy's current state, so well-behaved clients
+//! can tune themselves instead of finding the limits by running into them.
+
+use serde::Serialize;
+use crate::server::ProxyState;
+use crate::{classify, routes, slo};
+
+/// Poll intervals are stretched by this factor while the proxy is degraded.
+const DEGRADED_BACKOFF: u64 = 2;
+
+/// Hints for a route of the CF api.
+#[derive(Debug, Serialize)]
+pub struct RouteHint {
+    /// Template of the route (see [`crate::classify`]).
+    pub template: &'static str,
+    /// How long responses are cached, polling more often returns the same response.
+    pub cache_ttl_secs: u64,
+    /// How often to poll the route at most.
+    pub suggested_poll_interval_secs: u64,
+}
+
+/// What `GET /_hints` answers with.
+#[derive(Debug, Serialize)]
+pub struct Hints {
+    /// Requests allowed per hour per IP, for clients without a token.
+    pub requests_per_hour: u32,
+    /// Unsigned requests allowed per hour per IP, if request signing is enabled.
+    pub anonymous_requests_per_hour: Option<u32>,
+    /// The interval at which a client can poll without ever hitting its limit.
+    pub min_poll_interval_secs: u64,
+    /// Whether the proxy is saving its error budget (see [`crate::slo`]), and polls should be spread out further.
+    pub degraded: bool,
+    pub routes: Vec<RouteHint>,
+}
+
+impl Hints {
+    /// Collects hints from the configuration & state of the proxy with `state`.
+    pub fn collect(state: &ProxyState) -> Self {
+        let config = state.config();
+        let min_poll_interval_secs = match config.req_limit_per_hour() {
+            0 => 3600,
+            limit => (3600.0 / limit as f64).ceil() as u64,
+        };
+        let cache_ttl_secs = match state.cache().is_enabled() && !config.strict_passthrough() {
+            true => config.cache_ttl().as_secs(),
+            false => 0,
+        };
+        let degraded = slo::SLO.is_degraded();
+        let backoff = if degraded { DEGRADED_BACKOFF } else { 1 };
+        let routes = classify::ROUTE_TEMPLATES.iter()
+            .filter(|template| routes::is_allowed(template, config))
+            .map(|template| RouteHint {
+                template,
+                cache_ttl_secs,
+                suggested_poll_interval_secs: min_poll_interval_secs.max(cache_ttl_secs) * backoff,
+            })
+            .collect();
+        Hints {
+            requests_per_hour: config.req_limit_per_hour(),
+            anonymous_requests_per_hour: config.anonymous_req_limit_per_hour(),
+            min_poll_interval_secs: min_poll_interval_secs * backoff,
+            degraded,
+            routes,
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Hooks for applications embedding the proxy, to manipulate, audit or block requests without forking it.
+//!
+//! Hooks are registered with [`ProxyState::with_hook`](crate::server::ProxyState::with_hook) and run in the
+//! order they were registered. They see every request before the proxy does anything with it, & every response
+//! right before it's sent - responses of the proxy's own errors go to [`ProxyHook::on_error`] instead of
+//! [`ProxyHook::on_response`].
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+
+/// Custom logic run for each request & response.
+pub trait ProxyHook: Send + Sync {
+    /// Called with each request before the proxy handles it, and may change it. Returning a response answers
+    /// the request with it instead, without handling it any further.
+    fn on_request(&self, _req: &mut Request<Body>, _remote_addr: IpAddr) -> Option<Response<Body>> {
+        None
+    }
+
+    /// Called with each response right before it's sent, unless it's one of the proxy's own errors.
+    fn on_response(&self, _request: &RequestSummary, _response: &mut Response<Body>) {}
+
+    /// Called with the response of an error of the proxy itself (e.g. a rejected request, or Curseforge not
+    /// being reachable) right before it's sent.
+    fn on_error(&self, _request: &RequestSummary, _error: &ErrorInfo, _response: &mut Response<Body>) {}
+}
+
+/// The request a response answers.
+#[derive(Debug, Clone)]
+pub struct RequestSummary {
+    /// The client's IP address, as determined from the forwarding headers if the proxy trusts them.
+    pub remote_addr: IpAddr,
+    pub method: Method,
+    pub uri: Uri,
+}
+
+/// An error of the proxy itself. Responses of such errors carry it as an extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorInfo {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+/// Runs the [`ProxyHook::on_request`] hooks, stopping at the first that answers the request.
+pub(crate) fn on_request(hooks: &[Arc<dyn ProxyHook>], req: &mut Request<Body>, remote_addr: IpAddr) -> Option<Response<Body>> {
+    hooks.iter().find_map(|hook| hook.on_request(req, remote_addr))
+}
+
+/// Runs the [`ProxyHook::on_error`] hooks for the proxy's own errors, the [`ProxyHook::on_response`] hooks for
+/// all other responses.
+pub(crate) fn on_response(hooks: &[Arc<dyn ProxyHook>], request: &RequestSummary, response: &mut Response<Body>) {
+    match response.extensions().get::<ErrorInfo>().cloned() {
+        Some(error) => hooks.iter().for_each(|hook| hook.on_error(request, &error, response)),
+        None => hooks.iter().for_each(|hook| hook.on_response(request, response)),
+    }
+}
@@ -0,0 +1,313 @@
+//! A pool of CF api keys that requests are spread across.
+//!
+//! Keys are read from the `CF_API_KEYS` env variable as a comma separated list, or from `CF_API_KEY` if only a
+//! single key is used (or from the files in `CF_API_KEYS_FILE` / `CF_API_KEY_FILE`, see [`crate::secrets`]). Each request picks a key according to `KEY_ROTATION`:
+//! - `round-robin` (default): use the keys in turn
+//! - `least-used`: use the key that made the fewest requests so far
+//!
+//! When the CF api answers a request with `403` or `429`, the key that was used is sidelined for
+//! `KEY_SIDELINE_SECS` seconds, during which other keys are preferred.
+//!
+//! On startup, every key is checked with a lightweight request. Depending on `STARTUP_KEY_CHECK`, a key that
+//! Curseforge rejects is logged as a warning (`warn`, default) or stops the proxy from starting (`fail`).
+//!
+//! Keys can be rotated without a restart: on `SIGHUP`, the `.env` file, env variables, key files and secret
+//! stores are read again and the pool switches to the new keys. Keys that stay in the pool keep their usage counts and sidelining.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use hyper::StatusCode;
+use hyper::header::HeaderValue;
+use lazy_static::lazy_static;
+use crate::config::ProxyConfig;
+use crate::secrets;
+
+lazy_static! {
+    /// The keys configured in `CF_API_KEYS` (or `CF_API_KEY`).
+    pub static ref KEY_POOL: KeyPool = {
+        let keys = keys_in(&|var| env::var(var).ok()).unwrap_or_else(|e| panic!("Expected CF api keys to be readable: {}", e))
+            .expect("Expected CF_API_KEY or CF_API_KEYS to contain a cf api key");
+        KeyPool::new(split_keys(&keys), *KEY_ROTATION, *KEY_SIDELINE)
+            .expect("Expected CF api keys to be valid header values")
+    };
+
+    /// How keys are picked. Read from the `KEY_ROTATION` env variable.
+    pub static ref KEY_ROTATION: Rotation = env::var("KEY_ROTATION").unwrap_or(String::from("round-robin"))
+        .parse::<Rotation>().expect("Expected KEY_ROTATION env var to be either round-robin or least-used");
+
+    /// How long keys rejected by Curseforge are sidelined. Read from the `KEY_SIDELINE_SECS` env variable.
+    pub static ref KEY_SIDELINE: Duration = Duration::from_secs(env::var("KEY_SIDELINE_SECS").unwrap_or(String::from("300"))
+        .parse::<u64>().expect("Expected KEY_SIDELINE_SECS env var to contain a number"));
+
+    /// How keys are checked on startup. Read from the `STARTUP_KEY_CHECK` env variable.
+    pub static ref STARTUP_KEY_CHECK: KeyCheck = env::var("STARTUP_KEY_CHECK").unwrap_or(String::from("warn"))
+        .parse::<KeyCheck>().expect("Expected STARTUP_KEY_CHECK env var to be one of off, warn or fail");
+}
+
+/// The env variables keys are read from.
+const KEY_VARS: [&str; 4] = ["CF_API_KEYS", "CF_API_KEYS_FILE", "CF_API_KEY", "CF_API_KEY_FILE"];
+
+/// Reads the configured keys from `CF_API_KEYS`, or `CF_API_KEY` if that's unset, looking them up with `lookup`.
+fn keys_in(lookup: secrets::Lookup<'_>) -> Result<Option<String>, String> {
+    match secrets::secret_in("CF_API_KEYS", lookup)?.filter(|keys| !keys.trim().is_empty()) {
+        Some(keys) => Ok(Some(keys)),
+        None => secrets::secret_in("CF_API_KEY", lookup),
+    }
+}
+
+fn split_keys(keys: &str) -> impl Iterator<Item = &str> {
+    keys.split(',').map(str::trim).filter(|key| !key.is_empty())
+}
+
+/// Reads the keys from the `.env` file, env variables and secret stores again, and switches [`KEY_POOL`] to them.
+///
+/// Values of the key variables in the `.env` file win over the process environment here, as that can't be changed
+/// from outside. The process environment itself is left alone, other threads may be reading it. If no valid keys
+/// are configured anymore, the pool keeps using the old keys.
+pub async fn reload_keys() {
+    // Deprecated in favor of loading the file into the process environment, which is what's avoided here
+    #[allow(deprecated)]
+    let file: HashMap<String, String> = dotenv::from_path_iter(".env")
+        .map(|vars| vars.filter_map(Result::ok).collect())
+        .unwrap_or_default();
+    let lookup = |var: &str| match KEY_VARS.contains(&var) {
+        true => file.get(var).cloned().or_else(|| env::var(var).ok()),
+        false => env::var(var).ok().or_else(|| file.get(var).cloned()),
+    };
+    if let Err(e) = secrets::resolve_secrets_in(&lookup).await {
+        return eprintln!("<!> Could not reload CF api keys, {} - keeping the old ones", e);
+    }
+    match keys_in(&lookup) {
+        Ok(Some(keys)) if KEY_POOL.replace_keys(split_keys(&keys)) => println!("<-> Reloaded {} CF api key(s)", KEY_POOL.len()),
+        Err(e) => eprintln!("<!> Could not reload CF api keys, {} - keeping the old ones", e),
+        _ => eprintln!("<!> Could not reload CF api keys, no valid keys configured - keeping the old ones"),
+    }
+}
+
+/// How a key is picked for each request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// Use the keys in turn.
+    RoundRobin,
+    /// Use the key that made the fewest requests so far.
+    LeastUsed,
+}
+
+impl std::str::FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Rotation::RoundRobin),
+            "least-used" => Ok(Rotation::LeastUsed),
+            other => Err(format!("unknown key rotation `{}`", other)),
+        }
+    }
+}
+
+/// What happens if a key is rejected by Curseforge on startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCheck {
+    /// Keys are not checked.
+    Off,
+    /// Rejected keys are logged and sidelined.
+    Warn,
+    /// The proxy doesn't start.
+    Fail,
+}
+
+impl std::str::FromStr for KeyCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(KeyCheck::Off),
+            "warn" => Ok(KeyCheck::Warn),
+            "fail" => Ok(KeyCheck::Fail),
+            other => Err(format!("unknown key check `{}`", other)),
+        }
+    }
+}
+
+/// How long checking a key on startup may take, so an unreachable Curseforge doesn't hold up the start.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Checks every key of `config` (the [`KEY_POOL`], unless it has keys of its own) with a lightweight request
+/// to its CF api.
+///
+/// Rejected keys are sidelined. Returns an error if a key was rejected and `check` is [`KeyCheck::Fail`] -
+/// keys that couldn't be checked because Curseforge is unreachable only cause a warning.
+pub async fn check_keys(config: &ProxyConfig, check: KeyCheck) -> Result<(), String> {
+    if check == KeyCheck::Off {
+        return Ok(());
+    }
+    let mut rejected = Vec::new();
+    for key in config.key_pool().keys() {
+        let result = tokio::time::timeout(CHECK_TIMEOUT, crate::check_api_key(key.clone(), config)).await;
+        match result.map_err(|_| String::from("timed out")).and_then(|result| result.map_err(|err| err.to_string())) {
+            Ok(status) if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN => {
+                eprintln!("<!> ==========================================================================");
+                eprintln!("<!> CF api key #{} was rejected by Curseforge with {} - requests using it will fail!", key.index + 1, status.as_u16());
+                eprintln!("<!> ==========================================================================");
+                config.key_pool().report(&key, StatusCode::FORBIDDEN);
+                rejected.push(format!("#{}", key.index + 1));
+            }
+            Ok(_) => {}
+            Err(err) => eprintln!("<!> Could not check CF api key #{}: {}", key.index + 1, err),
+        }
+    }
+    match (check, rejected.is_empty()) {
+        (KeyCheck::Fail, false) => Err(format!("CF api key(s) {} rejected by Curseforge", rejected.join(", "))),
+        _ => Ok(()),
+    }
+}
+
+/// A key in the pool.
+#[derive(Debug)]
+struct PooledKey {
+    key: HeaderValue,
+    uses: AtomicU64,
+    sidelined_until: Mutex<Option<Instant>>,
+}
+
+impl PooledKey {
+    fn sidelined_until(&self, now: Instant) -> Option<Instant> {
+        self.sidelined_until.lock().unwrap().filter(|until| *until > now)
+    }
+}
+
+/// A key picked for a request. Report the response status back with [`KeyPool::report`].
+#[derive(Debug, Clone)]
+pub struct PickedKey {
+    /// Position of the key in the pool.
+    pub index: usize,
+    /// The key, as a header value for the `x-api-key` header.
+    pub key: HeaderValue,
+}
+
+/// The CF api keys requests are spread across.
+#[derive(Debug)]
+pub struct KeyPool {
+    keys: RwLock<Vec<PooledKey>>,
+    rotation: Rotation,
+    sideline_for: Duration,
+    next: AtomicUsize,
+}
+
+impl KeyPool {
+    /// Creates a pool of the given keys. Returns `None` if there are no keys, or a key contains characters
+    /// that are not allowed in headers.
+    pub fn new<'a>(keys: impl IntoIterator<Item = &'a str>, rotation: Rotation, sideline_for: Duration) -> Option<Self> {
+        let keys = parse_keys(keys)?;
+        Some(KeyPool { keys: RwLock::new(keys), rotation, sideline_for, next: AtomicUsize::new(0) })
+    }
+
+    /// Switches the pool to the given keys. Keys that were in the pool before keep their state.
+    ///
+    /// Returns `false` and keeps the old keys if there are no keys, or a key contains characters that are
+    /// not allowed in headers.
+    pub fn replace_keys<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> bool {
+        let new_keys = match parse_keys(keys) {
+            Some(keys) => keys,
+            None => return false,
+        };
+        let mut keys = self.keys.write().unwrap();
+        let mut old_keys: Vec<PooledKey> = keys.drain(..).collect();
+        *keys = new_keys.into_iter()
+            .map(|new_key| match old_keys.iter().position(|old_key| old_key.key == new_key.key) {
+                Some(i) => old_keys.swap_remove(i),
+                None => new_key,
+            })
+            .collect();
+        true
+    }
+
+    /// Returns the number of keys in the pool.
+    pub fn len(&self) -> usize {
+        self.keys.read().unwrap().len()
+    }
+
+    /// Returns whether the pool has no keys. Always `false`, a pool can't be created without keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns all keys in the pool, without counting them as used.
+    pub fn keys(&self) -> Vec<PickedKey> {
+        self.keys.read().unwrap().iter().enumerate()
+            .map(|(index, key)| PickedKey { index, key: key.key.clone() })
+            .collect()
+    }
+
+    /// Returns the number of keys that are not sidelined.
+    pub fn healthy(&self) -> usize {
+        let now = Instant::now();
+        self.keys.read().unwrap().iter().filter(|key| key.sidelined_until(now).is_none()).count()
+    }
+
+    /// Picks the key to use for a request.
+    ///
+    /// Sidelined keys are only picked if every key is sidelined, in which case the one that gets back
+    /// into rotation first is used.
+    pub fn pick(&self) -> PickedKey {
+        let now = Instant::now();
+        let keys = self.keys.read().unwrap();
+        let healthy: Vec<usize> = (0..keys.len())
+            .filter(|&i| keys[i].sidelined_until(now).is_none())
+            .collect();
+
+        let index = if healthy.is_empty() {
+            (0..keys.len()).min_by_key(|&i| keys[i].sidelined_until(now)).unwrap_or(0)
+        } else {
+            match self.rotation {
+                Rotation::RoundRobin => healthy[self.next.fetch_add(1, Ordering::Relaxed) % healthy.len()],
+                Rotation::LeastUsed => healthy.into_iter()
+                    .min_by_key(|&i| keys[i].uses.load(Ordering::Relaxed))
+                    .unwrap_or(0),
+            }
+        };
+
+        let key = &keys[index];
+        key.uses.fetch_add(1, Ordering::Relaxed);
+        PickedKey { index, key: key.key.clone() }
+    }
+
+    /// Reports the status the CF api answered a request made with the key with.
+    ///
+    /// Sidelines the key if the status indicates that it was rejected or is being rate limited.
+    pub fn report(&self, key: &PickedKey, status: StatusCode) {
+        if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+            return;
+        }
+        // The pool might have been reloaded since the key was picked
+        let keys = self.keys.read().unwrap();
+        let pooled = keys.get(key.index)
+            .filter(|pooled| pooled.key == key.key)
+            .or_else(|| keys.iter().find(|pooled| pooled.key == key.key));
+        if let Some(pooled) = pooled {
+            let mut sidelined_until = pooled.sidelined_until.lock().unwrap();
+            if sidelined_until.map(|until| until <= Instant::now()).unwrap_or(true) {
+                eprintln!("<!> CF api key #{} was answered with {}, sidelining it for {}s", key.index + 1, status.as_u16(), self.sideline_for.as_secs());
+            }
+            *sidelined_until = Some(Instant::now() + self.sideline_for);
+        }
+    }
+}
+
+/// Turns keys into pooled keys, `None` if there are none or one is not a valid header value.
+fn parse_keys<'a>(keys: impl IntoIterator<Item = &'a str>) -> Option<Vec<PooledKey>> {
+    let keys = keys.into_iter()
+        .map(|key| {
+            let mut key = HeaderValue::from_str(key).ok()?;
+            key.set_sensitive(true);
+            Some(PooledKey { key, uses: AtomicU64::new(0), sidelined_until: Mutex::new(None) })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    match keys.is_empty() {
+        true => None,
+        false => Some(keys),
+    }
+}
@@ -0,0 +1,250 @@
+//! Translation of the legacy `addons-ecs.forgesvc.net` api.
+//!
+//! Older tools still speak the api Curseforge ran before CF Core. If `LEGACY_API` is enabled, the proxy accepts
+//! the routes of that api under `/api/v2/`, translates them to the corresponding `/v1` calls, and reshapes
+//! the responses: results aren't wrapped in `data`, and the fields legacy clients read (like `websiteUrl`,
+//! `attachments` and `gameVersion`) are added next to their CF Core counterparts. Download urls &
+//! descriptions are answered as plain text, like the legacy api did. Nothing is translated with
+//! `STRICT_PASSTHROUGH`.
+//!
+//! | Legacy route | CF Core route |
+//! |---|---|
+//! | `GET /api/v2/addon/{id}` | `GET /v1/mods/{id}` |
+//! | `POST /api/v2/addon` with `[ids]` | `POST /v1/mods` |
+//! | `GET /api/v2/addon/search` | `GET /v1/mods/search` |
+//! | `GET /api/v2/addon/{id}/description` | `GET /v1/mods/{id}/description` |
+//! | `GET /api/v2/addon/{id}/files` | `GET /v1/mods/{id}/files` |
+//! | `GET /api/v2/addon/{id}/file/{fileId}` | `GET /v1/mods/{id}/files/{fileId}` |
+//! | `GET /api/v2/addon/{id}/file/{fileId}/download-url` | `GET /v1/mods/{id}/files/{fileId}/download-url` |
+//! | `POST /api/v2/fingerprint` with `[fingerprints]` | `POST /v1/fingerprints` |
+
+use std::env;
+use std::net::IpAddr;
+use std::sync::Arc;
+use hyper::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use lazy_static::lazy_static;
+use serde_json::{json, Map, Value};
+use crate::cache::Cache;
+use crate::config::ProxyConfig;
+use crate::{error_response, finish_response, proxy_request_with_cache, ProxyError};
+
+lazy_static! {
+    /// Whether the legacy api is translated. Read from the `LEGACY_API` env variable.
+    pub static ref LEGACY_API: bool = env::var("LEGACY_API").unwrap_or(String::from("false"))
+        .parse::<bool>().expect("Expected LEGACY_API env var to be either true or false");
+}
+
+/// Search parameters of the legacy api, and their CF Core names.
+const SEARCH_PARAMS: &[(&str, &str)] = &[
+    ("gameId", "gameId"),
+    ("sectionId", "classId"),
+    ("categoryId", "categoryId"),
+    ("gameVersion", "gameVersion"),
+    ("searchFilter", "searchFilter"),
+    ("sort", "sortField"),
+    ("index", "index"),
+    ("pageSize", "pageSize"),
+];
+
+/// How the response to a translated request is reshaped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    Addon,
+    Addons,
+    Description,
+    Files,
+    File,
+    DownloadUrl,
+    Fingerprints,
+}
+
+/// A legacy request, translated to the CF api.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Translation {
+    /// Path & query of the CF Core route.
+    pub path: String,
+    /// Field the ids of a legacy batch body go into, for `POST` routes.
+    pub body_field: Option<&'static str>,
+    pub shape: Shape,
+}
+
+/// Translates a request for the legacy api, returns `None` if it's not for one of its routes.
+pub fn translate(method: &Method, uri: &Uri) -> Option<Translation> {
+    let segments: Vec<&str> = uri.path().strip_prefix("/api/v2/")?.trim_end_matches('/').split('/').collect();
+    let id = |segment: &str| segment.parse::<u64>().ok();
+    let (path, body_field, shape) = match (method, segments.as_slice()) {
+        (&Method::POST, ["addon"]) => (String::from("/v1/mods"), Some("modIds"), Shape::Addons),
+        (&Method::POST, ["fingerprint"]) => (String::from("/v1/fingerprints"), Some("fingerprints"), Shape::Fingerprints),
+        (&Method::GET, ["addon", "search"]) => (format!("/v1/mods/search?{}", search_query(uri.query().unwrap_or(""))), None, Shape::Addons),
+        (&Method::GET, ["addon", mod_id]) => (format!("/v1/mods/{}", id(mod_id)?), None, Shape::Addon),
+        (&Method::GET, ["addon", mod_id, "description"]) => (format!("/v1/mods/{}/description", id(mod_id)?), None, Shape::Description),
+        (&Method::GET, ["addon", mod_id, "files"]) => (format!("/v1/mods/{}/files", id(mod_id)?), None, Shape::Files),
+        (&Method::GET, ["addon", mod_id, "file", file_id]) => (format!("/v1/mods/{}/files/{}", id(mod_id)?, id(file_id)?), None, Shape::File),
+        (&Method::GET, ["addon", mod_id, "file", file_id, "download-url"]) => {
+            (format!("/v1/mods/{}/files/{}/download-url", id(mod_id)?, id(file_id)?), None, Shape::DownloadUrl)
+        }
+        _ => return None,
+    };
+    Some(Translation { path, body_field, shape })
+}
+
+fn search_query(query: &str) -> String {
+    query.split('&')
+        .filter_map(|param| param.split_once('='))
+        .filter_map(|(name, value)| {
+            let (_, renamed) = SEARCH_PARAMS.iter().find(|(legacy, _)| *legacy == name)?;
+            Some(format!("{}={}", renamed, value))
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Adds the fields of legacy mods to a CF Core mod.
+pub fn legacy_mod(mut value: Value) -> Value {
+    let object = match value.as_object_mut() {
+        Some(object) => object,
+        None => return value,
+    };
+    let website_url = object.get("links").and_then(|links| links.get("websiteUrl")).cloned().unwrap_or(Value::Null);
+    object.insert(String::from("websiteUrl"), website_url);
+
+    let attachment = |image: &Value, is_default: bool| json!({
+        "id": image["id"],
+        "title": image["title"],
+        "thumbnailUrl": image["thumbnailUrl"],
+        "url": image["url"],
+        "isDefault": is_default,
+    });
+    let logo = object.get("logo").filter(|logo| logo.is_object()).map(|logo| attachment(logo, true));
+    let screenshots = object.get("screenshots").and_then(Value::as_array).cloned().unwrap_or_default();
+    let attachments: Vec<Value> = logo.into_iter().chain(screenshots.iter().map(|image| attachment(image, false))).collect();
+    object.insert(String::from("attachments"), Value::Array(attachments));
+
+    if let Some(indexes) = object.get("latestFilesIndexes").and_then(Value::as_array) {
+        let latest: Vec<Value> = indexes.iter()
+            .map(|index| json!({
+                "gameVersion": index["gameVersion"],
+                "projectFileId": index["fileId"],
+                "projectFileName": index["filename"],
+                "fileType": index["releaseType"],
+                "modLoader": index["modLoader"],
+            }))
+            .collect();
+        object.insert(String::from("gameVersionLatestFiles"), Value::Array(latest));
+    }
+    if let Some(files) = object.remove("latestFiles") {
+        object.insert(String::from("latestFiles"), map_array(files, legacy_file));
+    }
+    value
+}
+
+/// Adds the fields of legacy files to a CF Core file.
+pub fn legacy_file(mut value: Value) -> Value {
+    let object = match value.as_object_mut() {
+        Some(object) => object,
+        None => return value,
+    };
+    let game_versions = object.get("gameVersions").cloned().unwrap_or_else(|| json!([]));
+    object.insert(String::from("gameVersion"), game_versions);
+    if let Some(dependencies) = object.get_mut("dependencies").and_then(Value::as_array_mut) {
+        for dependency in dependencies.iter_mut().filter_map(Value::as_object_mut) {
+            add_alias(dependency, "modId", "addonId");
+            add_alias(dependency, "relationType", "type");
+        }
+    }
+    if let Some(modules) = object.get_mut("modules").and_then(Value::as_array_mut) {
+        for module in modules.iter_mut().filter_map(Value::as_object_mut) {
+            add_alias(module, "name", "foldername");
+        }
+    }
+    value
+}
+
+/// Copies a field under its legacy name.
+fn add_alias(object: &mut Map<String, Value>, name: &str, legacy: &str) {
+    if let Some(value) = object.get(name).cloned() {
+        object.insert(legacy.to_string(), value);
+    }
+}
+
+fn map_array(value: Value, map: fn(Value) -> Value) -> Value {
+    match value {
+        Value::Array(values) => Value::Array(values.into_iter().map(map).collect()),
+        value => value,
+    }
+}
+
+/// Reshapes the `data` of a CF Core response like the legacy api's response, see the [module docs](self).
+pub fn reshape(shape: Shape, data: Value) -> Value {
+    match shape {
+        Shape::Addon => legacy_mod(data),
+        Shape::Addons => map_array(data, legacy_mod),
+        Shape::Files => map_array(data, legacy_file),
+        Shape::File => legacy_file(data),
+        Shape::Fingerprints => {
+            let mut data = data;
+            if let Some(matches) = data.get_mut("exactMatches").and_then(Value::as_array_mut) {
+                for found in matches.iter_mut() {
+                    if let Some(file) = found.get_mut("file") {
+                        *file = legacy_file(file.take());
+                    }
+                    if let Some(files) = found.get_mut("latestFiles") {
+                        *files = map_array(files.take(), legacy_file);
+                    }
+                }
+            }
+            data
+        }
+        Shape::Description | Shape::DownloadUrl => data,
+    }
+}
+
+/// Forwards a legacy request to the CF api as translated, and answers like the legacy api. `req` is the
+/// client's request, whose headers are sent along. Responses are cached in `cache`.
+pub async fn proxy_legacy(req: Request<Body>, translation: Translation, remote_addr: &IpAddr, cache: &Arc<dyn Cache>, config: &Arc<ProxyConfig>) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let legacy_uri = parts.uri.clone();
+    let uri = match translation.path.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => return error_response(StatusCode::BAD_REQUEST, "Malformed request"),
+    };
+    let body = match (translation.body_field, hyper::body::to_bytes(body).await) {
+        (_, Err(_)) => return error_response(StatusCode::BAD_REQUEST, "Could not read request body"),
+        (None, Ok(body)) => body.to_vec(),
+        (Some(field), Ok(body)) => match serde_json::from_slice::<Vec<Value>>(&body) {
+            Ok(ids) => json!({ field: ids }).to_string().into_bytes(),
+            Err(_) => return error_response(StatusCode::BAD_REQUEST, "Expected a JSON array of ids"),
+        },
+    };
+
+    let mut v1_req = Request::new(Body::empty());
+    *v1_req.method_mut() = parts.method;
+    *v1_req.uri_mut() = uri;
+    *v1_req.headers_mut() = parts.headers;
+    // Compressed responses couldn't be reshaped
+    v1_req.headers_mut().remove(ACCEPT_ENCODING);
+    if translation.body_field.is_some() {
+        v1_req.headers_mut().insert(CONTENT_LENGTH, body.len().into());
+        v1_req.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    }
+    *v1_req.body_mut() = Body::from(body);
+
+    let response = proxy_request_with_cache(v1_req, remote_addr, cache, config).await.unwrap_or_else(ProxyError::into_response);
+    if !response.status().is_success() {
+        return response;
+    }
+    let data = match hyper::body::to_bytes(response.into_body()).await.ok().and_then(|body| serde_json::from_slice::<Value>(&body).ok()) {
+        Some(mut body) => body["data"].take(),
+        None => return error_response(StatusCode::BAD_GATEWAY, "Curseforge sent an invalid response or closed the connection"),
+    };
+
+    let (content_type, body) = match (translation.shape, reshape(translation.shape, data)) {
+        (Shape::Description, Value::String(text)) => ("text/html; charset=utf-8", text),
+        (Shape::DownloadUrl, Value::String(text)) => ("text/plain; charset=utf-8", text),
+        (_, data) => ("application/json; charset=utf-8", data.to_string()),
+    };
+    let mut response = Response::new(Body::from(body));
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    finish_response(response, remote_addr, &legacy_uri, config)
+}
@@ -1,84 +1,728 @@
-use std::convert::Infallible;
 use std::env;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use hyper::header::{HeaderValue, HeaderName};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use hyper::header::{HeaderValue, HeaderName, CONTENT_TYPE, RETRY_AFTER, USER_AGENT};
 use hyper::http::uri::{Authority, Scheme};
-use hyper::{Body, Client, Request, Response, Uri};
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri, Version};
 use lazy_static::lazy_static;
+use rand::Rng;
+use crate::cache::Cache;
+use crate::config::ProxyConfig;
+use crate::telemetry::SpanKind;
+
+#[cfg(feature = "tls")]
+pub mod acme;
+pub mod batch;
+pub mod bearer;
+pub mod body_limit;
+pub mod breaker;
+pub mod cache;
+pub mod checksum;
+pub mod cidr;
+pub mod classify;
+pub mod compression;
+pub mod concurrency;
+pub mod config;
+pub mod cors;
+pub mod diagnostics;
+pub mod dns;
+pub mod downloads;
+pub mod egress;
+pub mod forwarding;
+pub mod graphql;
+pub mod hints;
+pub mod hooks;
+pub mod keys;
+pub mod legacy;
+pub mod limiter;
+pub mod listener;
+pub mod metering;
+pub mod metrics;
+pub mod mirror;
+pub mod paginate;
+pub mod pool;
+pub mod prefetch;
+pub mod profile;
+pub mod proxy_protocol;
+pub mod request_id;
+pub mod response_headers;
+pub mod routes;
+pub mod rules;
+pub mod secrets;
+pub mod sentry;
+pub mod server;
+pub mod signing;
+pub mod slo;
+pub mod statsd;
+pub mod telemetry;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod tiers;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod tokens;
+pub mod upstreams;
 
 lazy_static! {
-    /// The CF api key used to authenticate requests. Read from the `CF_API_KEY` env variable.
-    static ref CF_API_KEY: String = env::var("CF_API_KEY").expect("Expected CF_API_KEY to contain a cf api key");
+    /// The CF api. Read from the `CF_API_URL` env variable.
+    pub(crate) static ref CF_API: Upstream = Upstream::parse("CF_API_URL",
+        &env::var("CF_API_URL").unwrap_or(String::from("https://api.curseforge.com")));
+
+    /// Where requests go while the CF api is unreachable. Read from the `FALLBACK_API_URL` env variable.
+    static ref FALLBACK_API: Option<Upstream> = env::var("FALLBACK_API_URL").ok()
+        .filter(|url| !url.trim().is_empty())
+        .map(|url| Upstream::parse("FALLBACK_API_URL", url.trim()));
+
+    /// Whether the CF api key is sent to the fallback too. Read from the `FALLBACK_SEND_API_KEY` env variable.
+    static ref FALLBACK_SEND_API_KEY: bool = env::var("FALLBACK_SEND_API_KEY").unwrap_or(String::from("false"))
+        .parse::<bool>().expect("Expected FALLBACK_SEND_API_KEY env var to be either true or false");
+
+    /// How long to wait for the CF api to answer a request. Read from the `UPSTREAM_TIMEOUT_SECS` env variable.
+    pub static ref UPSTREAM_TIMEOUT: Duration = Duration::from_secs(
+        profile::env_or("UPSTREAM_TIMEOUT_SECS", "30")
+            .parse::<u64>().expect("Expected UPSTREAM_TIMEOUT_SECS env var to contain a number")
+    );
+
+    /// How often idempotent requests are retried if the CF api can't be reached. Read from the
+    /// `UPSTREAM_RETRIES` env variable.
+    pub static ref UPSTREAM_RETRIES: u32 = env::var("UPSTREAM_RETRIES").unwrap_or(String::from("2"))
+        .parse::<u32>().expect("Expected UPSTREAM_RETRIES env var to contain a number");
+
+    /// Backoff before the first retry, doubled for every further one. Read from the `RETRY_BACKOFF_MS` env variable.
+    pub static ref RETRY_BACKOFF: Duration = Duration::from_millis(env::var("RETRY_BACKOFF_MS").unwrap_or(String::from("100"))
+        .parse::<u64>().expect("Expected RETRY_BACKOFF_MS env var to contain a number"));
+
+    /// Whether the proxy passes requests and responses through unchanged, apart from the host & api key.
+    /// Read from the `STRICT_PASSTHROUGH` env variable.
+    pub static ref STRICT_PASSTHROUGH: bool = env::var("STRICT_PASSTHROUGH").unwrap_or(String::from("false"))
+        .parse::<bool>().expect("Expected STRICT_PASSTHROUGH env var to be either true or false");
+
+    /// `User-Agent` sent to the CF api in place of the client's, so CF can tell where requests come from. Read
+    /// from the `UPSTREAM_USER_AGENT` env variable, the client's `User-Agent` is forwarded if it's empty.
+    pub static ref UPSTREAM_USER_AGENT: Option<HeaderValue> = match env::var("UPSTREAM_USER_AGENT") {
+        Ok(agent) if agent.trim().is_empty() => None,
+        Ok(agent) => Some(agent.parse::<HeaderValue>().expect("Expected UPSTREAM_USER_AGENT env var to contain a valid header value")),
+        Err(_) => Some(HeaderValue::from_static(concat!("cfproxy/", env!("CARGO_PKG_VERSION"), " (+https://github.com/bmpm-mc/cfproxy)"))),
+    };
+
+    /// Header reverse proxies report the client's IP address in. Read from the `REAL_IP_HEADER` env variable.
+    pub static ref REAL_IP_HEADER: HeaderName = env::var("REAL_IP_HEADER").unwrap_or(String::from("Fly-Client-IP"))
+        .parse::<HeaderName>().expect("Expected REAL_IP_HEADER env var to contain a header name");
+
+    /// Addresses of reverse proxies that are trusted to report the client's IP address. Read from the
+    /// `TRUSTED_PROXIES` env variable, as a comma separated list of IP ranges.
+    pub static ref TRUSTED_PROXIES: Vec<cidr::Cidr> = env::var("TRUSTED_PROXIES").unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|range| !range.is_empty())
+        .map(|range| range.parse::<cidr::Cidr>().expect("Expected TRUSTED_PROXIES env var to contain a comma separated list of IP ranges"))
+        .collect();
+}
+
+/// A server requests are forwarded to.
+#[derive(Debug)]
+pub(crate) struct Upstream {
+    scheme: Scheme,
+    pub(crate) authority: Authority,
+    /// `Host` header of requests to the server.
+    host: HeaderValue,
+}
+
+impl Upstream {
+    /// Parses the base url in the env variable `var`.
+    fn parse(var: &str, url: &str) -> Self {
+        Upstream::try_parse(url).unwrap_or_else(|err| panic!("Expected {} env var to contain a url: {}", var, err))
+    }
+
+    /// Parses a base url.
+    pub(crate) fn try_parse(url: &str) -> Result<Self, String> {
+        let parts = url.parse::<Uri>().map_err(|_| format!("`{}` is not a url", url))?.into_parts();
+        let authority = parts.authority.ok_or_else(|| format!("`{}` has no host", url))?;
+        Ok(Upstream {
+            scheme: parts.scheme.ok_or_else(|| format!("`{}` has no scheme", url))?,
+            host: HeaderValue::from_str(authority.as_str()).map_err(|_| format!("`{}` has an invalid host", url))?,
+            authority,
+        })
+    }
+}
+
+/// Why a request could not be forwarded to the CF api.
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyError {
+    /// The client's request has no path the CF api could be asked for, like `CONNECT` requests.
+    #[error("invalid request: request has no path")]
+    MissingPath(#[source] hyper::http::uri::InvalidUriParts),
+    /// The path left after removing the prefix of an upstream route (see [`upstreams`]) isn't a valid path.
+    #[error("invalid request: invalid path for the upstream")]
+    InvalidPath(#[source] hyper::http::uri::InvalidUri),
+    /// The CF api could not be reached, or its response could not be read.
+    #[error(transparent)]
+    Upstream(#[from] hyper::Error),
+    /// The CF api did not answer within [`UPSTREAM_TIMEOUT`].
+    #[error("no response within {} seconds", UPSTREAM_TIMEOUT.as_secs())]
+    Timeout,
+    /// The circuit breaker is open (see [`breaker`]), requests can be tried again after the duration.
+    #[error("circuit breaker is open")]
+    CircuitOpen(Duration),
+    /// The proxy is at its limit of requests to the CF api (see [`limiter`]), requests can be tried again
+    /// after the duration.
+    #[error("upstream rate limit reached")]
+    Throttled(Duration),
+    /// The adaptive limit of calls to the CF api is reached (see [`concurrency`]).
+    #[error("upstream concurrency limit reached")]
+    Overloaded,
+    /// The lookup of a chunk of a split batch request (see [`batch`]) panicked or was cancelled.
+    #[error("lookup of a chunk failed")]
+    ChunkFailed(#[source] tokio::task::JoinError),
+}
+
+impl ProxyError {
+    /// Returns whether the client's request was at fault, rather than the CF api or the proxy's limits.
+    pub fn is_invalid_request(&self) -> bool {
+        matches!(self, ProxyError::MissingPath(_) | ProxyError::InvalidPath(_))
+    }
+
+    /// Builds the error response the proxy answers with when forwarding failed (see [`error_response`]), with a
+    /// `Retry-After` header for failures that go away by themselves.
+    pub fn into_response(self) -> Response<Body> {
+        match self {
+            ProxyError::MissingPath(_) | ProxyError::InvalidPath(_) => error_response(StatusCode::BAD_REQUEST, "Malformed request"),
+            ProxyError::Upstream(err) => {
+                let (status, message) = gateway_error(&err);
+                error_response(status, message)
+            }
+            ProxyError::Timeout => error_response(StatusCode::GATEWAY_TIMEOUT, "Curseforge did not answer in time"),
+            ProxyError::CircuitOpen(retry_after) => {
+                let response = error_response(StatusCode::SERVICE_UNAVAILABLE, "Curseforge is unavailable, try again later");
+                with_retry_after(response, retry_after)
+            }
+            ProxyError::Throttled(retry_after) => {
+                let response = error_response(StatusCode::SERVICE_UNAVAILABLE, "Too many requests to Curseforge, try again later");
+                with_retry_after(response, retry_after)
+            }
+            ProxyError::Overloaded => {
+                let response = error_response(StatusCode::SERVICE_UNAVAILABLE, "Curseforge is answering slowly, try again later");
+                with_retry_after(response, Duration::from_secs(1))
+            }
+            ProxyError::ChunkFailed(_) => error_response(StatusCode::BAD_GATEWAY, "Could not look up all of the ids"),
+        }
+    }
 }
 
-/// Converts a request to this server into a request that can be made against the Curseforge API.
+/// Converts a request to this server into a request that can be made against the Curseforge API (or another
+/// upstream).
 /// 
 /// Modifies the request by
-/// - replacing the base url with https://api.curseforge.com
-/// - setting the host to api.curseforge.com
-/// - adding the API key read from the env variable
-fn get_proxy_req(mut req: Request<Body>) -> Request<Body> {
-
+/// - replacing the base url with https://api.curseforge.com (or `CF_API_URL`)
+/// - setting the host to api.curseforge.com (or the host of `CF_API_URL`)
+/// - adding the given authentication header (the API key, for the CF api), if any
+/// - replacing the client's `User-Agent` with [`UPSTREAM_USER_AGENT`], unless requests are passed through as-is
+///   (see [`ProxyConfig::strict_passthrough`])
+/// - removing hop-by-hop headers and the client's credentials (see [`forwarding`]), unless requests are passed
+///   through as-is
+///
+/// Fails if the request has no path, like `CONNECT` requests.
+fn get_proxy_req(mut req: Request<Body>, upstream: &Upstream, auth: Option<(HeaderName, HeaderValue)>, config: &ProxyConfig) -> Result<Request<Body>, ProxyError> {
     // Set authority part of URL to the Curseforge API & scheme to HTTPS
     let mut uri_parts = req.uri_mut().clone().into_parts();
-    uri_parts.authority = Some(Authority::from_static("api.curseforge.com"));
-    uri_parts.scheme = Some(Scheme::HTTPS);
-    *req.uri_mut() = Uri::from_parts(uri_parts).unwrap();
+    uri_parts.authority = Some(upstream.authority.clone());
+    uri_parts.scheme = Some(upstream.scheme.clone());
+    *req.uri_mut() = Uri::from_parts(uri_parts).map_err(ProxyError::MissingPath)?;
+
+    // The HTTP version is about the client's connection - the upstream is spoken to with HTTP/2 if it offers it
+    if req.version() == Version::HTTP_2 {
+        *req.version_mut() = Version::HTTP_11;
+    }
 
     // Set HOST header, otherwise CF will reject requests
-    req.headers_mut().insert(HeaderName::from_static("host"), HeaderValue::from_static("api.curseforge.com"));
+    req.headers_mut().insert(HeaderName::from_static("host"), upstream.host.clone());
+
+    // Forward none of the client's credentials, just the proxy's - along with the operator's own headers
+    if !config.strict_passthrough() {
+        forwarding::scrub_client_headers(req.headers_mut());
+        forwarding::add_headers(req.headers_mut(), &forwarding::EXTRA_REQUEST_HEADERS);
+    }
 
     // Set authentification header
-    req.headers_mut().insert("x-api-key", HeaderValue::from_str(&CF_API_KEY[..]).unwrap());
+    if let Some((name, value)) = auth {
+        req.headers_mut().insert(name, value);
+    }
 
-    req
+    // Identify the proxy, rather than whatever app the client is
+    if let Some(agent) = UPSTREAM_USER_AGENT.as_ref().filter(|_| !config.strict_passthrough()) {
+        req.headers_mut().insert(USER_AGENT, agent.clone());
+    }
+
+    // Headers about the client's connection don't apply to the proxy's connection
+    if !config.strict_passthrough() {
+        forwarding::strip_hop_by_hop(req.headers_mut());
+    }
+
+    Ok(req)
+}
+
+fn api_key_header(key: HeaderValue) -> (HeaderName, HeaderValue) {
+    (HeaderName::from_static("x-api-key"), key)
+}
+
+/// Builds a response for a request the proxy could not or would not handle, with a JSON body like
+/// `{"error":{"status":504,"code":"gateway_timeout","message":"..."},"source":"proxy"}`.
+///
+/// `source` tells errors of the proxy apart from errors of the CF api, which are forwarded as they are.
+pub fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    let code = status.canonical_reason().unwrap_or("error").to_ascii_lowercase().replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+    let body = serde_json::json!({
+        "error": { "status": status.as_u16(), "code": code, "message": message },
+        "source": "proxy",
+    });
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .extension(hooks::ErrorInfo { status, message: message.to_string() })
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Adds a `Retry-After` header asking the client to wait at least `wait`, in whole seconds.
+pub fn with_retry_after(mut response: Response<Body>, wait: Duration) -> Response<Body> {
+    response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(wait.as_secs_f64().ceil() as u64));
+    response
 }
 
 /// Returns the IP address of the remote connection.
 /// 
 /// This server might be deployed behind a reverse proxy, in which case the 'real' ip address is
-/// provided in the header 'Fly-Client-IP'
+/// provided in the header 'Fly-Client-IP' (or `REAL_IP_HEADER`). The header is only honored if the connection
+/// comes from one of the [`TRUSTED_PROXIES`], otherwise any client could pick its own address.
+///
+/// `X-Forwarded-For` lists every hop a request went through, and every hop appends the address it received
+/// the request from. Only the hops appended by trusted proxies can be relied on, so the client is the
+/// rightmost address that is not a trusted proxy. Hops left of one that isn't an address can't be relied on
+/// either, then the client is the hop right of it.
 pub fn get_real_ip_addr(req: &Request<Body>, remote_addr: &IpAddr) -> IpAddr {
-    if let Some(client_ip) = req.headers().get("Fly-Client-IP") {
-        let client_ip: String = client_ip.to_str().unwrap().into();
-        if !client_ip.is_empty() {
-            if let Ok(client_ip) = client_ip.parse::<Ipv4Addr>() {
-                return IpAddr::V4(client_ip);
+    let is_trusted = |ip: &IpAddr| TRUSTED_PROXIES.iter().any(|proxy| proxy.contains(ip));
+    if !is_trusted(remote_addr) {
+        return *remote_addr;
+    }
+    if *REAL_IP_HEADER == "x-forwarded-for" {
+        // Proxies may append another header instead of extending the existing one
+        let hops: Vec<Option<IpAddr>> = req.headers().get_all(&*REAL_IP_HEADER).iter()
+            .flat_map(|header| match header.to_str() {
+                Ok(header) => header.split(',').map(|hop| hop.trim().parse::<IpAddr>().ok()).collect(),
+                Err(_) => vec![None],
+            })
+            .collect();
+        let mut client = *remote_addr;
+        for hop in hops.into_iter().rev() {
+            match hop {
+                Some(hop) if is_trusted(&hop) => client = hop,
+                Some(hop) => return hop,
+                None => break,
             }
-            if let Ok(client_ip) = client_ip.parse::<Ipv6Addr>() {
-                return IpAddr::V6(client_ip);
+        }
+        return client;
+    }
+    req.headers().get(&*REAL_IP_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.trim().parse::<IpAddr>().ok())
+        .unwrap_or(*remote_addr)
+}
+
+/// Makes the request against the CF API with the next key from the pool.
+///
+/// Request gets mutated with [`get_proxy_req`], the key is reported back to the pool along with the response status.
+/// Idempotent requests are retried up to [`UPSTREAM_RETRIES`] times if the CF API can't be reached, waiting
+/// a jittered, exponentially growing backoff between tries.
+pub(crate) async fn request_cf(req: Request<Body>, config: &ProxyConfig) -> Result<Response<Body>, ProxyError> {
+    if *UPSTREAM_RETRIES == 0 || !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return request_cf_or_fallback(req, config.key_pool().pick(), config).await;
+    }
+
+    // Keep the body around to send it again
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let mut attempt = 0;
+    loop {
+        match request_cf_or_fallback(rebuild_request(&parts, &body), config.key_pool().pick(), config).await {
+            Err(ProxyError::Upstream(err)) if attempt < *UPSTREAM_RETRIES && is_transient(&err) => {
+                let backoff = *RETRY_BACKOFF * 2u32.pow(attempt);
+                let jitter = rand::thread_rng().gen_range(0..=backoff.as_millis() as u64);
+                println!("{}<!> {} failed ({}), retrying in {}ms", request_id::prefix(), parts.uri.path(), err, jitter);
+                metrics::METRICS.record_upstream_retry();
+                tokio::time::sleep(Duration::from_millis(jitter)).await;
+                attempt += 1;
             }
+            result => return result,
+        }
+    }
+}
+
+/// Builds a copy of a request that was taken apart.
+fn rebuild_request(parts: &hyper::http::request::Parts, body: &Bytes) -> Request<Body> {
+    let mut req = Request::new(Body::from(body.clone()));
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+    if let Some(span) = parts.extensions.get::<telemetry::SpanContext>() {
+        req.extensions_mut().insert(*span);
+    }
+    req
+}
+
+/// Requests with larger bodies aren't kept around to be sent to the [`FALLBACK_API`], unless they may be retried.
+const MAX_FALLBACK_BODY_BYTES: u64 = 64 * 1024;
+
+/// Makes the request against the CF API, or against the [`FALLBACK_API`] if the CF API can't be reached or the
+/// circuit breaker is open.
+async fn request_cf_or_fallback(req: Request<Body>, api_key: keys::PickedKey, config: &ProxyConfig) -> Result<Response<Body>, ProxyError> {
+    let keeps_body = matches!(*req.method(), Method::GET | Method::HEAD)
+        || req.body().size_hint().exact().is_some_and(|len| len <= MAX_FALLBACK_BODY_BYTES);
+    let fallback = match &*FALLBACK_API {
+        Some(fallback) if keeps_body => fallback,
+        _ => return request_cf_with_key(req, api_key, config).await,
+    };
+
+    // Keep the body around to send it to the fallback
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    match request_cf_with_key(rebuild_request(&parts, &body), api_key.clone(), config).await {
+        Err(err) if is_unreachable(&err) => {
+            println!("{}<!> {} failed ({}), forwarding to {}", request_id::prefix(), parts.uri.path(), err, fallback.authority);
+            let auth = Some(api_key_header(api_key.key)).filter(|_| *FALLBACK_SEND_API_KEY);
+            let proxy_req = get_proxy_req(rebuild_request(&parts, &body), fallback, auth, config)?;
+            request_fallback(proxy_req, config).await
         }
+        result => result,
+    }
+}
+
+/// Returns whether a request failed without reaching the CF api.
+fn is_unreachable(err: &ProxyError) -> bool {
+    match err {
+        ProxyError::Upstream(err) => err.is_connect(),
+        ProxyError::CircuitOpen(_) => true,
+        _ => false,
+    }
+}
+
+/// Returns whether a request that failed with the error may succeed when tried again.
+fn is_transient(err: &hyper::Error) -> bool {
+    err.is_connect() || err.is_closed() || err.is_incomplete_message()
+        || std::error::Error::source(err).and_then(|source| source.downcast_ref::<std::io::Error>()).is_some()
+}
+
+/// Makes a lightweight request against the CF API in `config` with the given key, and returns the status it was
+/// answered with.
+pub async fn check_api_key(api_key: keys::PickedKey, config: &ProxyConfig) -> Result<StatusCode, ProxyError> {
+    let req = Request::get("/v1/games?pageSize=1").body(Body::empty()).unwrap();
+    request_cf_with_key(req, api_key, config).await.map(|resp| resp.status())
+}
+
+async fn request_cf_with_key(req: Request<Body>, api_key: keys::PickedKey, config: &ProxyConfig) -> Result<Response<Body>, ProxyError> {
+    // Get new CF api request from current request
+    let proxy_req = get_proxy_req(req, config.cf_api(), Some(api_key_header(api_key.key.clone())), config)?;
+
+    // Fail fast while the CF api is considered down
+    breaker::BREAKER.check().map_err(ProxyError::CircuitOpen)?;
+
+    let result = send_limited(proxy_req, config).await;
+    if let Ok(resp) = &result {
+        config.key_pool().report(&api_key, resp.status());
+    }
+    result
+}
+
+/// Sends a request to the CF api once the upstream rate limit & concurrency limit allow it, and records how long
+/// it took.
+///
+/// The circuit breaker is asked last, so a probe request is only let through when it's actually sent, and always
+/// reported back.
+async fn send_limited(proxy_req: Request<Body>, config: &ProxyConfig) -> Result<Response<Body>, ProxyError> {
+    // Stay under the rate Curseforge allows the proxy as a whole
+    if let Some(upstream_limiter) = &*limiter::UPSTREAM_LIMITER {
+        let mut span = telemetry::span_for(&proxy_req, "upstream rate limit", SpanKind::Internal);
+        let ready = limiter::until_ready(|| limiter::check_direct(upstream_limiter), config.rate_limit_max_wait()).await;
+        span.set_attribute("rate_limit.rejected", ready.is_err());
+        ready.map_err(ProxyError::Throttled)?;
+    }
+
+    // Shed calls beyond what Curseforge currently answers in time
+    let permit = match &*concurrency::UPSTREAM_CONCURRENCY {
+        Some(adaptive) => Some(adaptive.try_acquire().ok_or(ProxyError::Overloaded)?),
+        None => None,
+    };
+
+    breaker::BREAKER.allow().map_err(ProxyError::CircuitOpen)?;
+
+    let in_flight = metrics::METRICS.track_upstream_call();
+    let started = Instant::now();
+    let result = send_upstream(proxy_req, config).await;
+    drop(in_flight);
+    drop(permit);
+    if let Some(adaptive) = &*concurrency::UPSTREAM_CONCURRENCY {
+        adaptive.record(started.elapsed(), matches!(result, Err(ProxyError::Timeout)));
+    }
+    metrics::record_timing("upstream.duration", started.elapsed(), &[]);
+    match &result {
+        Ok(_) => metrics::METRICS.record_upstream_request(),
+        Err(_) => metrics::METRICS.record_upstream_error(),
+    }
+    breaker::BREAKER.record(result.as_ref().map(|resp| !resp.status().is_server_error()).unwrap_or(false));
+    result
+}
+
+/// Sends a request to the `FALLBACK_API_URL`. It's neither held to the limits of calls to the CF api nor counted
+/// against the quota of the api key.
+async fn request_fallback(proxy_req: Request<Body>, config: &ProxyConfig) -> Result<Response<Body>, ProxyError> {
+    let in_flight = metrics::METRICS.track_upstream_call();
+    let result = send_upstream(proxy_req, config).await;
+    drop(in_flight);
+    match &result {
+        Ok(_) => metrics::METRICS.record_fallback_request(),
+        Err(_) => metrics::METRICS.record_upstream_error(),
+    }
+    result
+}
+
+/// Makes the request against the route's upstream, without the route's prefix (see [`upstreams`]).
+async fn request_routed(mut req: Request<Body>, route: &upstreams::UpstreamRoute, config: &ProxyConfig) -> Result<Response<Body>, ProxyError> {
+    let path_and_query = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let stripped = route.strip_prefix(path_and_query).unwrap_or(path_and_query);
+    *req.uri_mut() = stripped.parse::<Uri>().map_err(ProxyError::InvalidPath)?;
+    let proxy_req = get_proxy_req(req, &route.upstream, route.auth.clone(), config)?;
+
+    let in_flight = metrics::METRICS.track_upstream_call();
+    let result = send_upstream(proxy_req, config).await;
+    drop(in_flight);
+    match &result {
+        Ok(_) => metrics::METRICS.record_routed_request(route.authority()),
+        Err(_) => metrics::METRICS.record_upstream_error(),
+    }
+    result
+}
+
+/// Sends a request that was converted with [`get_proxy_req`], waiting at most [`UPSTREAM_TIMEOUT`] for the response.
+pub(crate) async fn send_upstream(mut proxy_req: Request<Body>, config: &ProxyConfig) -> Result<Response<Body>, ProxyError> {
+    let mut span = telemetry::span_for(&proxy_req, format!("upstream {}", proxy_req.method()), SpanKind::Client);
+    if !config.strict_passthrough() {
+        telemetry::propagate(&span, proxy_req.headers_mut());
+    }
+    span.set_attribute("http.request.method", proxy_req.method().as_str());
+    span.set_attribute("server.address", proxy_req.uri().host().unwrap_or_default());
+    span.set_attribute("url.path", proxy_req.uri().path());
+    let result = match tokio::time::timeout(*UPSTREAM_TIMEOUT, pool::client(config.strict_passthrough()).request(proxy_req)).await {
+        Ok(result) => result.map_err(ProxyError::from),
+        Err(_) => Err(ProxyError::Timeout),
+    };
+    match &result {
+        Ok(resp) => {
+            span.set_attribute("http.response.status_code", resp.status().as_u16());
+            if resp.status().is_server_error() {
+                span.set_error(resp.status().as_str());
+            }
+        }
+        Err(err) => span.set_error(err.to_string()),
+    }
+    result
+}
+
+/// Forwards the request to the CF API in `config` (or the upstream its path is routed to, see [`upstreams`])
+/// and returns the response as-is.
+///
+/// Request gets mutated with [`get_proxy_req`]. Unlike [`proxy_request_with_cache`], nothing is cached, and
+/// failures are returned as they are instead of as error responses.
+pub async fn forward_request(req: Request<Body>, config: &Arc<ProxyConfig>) -> Result<Response<Body>, ProxyError> {
+    match (upstreams::route_for(req.uri().path(), config), *batch::BATCH_CHUNK_SIZE > 0 && !config.strict_passthrough()) {
+        (Some(route), _) => request_routed(req, route, config).await,
+        (None, true) => batch::request_cf_in_chunks(req, config).await,
+        (None, false) => request_cf(req, config).await,
     }
-    *remote_addr
 }
 
 /// Forwards the request to the CF API and returns the API's response.
 /// 
-/// Request gets mutated with [`get_proxy_request`], Response gets returned directly.
-/// `remote_addr` is only used for logging.
-pub async fn proxy_request_to_cf(req: Request<Body>, remote_addr: &IpAddr) -> Result<Response<Body>, Infallible> {
-    // Get new CF api request from current request
-    let proxy_req = get_proxy_req(req);
+/// Request gets mutated with [`get_proxy_req`], Response gets returned directly - or from the cache,
+/// if caching is enabled (see [`cache`]).
+/// `remote_addr` is only used for logging. The CF API & keys are those configured in the environment (see
+/// [`config`]). Failures are logged & returned as a [`ProxyError`], [`ProxyError::into_response`] turns them
+/// into the error response the proxy would answer with.
+pub async fn proxy_request_to_cf(req: Request<Body>, remote_addr: &IpAddr) -> Result<Response<Body>, ProxyError> {
+    let cache: Arc<dyn Cache> = cache::CACHE.clone();
+    proxy_request_with_cache(req, remote_addr, &cache, &config::ENV_CONFIG).await
+}
+
+/// Like [`proxy_request_to_cf`], but caches responses in `cache` instead of the process' cache, and sends
+/// requests to the CF API configured in `config`.
+pub async fn proxy_request_with_cache(mut req: Request<Body>, remote_addr: &IpAddr, cache: &Arc<dyn Cache>, config: &Arc<ProxyConfig>) -> Result<Response<Body>, ProxyError> {
+    let started = Instant::now();
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let prefetch = *prefetch::PREFETCH_NEXT_PAGE && req.extensions().get::<prefetch::NoPrefetch>().is_none();
 
-    // Init HTTPS client
-    let https = hyper_tls::HttpsConnector::new();
-    let client = Client::builder().build::<_, Body>(https);
-    let uri = proxy_req.uri().clone();
+    // Answer from the cache if possible - with expired responses too, while the error budget is nearly used up
+    let cache_key = match cache.is_enabled() && !config.strict_passthrough() {
+        true => cache::cache_key(&req),
+        false => None,
+    };
+    let mut span = telemetry::span_for(&req, "cache lookup", SpanKind::Internal);
+    let (cached, label) = match cache_key.as_deref() {
+        Some(key) if slo::SLO.is_degraded() => (cache.get_stale(key), "cached, degraded"),
+        Some(key) => (cache.get(key), "cached"),
+        None => (None, ""),
+    };
+    let cached = cached.filter(|cached| cached.matches(req.headers()));
+    span.set_attribute("cache.hit", cached.is_some());
+    drop(span);
+    if let Some(cached) = cached {
+        println!("[{}{}] <-> {} => {} ({})", remote_addr, request_id::tag(), uri.path(), cached.status.as_str(), label);
+        slo::SLO.record(true, started.elapsed());
+        return Ok(finish_response(cached.to_response_for(req.headers()), remote_addr, &uri, config));
+    }
+    if cache_key.is_some() {
+        cache::normalize_accept_encoding(req.headers_mut());
+    }
+    let headers = cache_key.as_ref().map(|_| req.headers().clone());
 
     // Do request & send back response
-    match client.request(proxy_req).await {
+    let result = match forward_request(req, config).await {
+        Err(err) if err.is_invalid_request() => {
+            println!("[{}{}] <!> {} rejected: {}", remote_addr, request_id::tag(), uri.path(), err);
+            return Err(err);
+        }
+        result => result,
+    };
+    slo::SLO.record(result.as_ref().map(|resp| !resp.status().is_server_error()).unwrap_or(false), started.elapsed());
+    match result {
         Ok(resp) => {
-            println!("[{}] <-> {} => {}", remote_addr.to_string(), uri.path(), resp.status().as_str());
-            Ok::<_, Infallible>(resp)
+            println!("[{}{}] <-> {} => {}", remote_addr, request_id::tag(), uri.path(), resp.status().as_str());
+            let resp = match prepare_response(resp, config).await {
+                Ok(resp) => resp,
+                Err(err) => {
+                    eprintln!("[{}{}] <!> {} failed: {:#?}", remote_addr, request_id::tag(), uri.path(), err);
+                    sentry::capture_upstream_failure(&method, &uri, &err);
+                    return Err(ProxyError::Upstream(err));
+                }
+            };
+
+            let resp = match cache_key {
+                Some(key) if cache::is_cacheable(resp.status(), resp.headers()) => {
+                    let (parts, body) = resp.into_parts();
+                    let body = match hyper::body::to_bytes(body).await {
+                        Ok(body) => body,
+                        Err(err) => {
+                            eprintln!("[{}{}] <!> {} failed: {:#?}", remote_addr, request_id::tag(), uri.path(), err);
+                            sentry::capture_upstream_failure(&method, &uri, &err);
+                            return Err(ProxyError::Upstream(err));
+                        }
+                    };
+                    let headers = headers.unwrap_or_default();
+                    let cached = cache::CachedResponse::new(parts.status, parts.headers, body.clone()).varying_on(&headers);
+                    let resp = cached.to_response_for(&headers);
+                    cache.insert(key, cached);
+                    if prefetch {
+                        prefetch::prefetch_next_page(&uri, headers, &body, cache.clone(), config.clone());
+                    }
+                    resp
+                }
+                _ => resp,
+            };
+            Ok(finish_response(resp, remote_addr, &uri, config))
         }
         Err(err) => {
-            eprintln!("[{}] <!> {} failed: {:#?}", remote_addr.to_string(), uri.path(), err);
-            Ok::<_, Infallible>(Response::builder()
-                .status(500)
-                .body(Body::from("Proxy Server Error while reading request"))
-                .unwrap()
-            )
+            match &err {
+                ProxyError::Timeout => {
+                    eprintln!("[{}{}] <!> {} timed out after {}s", remote_addr, request_id::tag(), uri.path(), UPSTREAM_TIMEOUT.as_secs());
+                    sentry::capture_upstream_failure(&method, &uri, &err);
+                }
+                ProxyError::CircuitOpen(_) => {
+                    println!("[{}{}] <!> {} not forwarded, circuit breaker is open", remote_addr, request_id::tag(), uri.path());
+                }
+                ProxyError::Throttled(_) => {
+                    println!("[{}{}] <!> {} not forwarded, upstream rate limit reached", remote_addr, request_id::tag(), uri.path());
+                    metrics::METRICS.record_upstream_throttled();
+                }
+                ProxyError::Overloaded => {
+                    println!("[{}{}] <!> {} not forwarded, upstream concurrency limit reached", remote_addr, request_id::tag(), uri.path());
+                }
+                ProxyError::Upstream(cause) => {
+                    eprintln!("[{}{}] <!> {} failed: {:#?}", remote_addr, request_id::tag(), uri.path(), cause);
+                    sentry::capture_upstream_failure(&method, &uri, cause);
+                }
+                ProxyError::ChunkFailed(cause) => {
+                    eprintln!("[{}{}] <!> {} failed, lookup of a chunk failed: {}", remote_addr, request_id::tag(), uri.path(), cause);
+                    sentry::capture_upstream_failure(&method, &uri, &err);
+                }
+                ProxyError::MissingPath(_) | ProxyError::InvalidPath(_) => {}
+            }
+            Err(err)
         }
     }
-}
\ No newline at end of file
+}
+
+/// Prepares a response of the CF api before it's cached or sent to the client: strips hop-by-hop headers &
+/// those the [header policy](response_headers) doesn't let through, and rewrites download urls (see
+/// [`downloads`]). Responses are left as they are in strict pass-through mode.
+pub(crate) async fn prepare_response(mut resp: Response<Body>, config: &ProxyConfig) -> Result<Response<Body>, hyper::Error> {
+    if config.strict_passthrough() {
+        return Ok(resp);
+    }
+    forwarding::strip_hop_by_hop(resp.headers_mut());
+    response_headers::RESPONSE_HEADER_POLICY.apply(resp.headers_mut());
+    match downloads::DOWNLOAD_URL_BASE.as_deref() {
+        Some(base) => downloads::rewrite_response(resp, base).await,
+        None => Ok(resp),
+    }
+}
+
+/// Picks the status & message to answer with when talking to the CF api failed.
+///
+/// Every failure is a `502`, the message tells apart whether Curseforge couldn't be reached, the TLS handshake
+/// failed, or the connection broke down after it was established.
+pub(crate) fn gateway_error(err: &hyper::Error) -> (StatusCode, &'static str) {
+    let mut source = std::error::Error::source(err);
+    while let Some(cause) = source {
+        if is_tls_failure(cause) {
+            return (StatusCode::BAD_GATEWAY, "TLS handshake with Curseforge failed");
+        }
+        source = cause.source();
+    }
+    match err.is_connect() {
+        true => (StatusCode::BAD_GATEWAY, "Curseforge could not be reached"),
+        false => (StatusCode::BAD_GATEWAY, "Curseforge sent an invalid response or closed the connection"),
+    }
+}
+
+/// Returns whether the error is a failed TLS handshake, or TLS that couldn't be set up.
+#[cfg(feature = "tls")]
+fn is_tls_failure(cause: &(dyn std::error::Error + 'static)) -> bool {
+    let handshake_failed = cause.downcast_ref::<std::io::Error>().and_then(|e| e.get_ref())
+        .map_or(cause.is::<openssl::ssl::Error>(), |inner| inner.is::<openssl::ssl::Error>());
+    handshake_failed || cause.is::<openssl::error::ErrorStack>()
+}
+
+/// Without the `tls` feature, no TLS is set up that could fail.
+#[cfg(not(feature = "tls"))]
+fn is_tls_failure(_cause: &(dyn std::error::Error + 'static)) -> bool {
+    false
+}
+
+/// Adds the extra response headers & the `Via` header (see [`forwarding`]) and hashes the body on its way to the client, if enabled (see
+/// [`checksum`]). Responses are left as they are in strict pass-through mode.
+pub(crate) fn finish_response(mut resp: Response<Body>, remote_addr: &IpAddr, uri: &Uri, config: &ProxyConfig) -> Response<Body> {
+    if config.strict_passthrough() {
+        return resp;
+    }
+    forwarding::add_headers(resp.headers_mut(), &forwarding::EXTRA_RESPONSE_HEADERS);
+    forwarding::stamp_via(resp.headers_mut());
+    if !*checksum::CHECKSUM_TRAILER {
+        return resp;
+    }
+    // The body is hashed in a task of its own, which doesn't know the request's id
+    let (remote_addr, tag, path) = (*remote_addr, request_id::tag(), uri.path().to_string());
+    checksum::with_checksum_trailer(resp, move |checksum| {
+        println!("[{}{}] <-> {} sha256 {}", remote_addr, tag, path, checksum);
+    })
+}
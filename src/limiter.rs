@@ -0,0 +1,187 @@
+//! The per-IP rate limiter, behind a trait so embedders can swap it out (see
+//! [`ProxyState::with_rate_limiter`](crate::server::ProxyState::with_rate_limiter)), and the global limiter
+//! of requests to the CF api.
+//!
+//! Per-IP limits don't bound the sum of all clients' requests, which Curseforge limits per api key. With
+//! `UPSTREAM_REQ_LIMIT_PER_SEC`, requests to the CF api wait for a global budget too - and are shed with `503`
+//! if they'd wait longer than `RATE_LIMIT_MAX_WAIT_SECS`.
+//!
+//! Waiting clients are bounded too: at most `RATE_LIMIT_MAX_WAITERS_PER_CLIENT` requests of a client, and
+//! `RATE_LIMIT_MAX_WAITERS` requests in total, wait for a rate limiter at the same time (see [`WaitQueue`]).
+//! Further requests are rejected with `429` right away, so a flood from one address can't pile up tasks.
+
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::Duration;
+use governor::clock::{Clock, DefaultClock};
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::state::{InMemoryState, NotKeyed};
+use governor::{Quota, RateLimiter};
+use lazy_static::lazy_static;
+use rand::Rng;
+
+lazy_static! {
+    /// How long a request may wait for a rate limiter, before it's rejected with `429` instead. Read from the
+    /// `RATE_LIMIT_MAX_WAIT_SECS` env variable, requests wait as long as needed without it.
+    pub static ref RATE_LIMIT_MAX_WAIT: Option<Duration> = env::var("RATE_LIMIT_MAX_WAIT_SECS").ok()
+        .map(|secs| Duration::from_secs(secs.parse::<u64>().expect("Expected RATE_LIMIT_MAX_WAIT_SECS env var to contain a number")));
+
+    /// Limits requests to the CF api across all clients. Read from the `UPSTREAM_REQ_LIMIT_PER_SEC` env
+    /// variable, `None` if it's `0` or not set.
+    pub static ref UPSTREAM_LIMITER: Option<DirectRateLimiter> = env::var("UPSTREAM_REQ_LIMIT_PER_SEC").ok()
+        .map(|limit| limit.parse::<u32>().expect("Expected UPSTREAM_REQ_LIMIT_PER_SEC env var to contain a number"))
+        .and_then(NonZeroU32::new)
+        .map(|limit| RateLimiter::direct(Quota::per_second(limit)));
+
+    /// Bounds the requests waiting for a rate limiter. Read from the `RATE_LIMIT_MAX_WAITERS_PER_CLIENT` &
+    /// `RATE_LIMIT_MAX_WAITERS` env variables.
+    pub static ref WAIT_QUEUE: WaitQueue = WaitQueue::new(
+        env::var("RATE_LIMIT_MAX_WAITERS_PER_CLIENT").unwrap_or(String::from("10"))
+            .parse::<usize>().expect("Expected RATE_LIMIT_MAX_WAITERS_PER_CLIENT env var to contain a number"),
+        env::var("RATE_LIMIT_MAX_WAITERS").unwrap_or(String::from("1000"))
+            .parse::<usize>().expect("Expected RATE_LIMIT_MAX_WAITERS env var to contain a number"),
+    );
+}
+
+/// The rate limiter the proxy uses by default: a GCRA limiter keyed by IP, on the wall clock.
+pub type IpRateLimiter = RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, DefaultClock>;
+
+/// A GCRA limiter keyed by client identity, on the wall clock.
+pub type IdentityRateLimiter = RateLimiter<String, DefaultKeyedStateStore<String>, DefaultClock>;
+
+/// A GCRA limiter without keys, on the wall clock.
+pub type DirectRateLimiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock>;
+
+/// Takes one request off the limiter's budget, or returns how long to wait until it has budget again.
+pub fn check_direct(limiter: &DirectRateLimiter) -> Result<(), Duration> {
+    limiter.check().map_err(|not_until| not_until.wait_time_from(DefaultClock::default().now()))
+}
+
+/// Takes one request of the client off the limiter's budget, or returns how long to wait until it has budget again.
+pub fn check_identity(limiter: &IdentityRateLimiter, identity: &str) -> Result<(), Duration> {
+    limiter.check_key(&identity.to_string()).map_err(|not_until| not_until.wait_time_from(DefaultClock::default().now()))
+}
+
+/// A rate limiter keyed by client IP.
+pub trait RateLimit: Send + Sync {
+    /// Takes one request off the budget of the IP, or returns how long to wait until it has budget again.
+    fn check_key(&self, key: &IpAddr) -> Result<(), Duration>;
+
+    /// Returns the number of IPs the limiter keeps state for.
+    fn tracked_keys(&self) -> usize;
+}
+
+impl RateLimit for IpRateLimiter {
+    fn check_key(&self, key: &IpAddr) -> Result<(), Duration> {
+        RateLimiter::check_key(self, key).map_err(|not_until| not_until.wait_time_from(DefaultClock::default().now()))
+    }
+
+    fn tracked_keys(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Waits until the limiter allows a request of the IP, adding up to a second of jitter to each wait so
+/// clients that hit the limit together don't retry together.
+///
+/// Returns whether the request had to wait.
+pub async fn until_key_ready(limiter: &dyn RateLimit, key: &IpAddr) -> bool {
+    until_ready(|| limiter.check_key(key), None).await == Ok(true)
+}
+
+/// Waits until `check` allows a request, like [`until_key_ready`] - unless the wait would be longer than
+/// `max_wait`, then returns how long the client should wait before trying again.
+///
+/// Returns whether the request had to wait.
+pub async fn until_ready(check: impl Fn() -> Result<(), Duration>, max_wait: Option<Duration>) -> Result<bool, Duration> {
+    let mut waited = false;
+    while let Err(wait) = check() {
+        if max_wait.map(|max_wait| wait > max_wait).unwrap_or(false) {
+            return Err(wait);
+        }
+        waited = true;
+        let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..1000));
+        tokio::time::sleep(wait + jitter).await;
+    }
+    Ok(waited)
+}
+
+/// Like [`until_ready`], but the request takes a slot of the client in the [`WAIT_QUEUE`] while it waits, and
+/// returns right away if the client or the queue has no slot left.
+pub async fn until_ready_queued(client: &str, check: impl Fn() -> Result<(), Duration>, max_wait: Option<Duration>) -> Result<bool, Duration> {
+    let wait = match check() {
+        Ok(()) => return Ok(false),
+        Err(wait) => wait,
+    };
+    let _slot = WAIT_QUEUE.enter(client).ok_or(wait)?;
+    if max_wait.map(|max_wait| wait > max_wait).unwrap_or(false) {
+        return Err(wait);
+    }
+    let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..1000));
+    tokio::time::sleep(wait + jitter).await;
+    until_ready(check, max_wait).await.map(|_| true)
+}
+
+/// Counts the requests waiting for a rate limiter, per client and in total.
+#[derive(Debug)]
+pub struct WaitQueue {
+    per_client: usize,
+    total: usize,
+    /// Waiting requests per client, and in total.
+    waiting: Mutex<(HashMap<String, usize>, usize)>,
+}
+
+impl WaitQueue {
+    /// Creates a queue with room for `per_client` requests per client, and `total` requests overall.
+    pub fn new(per_client: usize, total: usize) -> Self {
+        WaitQueue { per_client, total, waiting: Mutex::new((HashMap::new(), 0)) }
+    }
+
+    /// Takes a slot for a request of the client, or returns `None` if the client or the queue is full. The
+    /// slot is given back when it's dropped.
+    pub fn enter(&self, client: &str) -> Option<WaitSlot<'_>> {
+        let mut waiting = self.waiting.lock().unwrap();
+        let (clients, total) = &mut *waiting;
+        let of_client = clients.get(client).copied().unwrap_or(0);
+        if of_client >= self.per_client || *total >= self.total {
+            return None;
+        }
+        clients.insert(client.to_string(), of_client + 1);
+        *total += 1;
+        Some(WaitSlot { queue: self, client: client.to_string() })
+    }
+
+    /// Returns the number of waiting requests.
+    pub fn len(&self) -> usize {
+        self.waiting.lock().unwrap().1
+    }
+
+    /// Returns whether no request is waiting.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A request's place in a [`WaitQueue`].
+#[derive(Debug)]
+pub struct WaitSlot<'a> {
+    queue: &'a WaitQueue,
+    client: String,
+}
+
+impl Drop for WaitSlot<'_> {
+    fn drop(&mut self) {
+        let mut waiting = self.queue.waiting.lock().unwrap();
+        let (clients, total) = &mut *waiting;
+        if let Some(of_client) = clients.get_mut(&self.client) {
+            *of_client -= 1;
+            if *of_client == 0 {
+                clients.remove(&self.client);
+            }
+        }
+        *total -= 1;
+    }
+}
@@ -0,0 +1,848 @@
+//! Accepting client connections, and finding out who's on the other end of them.
+//!
+//! Rate limits bound requests, not connections held open without sending any. With `MAX_CONNECTIONS_PER_IP`,
+//! connections beyond the cap are closed as soon as they're accepted (or once their PROXY protocol header
+//! tells who they're from). Connections of [trusted proxies](crate::TRUSTED_PROXIES) aren't capped, since
+//! they carry many clients.
+//!
+//! Connections that neither send nor receive anything for `IDLE_TIMEOUT_SECS`, while no request of theirs is
+//! being handled, are closed too - so slow clients can't pin connections forever. Connections of clients that
+//! vanished without closing them (a launcher on a flaky home network) are noticed sooner with TCP keepalive
+//! (`TCP_KEEPALIVE_SECS`), and `MAX_REQUESTS_PER_CONNECTION` closes HTTP/1.1 connections after as many requests.
+//!
+//! Besides a TCP port, the proxy can listen at a unix socket (`LISTEN=unix:<path>`), e.g. behind a web server
+//! on the same host. Connections through the socket count as coming from `127.0.0.1`, so the web server's
+//! `REAL_IP_HEADER` is trusted if `TRUSTED_PROXIES` contains it.
+//!
+//! Under systemd socket activation, the proxy accepts connections on the socket systemd passes it (see
+//! [`systemd_listen_fds`]) instead of binding one itself.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::future::Future;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use lazy_static::lazy_static;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Sleep};
+#[cfg(feature = "tls")]
+use openssl::ssl::SslAcceptor;
+use crate::routes::RouteSet;
+#[cfg(feature = "tls")]
+use crate::tls::{ReloadingCertificate, TlsStream};
+use crate::{proxy_protocol, TRUSTED_PROXIES};
+
+lazy_static! {
+    /// How many connections a client IP may hold open at once. Read from the `MAX_CONNECTIONS_PER_IP` env
+    /// variable, `0` means no limit.
+    pub static ref MAX_CONNECTIONS_PER_IP: usize = env::var("MAX_CONNECTIONS_PER_IP").unwrap_or(String::from("0"))
+        .parse::<usize>().expect("Expected MAX_CONNECTIONS_PER_IP env var to contain a number");
+
+    /// How long a client has to send the headers of a request. Read from the `HEADER_READ_TIMEOUT_SECS` env
+    /// variable.
+    pub static ref HEADER_READ_TIMEOUT: Duration = Duration::from_secs(env::var("HEADER_READ_TIMEOUT_SECS").unwrap_or(String::from("10"))
+        .parse::<u64>().expect("Expected HEADER_READ_TIMEOUT_SECS env var to contain a number"));
+
+    /// How long an idle connection is kept open. Read from the `IDLE_TIMEOUT_SECS` env variable, `None` if it's `0`.
+    pub static ref IDLE_TIMEOUT: Option<Duration> = match env::var("IDLE_TIMEOUT_SECS").unwrap_or(String::from("60"))
+        .parse::<u64>().expect("Expected IDLE_TIMEOUT_SECS env var to contain a number")
+    {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+
+    /// How long a TCP connection may be silent before the OS checks whether the client is still there. Read
+    /// from the `TCP_KEEPALIVE_SECS` env variable, `None` if it's `0`.
+    pub static ref TCP_KEEPALIVE: Option<Duration> = match env::var("TCP_KEEPALIVE_SECS").unwrap_or(String::from("0"))
+        .parse::<u64>().expect("Expected TCP_KEEPALIVE_SECS env var to contain a number")
+    {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+
+    /// How many requests a HTTP/1.1 connection may send before it's closed. Read from the
+    /// `MAX_REQUESTS_PER_CONNECTION` env variable, `0` means no limit.
+    pub static ref MAX_REQUESTS_PER_CONNECTION: usize = env::var("MAX_REQUESTS_PER_CONNECTION").unwrap_or(String::from("0"))
+        .parse::<usize>().expect("Expected MAX_REQUESTS_PER_CONNECTION env var to contain a number");
+
+    /// How many requests a HTTP/2 connection may have in flight at once. Read from the
+    /// `MAX_STREAMS_PER_CONNECTION` env variable.
+    pub static ref MAX_STREAMS_PER_CONNECTION: u32 = env::var("MAX_STREAMS_PER_CONNECTION").unwrap_or(String::from("100"))
+        .parse::<u32>().expect("Expected MAX_STREAMS_PER_CONNECTION env var to contain a number");
+
+    /// Permissions of the unix socket the proxy listens at, if it does. Read from the `LISTEN_SOCKET_MODE` env
+    /// variable, as an octal number.
+    pub static ref LISTEN_SOCKET_MODE: u32 = u32::from_str_radix(&env::var("LISTEN_SOCKET_MODE").unwrap_or(String::from("660")), 8)
+        .expect("Expected LISTEN_SOCKET_MODE env var to contain an octal number");
+
+    /// Whether the proxy shares its addresses with other processes, so a new instance can start listening
+    /// before the old one stops. Read from the `REUSE_PORT` env variable.
+    pub static ref REUSE_PORT: bool = env::var("REUSE_PORT").unwrap_or(String::from("false"))
+        .parse::<bool>().expect("Expected REUSE_PORT env var to be either true or false");
+}
+
+/// The address connections of unix sockets are from.
+const UNIX_PEER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// The first file descriptor systemd passes sockets as.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the listening sockets systemd passed to the process with socket activation, like `sd_listen_fds`.
+///
+/// The `LISTEN_FDS` & `LISTEN_PID` env variables are removed, so processes started by the proxy don't take the
+/// sockets for theirs.
+#[cfg(unix)]
+pub fn systemd_listen_fds() -> Vec<RawFd> {
+    let for_process = env::var("LISTEN_PID").ok()
+        .and_then(|pid| pid.trim().parse::<u32>().ok()) == Some(std::process::id());
+    let count = env::var("LISTEN_FDS").ok().and_then(|count| count.trim().parse::<RawFd>().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    match (for_process, count) {
+        (true, Some(count)) => (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Where the proxy accepts connections: a TCP address like `0.0.0.0:3000`, or a unix socket like
+/// `unix:/run/cfproxy.sock`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(addr: &str) -> Result<Self, Self::Err> {
+        match addr.strip_prefix("unix:") {
+            Some("") => Err(String::from("unix socket path is empty")),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => addr.parse::<SocketAddr>().map(ListenAddr::Tcp).map_err(|e| format!("invalid address {}: {}", addr, e)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+/// An address to listen at, along with the routes served there: `<addr>` for all routes, or
+/// `<routes>@<addr>` like `local@127.0.0.1:3001` (see [`RouteSet`]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    pub addr: ListenAddr,
+    pub routes: RouteSet,
+}
+
+impl Binding {
+    /// Parses a comma separated list of bindings, like `[::]:3000,local@127.0.0.1:3001`.
+    pub fn parse_list(list: &str) -> Result<Vec<Binding>, String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|binding| !binding.is_empty())
+            .map(str::parse::<Binding>)
+            .collect()
+    }
+}
+
+impl FromStr for Binding {
+    type Err = String;
+
+    fn from_str(binding: &str) -> Result<Self, Self::Err> {
+        match binding.split_once('@') {
+            Some((routes, addr)) if routes.parse::<RouteSet>().is_ok() => {
+                Ok(Binding { addr: addr.parse()?, routes: routes.parse()? })
+            }
+            _ => Ok(Binding { addr: binding.parse()?, routes: RouteSet::All }),
+        }
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.routes {
+            RouteSet::All => write!(f, "{}", self.addr),
+            routes => write!(f, "{}@{}", routes, self.addr),
+        }
+    }
+}
+
+/// How long a client has to send its PROXY protocol header and complete the TLS handshake.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long accepting at a unix socket pauses after it failed, e.g. because the process ran out of file
+/// descriptors. The same as hyper's `AddrIncoming` does for TCP.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Counts open connections per client IP.
+#[derive(Debug)]
+struct IpConnections {
+    /// Connections allowed per IP, `0` for no limit.
+    max: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl IpConnections {
+    /// Takes a slot for a connection of the IP, or returns `None` if it has `max` connections open already.
+    fn enter(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionSlot> {
+        if self.max == 0 || TRUSTED_PROXIES.iter().any(|proxy| proxy.contains(&ip)) {
+            return Some(ConnectionSlot(None));
+        }
+        let mut open = self.open.lock().unwrap();
+        let of_ip = open.entry(ip).or_insert(0);
+        if *of_ip >= self.max {
+            return None;
+        }
+        *of_ip += 1;
+        Some(ConnectionSlot(Some((Arc::clone(self), ip))))
+    }
+}
+
+/// A connection's place in [`IpConnections`], given back when the connection is dropped.
+#[derive(Debug)]
+struct ConnectionSlot(Option<(Arc<IpConnections>, IpAddr)>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        if let Some((connections, ip)) = self.0.take() {
+            let mut open = connections.open.lock().unwrap();
+            if let Some(of_ip) = open.get_mut(&ip) {
+                *of_ip -= 1;
+                if *of_ip == 0 {
+                    open.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// Requests of a connection that are being handled. The connection doesn't time out while there are any.
+#[derive(Debug, Default)]
+pub struct Activity(AtomicUsize);
+
+impl Activity {
+    /// Counts a request as being handled, until the returned guard is dropped.
+    pub fn busy(self: &Arc<Self>) -> BusyGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        BusyGuard(Arc::clone(self))
+    }
+
+    fn is_busy(&self) -> bool {
+        self.0.load(Ordering::Relaxed) > 0
+    }
+}
+
+/// Decrements [`Activity`] when dropped.
+#[derive(Debug)]
+pub struct BusyGuard(Arc<Activity>);
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Closes a connection that stays idle for too long.
+#[derive(Debug)]
+struct IdleTimer {
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl IdleTimer {
+    fn new(timeout: Duration) -> Self {
+        IdleTimer { timeout, sleep: Box::pin(tokio::time::sleep(timeout)) }
+    }
+
+    /// Starts the timeout over, waking the task once it expires - the connection's io might not be polled
+    /// again otherwise.
+    fn restart(&mut self, cx: &mut Context<'_>) {
+        let deadline = Instant::now() + self.timeout;
+        self.sleep.as_mut().reset(deadline);
+        let _ = self.sleep.as_mut().poll(cx);
+    }
+
+    /// Returns whether the connection timed out, waking the task once it does otherwise.
+    fn poll_expired(&mut self, cx: &mut Context<'_>, activity: &Activity) -> bool {
+        if activity.is_busy() {
+            self.restart(cx);
+            return false;
+        }
+        self.sleep.as_mut().poll(cx).is_ready()
+    }
+}
+
+/// A connection, as it was accepted.
+#[derive(Debug)]
+enum Socket {
+    Tcp(AddrStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The bytes of a connection, before TLS is terminated.
+#[derive(Debug)]
+struct RawStream {
+    stream: Socket,
+    /// Bytes that were read past the PROXY protocol header, and still have to be handed on.
+    buffered: Vec<u8>,
+}
+
+impl AsyncRead for RawStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if !self.buffered.is_empty() {
+            let len = self.buffered.len().min(buf.remaining());
+            buf.put_slice(&self.buffered[..len]);
+            self.buffered.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RawStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// The bytes the server reads & writes, plain or through TLS.
+#[derive(Debug)]
+enum Io {
+    Plain(RawStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<RawStream>>),
+}
+
+/// What TLS is terminated with.
+#[cfg(feature = "tls")]
+type Acceptor = Arc<SslAcceptor>;
+
+/// Without the `tls` feature, TLS is never terminated.
+#[cfg(not(feature = "tls"))]
+type Acceptor = std::convert::Infallible;
+
+/// A connection of a client.
+#[derive(Debug)]
+pub struct ClientStream {
+    io: Io,
+    remote_addr: SocketAddr,
+    /// Identity of the client's TLS certificate.
+    identity: Option<String>,
+    activity: Arc<Activity>,
+    idle: Option<IdleTimer>,
+    /// Routes served to the connection, those of the listener it came in on.
+    routes: RouteSet,
+    _slot: ConnectionSlot,
+}
+
+impl ClientStream {
+    fn new(io: Io, remote_addr: SocketAddr, idle_timeout: Option<Duration>, slot: ConnectionSlot) -> Self {
+        let idle = idle_timeout.map(IdleTimer::new);
+        let identity = match &io {
+            #[cfg(feature = "tls")]
+            Io::Tls(stream) => stream.client_identity(),
+            Io::Plain(_) => None,
+        };
+        ClientStream { io, remote_addr, identity, activity: Arc::default(), idle, routes: RouteSet::All, _slot: slot }
+    }
+
+    /// Returns the address of the client - the one its load balancer reported, if PROXY protocol is enabled.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Returns whether the client connected with TLS.
+    pub fn is_tls(&self) -> bool {
+        !matches!(self.io, Io::Plain(_))
+    }
+
+    /// Returns the identity of the client's TLS certificate, if it presented one.
+    pub fn client_identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    /// Returns the routes served to the connection.
+    pub fn routes(&self) -> RouteSet {
+        self.routes
+    }
+
+    /// Returns the requests of the connection that are being handled.
+    pub fn activity(&self) -> Arc<Activity> {
+        Arc::clone(&self.activity)
+    }
+
+    /// Restarts the idle timer after the result of an io operation, or fails the operation if the timer expired.
+    fn check_idle<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        let idle = match self.idle.as_mut() {
+            Some(idle) => idle,
+            None => return poll,
+        };
+        match poll {
+            Poll::Ready(result) => {
+                idle.restart(cx);
+                Poll::Ready(result)
+            }
+            Poll::Pending if idle.poll_expired(cx, &self.activity) => {
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "connection was idle for too long")))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let poll = match &mut self.io {
+            Io::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Io::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        };
+        self.check_idle(cx, poll)
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = match &mut self.io {
+            Io::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Io::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        };
+        self.check_idle(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.io {
+            Io::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Io::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.io {
+            Io::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Io::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Reads the PROXY protocol header off a fresh connection, returning the client's address and the bytes read
+/// past the header.
+async fn read_proxy_header(stream: &mut Socket, peer: SocketAddr) -> Result<(SocketAddr, Vec<u8>), String> {
+    let mut buf = Vec::with_capacity(256);
+    loop {
+        let mut chunk = [0; 256];
+        let read = stream.read(&mut chunk).await.map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Err(String::from("connection closed before the PROXY protocol header was complete"));
+        }
+        buf.extend_from_slice(&chunk[..read]);
+        if let Some(header) = proxy_protocol::parse_header(&buf)? {
+            let remote_addr = header.source.unwrap_or(peer);
+            buf.drain(..header.len);
+            return Ok((remote_addr, buf));
+        }
+    }
+}
+
+/// Sets up a fresh connection: reads its PROXY protocol header if `proxy_protocol` is set, and does the TLS
+/// handshake if there's an `acceptor`.
+async fn set_up(mut stream: Socket, peer: SocketAddr, proxy_protocol: bool, acceptor: Option<Acceptor>, connections: Arc<IpConnections>, idle_timeout: Option<Duration>) -> Result<ClientStream, String> {
+    let (remote_addr, buffered) = match proxy_protocol {
+        true => read_proxy_header(&mut stream, peer).await?,
+        false => (peer, Vec::new()),
+    };
+    let slot = connections.enter(remote_addr.ip()).ok_or_else(|| String::from("too many connections"))?;
+    let raw = RawStream { stream, buffered };
+    let io = match acceptor {
+        #[cfg(feature = "tls")]
+        Some(acceptor) => Io::Tls(Box::new(TlsStream::accept(&acceptor, raw).await.map_err(|e| format!("TLS handshake failed: {}", e))?)),
+        #[cfg(not(feature = "tls"))]
+        Some(never) => match never {},
+        None => Io::Plain(raw),
+    };
+    Ok(ClientStream::new(io, remote_addr, idle_timeout, slot))
+}
+
+/// A unix socket file, removed again when dropped - unless another process replaced it by then.
+#[cfg(unix)]
+#[derive(Debug)]
+struct SocketFile {
+    path: PathBuf,
+    /// Inode of the file when it was bound.
+    inode: u64,
+}
+
+#[cfg(unix)]
+impl SocketFile {
+    /// Binds a unix socket at `path` with the permissions `mode`. A socket file left behind by a previous run
+    /// is replaced, and so is one something still listens at if `take_over` is set - new connections then
+    /// come to this socket, while the other listener keeps the ones it accepted already.
+    fn bind(path: PathBuf, mode: u32, take_over: bool) -> io::Result<(UnixListener, Self)> {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+            }
+            if !take_over && std::os::unix::net::UnixStream::connect(&path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("something is listening at {} already", path.display())));
+            }
+            std::fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+        let inode = std::fs::symlink_metadata(&path)?.ino();
+        Ok((listener, SocketFile { path, inode }))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        use std::os::unix::fs::MetadataExt;
+
+        if std::fs::symlink_metadata(&self.path).ok().map(|metadata| metadata.ino()) == Some(self.inode) {
+            std::fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+/// Binds a TCP listener at `addr` with `SO_REUSEPORT`, so other processes can bind the same address - the
+/// kernel spreads new connections across all of them.
+#[cfg(unix)]
+fn bind_reusing_port(addr: &SocketAddr) -> io::Result<std::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Where connections are accepted.
+enum Listener {
+    Tcp(AddrIncoming),
+    /// A unix socket, along with its file unless someone else bound it, and the pause after an accept error.
+    #[cfg(unix)]
+    Unix(UnixListener, Option<SocketFile>, Option<Pin<Box<Sleep>>>),
+}
+
+impl Listener {
+    /// Accepts the next connection, along with the address it's from.
+    ///
+    /// Errors accepting at a unix socket (like running out of file descriptors) are logged, and accepting is
+    /// retried after [`ACCEPT_ERROR_BACKOFF`] - hyper stops serving on the first error it's handed.
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<(Socket, SocketAddr)>>> {
+        match self {
+            Listener::Tcp(incoming) => Pin::new(incoming).poll_accept(cx)
+                .map_ok(|stream| {
+                    let peer = stream.remote_addr();
+                    (Socket::Tcp(stream), peer)
+                }),
+            #[cfg(unix)]
+            Listener::Unix(listener, _, backoff) => loop {
+                if let Some(sleep) = backoff {
+                    match sleep.as_mut().poll(cx) {
+                        Poll::Ready(()) => *backoff = None,
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                match listener.poll_accept(cx) {
+                    Poll::Ready(Ok((stream, _))) => return Poll::Ready(Some(Ok((Socket::Unix(stream), UNIX_PEER_ADDR)))),
+                    Poll::Ready(Err(err)) => {
+                        println!("<!> Failed to accept a connection: {}", err);
+                        *backoff = Some(Box::pin(tokio::time::sleep(ACCEPT_ERROR_BACKOFF)));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                }
+            },
+        }
+    }
+}
+
+/// Accepts connections for the server.
+///
+/// With `proxy_protocol`, connections are only handed to the server once their PROXY protocol header was
+/// read, and with TLS once their handshake is done. Both happen in the background, so a slow client doesn't
+/// hold up other connections.
+pub struct ClientIncoming {
+    listeners: Vec<(Listener, RouteSet)>,
+    /// Listener to accept from first, so a busy one doesn't starve the others.
+    next: usize,
+    proxy_protocol: bool,
+    connections: Arc<IpConnections>,
+    idle_timeout: Option<Duration>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<ReloadingCertificate>>,
+    ready: (mpsc::UnboundedSender<ClientStream>, mpsc::UnboundedReceiver<ClientStream>),
+}
+
+impl ClientIncoming {
+    /// Binds to `addr`, without limits on connections.
+    pub fn bind(addr: &SocketAddr, proxy_protocol: bool) -> Result<Self, hyper::Error> {
+        Ok(Self::new(Listener::Tcp(AddrIncoming::bind(addr)?), proxy_protocol))
+    }
+
+    /// Binds to `addr` like [`ClientIncoming::bind`], but lets other processes bind it too (`SO_REUSEPORT`).
+    #[cfg(unix)]
+    pub fn bind_reusing_port(addr: &SocketAddr, proxy_protocol: bool) -> io::Result<Self> {
+        let listener = tokio::net::TcpListener::from_std(bind_reusing_port(addr)?)?;
+        let incoming = AddrIncoming::from_listener(listener).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Self::new(Listener::Tcp(incoming), proxy_protocol))
+    }
+
+    /// Binds a unix socket at `path` with the permissions `mode`, without limits on connections. The socket
+    /// file is removed once the returned value is dropped. With `take_over`, a socket another process still
+    /// listens at is replaced.
+    #[cfg(unix)]
+    pub fn bind_unix(path: PathBuf, mode: u32, take_over: bool, proxy_protocol: bool) -> io::Result<Self> {
+        let (listener, file) = SocketFile::bind(path, mode, take_over)?;
+        Ok(Self::new(Listener::Unix(listener, Some(file), None), proxy_protocol))
+    }
+
+    /// Accepts connections on an inherited listening socket, TCP or unix, without limits on connections.
+    /// Takes ownership of `fd`.
+    #[cfg(unix)]
+    pub fn from_fd(fd: RawFd, proxy_protocol: bool) -> io::Result<Self> {
+        // SAFETY: the caller hands over the file descriptor, and it's only ever owned by one listener
+        let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+        if unix.local_addr().is_ok() {
+            unix.set_nonblocking(true)?;
+            return Ok(Self::new(Listener::Unix(UnixListener::from_std(unix)?, None, None), proxy_protocol));
+        }
+        // SAFETY: as above, the file descriptor was just released by the unix listener
+        let tcp = unsafe { std::net::TcpListener::from_raw_fd(unix.into_raw_fd()) };
+        tcp.local_addr()?;
+        tcp.set_nonblocking(true)?;
+        let incoming = AddrIncoming::from_listener(tokio::net::TcpListener::from_std(tcp)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Self::new(Listener::Tcp(incoming), proxy_protocol))
+    }
+
+    fn new(listener: Listener, proxy_protocol: bool) -> Self {
+        ClientIncoming {
+            listeners: vec![(listener, RouteSet::All)],
+            next: 0,
+            proxy_protocol,
+            connections: Arc::new(IpConnections { max: 0, open: Mutex::new(HashMap::new()) }),
+            idle_timeout: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            ready: mpsc::unbounded_channel(),
+        }
+    }
+
+    /// Allows `max` connections per client IP, `0` for no limit.
+    pub fn max_connections_per_ip(self, max: usize) -> Self {
+        ClientIncoming { connections: Arc::new(IpConnections { max, open: Mutex::new(HashMap::new()) }), ..self }
+    }
+
+    /// Closes connections that are idle for longer than `timeout`.
+    pub fn idle_timeout(self, timeout: Option<Duration>) -> Self {
+        ClientIncoming { idle_timeout: timeout, ..self }
+    }
+
+    /// Enables TCP keepalive on connections accepted at TCP addresses, probing silent connections after
+    /// `keepalive`. Has no effect on unix sockets.
+    pub fn tcp_keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        for (listener, _) in self.listeners.iter_mut() {
+            if let Listener::Tcp(incoming) = listener {
+                incoming.set_keepalive(keepalive);
+            }
+        }
+        self
+    }
+
+    /// Terminates TLS with the certificate, picking up reloads of it for new connections.
+    #[cfg(feature = "tls")]
+    pub fn tls(self, certificate: Option<Arc<ReloadingCertificate>>) -> Self {
+        ClientIncoming { tls: certificate, ..self }
+    }
+
+    /// Returns what TLS is terminated with for new connections, if anything.
+    #[cfg(feature = "tls")]
+    fn acceptor(&self) -> Option<Acceptor> {
+        self.tls.as_ref().map(|certificate| certificate.acceptor())
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn acceptor(&self) -> Option<Acceptor> {
+        None
+    }
+
+    /// Serves only `routes` to connections accepted so far.
+    pub fn routes(mut self, routes: RouteSet) -> Self {
+        for (_, served) in self.listeners.iter_mut() {
+            *served = routes;
+        }
+        self
+    }
+
+    /// Accepts connections of `other`'s listeners too, with the routes they serve.
+    pub fn join(mut self, other: ClientIncoming) -> Self {
+        self.listeners.extend(other.listeners);
+        self
+    }
+
+    /// Returns the addresses connections are accepted at.
+    pub fn local_addrs(&self) -> Vec<ListenAddr> {
+        self.listeners.iter().map(|(listener, _)| match listener {
+            Listener::Tcp(incoming) => ListenAddr::Tcp(incoming.local_addr()),
+            #[cfg(unix)]
+            Listener::Unix(_, Some(file), _) => ListenAddr::Unix(file.path.clone()),
+            #[cfg(unix)]
+            Listener::Unix(listener, None, _) => ListenAddr::Unix(listener.local_addr().ok()
+                .and_then(|addr| addr.as_pathname().map(PathBuf::from))
+                .unwrap_or_default()),
+        }).collect()
+    }
+
+    /// Accepts the next connection of any of the listeners, along with the address it's from & the routes
+    /// served to it.
+    fn poll_listeners(&mut self, cx: &mut Context<'_>) -> Poll<(Socket, SocketAddr, RouteSet)> {
+        let count = self.listeners.len();
+        for offset in 0..count {
+            let index = (self.next + offset) % count;
+            let (listener, routes) = &mut self.listeners[index];
+            match listener.poll_accept(cx) {
+                Poll::Ready(Some(Ok((stream, peer)))) => {
+                    let routes = *routes;
+                    self.next = (index + 1) % count;
+                    return Poll::Ready((stream, peer, routes));
+                }
+                // Listeners back off after errors themselves, so they're only logged - one listener failing
+                // doesn't stop the others
+                Poll::Ready(Some(Err(err))) => println!("<!> Failed to accept a connection: {}", err),
+                Poll::Ready(None) | Poll::Pending => {}
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Accept for ClientIncoming {
+    type Conn = ClientStream;
+    type Error = io::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<ClientStream>>> {
+        loop {
+            if let Poll::Ready(Some(stream)) = self.ready.1.poll_recv(cx) {
+                return Poll::Ready(Some(Ok(stream)));
+            }
+            let (stream, peer, routes) = match self.poll_listeners(cx) {
+                Poll::Ready(accepted) => accepted,
+                Poll::Pending => return Poll::Pending,
+            };
+            let acceptor = self.acceptor();
+            if !self.proxy_protocol && acceptor.is_none() {
+                match self.connections.enter(peer.ip()) {
+                    Some(slot) => {
+                        let io = Io::Plain(RawStream { stream, buffered: Vec::new() });
+                        let stream = ClientStream { routes, ..ClientStream::new(io, peer, self.idle_timeout, slot) };
+                        return Poll::Ready(Some(Ok(stream)));
+                    }
+                    None => {
+                        println!("[{}] <!> Closing connection: too many connections", peer.ip());
+                        continue;
+                    }
+                }
+            }
+
+            let ready = self.ready.0.clone();
+            let connections = Arc::clone(&self.connections);
+            let idle_timeout = self.idle_timeout;
+            let proxy_protocol = self.proxy_protocol;
+            tokio::spawn(async move {
+                match tokio::time::timeout(HEADER_TIMEOUT, set_up(stream, peer, proxy_protocol, acceptor, connections, idle_timeout)).await {
+                    Ok(Ok(stream)) => {
+                        ready.send(ClientStream { routes, ..stream }).ok();
+                    }
+                    Ok(Err(err)) => println!("[{}] <!> Closing connection: {}", peer.ip(), err),
+                    Err(_) if proxy_protocol => println!("[{}] <!> Closing connection: no PROXY protocol header", peer.ip()),
+                    Err(_) => println!("[{}] <!> Closing connection: no TLS handshake", peer.ip()),
+                }
+            });
+        }
+    }
+}
@@ -12,73 +12,127 @@
 //! In order to prevent abuse of the api key which is used in every request, this proxy server rate limits per IP.
 
 use std::env;
-use std::convert::Infallible;
-use std::net::{SocketAddr, IpAddr};
+use std::net::SocketAddr;
 use std::num::NonZeroU32;
-use std::sync::Arc;
+use std::path::Path;
 use std::time::Duration;
 use dotenv::dotenv;
-use governor::{RateLimiter, Quota, Jitter};
-use hyper::server::conn::AddrStream;
-use hyper::{Body, Request, Server};
-use hyper::service::{make_service_fn, service_fn};
 use lazy_static::lazy_static;
-use tokio;
+use cfproxy::config::ProxyConfig;
+use cfproxy::diagnostics::ShutdownReport;
+#[cfg(unix)]
+use cfproxy::keys;
+use cfproxy::listener::{Binding, ListenAddr};
+use cfproxy::routes::RouteSet;
+use cfproxy::sentry;
+use cfproxy::server::{ProxyServer, REQ_LIMIT_PER_HOUR};
+use cfproxy::tokens;
+#[cfg(unix)]
+use cfproxy::diagnostics::DiagnosticReport;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
 lazy_static! {
     /// The port this proxy is running at. Read from the `PORT` env variable.
     static ref PORT: u16 = env::var("PORT").unwrap_or(String::from("3000"))
         .parse::<u16>().expect("Expected PORT environment variable to contain a number");
 
-    /// How many requests per secs are allowed per ip. Read from the `REQ_LIMIT_PER_HOUR` env variable.
-    static ref REQ_LIMIT_PER_HOUR: u32 = env::var("REQ_LIMIT_PER_HOUR").unwrap_or(String::from("21600"))
-        .parse::<u32>().expect("Expected REQ_LIMIT_PER_HOUR env var to contain a number");
+    /// Where the proxy accepts connections, `host:port` or `unix:<path>` - each optionally prefixed with the
+    /// routes served there, like `local@127.0.0.1:3001`. Read from the `LISTEN` env variable, as a comma
+    /// separated list, all interfaces at [`PORT`] if unset.
+    static ref LISTEN: Vec<Binding> = match env::var("LISTEN") {
+        Ok(list) if !list.trim().is_empty() => Binding::parse_list(&list)
+            .unwrap_or_else(|e| panic!("Expected LISTEN env var to contain a comma separated list of host:port or unix:<path>: {}", e)),
+        _ => vec![Binding { addr: ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], *PORT))), routes: RouteSet::All }],
+    };
+}
+
+/// How long to wait for errors to be reported to Sentry before exiting.
+const SENTRY_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Resolves once the process is asked to shut down, via SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        signal(SignalKind::terminate()).expect("Expected to be able to listen for SIGTERM").recv().await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
 }
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
+    sentry::install_panic_hook();
+
+    // `cfproxy issue-token <requests per hour>` adds a new client token to the token store
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("issue-token") {
+        let path = tokens::TOKEN_STORE_FILE.as_ref().expect("Expected TOKEN_STORE_FILE env var to be set");
+        let requests_per_hour = args.get(2).and_then(|quota| quota.parse::<NonZeroU32>().ok())
+            .unwrap_or_else(|| NonZeroU32::new(*REQ_LIMIT_PER_HOUR).expect("Expected req limit to not be null"));
+        match tokens::issue_token(Path::new(path), requests_per_hour) {
+            Ok(token) => println!("{}", token),
+            Err(e) => eprintln!("<!> Could not issue token: {}", e),
+        }
+        return;
+    }
 
-    let addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], *PORT));
-
-    // Init the rate limiter in an ARC so it can be shared across requests
-    let rate_limit_quota = Quota::per_hour(NonZeroU32::new(*REQ_LIMIT_PER_HOUR).expect("Expected req limit to not be null"));
-    let limiter = RateLimiter::<IpAddr, _, _>::keyed(rate_limit_quota);
-    let bucket = Arc::new(limiter);
-
-    let service = make_service_fn(move |socket: &AddrStream| {
-
-        let remote_addr = socket.remote_addr().ip();
-        let bucket = Arc::clone(&bucket);
-
-        async move {
-
-            let service = service_fn(move |req: Request<Body>| {
-
-                let bucket = Arc::clone(&bucket);
+    // Set up on a task, so panics over broken config are reported to Sentry before exiting
+    let server = match tokio::spawn(async { ProxyServer::new(ProxyConfig::from_env()).listen(LISTEN.clone()) }).await {
+        Ok(server) => server,
+        Err(_) => {
+            sentry::flush(SENTRY_FLUSH_TIMEOUT).await;
+            std::process::exit(1);
+        }
+    };
+
+    // Dump a diagnostic report to the log on SIGUSR1
+    #[cfg(unix)]
+    {
+        let state = server.state().clone();
+        let mut signals = signal(SignalKind::user_defined1()).expect("Expected to be able to listen for SIGUSR1");
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                println!("{}", DiagnosticReport::collect(&state));
+            }
+        });
+    }
 
-                async move {
-                    // Wait until the rate limiter allows this request
-                    let remote_addr = cfproxy::get_real_ip_addr(&req, &remote_addr);
-                    bucket.until_key_ready_with_jitter(&remote_addr, Jitter::up_to(Duration::from_secs(1))).await;
-                    if let Err(_) = bucket.check_key(&remote_addr) {
-                        println!("[{}] <!> Rate limit was hit", remote_addr.to_string());
-                    }
-                    cfproxy::proxy_request_to_cf(req, &remote_addr).await
+    // Reload the CF api keys & client tokens on SIGHUP
+    #[cfg(unix)]
+    {
+        let mut signals = signal(SignalKind::hangup()).expect("Expected to be able to listen for SIGHUP");
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                keys::reload_keys().await;
+                match tokens::reload_tokens() {
+                    Ok(_) if tokens::TOKEN_STORE_FILE.is_none() => {}
+                    Ok(count) => println!("<-> Reloaded {} client token(s)", count),
+                    Err(e) => eprintln!("<!> Could not reload client tokens from {} - keeping the old ones", e),
                 }
-            });
+            }
+        });
+    }
 
-            // Pass the request to the service handler
-            Ok::<_, Infallible>(service)
+    match tokio::spawn(server.run(shutdown_signal())).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            eprintln!("<!> {}", e);
+            sentry::capture(sentry::Event::new("StartupError", e).with_level(sentry::Level::Fatal));
+            sentry::flush(SENTRY_FLUSH_TIMEOUT).await;
+            std::process::exit(1);
+        }
+        Err(_) => {
+            sentry::flush(SENTRY_FLUSH_TIMEOUT).await;
+            std::process::exit(1);
         }
-    });
-
-    let server = Server::bind(&addr).serve(service);
-
-    println!("<-> Server starting at port {}", *PORT);
-
-    // Run until end of time
-    if let Err(e) = server.await {
-        eprintln!("<!> Server error: {}", e);
     }
+    sentry::flush(SENTRY_FLUSH_TIMEOUT).await;
+    println!("{}", ShutdownReport::collect());
 }
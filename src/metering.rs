@@ -0,0 +1,99 @@
+//! Counting the bytes of request & response bodies.
+//!
+//! Bodies are counted as they stream through the proxy, without buffering them: a request's body as it's read,
+//! a response's body as it's sent to the client. Bodies whose size is known up front (cached responses, the
+//! proxy's own answers) are counted right away instead. The totals are part of the [metrics](crate::metrics),
+//! and with `LOG_BODY_BYTES`, every request's counts are logged once its response was sent.
+
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use hyper::body::HttpBody;
+use hyper::{Body, Request, Response};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Whether the byte counts of every request are logged. Read from the `LOG_BODY_BYTES` env variable.
+    pub static ref LOG_BODY_BYTES: bool = env::var("LOG_BODY_BYTES").unwrap_or(String::from("false"))
+        .parse::<bool>().expect("Expected LOG_BODY_BYTES env var to be either true or false");
+}
+
+/// The bytes of a request's body that were read so far.
+#[derive(Debug, Clone, Default)]
+pub struct BodyMeter(Arc<AtomicU64>);
+
+impl BodyMeter {
+    pub fn bytes(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The body bytes of a request & its response.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ByteCounts {
+    /// Bytes of the request body that were read.
+    pub received: u64,
+    /// Bytes of the response body that were sent.
+    pub sent: u64,
+}
+
+/// Makes the request count the bytes of its body as it's read.
+pub fn meter_request(req: Request<Body>) -> (Request<Body>, BodyMeter) {
+    let meter = BodyMeter::default();
+    if req.body().is_end_stream() {
+        return (req, meter);
+    }
+    let (parts, body) = req.into_parts();
+    let counter = meter.0.clone();
+    let body = pipe(body, move |chunk| {
+        counter.fetch_add(chunk, Ordering::Relaxed);
+    }, || {});
+    (Request::from_parts(parts, body), meter)
+}
+
+/// Makes the response count the bytes of its body as they're sent. `on_complete` is called with the counts of
+/// the request read through `request` once the body is done, or the client went away before.
+pub fn meter_response<F>(response: Response<Body>, request: BodyMeter, on_complete: F) -> Response<Body>
+    where F: FnOnce(ByteCounts) + Send + 'static
+{
+    if let Some(size) = response.body().size_hint().exact() {
+        on_complete(ByteCounts { received: request.bytes(), sent: size });
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let sent = Arc::new(AtomicU64::new(0));
+    let counter = sent.clone();
+    let body = pipe(body, move |chunk| {
+        counter.fetch_add(chunk, Ordering::Relaxed);
+    }, move || on_complete(ByteCounts { received: request.bytes(), sent: sent.load(Ordering::Relaxed) }));
+    Response::from_parts(parts, body)
+}
+
+/// Streams `body` through a new body, calling `on_chunk` with the size of every chunk passed on, and `on_done`
+/// once the body ended or was aborted.
+fn pipe<C, D>(mut body: Body, mut on_chunk: C, on_done: D) -> Body
+    where C: FnMut(u64) + Send + 'static, D: FnOnce() + Send + 'static
+{
+    let (mut sender, piped) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => {
+                    sender.abort();
+                    return on_done();
+                }
+            };
+            let len = chunk.len() as u64;
+            if sender.send_data(chunk).await.is_err() {
+                return on_done();
+            }
+            on_chunk(len);
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            sender.send_trailers(trailers).await.ok();
+        }
+        on_done();
+    });
+    piped
+}
@@ -0,0 +1,622 @@
+//! Request counters kept by the proxy.
+//!
+//! Counters live in memory for the lifetime of the process. If `METRICS_SNAPSHOT_FILE` is set, they are
+//! periodically written to that file and restored from it on boot, so a restart doesn't reset figures like
+//! the number of requests made against the CF api key today. Instances sharing the file, like the old & new
+//! instance during a zero-downtime restart with `REUSE_PORT`, add their counts to what's in it rather than
+//! overwriting each other's. Counters, gauges & timings can be pushed to a
+//! [`MetricsSink`] too, like a [StatsD agent](crate::statsd).
+//!
+//! Request latencies are kept as histograms per route template & status class (see [`route_label`]), so raw
+//! paths like `/v1/mods/238222` don't each get their own histogram.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hyper::StatusCode;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use crate::{classify, routes, statsd};
+
+lazy_static! {
+    /// The counters of this process.
+    pub static ref METRICS: Metrics = Metrics::default();
+
+    /// Where to persist metric snapshots. Read from the `METRICS_SNAPSHOT_FILE` env variable.
+    /// Snapshots are disabled if unset.
+    pub static ref METRICS_SNAPSHOT_FILE: Option<String> = env::var("METRICS_SNAPSHOT_FILE").ok()
+        .filter(|path| !path.is_empty());
+
+    /// How often to write a snapshot. Read from the `METRICS_SNAPSHOT_INTERVAL_SECS` env variable.
+    pub static ref METRICS_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(
+        env::var("METRICS_SNAPSHOT_INTERVAL_SECS").unwrap_or(String::from("60"))
+            .parse::<u64>().expect("Expected METRICS_SNAPSHOT_INTERVAL_SECS env var to contain a number")
+    );
+}
+
+/// Returns the current UTC day as days since the unix epoch.
+fn current_day() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86400
+}
+
+/// Maps a request path to the endpoint it is counted under: the template of its route (see [`classify`]),
+/// so that e.g. every `/v1/mods/<id>` request counts towards the same endpoint.
+pub fn endpoint_of(path: &str) -> String {
+    classify::classify(path).template.into_owned()
+}
+
+/// The label requests to paths that aren't routes of the CF api or the proxy are recorded under.
+pub const UNKNOWN_ROUTE: &str = "unknown";
+
+/// Upper bounds of the buckets of [`LatencyHistogram`]s, in milliseconds. Slower requests go to an extra bucket.
+pub const LATENCY_BUCKETS_MS: &[u64] = &[5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Maps a request path, including the `BASE_PATH`, to the route its latency is recorded under: the template
+/// of its route of the CF api (see [`classify`]), the path of a local route, or [`UNKNOWN_ROUTE`]. Unlike
+/// [`endpoint_of`], unknown paths all share a label, so clients can't create a histogram per path.
+pub fn route_label(path: &str) -> Cow<'static, str> {
+    route_label_under(path, &routes::BASE_PATH)
+}
+
+/// Like [`route_label`], for a proxy mounted under `base_path` (see [`ProxyConfig`](crate::config::ProxyConfig)).
+pub fn route_label_under(path: &str, base_path: &str) -> Cow<'static, str> {
+    let path = match base_path {
+        "" => path,
+        base_path => match path.strip_prefix(base_path) {
+            Some("") => "/",
+            Some(rest) if rest.starts_with('/') => rest,
+            _ => return Cow::Borrowed(UNKNOWN_ROUTE),
+        },
+    };
+    route_of(path)
+}
+
+/// Like [`route_label`], for a path relative to the `BASE_PATH`.
+pub fn route_of(path: &str) -> Cow<'static, str> {
+    if let Some(local) = routes::LOCAL_ROUTES.iter().find(|route| route.path == path) {
+        return Cow::Borrowed(local.path);
+    }
+    let route = classify::classify(path);
+    match route.known {
+        true => route.template,
+        false => Cow::Borrowed(UNKNOWN_ROUTE),
+    }
+}
+
+/// Returns the class of a status, like `2xx`.
+pub fn status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() / 100 {
+        1 => "1xx",
+        2 => "2xx",
+        3 => "3xx",
+        4 => "4xx",
+        _ => "5xx",
+    }
+}
+
+/// How many requests were made against the CF api key on a given day.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    /// UTC day, as days since the unix epoch.
+    pub day: u64,
+    /// Upstream requests made on that day.
+    pub used: u64,
+}
+
+/// A point-in-time copy of all counters, as written to the snapshot file.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    /// Requests received by the proxy.
+    pub total_requests: u64,
+    /// Requests received by the proxy, per route (see [`route_label`]).
+    pub endpoints: HashMap<String, u64>,
+    /// Requests forwarded to the CF api today.
+    pub quota: QuotaUsage,
+}
+
+impl MetricsSnapshot {
+    /// Returns the counts made since the `earlier` snapshot.
+    fn since(&self, earlier: &MetricsSnapshot) -> MetricsSnapshot {
+        let endpoints = self.endpoints.iter()
+            .map(|(route, count)| (route.clone(), count.saturating_sub(earlier.endpoints.get(route).copied().unwrap_or(0))))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        let used = match earlier.quota.day == self.quota.day {
+            true => self.quota.used.saturating_sub(earlier.quota.used),
+            false => self.quota.used,
+        };
+        MetricsSnapshot {
+            total_requests: self.total_requests.saturating_sub(earlier.total_requests),
+            endpoints,
+            quota: QuotaUsage { day: self.quota.day, used },
+        }
+    }
+
+    /// Adds the counts of `other`. Quota usage of an earlier day than the one counted is dropped.
+    fn add(&mut self, other: &MetricsSnapshot) {
+        self.total_requests += other.total_requests;
+        for (route, count) in &other.endpoints {
+            *self.endpoints.entry(route.clone()).or_insert(0) += count;
+        }
+        if other.quota.day > self.quota.day {
+            self.quota = other.quota;
+        } else if other.quota.day == self.quota.day {
+            self.quota.used += other.quota.used;
+        }
+    }
+}
+
+/// How long a process waits for another one to finish writing the snapshot file.
+const SNAPSHOT_LOCK_WAIT: Duration = Duration::from_secs(1);
+
+/// Age after which a lock on the snapshot file is considered left behind by a process that died mid-write.
+const SNAPSHOT_LOCK_STALE: Duration = Duration::from_secs(30);
+
+/// A lock on the snapshot file, held as `<file>.lock` until it's dropped.
+struct SnapshotLock(PathBuf);
+
+impl SnapshotLock {
+    fn acquire(path: &Path) -> io::Result<Self> {
+        let lock = path.with_extension("lock");
+        let started = SystemTime::now();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&lock) {
+                Ok(_) => return Ok(SnapshotLock(lock)),
+                Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+                Err(_) => {}
+            }
+            let age = fs::metadata(&lock).and_then(|metadata| metadata.modified())
+                .map(|modified| modified.elapsed().unwrap_or_default())
+                .unwrap_or_default();
+            if age > SNAPSHOT_LOCK_STALE {
+                fs::remove_file(&lock).ok();
+            } else if started.elapsed().unwrap_or_default() > SNAPSHOT_LOCK_WAIT {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, format!("{} is held by another process", lock.display())));
+            } else {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+}
+
+impl Drop for SnapshotLock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Counters covering only the current run of the process. These are not part of snapshots.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RunStats {
+    /// Requests received by the proxy.
+    pub requests: u64,
+    /// Requests forwarded to the CF api.
+    pub upstream_requests: u64,
+    /// Requests forwarded to the `FALLBACK_API_URL` instead of the CF api.
+    pub fallback_requests: u64,
+    /// Requests for which the CF api could not be reached.
+    pub upstream_errors: u64,
+    /// Requests to the CF api that were retried after a transient failure.
+    pub upstream_retries: u64,
+    /// Requests rejected by the proxy itself, e.g. due to a missing token.
+    pub rejected_requests: u64,
+    /// Requests shed because the proxy was handling `MAX_IN_FLIGHT_REQUESTS` requests already.
+    pub shed_requests: u64,
+    /// Requests rejected because the client hit its rate limit.
+    pub rate_limited_requests: u64,
+    /// Requests not forwarded because the proxy hit its rate limit of calls to the CF api.
+    pub upstream_throttled: u64,
+    /// The most client connections that were open at once.
+    pub peak_connections: u64,
+    /// The most calls to the CF api that were in flight at once.
+    pub peak_upstream_calls: u64,
+    /// Bytes of request bodies read from clients.
+    pub bytes_received: u64,
+    /// Bytes of response bodies sent to clients.
+    pub bytes_sent: u64,
+}
+
+/// Tags of a metric, as name & value.
+pub type Tags<'a> = [(&'a str, &'a str)];
+
+/// Something metrics are pushed to.
+pub trait MetricsSink: Send + Sync {
+    /// Reports that a counter increased by `value`.
+    fn count(&self, name: &str, value: u64, tags: &Tags<'_>);
+    /// Reports the current value of a gauge.
+    fn gauge(&self, name: &str, value: u64, tags: &Tags<'_>);
+    /// Reports how long something took.
+    fn timing(&self, name: &str, duration: Duration, tags: &Tags<'_>);
+}
+
+/// Reports how long something took, like handling a request, to the metrics sink - if there is one.
+pub fn record_timing(name: &str, duration: Duration, tags: &Tags<'_>) {
+    if let Some(sink) = statsd::SINK.as_ref() {
+        sink.timing(name, duration, tags);
+    }
+}
+
+/// Latencies of requests, counted in the buckets of [`LATENCY_BUCKETS_MS`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LatencyHistogram {
+    /// Requests per bucket, the last one counts requests slower than all of [`LATENCY_BUCKETS_MS`].
+    pub buckets: Vec<u64>,
+    /// Requests recorded.
+    pub count: u64,
+    /// Latencies of all requests added up, in milliseconds.
+    pub sum_ms: u64,
+    /// The slowest latency, in milliseconds.
+    pub max_ms: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram { buckets: vec![0; LATENCY_BUCKETS_MS.len() + 1], count: 0, sum_ms: 0, max_ms: 0 }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let ms = latency.as_millis().min(u64::MAX as u128) as u64;
+        let bucket = LATENCY_BUCKETS_MS.iter().position(|bound| ms <= *bound).unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_ms = self.sum_ms.saturating_add(ms);
+        self.max_ms = self.max_ms.max(ms);
+    }
+
+    /// Returns the latency `quantile` (like `0.99`) of the requests stays under, in milliseconds: the upper bound
+    /// of its bucket, or the slowest latency for the last bucket. `None` if no requests were recorded.
+    pub fn quantile_ms(&self, quantile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(LATENCY_BUCKETS_MS.get(bucket).map(|bound| (*bound).min(self.max_ms)).unwrap_or(self.max_ms));
+            }
+        }
+        Some(self.max_ms)
+    }
+}
+
+/// The latencies of requests to a route, with a class of status.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RouteLatency {
+    /// See [`route_label`].
+    pub route: String,
+    /// See [`status_class`].
+    pub status: &'static str,
+    #[serde(flatten)]
+    pub histogram: LatencyHistogram,
+}
+
+/// Decrements a gauge of [`Metrics`] when dropped.
+#[derive(Debug)]
+pub struct GaugeGuard<'a>(&'a AtomicU64);
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters for requests handled by the proxy.
+///
+/// Gauges (active connections, in-flight upstream calls) and [`RunStats`] describe the current run of the
+/// process and are not part of snapshots.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    total_requests: AtomicU64,
+    endpoints: Mutex<HashMap<String, u64>>,
+    quota: Mutex<QuotaUsage>,
+    active_connections: AtomicU64,
+    upstream_calls_in_flight: AtomicU64,
+    run_requests: AtomicU64,
+    run_upstream_requests: AtomicU64,
+    fallback_requests: AtomicU64,
+    upstream_errors: AtomicU64,
+    upstream_retries: AtomicU64,
+    rejected_requests: AtomicU64,
+    shed_requests: AtomicU64,
+    rate_limited_requests: AtomicU64,
+    upstream_throttled: AtomicU64,
+    peak_connections: AtomicU64,
+    peak_upstream_calls: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    routed_requests: Mutex<HashMap<String, u64>>,
+    latencies: Mutex<HashMap<(Cow<'static, str>, &'static str), LatencyHistogram>>,
+    /// Counters as of the last save to, or restore from, the snapshot file.
+    saved: Mutex<MetricsSnapshot>,
+}
+
+impl Metrics {
+    /// Counts an open client connection, until the returned guard is dropped.
+    pub fn track_connection(&self) -> GaugeGuard<'_> {
+        let connections = self.active_connections.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_connections.fetch_max(connections, Ordering::Relaxed);
+        GaugeGuard(&self.active_connections)
+    }
+
+    /// Counts a call to the CF api that is in flight, until the returned guard is dropped.
+    pub fn track_upstream_call(&self) -> GaugeGuard<'_> {
+        let calls = self.upstream_calls_in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_upstream_calls.fetch_max(calls, Ordering::Relaxed);
+        GaugeGuard(&self.upstream_calls_in_flight)
+    }
+
+    /// Returns the number of open client connections.
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of calls to the CF api that are in flight.
+    pub fn upstream_calls_in_flight(&self) -> u64 {
+        self.upstream_calls_in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request a client sent the proxy for the given path, including the `BASE_PATH`.
+    pub fn record_request(&self, path: &str) {
+        self.record_route_request(&route_label(path));
+    }
+
+    /// Counts a request a client sent the proxy for the route, see [`route_label`].
+    pub fn record_route_request(&self, route: &str) {
+        self.total_requests.fetch_add(1, Ordering::Relaxed);
+        self.run_requests.fetch_add(1, Ordering::Relaxed);
+        *self.endpoints.lock().unwrap().entry(route.to_string()).or_insert(0) += 1;
+    }
+
+    /// Counts a request that was forwarded to the CF api, i.e. consumed quota of the api key.
+    pub fn record_upstream_request(&self) {
+        self.run_upstream_requests.fetch_add(1, Ordering::Relaxed);
+        let today = current_day();
+        let mut quota = self.quota.lock().unwrap();
+        if quota.day != today {
+            *quota = QuotaUsage { day: today, used: 0 };
+        }
+        quota.used += 1;
+    }
+
+    /// Counts a request that was forwarded to another upstream than the CF api (see [`upstreams`](crate::upstreams)),
+    /// by the upstream's host. These don't consume quota of the api key.
+    pub fn record_routed_request(&self, upstream: &str) {
+        *self.routed_requests.lock().unwrap().entry(upstream.to_string()).or_insert(0) += 1;
+    }
+
+    /// Counts a request that was forwarded to the `FALLBACK_API_URL`. These don't consume quota of the api key.
+    pub fn record_fallback_request(&self) {
+        self.fallback_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the requests forwarded to other upstreams than the CF api in this run, by the upstream's host.
+    pub fn routed_requests(&self) -> HashMap<String, u64> {
+        self.routed_requests.lock().unwrap().clone()
+    }
+
+    /// Counts a request for which the CF api could not be reached.
+    pub fn record_upstream_error(&self) {
+        self.upstream_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a retry of a request to the CF api.
+    pub fn record_upstream_retry(&self) {
+        self.upstream_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request that was rejected by the proxy itself.
+    pub fn record_rejected_request(&self) {
+        self.rejected_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request that was shed because the proxy was saturated.
+    pub fn record_shed_request(&self) {
+        self.shed_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request that was rejected because the client hit its rate limit.
+    pub fn record_rate_limited_request(&self) {
+        self.rate_limited_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request that wasn't forwarded because of the proxy's rate limit of calls to the CF api.
+    pub fn record_upstream_throttled(&self) {
+        self.upstream_throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the body bytes of a request & its response.
+    pub fn record_body_bytes(&self, received: u64, sent: u64) {
+        self.bytes_received.fetch_add(received, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+    }
+
+    /// Records how long a request took, under its route & status class.
+    pub fn record_latency(&self, route: Cow<'static, str>, status: &'static str, latency: Duration) {
+        self.latencies.lock().unwrap().entry((route, status)).or_default().record(latency);
+    }
+
+    /// Returns the latency histograms of the current run, sorted by route & status class.
+    pub fn latencies(&self) -> Vec<RouteLatency> {
+        let mut latencies = self.latencies.lock().unwrap().iter()
+            .map(|((route, status), histogram)| RouteLatency { route: route.to_string(), status, histogram: histogram.clone() })
+            .collect::<Vec<_>>();
+        latencies.sort_by(|a, b| (&a.route, a.status).cmp(&(&b.route, b.status)));
+        latencies
+    }
+
+    /// Returns the counters of the current run.
+    pub fn run_stats(&self) -> RunStats {
+        RunStats {
+            requests: self.run_requests.load(Ordering::Relaxed),
+            upstream_requests: self.run_upstream_requests.load(Ordering::Relaxed),
+            fallback_requests: self.fallback_requests.load(Ordering::Relaxed),
+            upstream_errors: self.upstream_errors.load(Ordering::Relaxed),
+            upstream_retries: self.upstream_retries.load(Ordering::Relaxed),
+            rejected_requests: self.rejected_requests.load(Ordering::Relaxed),
+            shed_requests: self.shed_requests.load(Ordering::Relaxed),
+            rate_limited_requests: self.rate_limited_requests.load(Ordering::Relaxed),
+            upstream_throttled: self.upstream_throttled.load(Ordering::Relaxed),
+            peak_connections: self.peak_connections.load(Ordering::Relaxed),
+            peak_upstream_calls: self.peak_upstream_calls.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reports the counters & gauges to the sink. Counters are reported as how much they increased since
+    /// `last`, which is updated to the current counts.
+    pub fn report_to(&self, sink: &dyn MetricsSink, last: &mut RunStats) {
+        let stats = self.run_stats();
+        let counters = [
+            ("requests", stats.requests, last.requests),
+            ("requests.rejected", stats.rejected_requests, last.rejected_requests),
+            ("requests.shed", stats.shed_requests, last.shed_requests),
+            ("requests.rate_limited", stats.rate_limited_requests, last.rate_limited_requests),
+            ("upstream.requests", stats.upstream_requests, last.upstream_requests),
+            ("fallback.requests", stats.fallback_requests, last.fallback_requests),
+            ("upstream.errors", stats.upstream_errors, last.upstream_errors),
+            ("upstream.retries", stats.upstream_retries, last.upstream_retries),
+            ("upstream.throttled", stats.upstream_throttled, last.upstream_throttled),
+            ("bytes.received", stats.bytes_received, last.bytes_received),
+            ("bytes.sent", stats.bytes_sent, last.bytes_sent),
+        ];
+        for (name, count, last) in counters {
+            sink.count(name, count.saturating_sub(last), &[]);
+        }
+        sink.gauge("connections.active", self.active_connections(), &[]);
+        sink.gauge("upstream.in_flight", self.upstream_calls_in_flight(), &[]);
+        let quota = *self.quota.lock().unwrap();
+        sink.gauge("quota.used", if quota.day == current_day() { quota.used } else { 0 }, &[]);
+        *last = stats;
+    }
+
+    /// Returns a copy of all snapshotted counters.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let mut quota = *self.quota.lock().unwrap();
+        if quota.day != current_day() {
+            quota = QuotaUsage { day: current_day(), used: 0 };
+        }
+        MetricsSnapshot {
+            total_requests: self.total_requests.load(Ordering::Relaxed),
+            endpoints: self.endpoints.lock().unwrap().clone(),
+            quota,
+        }
+    }
+
+    /// Replaces all counters with the ones from the snapshot.
+    ///
+    /// Quota usage of a previous day is dropped, since the quota resets daily.
+    pub fn restore(&self, snapshot: MetricsSnapshot) {
+        let mut saved = self.saved.lock().unwrap();
+        self.total_requests.store(snapshot.total_requests, Ordering::Relaxed);
+        *self.endpoints.lock().unwrap() = snapshot.endpoints;
+        *self.quota.lock().unwrap() = if snapshot.quota.day == current_day() {
+            snapshot.quota
+        } else {
+            QuotaUsage { day: current_day(), used: 0 }
+        };
+        *saved = self.snapshot();
+    }
+
+    /// Adds counts made elsewhere, e.g. by another process sharing the snapshot file.
+    fn absorb(&self, counts: &MetricsSnapshot) {
+        self.total_requests.fetch_add(counts.total_requests, Ordering::Relaxed);
+        let mut endpoints = self.endpoints.lock().unwrap();
+        for (route, count) in &counts.endpoints {
+            *endpoints.entry(route.clone()).or_insert(0) += count;
+        }
+        let mut quota = self.quota.lock().unwrap();
+        if quota.day == counts.quota.day {
+            quota.used += counts.quota.used;
+        }
+    }
+
+    /// Adds the counts made since the last save or restore to the snapshot in the given file.
+    ///
+    /// Other processes may have added theirs to the file in the meantime, e.g. the old & new instance during a
+    /// zero-downtime restart. Their counts are taken over, so all processes sharing the file end up with the
+    /// same totals. The file is locked while it's merged, and the snapshot is written to a temporary file first
+    /// and then moved into place, so a crash mid-write never leaves a corrupt snapshot behind.
+    pub fn save_to(&self, path: &Path) -> io::Result<()> {
+        let _lock = SnapshotLock::acquire(path)?;
+        let mut saved = self.saved.lock().unwrap();
+        let mut merged = match fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => MetricsSnapshot::default(),
+            Err(e) => return Err(e),
+        };
+        let current = self.snapshot();
+        merged.add(&current.since(&saved));
+
+        let json = serde_json::to_vec(&merged)?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, json)?;
+        fs::rename(&tmp_path, path)?;
+        self.absorb(&merged.since(&current));
+        *saved = merged;
+        Ok(())
+    }
+
+    /// Restores all counters from the snapshot in the given file.
+    pub fn load_from(&self, path: &Path) -> io::Result<()> {
+        let json = fs::read(path)?;
+        let snapshot = serde_json::from_slice(&json)?;
+        self.restore(snapshot);
+        Ok(())
+    }
+}
+
+/// Restores [`METRICS`] from the snapshot file, if there is one.
+///
+/// Does nothing if `METRICS_SNAPSHOT_FILE` is not set.
+pub fn restore_snapshot() {
+    let path = match METRICS_SNAPSHOT_FILE.as_ref() {
+        Some(path) => Path::new(path),
+        None => return,
+    };
+    match METRICS.load_from(path) {
+        Ok(()) => println!("<-> Restored metrics from {}", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => eprintln!("<!> Could not restore metrics from {}: {}", path.display(), e),
+    }
+}
+
+/// Writes a snapshot of [`METRICS`] to the snapshot file.
+///
+/// Does nothing if `METRICS_SNAPSHOT_FILE` is not set.
+pub fn save_snapshot() {
+    if let Some(path) = METRICS_SNAPSHOT_FILE.as_ref() {
+        if let Err(e) = METRICS.save_to(Path::new(path)) {
+            eprintln!("<!> Could not write metrics snapshot to {}: {}", path, e);
+        }
+    }
+}
+
+/// Keeps writing snapshots of [`METRICS`] to the snapshot file, forever.
+///
+/// Returns immediately if `METRICS_SNAPSHOT_FILE` is not set.
+pub async fn persist_snapshots() {
+    if METRICS_SNAPSHOT_FILE.is_none() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(*METRICS_SNAPSHOT_INTERVAL);
+    // The first tick completes immediately, skip it
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        save_snapshot();
+    }
+}
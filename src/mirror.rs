@@ -0,0 +1,157 @@
+//! A mirror of downloaded files, on local disk or in an S3-compatible store.
+//!
+//! If `DOWNLOAD_MIRROR_DIR` is set, files downloaded through the proxy (see [`crate::downloads`]) are written
+//! to that directory while they are streamed to the client, and later downloads of the same file are served
+//! from disk instead of the CDN. With `DOWNLOAD_MIRROR_S3_BUCKET` instead, they're uploaded to that bucket (see
+//! [`s3`]). Files are stored under their file id & SHA-1 hash as reported by the CF api, so a file that was
+//! replaced upstream is never served from a stale copy. Files without a hash aren't mirrored.
+//!
+//! A download only ends up in the mirror if it completed: on disk, files are written under a temporary name and
+//! renamed once the last byte arrived. The mirror isn't evicted, its directory or bucket can be cleared at any time.
+
+pub mod s3;
+
+use std::env;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Response};
+use lazy_static::lazy_static;
+use rand::Rng;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+lazy_static! {
+    /// The mirror of this process. Read from the `DOWNLOAD_MIRROR_DIR` or `DOWNLOAD_MIRROR_S3_BUCKET` env variables,
+    /// files aren't mirrored if both are empty.
+    pub static ref MIRROR: Option<Box<dyn MirrorStore>> = match (env_value("DOWNLOAD_MIRROR_DIR"), env_value("DOWNLOAD_MIRROR_S3_BUCKET")) {
+        (Some(dir), _) => Some(Box::new(
+            DiskMirror::new(PathBuf::from(dir)).expect("Expected DOWNLOAD_MIRROR_DIR env var to point to a writable directory")
+        )),
+        (None, Some(bucket)) => Some(Box::new(
+            s3::S3Mirror::from_env(&bucket).unwrap_or_else(|e| panic!("Expected the DOWNLOAD_MIRROR_S3_BUCKET mirror to be set up: {}", e))
+        )),
+        (None, None) => None,
+    };
+}
+
+/// Size of the chunks mirrored files are read in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn env_value(name: &str) -> Option<String> {
+    env::var(name).ok().map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+/// Returns the key a file is mirrored under, or `None` if the hash isn't a hex-encoded hash.
+pub fn key(file_id: u64, sha1: &str) -> Option<String> {
+    match !sha1.is_empty() && sha1.bytes().all(|b| b.is_ascii_hexdigit()) {
+        true => Some(format!("{}-{}", file_id, sha1.to_ascii_lowercase())),
+        false => None,
+    }
+}
+
+/// Where downloaded files are mirrored, see the [module docs](self).
+pub trait MirrorStore: Send + Sync {
+    /// Returns a response streaming the mirrored file, or `None` if the file isn't mirrored.
+    fn open<'a>(&'a self, key: &'a str) -> Pin<Box<dyn Future<Output = Option<Response<Body>>> + Send + 'a>>;
+
+    /// Makes the response write its body to the mirror while it's streamed to the client.
+    ///
+    /// If the body can't be read, the client goes away or the file can't be written, the response is still
+    /// streamed as far as possible but nothing is mirrored.
+    fn store(&self, key: &str, response: Response<Body>) -> Response<Body>;
+}
+
+/// Mirrored files in a directory, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct DiskMirror {
+    dir: PathBuf,
+}
+
+impl DiskMirror {
+    /// Creates a mirror in the directory, creating the directory if it doesn't exist.
+    pub fn new(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(DiskMirror { dir })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    async fn read(&self, key: &str) -> Option<Response<Body>> {
+        let mut file = File::open(self.path(key)).await.ok()?;
+        let length = file.metadata().await.ok()?.len();
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let mut buf = vec![0; CHUNK_SIZE];
+            loop {
+                match file.read(&mut buf).await {
+                    Ok(0) => return,
+                    Ok(read) => {
+                        if sender.send_data(Bytes::copy_from_slice(&buf[..read])).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(_) => return sender.abort(),
+                }
+            }
+        });
+
+        let mut response = Response::new(body);
+        response.headers_mut().insert(CONTENT_LENGTH, length.into());
+        response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+        Some(response)
+    }
+}
+
+impl MirrorStore for DiskMirror {
+    fn open<'a>(&'a self, key: &'a str) -> Pin<Box<dyn Future<Output = Option<Response<Body>>> + Send + 'a>> {
+        Box::pin(self.read(key))
+    }
+
+    fn store(&self, key: &str, response: Response<Body>) -> Response<Body> {
+        let (parts, mut body) = response.into_parts();
+        let path = self.path(key);
+        // Concurrent downloads of the same file each write their own temporary file
+        let partial = self.dir.join(format!(".{}.{:016x}.part", key, rand::thread_rng().gen::<u64>()));
+
+        let (mut sender, mirrored_body) = Body::channel();
+        tokio::spawn(async move {
+            let mut file = File::create(&partial).await.ok();
+            while let Some(chunk) = body.data().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        fs::remove_file(&partial).await.ok();
+                        return sender.abort();
+                    }
+                };
+                if let Some(writing) = file.as_mut() {
+                    if writing.write_all(&chunk).await.is_err() {
+                        file = None;
+                        fs::remove_file(&partial).await.ok();
+                    }
+                }
+                if sender.send_data(chunk).await.is_err() {
+                    fs::remove_file(&partial).await.ok();
+                    return;
+                }
+            }
+
+            if let Some(mut file) = file {
+                match file.flush().await {
+                    Ok(_) if fs::rename(&partial, &path).await.is_ok() => println!("<-> Mirrored {}", path.display()),
+                    _ => {
+                        fs::remove_file(&partial).await.ok();
+                    }
+                }
+            }
+        });
+
+        Response::from_parts(parts, mirrored_body)
+    }
+}
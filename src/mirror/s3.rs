@@ -0,0 +1,173 @@
+//! A mirror in an S3-compatible bucket.
+//!
+//! Files are kept as objects named after their key in the bucket in `DOWNLOAD_MIRROR_S3_BUCKET`, addressed
+//! path-style so any S3-compatible store works. Requests are signed with the same `AWS_*` credentials & region
+//! as [AWS Secrets Manager](crate::secrets::aws). `DOWNLOAD_MIRROR_S3_ENDPOINT` overrides the endpoint, e.g. for
+//! MinIO or R2.
+//!
+//! Files are uploaded while they're streamed to the client. S3 only stores an object once all of its bytes
+//! arrived, so an aborted download never shows up in the mirror. The upload needs the file's length up front,
+//! files the CDN sends without a `Content-Length` aren't mirrored.
+
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hyper::body::HttpBody;
+use hyper::client::Client;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use crate::pool::{self, UpstreamConnector};
+use crate::request_id;
+use crate::secrets::aws::{self, Credentials};
+use super::MirrorStore;
+
+/// Mirrored files in an S3 bucket, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct S3Mirror {
+    bucket: String,
+    region: String,
+    endpoint: Uri,
+    credentials: Credentials,
+    client: Client<UpstreamConnector, Body>,
+}
+
+impl S3Mirror {
+    /// Creates a mirror in the bucket, using the region's public endpoint unless `endpoint` is given.
+    pub fn new(bucket: &str, region: &str, endpoint: Option<&str>, credentials: Credentials) -> Result<Self, String> {
+        let endpoint = endpoint.map(String::from)
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com/", region))
+            .parse::<Uri>().map_err(|e| format!("invalid S3 endpoint: {}", e))?;
+        let client = pool::standalone_client()?;
+        Ok(S3Mirror { bucket: bucket.to_string(), region: region.to_string(), endpoint, credentials, client })
+    }
+
+    /// Creates a mirror in the bucket from the `AWS_*` & `DOWNLOAD_MIRROR_S3_ENDPOINT` env variables.
+    pub fn from_env(bucket: &str) -> Result<Self, String> {
+        let endpoint = env::var("DOWNLOAD_MIRROR_S3_ENDPOINT").ok().filter(|endpoint| !endpoint.trim().is_empty());
+        S3Mirror::new(bucket, &aws::region_from_env()?, endpoint.as_deref(), aws::Credentials::from_env()?)
+    }
+
+    /// Builds a signed request for the object of the key. Bodies of `PUT`s are streamed unsigned.
+    fn request(&self, method: Method, key: &str, body: Body, content_length: Option<u64>) -> Result<Request<Body>, String> {
+        let path = format!("{}/{}/{}", self.endpoint.path().trim_end_matches('/'), self.bucket, key);
+        let host = self.endpoint.authority().map(|authority| authority.as_str()).unwrap_or_default().to_string();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+        let date = aws::amz_date(now);
+        let payload_hash = match method {
+            Method::PUT => String::from("UNSIGNED-PAYLOAD"),
+            _ => aws::payload_hash(b""),
+        };
+
+        let mut headers = vec![
+            ("host", host.as_str()),
+            ("x-amz-content-sha256", payload_hash.as_str()),
+            ("x-amz-date", date.as_str()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.as_str()));
+        }
+        let (canonical, signed_headers) = aws::canonical_request_for_hash(method.as_str(), &path, "", &headers, &payload_hash);
+        let authorization = aws::authorization(&self.credentials, &date, &self.region, "s3", &signed_headers, &canonical);
+
+        let uri = Uri::builder()
+            .scheme(self.endpoint.scheme_str().unwrap_or("https"))
+            .authority(host.as_str())
+            .path_and_query(path.as_str())
+            .build().map_err(|e| e.to_string())?;
+        let mut req = Request::builder().method(method).uri(uri);
+        for (name, value) in &headers {
+            req = req.header(*name, *value);
+        }
+        if let Some(length) = content_length {
+            req = req.header(CONTENT_LENGTH, length);
+        }
+        req.header("authorization", authorization).body(body).map_err(|e| e.to_string())
+    }
+
+    async fn read(&self, key: &str) -> Option<Response<Body>> {
+        let req = self.request(Method::GET, key, Body::empty(), None).ok()?;
+        let resp = match self.client.request(req).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                println!("{}<!> Could not reach the S3 mirror: {}", request_id::prefix(), e);
+                return None;
+            }
+        };
+        match resp.status() {
+            StatusCode::OK => {}
+            StatusCode::NOT_FOUND => return None,
+            status => {
+                println!("{}<!> S3 mirror answered {} for {}", request_id::prefix(), status.as_str(), key);
+                return None;
+            }
+        }
+
+        let (parts, body) = resp.into_parts();
+        let mut response = Response::new(body);
+        if let Some(length) = parts.headers.get(CONTENT_LENGTH) {
+            response.headers_mut().insert(CONTENT_LENGTH, length.clone());
+        }
+        response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+        Some(response)
+    }
+}
+
+impl MirrorStore for S3Mirror {
+    fn open<'a>(&'a self, key: &'a str) -> Pin<Box<dyn Future<Output = Option<Response<Body>>> + Send + 'a>> {
+        Box::pin(self.read(key))
+    }
+
+    fn store(&self, key: &str, response: Response<Body>) -> Response<Body> {
+        let length = response.headers().get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<u64>().ok());
+        let (upload_sender, upload_body) = Body::channel();
+        let upload = match length.map(|length| self.request(Method::PUT, key, upload_body, Some(length))) {
+            Some(Ok(upload)) => upload,
+            _ => return response,
+        };
+
+        let (parts, mut body) = response.into_parts();
+        let (mut sender, mirrored_body) = Body::channel();
+        let client = self.client.clone();
+        let object = format!("s3://{}/{}", self.bucket, key);
+        tokio::spawn(async move {
+            let uploaded = tokio::spawn(client.request(upload));
+            let mut uploading = Some(upload_sender);
+            while let Some(chunk) = body.data().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        // The upload is cut short, so S3 drops it
+                        if let Some(uploading) = uploading {
+                            uploading.abort();
+                        }
+                        return sender.abort();
+                    }
+                };
+                if let Some(upload) = uploading.as_mut() {
+                    if upload.send_data(chunk.clone()).await.is_err() {
+                        uploading = None;
+                    }
+                }
+                if sender.send_data(chunk).await.is_err() {
+                    if let Some(uploading) = uploading {
+                        uploading.abort();
+                    }
+                    return;
+                }
+            }
+
+            drop(uploading);
+            match uploaded.await {
+                Ok(Ok(resp)) if resp.status().is_success() => println!("<-> Mirrored {}", object),
+                Ok(Ok(resp)) => println!("<!> S3 mirror answered {} storing {}", resp.status().as_str(), object),
+                Ok(Err(e)) => println!("<!> Could not store {}: {}", object, e),
+                Err(_) => {}
+            }
+        });
+
+        Response::from_parts(parts, mirrored_body)
+    }
+}
@@ -0,0 +1,153 @@
+//! Following the CF api's pagination on behalf of clients.
+//!
+//! Searches and file listings are paginated. Requests with `x-proxy-paginate=all` in the query are answered
+//! with all pages at once: the proxy requests page after page (through the cache, like any other request,
+//! but without [prefetching](crate::prefetch) the page after - it's requested right away anyway) and combines
+//! their results, up to `PAGINATE_MAX_RESULTS`. Pages are always requested with the largest page size the CF
+//! api allows, and at most as many pages as `PAGINATE_MAX_RESULTS` takes at that size are requested.
+//! Every page after the first uses up another request of the client's rate limit.
+//!
+//! The combined response has the shape of a single page, with a `pagination` covering all results it holds.
+//! If a page fails, the client receives that page's response. Nothing is combined with `STRICT_PASSTHROUGH`.
+
+use std::env;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use hyper::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use crate::cache::Cache;
+use crate::config::ProxyConfig;
+use crate::{classify, error_response, finish_response, metrics, prefetch, proxy_request_with_cache, request_id, with_retry_after, ProxyError};
+
+lazy_static! {
+    /// How many results a combined response holds at most. Read from the `PAGINATE_MAX_RESULTS` env variable.
+    pub static ref PAGINATE_MAX_RESULTS: usize = env::var("PAGINATE_MAX_RESULTS").unwrap_or(String::from("500"))
+        .parse::<usize>().expect("Expected PAGINATE_MAX_RESULTS env var to contain a number");
+}
+
+/// The query parameter clients ask for all pages with.
+pub const PAGINATE_PARAM: &str = "x-proxy-paginate";
+
+/// The largest page size the CF api allows.
+pub const MAX_PAGE_SIZE: u64 = 50;
+
+/// Returns how many pages are requested for a combined response at most.
+pub fn max_pages() -> usize {
+    PAGINATE_MAX_RESULTS.div_ceil(MAX_PAGE_SIZE as usize).max(1)
+}
+
+/// Routes of the CF api that are paginated.
+pub const PAGINATED_ROUTES: &[&str] = &["/v1/mods/search", "/v1/mods/{id}/files"];
+
+/// Returns the path & query of the first page to request if the client asks for all pages, `None` otherwise.
+pub fn first_page(uri: &Uri) -> Option<String> {
+    if !PAGINATED_ROUTES.contains(&classify::classify(uri.path()).template.as_ref()) {
+        return None;
+    }
+    let mut paginate = false;
+    let mut params: Vec<String> = uri.query().unwrap_or("").split('&')
+        .filter(|param| !param.is_empty())
+        .filter(|param| match param.split_once('=') {
+            Some((PAGINATE_PARAM, "all")) => {
+                paginate = true;
+                false
+            }
+            Some(("pageSize", _)) => false,
+            _ => true,
+        })
+        .map(String::from)
+        .collect();
+    if !paginate {
+        return None;
+    }
+    params.push(format!("pageSize={}", MAX_PAGE_SIZE));
+    Some(format!("{}?{}", uri.path(), params.join("&")))
+}
+
+/// Combines the bodies of pages into the body of one page, holding at most `max_results` results. Returns
+/// `None` if one of the bodies isn't a page.
+pub fn combine_pages(pages: &[Value], max_results: usize) -> Option<Value> {
+    let first = pages.first()?;
+    let mut data = Vec::new();
+    for page in pages {
+        data.extend(page.get("data")?.as_array()?.iter().cloned());
+    }
+    data.truncate(max_results);
+    Some(json!({
+        "data": data,
+        "pagination": {
+            "index": first["pagination"]["index"],
+            "pageSize": data.len(),
+            "resultCount": data.len(),
+            "totalCount": first["pagination"]["totalCount"],
+        },
+    }))
+}
+
+/// Requests pages starting at `first_page` until there are no more, [`PAGINATE_MAX_RESULTS`] or [`max_pages`]
+/// are reached, and answers with the combined page. `req` is the client's request, whose headers are sent along.
+///
+/// `charge` takes another request off the client's rate limit budget, it's called before every page after the
+/// first. Once the budget is used up, the client receives `429`.
+pub async fn proxy_all_pages(
+    req: Request<Body>,
+    first_page: String,
+    remote_addr: &IpAddr,
+    cache: &Arc<dyn Cache>,
+    config: &Arc<ProxyConfig>,
+    charge: &(dyn Fn() -> Result<(), Duration> + Sync),
+) -> Response<Body> {
+    let mut headers = req.headers().clone();
+    // Compressed pages couldn't be combined
+    headers.remove(ACCEPT_ENCODING);
+
+    let mut pages = Vec::new();
+    let mut results = 0;
+    let mut next = Some(first_page);
+    while let Some(page) = next.take().filter(|_| results < *PAGINATE_MAX_RESULTS && pages.len() < max_pages()) {
+        let uri = match page.parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(_) => break,
+        };
+        if !pages.is_empty() {
+            if let Err(wait) = charge() {
+                println!("[{}{}] <!> Rate limit was hit after {} pages", remote_addr, request_id::tag(), pages.len());
+                metrics::METRICS.record_rate_limited_request();
+                return with_retry_after(error_response(StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded"), wait);
+            }
+        }
+        let mut page_req = Request::new(Body::empty());
+        *page_req.uri_mut() = uri.clone();
+        *page_req.headers_mut() = headers.clone();
+        // The next page is requested right after, prefetching it would only request it twice
+        page_req.extensions_mut().insert(prefetch::NoPrefetch);
+
+        let response = proxy_request_with_cache(page_req, remote_addr, cache, config).await.unwrap_or_else(ProxyError::into_response);
+        if !response.status().is_success() {
+            return response;
+        }
+        let body = match hyper::body::to_bytes(response.into_body()).await {
+            Ok(body) => body,
+            Err(_) => return error_response(StatusCode::BAD_GATEWAY, "Curseforge sent an invalid response or closed the connection"),
+        };
+        let page = match serde_json::from_slice::<Value>(&body) {
+            Ok(page) => page,
+            Err(_) => return error_response(StatusCode::BAD_GATEWAY, "Curseforge sent an invalid response or closed the connection"),
+        };
+        results += page["data"].as_array().map(Vec::len).unwrap_or(0);
+        next = prefetch::next_page(&uri, &body);
+        pages.push(page);
+    }
+
+    let combined = match combine_pages(&pages, *PAGINATE_MAX_RESULTS) {
+        Some(combined) => combined,
+        None => return error_response(StatusCode::BAD_GATEWAY, "Curseforge sent an invalid response or closed the connection"),
+    };
+    println!("[{}{}] <-> {} => combined {} pages", remote_addr, request_id::tag(), req.uri().path(), pages.len());
+    let mut response = Response::new(Body::from(combined.to_string()));
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+    finish_response(response, remote_addr, req.uri(), config)
+}
@@ -0,0 +1,252 @@
+//! Connections to upstreams.
+//!
+//! All requests to upstreams go through one client, which keeps connections open between requests so they
+//! don't each pay for a TCP & TLS handshake. Upstreams that offer HTTP/2 via ALPN are spoken to with HTTP/2,
+//! so concurrent requests share a single connection instead of each holding one of its own. Plain `http`
+//! upstreams, like internal ones behind a load balancer that terminates TLS, can be spoken to with HTTP/2 too
+//! (h2c), with `UPSTREAM_H2C`. Connections go through an HTTP or SOCKS5 proxy if one is configured, see [`egress`],
+//! and upstreams' hosts are resolved with [`dns`](crate::dns).
+
+use std::env;
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::HttpConnector;
+use hyper::http::uri::Scheme;
+use hyper::service::Service;
+use hyper::{Body, Client, Uri};
+use lazy_static::lazy_static;
+#[cfg(feature = "tls")]
+use openssl::ssl::{SslConnector, SslMethod};
+#[cfg(feature = "tls")]
+use openssl::x509::X509;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+use crate::dns::Resolver;
+use crate::{egress, STRICT_PASSTHROUGH};
+
+lazy_static! {
+    /// Whether HTTP/2 is offered to upstreams. Read from the `UPSTREAM_HTTP2` env variable. Never offered with
+    /// [`STRICT_PASSTHROUGH`], since HTTP/2 can't preserve header case.
+    pub static ref UPSTREAM_HTTP2: bool = env::var("UPSTREAM_HTTP2").unwrap_or(String::from("true"))
+        .parse::<bool>().expect("Expected UPSTREAM_HTTP2 env var to be either true or false");
+
+    /// Whether `http` upstreams are spoken to with HTTP/2 right away, without negotiating it first. Read from
+    /// the `UPSTREAM_H2C` env variable. Never done with [`STRICT_PASSTHROUGH`].
+    pub static ref UPSTREAM_H2C: bool = env::var("UPSTREAM_H2C").unwrap_or(String::from("false"))
+        .parse::<bool>().expect("Expected UPSTREAM_H2C env var to be either true or false");
+
+    /// How many idle connections are kept open per upstream. Read from the `UPSTREAM_POOL_MAX_IDLE` env
+    /// variable.
+    pub static ref POOL_MAX_IDLE: usize = env::var("UPSTREAM_POOL_MAX_IDLE").unwrap_or(String::from("32"))
+        .parse::<usize>().expect("Expected UPSTREAM_POOL_MAX_IDLE env var to contain a number");
+
+    /// How long idle connections are kept open. Read from the `UPSTREAM_POOL_IDLE_TIMEOUT_SECS` env variable.
+    pub static ref POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(env::var("UPSTREAM_POOL_IDLE_TIMEOUT_SECS").unwrap_or(String::from("90"))
+        .parse::<u64>().expect("Expected UPSTREAM_POOL_IDLE_TIMEOUT_SECS env var to contain a number"));
+
+    /// How often HTTP/2 connections are pinged, so dead ones are noticed before requests are sent on them.
+    /// Read from the `UPSTREAM_HTTP2_KEEPALIVE_SECS` env variable, `0` means never.
+    pub static ref HTTP2_KEEPALIVE: Option<Duration> = Some(env::var("UPSTREAM_HTTP2_KEEPALIVE_SECS").unwrap_or(String::from("30"))
+        .parse::<u64>().expect("Expected UPSTREAM_HTTP2_KEEPALIVE_SECS env var to contain a number"))
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+
+    /// The client requests to upstreams are sent with, see [`client`].
+    pub(crate) static ref CLIENT: Client<UpstreamConnector, Body> = build_client(*STRICT_PASSTHROUGH);
+
+    /// The client requests of proxies that don't pass requests through like the process does are sent with.
+    static ref OTHER_CLIENT: Client<UpstreamConnector, Body> = build_client(!*STRICT_PASSTHROUGH);
+}
+
+/// Returns the client requests to upstreams are sent with, for a proxy in strict pass-through mode or not (see
+/// [`ProxyConfig::strict_passthrough`](crate::config::ProxyConfig::strict_passthrough)).
+pub(crate) fn client(strict: bool) -> &'static Client<UpstreamConnector, Body> {
+    match strict == *STRICT_PASSTHROUGH {
+        true => &CLIENT,
+        false => &OTHER_CLIENT,
+    }
+}
+
+/// Builds a client for requests to upstreams. In strict pass-through mode, they're only spoken to with HTTP/1.1,
+/// preserving the case of headers.
+fn build_client(strict: bool) -> Client<UpstreamConnector, Body> {
+    let connector = UpstreamConnector::new(*UPSTREAM_HTTP2 && !strict)
+        .expect("Expected to be able to set up TLS for upstreams")
+        .h2c(*UPSTREAM_H2C && !strict);
+    Client::builder()
+        .pool_max_idle_per_host(*POOL_MAX_IDLE)
+        .pool_idle_timeout(*POOL_IDLE_TIMEOUT)
+        .http1_preserve_header_case(strict)
+        .http2_adaptive_window(true)
+        .http2_keep_alive_interval(*HTTP2_KEEPALIVE)
+        .build(connector)
+}
+
+/// Creates a client for requests the proxy makes on its own behalf (to secret stores, or an ACME server),
+/// independent of the upstream settings.
+pub(crate) fn standalone_client() -> Result<Client<UpstreamConnector, Body>, String> {
+    let connector = UpstreamConnector::new(true).map_err(|e| format!("could not set up TLS: {}", e))?;
+    Ok(Client::builder().build(connector))
+}
+
+/// Connects to upstreams over plain TCP for `http` urls, and over TLS for `https` urls - unless built without
+/// the `tls` feature, then `https` upstreams can't be connected to.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    http: HttpConnector<Resolver>,
+    #[cfg(feature = "tls")]
+    tls: SslConnector,
+    h2c: bool,
+}
+
+impl UpstreamConnector {
+    /// Creates a connector that trusts the system's root certificates, and offers HTTP/2 if `http2` is set.
+    #[cfg(feature = "tls")]
+    pub fn new(http2: bool) -> io::Result<Self> {
+        Self::with_root_certificates(http2, &[])
+    }
+
+    /// Creates a connector for `http` upstreams. HTTP/2 is only offered to upstreams over TLS, so `http2` has no
+    /// effect.
+    #[cfg(not(feature = "tls"))]
+    pub fn new(_http2: bool) -> io::Result<Self> {
+        let mut http = HttpConnector::new_with_resolver(Resolver);
+        http.enforce_http(false);
+        Ok(UpstreamConnector { http, h2c: false })
+    }
+
+    /// Creates a connector that trusts the system's root certificates along with `root_certificates`, and
+    /// offers HTTP/2 if `http2` is set.
+    #[cfg(feature = "tls")]
+    pub fn with_root_certificates(http2: bool, root_certificates: &[X509]) -> io::Result<Self> {
+        let mut http = HttpConnector::new_with_resolver(Resolver);
+        http.enforce_http(false);
+
+        let invalid_data = |e: openssl::error::ErrorStack| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+        let mut tls = SslConnector::builder(SslMethod::tls_client()).map_err(invalid_data)?;
+        // Like native-tls, look for root certificates where the distribution keeps them
+        let probe = openssl_probe::probe();
+        if let Some(file) = probe.cert_file {
+            tls.set_ca_file(file).ok();
+        }
+        for cert in root_certificates {
+            tls.cert_store_mut().add_cert(cert.clone()).map_err(invalid_data)?;
+        }
+        tls.set_alpn_protos(if http2 { crate::tls::ALPN_PROTOCOLS } else { b"\x08http/1.1" }).map_err(invalid_data)?;
+        Ok(UpstreamConnector { http, tls: tls.build(), h2c: false })
+    }
+
+    /// Speaks HTTP/2 to `http` upstreams right away if `h2c` is set, instead of HTTP/1.1.
+    pub fn h2c(mut self, h2c: bool) -> Self {
+        self.h2c = h2c;
+        self
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<UpstreamStream, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let mut http = self.http.clone();
+        #[cfg(feature = "tls")]
+        let tls = self.tls.clone();
+        let h2c = self.h2c;
+        Box::pin(async move {
+            let secure = uri.scheme() == Some(&Scheme::HTTPS);
+            #[cfg(feature = "tls")]
+            let host = uri.host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']').to_string();
+            let stream = match egress::proxy_for(&uri) {
+                Some(proxy) => {
+                    let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+                    let mut stream = http.call(proxy.uri().clone()).await?;
+                    proxy.tunnel(&mut stream, uri.host().unwrap_or_default(), port).await?;
+                    stream
+                }
+                None => http.call(uri).await?,
+            };
+            match (secure, h2c) {
+                (false, false) => Ok(UpstreamStream::Plain(stream)),
+                (false, true) => Ok(UpstreamStream::H2c(stream)),
+                #[cfg(feature = "tls")]
+                (true, _) => {
+                    let ssl = tls.configure()?.into_ssl(&host)?;
+                    Ok(UpstreamStream::Tls(Box::new(TlsStream::connect(ssl, stream).await?)))
+                }
+                #[cfg(not(feature = "tls"))]
+                (true, _) => Err("https upstreams need cfproxy built with the `tls` feature".into()),
+            }
+        })
+    }
+}
+
+/// A connection to an upstream.
+#[derive(Debug)]
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    /// A plain connection that's spoken to with HTTP/2.
+    H2c(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        match self {
+            UpstreamStream::Plain(stream) => stream.connected(),
+            UpstreamStream::H2c(stream) => stream.connected().negotiated_h2(),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) if stream.alpn_protocol() == Some(b"h2") => stream.get_ref().connected().negotiated_h2(),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => stream.get_ref().connected(),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) | UpstreamStream::H2c(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) | UpstreamStream::H2c(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) | UpstreamStream::H2c(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) | UpstreamStream::H2c(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Prefetching of the next page of paginated responses.
+//!
+//! Clients paging through a search almost always request the next page seconds later. If
+//! `PREFETCH_NEXT_PAGE` is enabled (and the response cache is, see [`crate::cache`]), the proxy requests page
+//! N+1 in the background whenever it serves page N from the CF api, so the follow-up is answered from the cache.
+//!
+//! Requests the proxy makes on its own are bounded by `BACKGROUND_REQ_LIMIT_PER_HOUR` - a prefetch is skipped
+//! instead of delayed when the budget is used up.
+
+use std::collections::HashSet;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use governor::clock::DefaultClock;
+use governor::state::{InMemoryState, NotKeyed};
+use governor::{Quota, RateLimiter};
+use hyper::{Body, HeaderMap, Request, Uri};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use crate::cache::{self, Cache, CachedResponse};
+use crate::config::ProxyConfig;
+use crate::{profile, request_id};
+
+lazy_static! {
+    /// Whether the next page of paginated responses is prefetched. Read from the `PREFETCH_NEXT_PAGE` env variable.
+    pub static ref PREFETCH_NEXT_PAGE: bool = profile::env_or("PREFETCH_NEXT_PAGE", "false")
+        .parse::<bool>().expect("Expected PREFETCH_NEXT_PAGE env var to be either true or false");
+
+    /// How many requests per hour the proxy may make on its own. Read from the `BACKGROUND_REQ_LIMIT_PER_HOUR` env variable.
+    pub static ref BACKGROUND_REQ_LIMIT_PER_HOUR: NonZeroU32 = profile::env_or("BACKGROUND_REQ_LIMIT_PER_HOUR", "3600")
+        .parse::<NonZeroU32>().expect("Expected BACKGROUND_REQ_LIMIT_PER_HOUR env var to contain a positive number");
+
+    static ref BACKGROUND_LIMITER: RateLimiter<NotKeyed, InMemoryState, DefaultClock> =
+        RateLimiter::direct(Quota::per_hour(*BACKGROUND_REQ_LIMIT_PER_HOUR));
+
+    /// Pages that are being prefetched right now, so each is only requested once.
+    static ref IN_FLIGHT: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Marks a request whose next page isn't prefetched, because whoever made it requests that page right away
+/// anyway - like [`crate::paginate`] does. Kept in the request's extensions.
+#[derive(Debug, Clone, Copy)]
+pub struct NoPrefetch;
+
+/// The CF api doesn't return results beyond this index, requests for them fail with `400`.
+const MAX_INDEX: u64 = 10_000;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Pagination {
+    index: u64,
+    page_size: u64,
+    total_count: u64,
+}
+
+#[derive(Deserialize)]
+struct Paginated {
+    pagination: Pagination,
+}
+
+/// Returns the path & query of the page after the one the response body belongs to, or `None` if the
+/// response isn't paginated or is the last page.
+///
+/// The CF api pages with an `index` (the offset of the first result) and a `pageSize`, so the next page is
+/// requested by moving `index` forwards by the page size. All other query parameters are kept in order.
+pub fn next_page(uri: &Uri, body: &[u8]) -> Option<String> {
+    let pagination = serde_json::from_slice::<Paginated>(body).ok()?.pagination;
+    if pagination.page_size == 0 {
+        return None;
+    }
+    let next_index = pagination.index + pagination.page_size;
+    if next_index >= pagination.total_count || next_index >= MAX_INDEX {
+        return None;
+    }
+
+    let mut replaced = false;
+    let mut params: Vec<String> = uri.query().unwrap_or("").split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| match param.split_once('=') {
+            Some(("index", _)) => {
+                replaced = true;
+                format!("index={}", next_index)
+            }
+            _ => param.to_string(),
+        })
+        .collect();
+    if !replaced {
+        params.push(format!("index={}", next_index));
+    }
+    Some(format!("{}?{}", uri.path(), params.join("&")))
+}
+
+/// Prefetches the page after the one the response body belongs to into `cache`, in the background, from the
+/// CF api in `config`.
+///
+/// `headers` are the headers of the client's request, and are sent along with the prefetch. Does nothing if
+/// there is no next page, it's already cached or being prefetched, or the background budget is used up.
+pub fn prefetch_next_page(uri: &Uri, headers: HeaderMap, body: &[u8], cache: Arc<dyn Cache>, config: Arc<ProxyConfig>) {
+    let next = match next_page(uri, body) {
+        Some(next) => next,
+        None => return,
+    };
+    let next_uri = match next.parse::<Uri>() {
+        Ok(next_uri) => next_uri,
+        Err(_) => return,
+    };
+    if cache.contains(&next) || !IN_FLIGHT.lock().unwrap().insert(next.clone()) {
+        return;
+    }
+    if BACKGROUND_LIMITER.check().is_err() {
+        IN_FLIGHT.lock().unwrap().remove(&next);
+        return;
+    }
+
+    request_id::spawn(async move {
+        let mut req = Request::new(Body::empty());
+        *req.uri_mut() = next_uri;
+        *req.headers_mut() = headers.clone();
+
+        match crate::request_cf(req, &config).await {
+            Ok(resp) => {
+                println!("{}<-> Prefetched {} => {}", request_id::prefix(), next, resp.status().as_str());
+                // Cached like a response to the client would be, so it's served the same way
+                match crate::prepare_response(resp, &config).await {
+                    Ok(resp) if cache::is_cacheable(resp.status(), resp.headers()) => {
+                        let (parts, body) = resp.into_parts();
+                        if let Ok(body) = hyper::body::to_bytes(body).await {
+                            cache.insert(next.clone(), CachedResponse::new(parts.status, parts.headers, body).varying_on(&headers));
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => eprintln!("{}<!> Prefetching {} failed: {:#?}", request_id::prefix(), next, err),
+                }
+            }
+            Err(err) => eprintln!("{}<!> Prefetching {} failed: {:#?}", request_id::prefix(), next, err),
+        }
+        IN_FLIGHT.lock().unwrap().remove(&next);
+    });
+}
@@ -0,0 +1,83 @@
+//! Presets of limits for common kinds of deployments.
+//!
+//! Setting `LIMITS_PROFILE` to one of the presets changes the defaults of several settings at once, so new
+//! operators get a coherent configuration without tuning every variable. Variables that are set explicitly
+//! still win over the preset.
+//!
+//! The values each preset sets are listed in [`Profile::default_for`].
+
+use std::env;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// The selected preset. Read from the `LIMITS_PROFILE` env variable.
+    pub static ref LIMITS_PROFILE: Option<Profile> = env::var("LIMITS_PROFILE").ok()
+        .filter(|profile| !profile.trim().is_empty())
+        .map(|profile| profile.parse::<Profile>().expect("Expected LIMITS_PROFILE env var to be one of public-community, private-team or single-user"));
+}
+
+/// A preset of limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// A proxy open to everyone, like the "official" one: tight limits per client, and aggressive caching to
+    /// make the most of the shared key.
+    PublicCommunity,
+    /// A proxy shared by the apps of a team: generous limits, short-lived cache.
+    PrivateTeam,
+    /// A proxy used by a single person or app: limits are effectively off, little caching.
+    SingleUser,
+}
+
+impl std::str::FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "public-community" => Ok(Profile::PublicCommunity),
+            "private-team" => Ok(Profile::PrivateTeam),
+            "single-user" => Ok(Profile::SingleUser),
+            other => Err(format!("unknown limits profile `{}`", other)),
+        }
+    }
+}
+
+impl Profile {
+    /// Returns the value the preset sets for an env variable, if it sets one.
+    pub fn default_for(&self, var: &str) -> Option<&'static str> {
+        let value = match (self, var) {
+            (Profile::PublicCommunity, "REQ_LIMIT_PER_HOUR") => "3600",
+            (Profile::PrivateTeam, "REQ_LIMIT_PER_HOUR") => "36000",
+            (Profile::SingleUser, "REQ_LIMIT_PER_HOUR") => "360000",
+
+            (Profile::PublicCommunity, "BACKGROUND_REQ_LIMIT_PER_HOUR") => "3600",
+            (Profile::PrivateTeam, "BACKGROUND_REQ_LIMIT_PER_HOUR") => "7200",
+            (Profile::SingleUser, "BACKGROUND_REQ_LIMIT_PER_HOUR") => "600",
+
+            (Profile::PublicCommunity, "CACHE_TTL_SECS") => "300",
+            (Profile::PrivateTeam, "CACHE_TTL_SECS") => "60",
+            (Profile::SingleUser, "CACHE_TTL_SECS") => "30",
+
+            (Profile::PublicCommunity, "CACHE_MAX_ENTRIES") => "50000",
+            (Profile::PrivateTeam, "CACHE_MAX_ENTRIES") => "10000",
+            (Profile::SingleUser, "CACHE_MAX_ENTRIES") => "1000",
+
+            (Profile::PublicCommunity, "PREFETCH_NEXT_PAGE") => "true",
+            (Profile::PrivateTeam, "PREFETCH_NEXT_PAGE") => "true",
+            (Profile::SingleUser, "PREFETCH_NEXT_PAGE") => "false",
+
+            (Profile::PublicCommunity, "UPSTREAM_TIMEOUT_SECS") => "10",
+            (Profile::PrivateTeam, "UPSTREAM_TIMEOUT_SECS") => "30",
+            (Profile::SingleUser, "UPSTREAM_TIMEOUT_SECS") => "60",
+
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// Reads an env variable, falling back to the selected preset's value and then to `default`.
+pub fn env_or(var: &str, default: &str) -> String {
+    env::var(var).ok()
+        .or_else(|| LIMITS_PROFILE.and_then(|profile| profile.default_for(var)).map(String::from))
+        .unwrap_or_else(|| String::from(default))
+}
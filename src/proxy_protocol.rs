@@ -0,0 +1,105 @@
+//! Parsing of PROXY protocol headers, as sent by HAProxy and TCP load balancers.
+//!
+//! A load balancer that proxies plain TCP can't add a header carrying the client's address to the HTTP
+//! request. Instead, it sends a PROXY protocol header before the request, on every connection it opens.
+//! Both the human-readable version 1 (`PROXY TCP4 <src> <dst> <sport> <dport>\r\n`) and the binary version
+//! 2 are supported.
+//!
+//! If `PROXY_PROTOCOL` is enabled, every connection has to start with a header - connections without one
+//! are closed, so only enable it if all connections come through such a load balancer.
+
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Whether connections start with a PROXY protocol header. Read from the `PROXY_PROTOCOL` env variable.
+    pub static ref PROXY_PROTOCOL: bool = env::var("PROXY_PROTOCOL").unwrap_or(String::from("false"))
+        .parse::<bool>().expect("Expected PROXY_PROTOCOL env var to be either true or false");
+}
+
+/// Signature version 2 headers start with.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Version 1 headers are at most this long, including the `\r\n`.
+const V1_MAX_LEN: usize = 107;
+
+/// A parsed PROXY protocol header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The address of the client, `None` if the load balancer didn't send one (e.g. for its own health checks).
+    pub source: Option<SocketAddr>,
+    /// Length of the header in bytes, the request follows right after.
+    pub len: usize,
+}
+
+/// Parses the PROXY protocol header at the start of `buf`.
+///
+/// Returns `Ok(None)` if `buf` doesn't contain the whole header yet, and an error if it doesn't start with
+/// a valid header.
+pub fn parse_header(buf: &[u8]) -> Result<Option<ProxyHeader>, String> {
+    let prefix = &buf[..buf.len().min(V2_SIGNATURE.len())];
+    if V2_SIGNATURE.starts_with(prefix) {
+        return match buf.len() < V2_SIGNATURE.len() {
+            true => Ok(None),
+            false => parse_v2(buf),
+        };
+    }
+    if b"PROXY ".starts_with(&buf[..buf.len().min(6)]) {
+        return parse_v1(buf);
+    }
+    Err(String::from("connection doesn't start with a PROXY protocol header"))
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<ProxyHeader>, String> {
+    let end = match buf.windows(2).position(|window| window == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() >= V1_MAX_LEN => return Err(String::from("PROXY protocol v1 header is too long")),
+        None => return Ok(None),
+    };
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| String::from("PROXY protocol v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let source = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip = source.parse::<IpAddr>().map_err(|_| format!("invalid source address `{}`", source))?;
+            let port = source_port.parse::<u16>().map_err(|_| format!("invalid source port `{}`", source_port))?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(format!("invalid PROXY protocol v1 header `{}`", line)),
+    };
+    Ok(Some(ProxyHeader { source, len: end + 2 }))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<ProxyHeader>, String> {
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+    let (version, command) = (buf[12] >> 4, buf[12] & 0x0f);
+    if version != 2 {
+        return Err(format!("unsupported PROXY protocol version {}", version));
+    }
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let addresses = &buf[16..len];
+    let source = match (command, buf[13] >> 4) {
+        // LOCAL: the load balancer connected on its own behalf
+        (0, _) => None,
+        (1, 1) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([addresses[8], addresses[9]])))
+        }
+        (1, 2) if addresses.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), u16::from_be_bytes([addresses[32], addresses[33]])))
+        }
+        // Unix sockets & unspecified families carry no IP address
+        (1, 0 | 3) => None,
+        (1, _) => return Err(String::from("PROXY protocol v2 header is too short for its address family")),
+        (command, _) => return Err(format!("unsupported PROXY protocol command {}", command)),
+    };
+    Ok(Some(ProxyHeader { source, len }))
+}
@@ -0,0 +1,112 @@
+//! Request ids, to correlate the reports of clients with the proxy's logs.
+//!
+//! Every request gets an id: the one the client sent in `REQUEST_ID_HEADER` if it's reasonable, or a new random
+//! one otherwise. The id is part of every log line about the request, sent along to the upstream, and echoed in
+//! the response - except with `STRICT_PASSTHROUGH`, where the id is only logged.
+
+use std::env;
+use std::fmt;
+use std::future::Future;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Request, Response};
+use lazy_static::lazy_static;
+use tokio::task::JoinHandle;
+use crate::config::ProxyConfig;
+
+lazy_static! {
+    /// The header request ids are read from and sent in. Read from the `REQUEST_ID_HEADER` env variable.
+    pub static ref REQUEST_ID_HEADER: HeaderName = HeaderName::from_bytes(
+        env::var("REQUEST_ID_HEADER").unwrap_or(String::from("x-request-id")).as_bytes()
+    ).expect("Expected REQUEST_ID_HEADER env var to contain a valid header name");
+}
+
+tokio::task_local! {
+    /// The id of the request being handled.
+    static CURRENT: RequestId;
+}
+
+/// Client ids longer than this are replaced.
+const MAX_LEN: usize = 128;
+
+/// The id of a request.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Generates a new random id.
+    pub fn generate() -> Self {
+        RequestId(format!("{:032x}", rand::random::<u128>()))
+    }
+
+    /// Returns the id the client sent along with the request, if it's one that may be adopted: at most 128
+    /// visible ASCII characters, so it can't mess up log lines.
+    pub fn from_request<B>(req: &Request<B>) -> Option<Self> {
+        req.headers().get(&*REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|byte| byte.is_ascii_graphic()))
+            .map(|id| RequestId(id.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Gives the request its id - adopting the client's, or a new one - and keeps it in the request's extensions &
+/// header, so it's forwarded upstream by a proxy with `config`.
+pub fn assign<B>(req: &mut Request<B>, config: &ProxyConfig) -> RequestId {
+    let id = RequestId::from_request(req).unwrap_or_else(RequestId::generate);
+    if !config.strict_passthrough() {
+        // Generated ids only consist of hex digits, and adopted ones were a valid header value already
+        req.headers_mut().insert(REQUEST_ID_HEADER.clone(), HeaderValue::from_str(id.as_str()).unwrap());
+    }
+    req.extensions_mut().insert(id.clone());
+    id
+}
+
+/// Adds the id to the response of a proxy with `config`, so clients can report it.
+pub fn echo<B>(mut response: Response<B>, id: &RequestId, config: &ProxyConfig) -> Response<B> {
+    if !config.strict_passthrough() {
+        response.headers_mut().insert(REQUEST_ID_HEADER.clone(), HeaderValue::from_str(id.as_str()).unwrap());
+    }
+    response
+}
+
+/// Runs `f` as the handling of the request with the id, so log lines written by it carry the id.
+pub async fn scope<F: Future>(id: RequestId, f: F) -> F::Output {
+    CURRENT.scope(id, f).await
+}
+
+/// Spawns `f` as a task that's part of handling the current request, if any, so log lines written by it carry
+/// the request's id - `tokio::spawn` doesn't pass the id on.
+pub fn spawn<F>(f: F) -> JoinHandle<F::Output>
+    where F: Future + Send + 'static, F::Output: Send + 'static
+{
+    match current() {
+        Some(id) => tokio::spawn(CURRENT.scope(id, f)),
+        None => tokio::spawn(f),
+    }
+}
+
+/// Returns the id of the request being handled, if any.
+pub fn current() -> Option<RequestId> {
+    CURRENT.try_with(RequestId::clone).ok()
+}
+
+/// Returns the id of the request being handled for log lines that start with the client's address, like
+/// `[<ip><tag>] <-> ...` - a space and the id, or nothing outside of requests.
+pub fn tag() -> String {
+    current().map(|id| format!(" {}", id)).unwrap_or_default()
+}
+
+/// Returns the id of the request being handled for log lines that don't start with the client's address, like
+/// `<prefix><!> ...` - the id in brackets and a space, or nothing outside of requests.
+pub fn prefix() -> String {
+    current().map(|id| format!("[{}] ", id)).unwrap_or_default()
+}
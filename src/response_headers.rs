@@ -0,0 +1,93 @@
+//! Which headers of CF api responses are forwarded to clients.
+//!
+//! Not everything Curseforge sends is meant for the proxy's clients: cookies belong to the proxy's session,
+//! CDN & server headers reveal infrastructure details, and rate limit headers describe the quota of the proxy's api
+//! key rather than the client's. These are stripped by default (see [`DEFAULT_STRIPPED`]).
+//!
+//! `RESPONSE_HEADERS_STRIP` strips more headers, `RESPONSE_HEADERS_KEEP` forwards headers that would be
+//! stripped otherwise. Both are comma separated header names, a name ending in `*` matches every header
+//! starting with it. Nothing is stripped with `STRICT_PASSTHROUGH`.
+
+use std::env;
+use hyper::HeaderMap;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// The policy of this process, read from the `RESPONSE_HEADERS_STRIP` & `RESPONSE_HEADERS_KEEP` env variables.
+    pub static ref RESPONSE_HEADER_POLICY: HeaderPolicy = HeaderPolicy::new(
+        DEFAULT_STRIPPED.iter().map(|name| name.to_string())
+            .chain(names_from_env("RESPONSE_HEADERS_STRIP"))
+            .collect(),
+        names_from_env("RESPONSE_HEADERS_KEEP"),
+    );
+}
+
+/// Response headers that are stripped unless configured otherwise.
+pub const DEFAULT_STRIPPED: &[&str] = &[
+    // Cookies of the proxy's session with the api
+    "set-cookie",
+    "set-cookie2",
+    // CDN internals
+    "cf-ray",
+    "cf-cache-status",
+    "x-amz-cf-id",
+    "x-amz-cf-pop",
+    "x-amzn-*",
+    "x-cache",
+    "x-served-by",
+    "server-timing",
+    "report-to",
+    "nel",
+    "alt-svc",
+    // Software of the api's servers
+    "server",
+    "x-powered-by",
+    "x-aspnet-version",
+    "x-aspnetmvc-version",
+    // Rate limits of the proxy's api key
+    "x-ratelimit-*",
+    "ratelimit-*",
+];
+
+fn names_from_env(var: &str) -> Vec<String> {
+    env::var(var).unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+fn name_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+/// Header names (or prefixes, ending in `*`) to strip, and ones to forward regardless.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderPolicy {
+    strip: Vec<String>,
+    keep: Vec<String>,
+}
+
+impl HeaderPolicy {
+    /// Creates a policy from lowercase header names.
+    pub fn new(strip: Vec<String>, keep: Vec<String>) -> Self {
+        HeaderPolicy { strip, keep }
+    }
+
+    /// Returns whether the header is forwarded to clients.
+    pub fn is_forwarded(&self, name: &str) -> bool {
+        self.keep.iter().any(|pattern| name_matches(pattern, name))
+            || !self.strip.iter().any(|pattern| name_matches(pattern, name))
+    }
+
+    /// Removes the headers that aren't forwarded.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        let stripped: Vec<_> = headers.keys().filter(|name| !self.is_forwarded(name.as_str())).cloned().collect();
+        for name in stripped {
+            headers.remove(name);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! Routes the proxy answers itself, instead of forwarding them to the CF api.
+//!
+//! Local routes live under `/_` so they can't clash with paths of the CF api, except for `/status`. `GET /_routes` lists every
+//! local route along with the policies that apply to proxied paths, so clients and operators can discover
+//! what a deployment supports. `GET /_slo` reports the state of the SLOs (see [`crate::slo`]), `GET /_hints`
+//! suggests poll intervals to clients (see [`crate::hints`]), `GET /_status` reports runtime statistics (see
+//! [`StatusReport`]), and so does `GET /status`, an alias for dashboards that expect it there. In strict pass-through mode (see [`STRICT_PASSTHROUGH`](crate::STRICT_PASSTHROUGH)) the proxy answers none of them,
+//! requests for them are forwarded like any other.
+//!
+//! Only paths matching one of the `ALLOWED_PATHS` are forwarded to the CF api, everything else is answered
+//! with `404` - scanners probing for random paths don't get to use up the api quota. Requests with a
+//! method the route doesn't accept (see [`allowed_methods`]) are answered with `405`. Neither applies in
+//! strict pass-through mode, where every path & method is forwarded.
+//!
+//! The allowed paths & base path can be configured per proxy too, see [`ProxyConfig`].
+//!
+//! If the proxy is mounted under a `BASE_PATH` (like `/cfproxy` behind an existing site), the base path is
+//! removed from every request before anything else looks at it, and requests outside of it are answered
+//! with `404`. `GET /_routes` lists routes under the base path, so clients can use the paths it lists as they are.
+//! The original path is kept in an [`OriginalUri`] extension of the request, request signatures are made over it
+//! (see [`signing`](crate::signing)).
+//!
+//! Local routes report how the proxy is doing, so they're only answered to clients that would be allowed to
+//! make a proxied request: they need the same bearer token, signature and proxy token, are subject to the access
+//! rules and use up the client's rate limit. Listeners can be limited to a [`RouteSet`], like an admin port on
+//! localhost that only answers local routes next to a public port that only proxies - listeners that only answer
+//! local routes answer them to anyone who can connect, only checking the bearer token.
+
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use hyper::header::{HeaderValue, ALLOW, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use crate::config::ProxyConfig;
+use crate::diagnostics::StatusReport;
+use crate::server::ProxyState;
+use crate::{classify, error_response, hints, paginate, rules, slo};
+
+lazy_static! {
+    /// Globs of the paths that are forwarded to the CF api. Read from the `ALLOWED_PATHS` env variable, as a
+    /// comma separated list.
+    pub static ref ALLOWED_PATHS: Vec<String> = env::var("ALLOWED_PATHS").unwrap_or(String::from("/v1/**"))
+        .split(',')
+        .map(str::trim)
+        .filter(|glob| !glob.is_empty())
+        .map(String::from)
+        .collect();
+
+    /// Path the proxy is mounted under, without a trailing `/`. Read from the `BASE_PATH` env variable.
+    pub static ref BASE_PATH: String = normalize_base_path(&env::var("BASE_PATH").unwrap_or_default());
+}
+
+/// Normalizes a base path to start with a `/` and end without one, `/` itself becomes the empty base path.
+pub fn normalize_base_path(path: &str) -> String {
+    match path.trim().trim_matches('/') {
+        "" => String::new(),
+        path => format!("/{}", path),
+    }
+}
+
+/// The uri of a request as the client sent it, before the base path was removed from it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginalUri(pub Uri);
+
+/// Removes the base path from the request's uri, returns `false` if the request isn't under the base path.
+/// The uri the client sent is kept in an [`OriginalUri`] extension of the request.
+pub fn strip_base_path(req: &mut Request<Body>, base_path: &str) -> bool {
+    if base_path.is_empty() {
+        return true;
+    }
+    let path_and_query = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let stripped = match path_and_query.strip_prefix(base_path) {
+        Some(rest) if rest.is_empty() || rest.starts_with('?') => format!("/{}", rest),
+        Some(rest) if rest.starts_with('/') => rest.to_string(),
+        _ => return false,
+    };
+    match stripped.parse::<Uri>() {
+        Ok(uri) => {
+            let original = std::mem::replace(req.uri_mut(), uri);
+            req.extensions_mut().insert(OriginalUri(original));
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// A route the proxy answers itself.
+#[derive(Debug, Serialize)]
+pub struct LocalRoute {
+    pub path: &'static str,
+    pub methods: &'static [&'static str],
+    pub description: &'static str,
+}
+
+/// All local routes.
+pub const LOCAL_ROUTES: &[LocalRoute] = &[
+    LocalRoute {
+        path: "/_routes",
+        methods: &["GET"],
+        description: "Lists the routes handled by the proxy itself, and the policies of proxied paths",
+    },
+    LocalRoute {
+        path: "/_hints",
+        methods: &["GET"],
+        description: "Limits, cache TTLs and suggested poll intervals, for clients to tune themselves",
+    },
+    LocalRoute {
+        path: "/_slo",
+        methods: &["GET"],
+        description: "Availability & latency SLOs of proxied requests: error budgets and burn rates",
+    },
+    LocalRoute {
+        path: "/_status",
+        methods: &["GET"],
+        description: "Runtime statistics: uptime, request totals, in-flight requests, cache, rate limiters and upstream health",
+    },
+    LocalRoute {
+        path: "/status",
+        methods: &["GET"],
+        description: "Alias of /_status",
+    },
+];
+
+/// Returns whether the path is one of the [`LOCAL_ROUTES`].
+pub fn is_local(path: &str) -> bool {
+    LOCAL_ROUTES.iter().any(|route| route.path == path)
+}
+
+/// The routes a listener serves, requests for other routes are answered with `404`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteSet {
+    /// Local & proxied routes.
+    All,
+    /// Only routes that are forwarded to the CF api.
+    Proxy,
+    /// Only the [`LOCAL_ROUTES`].
+    Local,
+}
+
+impl RouteSet {
+    /// Returns whether requests for the path are served.
+    pub fn serves(self, path: &str) -> bool {
+        match self {
+            RouteSet::All => true,
+            RouteSet::Proxy => !is_local(path),
+            RouteSet::Local => is_local(path),
+        }
+    }
+}
+
+impl fmt::Display for RouteSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RouteSet::All => "all",
+            RouteSet::Proxy => "proxy",
+            RouteSet::Local => "local",
+        })
+    }
+}
+
+impl FromStr for RouteSet {
+    type Err = String;
+
+    fn from_str(routes: &str) -> Result<Self, Self::Err> {
+        match routes {
+            "all" => Ok(RouteSet::All),
+            "proxy" => Ok(RouteSet::Proxy),
+            "local" => Ok(RouteSet::Local),
+            _ => Err(format!("unknown route set {}, expected all, proxy or local", routes)),
+        }
+    }
+}
+
+/// Policies for a set of paths that are forwarded to the CF api.
+#[derive(Debug, Serialize)]
+pub struct ProxiedRoute {
+    /// Template of the route (see [`crate::classify`]), or glob of the paths, `**` matches any number of segments.
+    pub pattern: String,
+    pub methods: Vec<&'static str>,
+    /// How long responses to `GET` requests are cached, `0` if they aren't.
+    pub cache_ttl_secs: u64,
+    /// How many requests of the client's rate limit a request uses up at most - requests for all pages of a
+    /// paginated route (see [`crate::paginate`]) use up one per page.
+    pub rate_cost: u32,
+}
+
+/// A local route as listed by `GET /_routes`, under the base path.
+#[derive(Debug, Serialize)]
+pub struct ListedRoute {
+    pub path: String,
+    pub methods: &'static [&'static str],
+    pub description: &'static str,
+}
+
+/// What `GET /_routes` answers with.
+#[derive(Debug, Serialize)]
+pub struct RouteTable {
+    /// Path the proxy is mounted under, the paths & patterns of all routes start with it.
+    pub base_path: String,
+    pub local: Vec<ListedRoute>,
+    pub proxied: Vec<ProxiedRoute>,
+}
+
+impl RouteTable {
+    /// Builds the route table from the configuration of the proxy with `state`.
+    pub fn collect(state: &ProxyState) -> Self {
+        let config = state.config();
+        let base_path = config.base_path();
+        let cache_ttl_secs = match state.cache().is_enabled() && !config.strict_passthrough() {
+            true => config.cache_ttl().as_secs(),
+            false => 0,
+        };
+        let unknown = classify::Route { template: "".into(), known: false };
+        let proxied = classify::ROUTE_TEMPLATES.iter()
+            .filter(|template| is_allowed(template, config))
+            .map(|template| (template.to_string(), allowed_methods(&classify::classify(template))))
+            .chain(config.allowed_paths().iter().map(|glob| (glob.clone(), allowed_methods(&unknown))))
+            .map(|(pattern, methods)| ProxiedRoute {
+                rate_cost: match paginate::PAGINATED_ROUTES.contains(&pattern.as_str()) && !config.strict_passthrough() {
+                    true => paginate::max_pages() as u32,
+                    false => 1,
+                },
+                pattern: format!("{}{}", base_path, pattern),
+                methods: methods.to_vec(),
+                cache_ttl_secs,
+            })
+            .collect();
+        let local = LOCAL_ROUTES.iter()
+            .map(|route| ListedRoute { path: format!("{}{}", base_path, route.path), methods: route.methods, description: route.description })
+            .collect();
+        RouteTable { base_path: base_path.to_string(), local, proxied }
+    }
+}
+
+/// Routes of the CF api that take a lookup in the request body, and only accept `POST`.
+const POST_ROUTES: &[&str] = &[
+    "/v1/mods",
+    "/v1/mods/files",
+    "/v1/fingerprints",
+    "/v1/fingerprints/{gameId}",
+    "/v1/fingerprints/fuzzy",
+    "/v1/fingerprints/fuzzy/{gameId}",
+];
+
+/// Returns the methods that are forwarded for a route.
+///
+/// Known routes accept what the CF api accepts on them, paths that don't belong to a known route accept
+/// `GET`, `HEAD` and `POST`.
+pub fn allowed_methods(route: &classify::Route) -> &'static [&'static str] {
+    match (route.known, POST_ROUTES.contains(&route.template.as_ref())) {
+        (true, true) => &["POST"],
+        (true, false) => &["GET", "HEAD"],
+        (false, _) => &["GET", "HEAD", "POST"],
+    }
+}
+
+/// Answers with `405`, listing the allowed methods.
+pub fn method_not_allowed(methods: &[&str]) -> Response<Body> {
+    let mut response = error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    response.headers_mut().insert(ALLOW, HeaderValue::from_str(&methods.join(", ")).unwrap());
+    response
+}
+
+/// Returns whether requests for the path are forwarded to the CF api by a proxy with `config`.
+pub fn is_allowed(path: &str, config: &ProxyConfig) -> bool {
+    config.allowed_paths().iter().any(|glob| rules::glob_matches(glob, path))
+}
+
+/// Returns whether requests for the path are answered by a proxy with `config` itself, instead of being proxied.
+pub fn answers_locally(path: &str, config: &ProxyConfig) -> bool {
+    !config.strict_passthrough() && is_local(path)
+}
+
+/// Answers the request if it's for a local route of the proxy with `state`, returns `None` if it should be
+/// proxied. In strict pass-through mode there are no local routes, every request is proxied.
+pub fn handle_local(req: &Request<Body>, state: &ProxyState) -> Option<Response<Body>> {
+    if !answers_locally(req.uri().path(), state.config()) {
+        return None;
+    }
+    let route = LOCAL_ROUTES.iter().find(|route| route.path == req.uri().path())?;
+    if !route.methods.contains(&req.method().as_str()) {
+        return Some(method_not_allowed(route.methods));
+    }
+    match (req.method(), route.path) {
+        (&Method::GET, "/_routes") => Some(json_response(&RouteTable::collect(state))),
+        (&Method::GET, "/_hints") => Some(json_response(&hints::Hints::collect(state))),
+        (&Method::GET, "/_slo") => Some(json_response(&slo::SLO.report())),
+        (&Method::GET, "/_status" | "/status") => Some(json_response(&StatusReport::collect(state))),
+        _ => None,
+    }
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response<Body> {
+    let mut response = Response::new(Body::from(serde_json::to_string(value).unwrap()));
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
@@ -0,0 +1,172 @@
+//! An ordered list of access rules, evaluated for every request.
+//!
+//! Rules are read from the `ACCESS_RULES` env variable, separated by `;` or newlines. Each rule starts with
+//! an action, followed by the conditions a request has to meet for the rule to apply:
+//!
+//! ```text
+//! ACCESS_RULES="deny from 203.0.113.0/24; limit 1/s path /v1/mods/search; allow tier internal; deny method POST"
+//! ```
+//!
+//! Actions:
+//! - `allow`: let the request through, with the usual rate limits
+//! - `deny`: reject the request with `403`
+//! - `limit <rate>`: let the request through, limited by the rate (see [`crate::tiers::parse_rate`]) instead
+//!   of the usual rate limits. Each client (token, or IP address without a token) gets its own budget.
+//!
+//! Conditions:
+//! - `from <cidr>`: the client's IP address is in the range
+//! - `token <token>`: the client presented the token. A token ending in `*` matches every token starting with it
+//! - `tier <tier>`: the client's token is mapped to the tier (see [`crate::tiers`]). `anonymous` matches
+//!   clients without a token
+//! - `path <glob>`: the path matches the glob. `*` matches within a segment, `**` any number of segments
+//! - `method <methods>`: the request method is one of the comma separated methods
+//!
+//! The first rule whose conditions are all met applies. Requests that match no rule are allowed.
+
+use std::env;
+use std::net::IpAddr;
+use hyper::Method;
+use lazy_static::lazy_static;
+use crate::cidr::Cidr;
+use crate::tiers::{self, parse_rate, Tier, ANONYMOUS_TIER};
+
+lazy_static! {
+    /// The rules configured in `ACCESS_RULES`.
+    pub static ref ACCESS_RULES: Rules = Rules::parse(&env::var("ACCESS_RULES").unwrap_or_default())
+        .expect("Expected ACCESS_RULES env var to contain valid access rules");
+}
+
+/// What happens to a request a rule applies to.
+#[derive(Debug)]
+pub enum Action {
+    Allow,
+    Deny,
+    /// Limit the request by the tier's rate.
+    Limit(Tier),
+}
+
+/// A single rule.
+#[derive(Debug)]
+pub struct Rule {
+    pub action: Action,
+    from: Option<Cidr>,
+    token: Option<String>,
+    tier: Option<String>,
+    path: Option<String>,
+    methods: Option<Vec<Method>>,
+}
+
+/// The request a rule is evaluated against.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestInfo<'a> {
+    pub ip: IpAddr,
+    pub token: Option<&'a str>,
+    pub path: &'a str,
+    pub method: &'a Method,
+}
+
+/// Returns whether the path matches the glob. `*` matches within a segment, `**` any number of segments.
+pub fn glob_matches(glob: &str, path: &str) -> bool {
+    fn segment_matches(pattern: &str, segment: &str) -> bool {
+        match pattern.split_once('*') {
+            None => pattern == segment,
+            Some((prefix, rest)) => segment.strip_prefix(prefix)
+                .map(|segment| (0..=segment.len()).any(|i| segment.is_char_boundary(i) && segment_matches(rest, &segment[i..])))
+                .unwrap_or(false),
+        }
+    }
+    fn matches(glob: &[&str], path: &[&str]) -> bool {
+        match (glob.first(), path.first()) {
+            (None, None) => true,
+            (Some(&"**"), _) => matches(&glob[1..], path) || (!path.is_empty() && matches(glob, &path[1..])),
+            (Some(pattern), Some(segment)) => segment_matches(pattern, segment) && matches(&glob[1..], &path[1..]),
+            _ => false,
+        }
+    }
+    let glob: Vec<&str> = glob.trim_start_matches('/').split('/').collect();
+    let path: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    matches(&glob, &path)
+}
+
+impl Rule {
+    fn parse(index: usize, rule: &str) -> Result<Self, String> {
+        let mut words = rule.split_whitespace();
+        fn next<'a>(words: &mut impl Iterator<Item = &'a str>, rule: &str, what: &str) -> Result<&'a str, String> {
+            words.next().ok_or_else(|| format!("`{}`: expected {}", rule, what))
+        }
+
+        let action = match next(&mut words, rule, "an action")? {
+            "allow" => Action::Allow,
+            "deny" => Action::Deny,
+            "limit" => {
+                let rate = parse_rate(next(&mut words, rule, "a rate after `limit`")?)?;
+                Action::Limit(Tier::new(format!("rule #{}", index + 1), rate))
+            }
+            action => return Err(format!("`{}`: unknown action `{}`", rule, action)),
+        };
+        let mut parsed = Rule { action, from: None, token: None, tier: None, path: None, methods: None };
+        while let Some(condition) = words.next() {
+            let value = next(&mut words, rule, &format!("a value after `{}`", condition))?;
+            match condition {
+                "from" => parsed.from = Some(value.parse()?),
+                "token" => parsed.token = Some(value.to_string()),
+                "tier" => parsed.tier = Some(value.to_string()),
+                "path" => parsed.path = Some(value.to_string()),
+                "method" => parsed.methods = Some(value.split(',')
+                    .map(|method| method.trim().to_ascii_uppercase().parse::<Method>()
+                        .map_err(|_| format!("`{}`: invalid method `{}`", rule, method)))
+                    .collect::<Result<_, _>>()?),
+                condition => return Err(format!("`{}`: unknown condition `{}`", rule, condition)),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Returns whether the rule applies to the request.
+    pub fn matches(&self, req: &RequestInfo) -> bool {
+        let token_matches = |pattern: &String| match (pattern.strip_suffix('*'), req.token) {
+            (Some(prefix), Some(token)) => token.starts_with(prefix),
+            (None, Some(token)) => pattern == token,
+            (_, None) => false,
+        };
+        let tier_matches = |tier: &String| match req.token {
+            Some(token) => tiers::TIERS.for_token(token).map(|t| t.name() == tier).unwrap_or(false),
+            None => tier == ANONYMOUS_TIER,
+        };
+        self.from.map(|from| from.contains(&req.ip)).unwrap_or(true)
+            && self.token.as_ref().map(token_matches).unwrap_or(true)
+            && self.tier.as_ref().map(tier_matches).unwrap_or(true)
+            && self.path.as_ref().map(|glob| glob_matches(glob, req.path)).unwrap_or(true)
+            && self.methods.as_ref().map(|methods| methods.contains(req.method)).unwrap_or(true)
+    }
+}
+
+/// The ordered list of rules.
+#[derive(Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// Parses rules in the format of the `ACCESS_RULES` env variable. Empty lines and lines starting with `#`
+    /// are ignored.
+    pub fn parse(rules: &str) -> Result<Self, String> {
+        let rules = rules.split([';', '\n'])
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty() && !rule.starts_with('#'))
+            .enumerate()
+            .map(|(index, rule)| Rule::parse(index, rule))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Rules { rules })
+    }
+
+    /// Returns whether there are no rules.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns the first rule that applies to the request.
+    pub fn evaluate(&self, req: &RequestInfo) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches(req))
+    }
+}
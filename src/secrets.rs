@@ -0,0 +1,114 @@
+//! Secrets read from the environment, from files, or from a secret store.
+//!
+//! Every secret the proxy is configured with (`CF_API_KEY`, `CF_API_KEYS`, `SIGNING_SECRET`) can also be
+//! given as a path in the same variable suffixed with `_FILE`, e.g. `CF_API_KEY_FILE=/run/secrets/cf_api_key`.
+//! That's how Docker and Kubernetes mount secrets, and keeps the secret itself out of the environment.
+//!
+//! Secrets can also live in a secret store, in which case the variable holds a reference to the secret:
+//! - `vault:<path>#<field>` for HashiCorp Vault (see [`vault`])
+//! - `aws-sm:<secret id>[#<field>]` for AWS Secrets Manager (see [`aws`])
+//!
+//! Referenced secrets are fetched by [`resolve_secrets`] on startup and on `SIGHUP`, and are only kept in
+//! memory.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::RwLock;
+use lazy_static::lazy_static;
+
+pub mod aws;
+pub mod vault;
+
+lazy_static! {
+    /// Secrets fetched from secret stores, by the variable referencing them.
+    static ref RESOLVED: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+}
+
+/// The variables that hold secrets, and may reference secret stores.
+pub const SECRET_VARS: &[&str] = &["CF_API_KEY", "CF_API_KEYS", "SIGNING_SECRET"];
+
+/// A store that secrets can be fetched from.
+pub trait SecretProvider: Send + Sync {
+    /// Fetches the secret a reference (without the `<scheme>:` prefix) points to.
+    fn fetch<'a>(&'a self, reference: &'a str) -> Pin<Box<dyn Future<Output = Result<String, String>> + Send + 'a>>;
+}
+
+/// Splits a reference to a secret store into its scheme and the rest, `None` if the value is a plain secret.
+pub fn parse_reference(value: &str) -> Option<(&str, &str)> {
+    let (scheme, reference) = value.split_once(':')?;
+    match scheme {
+        "vault" | "aws-sm" => Some((scheme, reference)),
+        _ => None,
+    }
+}
+
+/// Creates the provider for a scheme from its env variables.
+fn provider(scheme: &str) -> Result<Box<dyn SecretProvider>, String> {
+    match scheme {
+        "vault" => Ok(Box::new(vault::VaultProvider::from_env()?)),
+        "aws-sm" => Ok(Box::new(aws::AwsSecretsManager::from_env()?)),
+        scheme => Err(format!("unknown secret store `{}`", scheme)),
+    }
+}
+
+/// Looks up the value of a variable, like [`env::var`] does in the process environment.
+pub type Lookup<'a> = &'a (dyn Fn(&str) -> Option<String> + Sync);
+
+fn process_env(var: &str) -> Option<String> {
+    env::var(var).ok()
+}
+
+/// Fetches the secrets referenced by any of the [`SECRET_VARS`], so [`env_secret`] can return them.
+pub async fn resolve_secrets() -> Result<(), String> {
+    resolve_secrets_in(&process_env).await
+}
+
+/// Like [`resolve_secrets`], but reads the [`SECRET_VARS`] with `lookup` instead of from the process environment.
+/// The secret stores are still configured from the process environment.
+pub async fn resolve_secrets_in(lookup: Lookup<'_>) -> Result<(), String> {
+    let mut resolved = HashMap::new();
+    for var in SECRET_VARS {
+        let value = match lookup(var) {
+            Some(value) => value,
+            None => continue,
+        };
+        if let Some((scheme, reference)) = parse_reference(&value) {
+            let secret = provider(scheme)?.fetch(reference).await.map_err(|e| format!("could not fetch {}: {}", var, e))?;
+            resolved.insert(var.to_string(), secret);
+        }
+    }
+    *RESOLVED.write().unwrap() = resolved;
+    Ok(())
+}
+
+/// Reads a secret from the env variable `var`, or from the file named in `<var>_FILE` if `var` is unset.
+///
+/// Leading & trailing whitespace (like the newline most editors end files with) is trimmed off secrets read
+/// from files. If `var` references a secret store, the secret fetched by [`resolve_secrets`] is returned.
+/// Returns an error if the file can't be read, or the referenced secret wasn't fetched.
+pub fn env_secret(var: &str) -> Result<Option<String>, String> {
+    secret_in(var, &process_env)
+}
+
+/// Like [`env_secret`], but reads `var` and `<var>_FILE` with `lookup` instead of from the process environment.
+pub fn secret_in(var: &str, lookup: Lookup<'_>) -> Result<Option<String>, String> {
+    if let Some(secret) = lookup(var) {
+        if parse_reference(&secret).is_none() {
+            return Ok(Some(secret));
+        }
+        return match RESOLVED.read().unwrap().get(var) {
+            Some(secret) => Ok(Some(secret.clone())),
+            None => Err(format!("{} references a secret store, but the secret was not fetched", var)),
+        };
+    }
+    let file_var = format!("{}_FILE", var);
+    match lookup(&file_var) {
+        Some(path) => fs::read_to_string(&path)
+            .map(|secret| Some(secret.trim().to_string()))
+            .map_err(|e| format!("could not read {} from {} ({}): {}", var, path, file_var, e)),
+        None => Ok(None),
+    }
+}
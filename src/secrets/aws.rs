@@ -0,0 +1,186 @@
+//! Secrets stored in AWS Secrets Manager.
+//!
+//! References look like `aws-sm:<secret id>`, or `aws-sm:<secret id>#<field>` for secrets holding a JSON
+//! object. Requests are signed with the credentials in `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+//! `AWS_SESSION_TOKEN` for the region in `AWS_REGION`. `AWS_ENDPOINT_URL` overrides the endpoint, e.g. for
+//! VPC endpoints.
+
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use hyper::{Body, Request, Uri};
+use sha2::{Digest, Sha256};
+use crate::pool;
+use super::{env_secret, SecretProvider};
+
+/// AWS credentials.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl Credentials {
+    /// Reads the credentials from the `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` env variables.
+    pub fn from_env() -> Result<Self, String> {
+        Ok(Credentials {
+            access_key_id: env::var("AWS_ACCESS_KEY_ID").map_err(|_| String::from("AWS_ACCESS_KEY_ID is not set"))?,
+            secret_access_key: env_secret("AWS_SECRET_ACCESS_KEY")?.ok_or_else(|| String::from("AWS_SECRET_ACCESS_KEY is not set"))?,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+/// Reads the region from the `AWS_REGION` env variable, or `AWS_DEFAULT_REGION` if it's unset.
+pub fn region_from_env() -> Result<String, String> {
+    env::var("AWS_REGION").or_else(|_| env::var("AWS_DEFAULT_REGION")).map_err(|_| String::from("AWS_REGION is not set"))
+}
+
+/// Reads secrets from AWS Secrets Manager.
+#[derive(Debug, Clone)]
+pub struct AwsSecretsManager {
+    region: String,
+    endpoint: Uri,
+    credentials: Credentials,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Builds the canonical request of Signature Version 4 from its parts. `headers` need to be lowercase and
+/// sorted by name. Returns the canonical request and the list of signed headers.
+pub fn canonical_request(method: &str, path: &str, query: &str, headers: &[(&str, &str)], payload: &[u8]) -> (String, String) {
+    canonical_request_for_hash(method, path, query, headers, &hex(&Sha256::digest(payload)))
+}
+
+/// Like [`canonical_request`], for a payload given by its hex-encoded SHA-256 hash - or `UNSIGNED-PAYLOAD` for
+/// payloads that are streamed without being signed.
+pub fn canonical_request_for_hash(method: &str, path: &str, query: &str, headers: &[(&str, &str)], payload_hash: &str) -> (String, String) {
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+    let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+    let request = format!("{}\n{}\n{}\n{}\n{}\n{}", method, path, query, canonical_headers, signed_headers, payload_hash);
+    (request, signed_headers)
+}
+
+/// Calculates the Signature Version 4 signature of a canonical request made at `amz_date` (`YYYYMMDDTHHMMSSZ`).
+pub fn signature(secret_access_key: &str, amz_date: &str, region: &str, service: &str, canonical_request: &str) -> String {
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex(&Sha256::digest(canonical_request.as_bytes())));
+    let key = hmac(format!("AWS4{}", secret_access_key).as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, service);
+    let key = hmac(&key, "aws4_request");
+    hex(&hmac(&key, &string_to_sign))
+}
+
+/// Returns the hex-encoded SHA-256 hash of a payload, as sent in `x-amz-content-sha256`.
+pub fn payload_hash(payload: &[u8]) -> String {
+    hex(&Sha256::digest(payload))
+}
+
+/// Builds the `Authorization` header of a request signed with Signature Version 4.
+pub fn authorization(credentials: &Credentials, amz_date: &str, region: &str, service: &str, signed_headers: &str, canonical_request: &str) -> String {
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}/{}/{}/aws4_request, SignedHeaders={}, Signature={}",
+        credentials.access_key_id, &amz_date[..8], region, service, signed_headers,
+        signature(&credentials.secret_access_key, amz_date, region, service, canonical_request),
+    )
+}
+
+/// Formats a unix timestamp as `YYYYMMDDTHHMMSSZ`.
+pub fn amz_date(unix_secs: u64) -> String {
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (unix_secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    let secs = unix_secs % 86400;
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+impl AwsSecretsManager {
+    /// Creates a provider for the region, using the public endpoint unless `endpoint` is given.
+    pub fn new(region: &str, endpoint: Option<&str>, credentials: Credentials) -> Result<Self, String> {
+        let endpoint = endpoint.map(String::from)
+            .unwrap_or_else(|| format!("https://secretsmanager.{}.amazonaws.com/", region))
+            .parse::<Uri>().map_err(|e| format!("invalid AWS endpoint: {}", e))?;
+        Ok(AwsSecretsManager { region: region.to_string(), endpoint, credentials })
+    }
+
+    /// Creates a provider from the `AWS_*` env variables.
+    pub fn from_env() -> Result<Self, String> {
+        AwsSecretsManager::new(&region_from_env()?, env::var("AWS_ENDPOINT_URL").ok().as_deref(), Credentials::from_env()?)
+    }
+
+    async fn get_secret_value(&self, reference: &str) -> Result<String, String> {
+        let (secret_id, field) = match reference.split_once('#') {
+            Some((secret_id, field)) => (secret_id, Some(field)),
+            None => (reference, None),
+        };
+        let payload = serde_json::json!({ "SecretId": secret_id }).to_string();
+        let host = self.endpoint.authority().map(|authority| authority.as_str()).unwrap_or_default().to_string();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+        let date = amz_date(now);
+
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1"),
+            ("host", host.as_str()),
+            ("x-amz-date", date.as_str()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.as_str()));
+        }
+        headers.push(("x-amz-target", "secretsmanager.GetSecretValue"));
+        let (canonical, signed_headers) = canonical_request("POST", self.endpoint.path(), "", &headers, payload.as_bytes());
+        let authorization = authorization(&self.credentials, &date, &self.region, "secretsmanager", &signed_headers, &canonical);
+
+        let mut req = Request::post(self.endpoint.clone());
+        for (name, value) in &headers {
+            req = req.header(*name, *value);
+        }
+        let req = req.header("authorization", authorization).body(Body::from(payload)).map_err(|e| e.to_string())?;
+        let client = pool::standalone_client()?;
+        let resp = client.request(req).await.map_err(|e| format!("could not reach AWS Secrets Manager: {}", e))?;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.map_err(|e| format!("could not read from AWS Secrets Manager: {}", e))?;
+        if !status.is_success() {
+            return Err(format!("AWS Secrets Manager answered `{}` with {}: {}", secret_id, status.as_u16(), String::from_utf8_lossy(&body)));
+        }
+
+        let answer: serde_json::Value = serde_json::from_slice(&body).map_err(|e| format!("unexpected answer from AWS Secrets Manager: {}", e))?;
+        let secret = answer["SecretString"].as_str().ok_or_else(|| format!("`{}` has no secret string", secret_id))?;
+        match field {
+            None => Ok(secret.to_string()),
+            Some(field) => {
+                let fields: serde_json::Value = serde_json::from_str(secret).map_err(|_| format!("`{}` is not a JSON object", secret_id))?;
+                match fields.get(field) {
+                    Some(serde_json::Value::String(value)) => Ok(value.clone()),
+                    Some(value) => Ok(value.to_string()),
+                    None => Err(format!("`{}` has no field `{}`", secret_id, field)),
+                }
+            }
+        }
+    }
+}
+
+impl SecretProvider for AwsSecretsManager {
+    fn fetch<'a>(&'a self, reference: &'a str) -> Pin<Box<dyn Future<Output = Result<String, String>> + Send + 'a>> {
+        Box::pin(self.get_secret_value(reference))
+    }
+}
@@ -0,0 +1,73 @@
+//! Secrets stored in HashiCorp Vault.
+//!
+//! References look like `vault:<path>#<field>`, e.g. `vault:secret/data/cfproxy#cf_api_key`, and are read
+//! from the Vault at `VAULT_ADDR` with the token in `VAULT_TOKEN` (or `VAULT_TOKEN_FILE`). Both the KV v1 and
+//! v2 secret engines are supported.
+
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use hyper::{Body, Request};
+use crate::pool;
+use super::{env_secret, SecretProvider};
+
+/// Reads secrets from a Vault server.
+#[derive(Debug, Clone)]
+pub struct VaultProvider {
+    addr: String,
+    token: String,
+    namespace: Option<String>,
+}
+
+impl VaultProvider {
+    /// Creates a provider reading from the Vault at `addr` (e.g. `https://vault.internal:8200`).
+    pub fn new(addr: &str, token: &str, namespace: Option<&str>) -> Self {
+        VaultProvider {
+            addr: addr.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            namespace: namespace.map(String::from),
+        }
+    }
+
+    /// Creates a provider from the `VAULT_ADDR`, `VAULT_TOKEN` and `VAULT_NAMESPACE` env variables.
+    pub fn from_env() -> Result<Self, String> {
+        let addr = env::var("VAULT_ADDR").map_err(|_| String::from("VAULT_ADDR is not set"))?;
+        let token = env_secret("VAULT_TOKEN")?.ok_or_else(|| String::from("VAULT_TOKEN is not set"))?;
+        Ok(VaultProvider::new(&addr, &token, env::var("VAULT_NAMESPACE").ok().as_deref()))
+    }
+
+    async fn read(&self, reference: &str) -> Result<String, String> {
+        let (path, field) = reference.split_once('#')
+            .ok_or_else(|| format!("`{}`: expected `<path>#<field>`", reference))?;
+        let mut req = Request::get(format!("{}/v1/{}", self.addr, path.trim_start_matches('/')))
+            .header("x-vault-token", &self.token);
+        if let Some(namespace) = &self.namespace {
+            req = req.header("x-vault-namespace", namespace);
+        }
+        let req = req.body(Body::empty()).map_err(|e| format!("`{}`: {}", reference, e))?;
+
+        let client = pool::standalone_client()?;
+        let resp = client.request(req).await.map_err(|e| format!("could not reach Vault: {}", e))?;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.map_err(|e| format!("could not read from Vault: {}", e))?;
+        if !status.is_success() {
+            return Err(format!("Vault answered `{}` with {}", path, status.as_u16()));
+        }
+
+        let secret: serde_json::Value = serde_json::from_slice(&body).map_err(|e| format!("unexpected answer from Vault: {}", e))?;
+        // KV v2 nests the secret's fields in another `data` object
+        let data = &secret["data"];
+        let value = data["data"].get(field).or_else(|| data.get(field));
+        match value {
+            Some(serde_json::Value::String(value)) => Ok(value.clone()),
+            Some(value) => Ok(value.to_string()),
+            None => Err(format!("`{}` has no field `{}`", path, field)),
+        }
+    }
+}
+
+impl SecretProvider for VaultProvider {
+    fn fetch<'a>(&'a self, reference: &'a str) -> Pin<Box<dyn Future<Output = Result<String, String>> + Send + 'a>> {
+        Box::pin(self.read(reference))
+    }
+}
@@ -0,0 +1,252 @@
+//! Reporting errors to Sentry.
+//!
+//! If `SENTRY_DSN` is set, failed calls to Curseforge, panics and startup failures (like broken config) are
+//! sent to Sentry as events, along with the request they happened for - so operators are alerted when errors
+//! spike instead of having to watch the logs. Events are sent in the background; when more than
+//! [`MAX_IN_FLIGHT`] are on their way, further ones are dropped.
+
+use std::env;
+use std::panic::{self, PanicHookInfo};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Uri};
+use lazy_static::lazy_static;
+use serde_json::{json, Map, Value};
+use crate::{classify, pool, request_id};
+
+lazy_static! {
+    /// Where events are sent. Read from the `SENTRY_DSN` env variable, errors aren't reported if unset.
+    pub static ref SENTRY_DSN: Option<Dsn> = env::var("SENTRY_DSN").ok()
+        .filter(|dsn| !dsn.is_empty())
+        .map(|dsn| dsn.parse::<Dsn>().expect("Expected SENTRY_DSN env var to be a DSN like https://<key>@<host>/<project>"));
+
+    /// The environment events are reported for, like `production`. Read from the `SENTRY_ENVIRONMENT` env variable.
+    pub static ref SENTRY_ENVIRONMENT: Option<String> = env::var("SENTRY_ENVIRONMENT").ok()
+        .filter(|environment| !environment.is_empty());
+
+    /// The release events are reported for. Read from the `SENTRY_RELEASE` env variable.
+    pub static ref SENTRY_RELEASE: String = env::var("SENTRY_RELEASE").ok()
+        .filter(|release| !release.is_empty())
+        .unwrap_or(format!("cfproxy@{}", env!("CARGO_PKG_VERSION")));
+}
+
+/// How many events may be on their way to Sentry at once.
+pub const MAX_IN_FLIGHT: usize = 32;
+
+/// Events being sent.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// A Sentry DSN, like `https://<public key>@o1.ingest.sentry.io/<project id>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dsn {
+    public_key: String,
+    secret_key: Option<String>,
+    /// Where events are posted to.
+    envelope_url: Uri,
+    dsn: String,
+}
+
+impl Dsn {
+    pub fn envelope_url(&self) -> &Uri {
+        &self.envelope_url
+    }
+
+    fn auth_header(&self) -> String {
+        let mut auth = format!("Sentry sentry_version=7, sentry_client=cfproxy/{}, sentry_key={}", env!("CARGO_PKG_VERSION"), self.public_key);
+        if let Some(secret) = &self.secret_key {
+            auth.push_str(&format!(", sentry_secret={}", secret));
+        }
+        auth
+    }
+}
+
+impl FromStr for Dsn {
+    type Err = String;
+
+    fn from_str(dsn: &str) -> Result<Self, Self::Err> {
+        let uri = dsn.trim().parse::<Uri>().map_err(|e| e.to_string())?;
+        let (scheme, authority) = uri.scheme_str().zip(uri.authority()).ok_or("DSN has no scheme or host")?;
+        let (credentials, host) = authority.as_str().rsplit_once('@').ok_or("DSN has no public key")?;
+        let (public_key, secret_key) = match credentials.split_once(':') {
+            Some((public_key, secret_key)) => (public_key.to_string(), Some(secret_key.to_string())),
+            None => (credentials.to_string(), None),
+        };
+        let (path, project) = uri.path().trim_end_matches('/').rsplit_once('/').ok_or("DSN has no project id")?;
+        if public_key.is_empty() || project.is_empty() {
+            return Err(String::from("DSN has no public key or project id"));
+        }
+        let envelope_url = format!("{}://{}{}/api/{}/envelope/", scheme, host, path, project).parse::<Uri>()
+            .map_err(|e| e.to_string())?;
+        Ok(Dsn { public_key, secret_key, envelope_url, dsn: dsn.trim().to_string() })
+    }
+}
+
+/// How bad an event is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Fatal,
+}
+
+/// An error to report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// The kind of error, like `UpstreamError`.
+    pub kind: String,
+    pub message: String,
+    pub level: Level,
+    /// The request the error happened for, as method & uri.
+    pub request: Option<(Method, Uri)>,
+    /// The id of the request the error happened for.
+    pub request_id: Option<String>,
+}
+
+impl Event {
+    /// Creates an error event, for the request being handled if there is one.
+    pub fn new(kind: impl Into<String>, message: impl Into<String>) -> Self {
+        Event {
+            kind: kind.into(),
+            message: message.into(),
+            level: Level::Error,
+            request: None,
+            request_id: request_id::current().map(|id| id.to_string()),
+        }
+    }
+
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn with_request(mut self, method: &Method, uri: &Uri) -> Self {
+        self.request = Some((method.clone(), uri.clone()));
+        self
+    }
+
+    /// Converts the event to the JSON Sentry expects.
+    pub fn to_json(&self, event_id: &str) -> Value {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let mut event = json!({
+            "event_id": event_id,
+            "timestamp": timestamp,
+            "platform": "native",
+            "logger": "cfproxy",
+            "level": match self.level {
+                Level::Error => "error",
+                Level::Fatal => "fatal",
+            },
+            "release": *SENTRY_RELEASE,
+            "exception": { "values": [{ "type": self.kind, "value": self.message }] },
+        });
+        if let Some(environment) = &*SENTRY_ENVIRONMENT {
+            event["environment"] = json!(environment);
+        }
+        if let Some((method, uri)) = &self.request {
+            event["transaction"] = json!(classify::classify(uri.path()).template);
+            event["request"] = json!({
+                "method": method.as_str(),
+                "url": uri.path(),
+                "query_string": uri.query().unwrap_or_default(),
+            });
+        }
+        if let Some(id) = &self.request_id {
+            let mut tags = Map::new();
+            tags.insert(String::from("request_id"), json!(id));
+            event["tags"] = Value::Object(tags);
+        }
+        event
+    }
+}
+
+/// Returns whether errors are reported at all.
+pub fn is_enabled() -> bool {
+    SENTRY_DSN.is_some()
+}
+
+/// Sends the event to Sentry in the background. Does nothing if reporting is disabled, there are too many events
+/// on their way already, or there's no runtime to send it on.
+pub fn capture(event: Event) {
+    let dsn = match &*SENTRY_DSN {
+        Some(dsn) => dsn,
+        None => return,
+    };
+    let runtime = match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => runtime,
+        Err(_) => return,
+    };
+    if IN_FLIGHT.fetch_add(1, Ordering::SeqCst) >= MAX_IN_FLIGHT {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+        return;
+    }
+    let req = envelope_request(dsn, &event);
+    runtime.spawn(async move {
+        match tokio::time::timeout(Duration::from_secs(10), pool::CLIENT.request(req)).await {
+            Ok(Ok(resp)) if resp.status().is_success() => {}
+            Ok(Ok(resp)) => eprintln!("<!> Could not report {} to Sentry, it answered {}", event.kind, resp.status().as_str()),
+            Ok(Err(err)) => eprintln!("<!> Could not report {} to Sentry: {}", event.kind, err),
+            Err(_) => eprintln!("<!> Could not report {} to Sentry, it did not answer in time", event.kind),
+        }
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Reports a failed call to an upstream.
+pub fn capture_upstream_failure(method: &Method, uri: &Uri, error: &impl std::fmt::Display) {
+    capture(Event::new("UpstreamError", error.to_string()).with_request(method, uri));
+}
+
+/// Waits until the events on their way were sent, for at most `timeout`.
+pub async fn flush(timeout: Duration) {
+    let started = Instant::now();
+    while IN_FLIGHT.load(Ordering::SeqCst) > 0 && started.elapsed() < timeout {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Reports panics, in addition to what the current panic hook does. Panics about env variables, like
+/// `Expected PORT env var to contain a number`, are reported as config errors.
+pub fn install_panic_hook() {
+    if !is_enabled() {
+        return;
+    }
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        capture(panic_event(info));
+        previous(info);
+    }));
+}
+
+fn panic_event(info: &PanicHookInfo<'_>) -> Event {
+    let message = info.payload().downcast_ref::<&str>().map(|message| message.to_string())
+        .or_else(|| info.payload().downcast_ref::<String>().cloned())
+        .unwrap_or(String::from("panic"));
+    let message = match info.location() {
+        Some(location) => format!("{} at {}:{}", message, location.file(), location.line()),
+        None => message,
+    };
+    let kind = match message.starts_with("Expected ") && message.contains(" env") {
+        true => "ConfigError",
+        false => "Panic",
+    };
+    Event::new(kind, message).with_level(Level::Fatal)
+}
+
+/// Builds the request posting the event to Sentry, as an envelope.
+fn envelope_request(dsn: &Dsn, event: &Event) -> Request<Body> {
+    let event_id = format!("{:032x}", rand::random::<u128>());
+    let payload = event.to_json(&event_id).to_string();
+    let envelope = format!("{}\n{}\n{}\n",
+        json!({ "event_id": event_id, "dsn": dsn.dsn }),
+        json!({ "type": "event", "length": payload.len() }),
+        payload);
+    let mut req = Request::new(Body::from(envelope));
+    *req.method_mut() = Method::POST;
+    *req.uri_mut() = dsn.envelope_url.clone();
+    req.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/x-sentry-envelope"));
+    if let Ok(auth) = HeaderValue::from_str(&dsn.auth_header()) {
+        req.headers_mut().insert(HeaderName::from_static("x-sentry-auth"), auth);
+    }
+    req
+}
@@ -0,0 +1,841 @@
+//! The proxy server, for running it from the binary or embedding it into another application.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), hyper::Error> {
+//! use cfproxy::server::ProxyHandle;
+//!
+//! let handle = ProxyHandle::start(([127, 0, 0, 1], 3000).into())?;
+//! // ...
+//! // Move the proxy to another port, keeping the rate limiter state
+//! let state = handle.shutdown().await?;
+//! let handle = ProxyHandle::start_with_state(([127, 0, 0, 1], 3001).into(), state)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::convert::Infallible;
+use std::env;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU32;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use governor::{Quota, RateLimiter};
+use hyper::header::{HeaderValue, AUTHORIZATION, CONNECTION, ORIGIN, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn, Service};
+use hyper::{Body, HeaderMap, Request, Response, Server, StatusCode, Version};
+use lazy_static::lazy_static;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use crate::body_limit::{self, BodyError};
+use crate::cache::{self, Cache, CacheStats, ResponseCache};
+use crate::config::{self, ProxyConfig};
+use crate::hooks::{self, ProxyHook, RequestSummary};
+use crate::limiter::{self, IdentityRateLimiter, IpRateLimiter, RateLimit};
+use crate::listener::{self, Binding, ClientIncoming, ClientStream, ListenAddr};
+use crate::routes::RouteSet;
+use crate::rules::Action;
+use crate::signing::Verification;
+use crate::telemetry::{self, SpanKind};
+#[cfg(feature = "tls")]
+use crate::{acme, tls};
+use crate::{batch, bearer, classify, compression, concurrency, cors, diagnostics, downloads, error_response, get_real_ip_addr, graphql, keys, legacy, metering, metrics, paginate, profile, proxy_protocol, proxy_request_with_cache, request_id, routes, rules, secrets, signing, statsd, tiers, tokens, upstreams, with_retry_after, ProxyError};
+
+lazy_static! {
+    /// How many requests per hour are allowed per ip. Read from the `REQ_LIMIT_PER_HOUR` env variable.
+    pub static ref REQ_LIMIT_PER_HOUR: u32 = profile::env_or("REQ_LIMIT_PER_HOUR", "21600")
+        .parse::<u32>().expect("Expected REQ_LIMIT_PER_HOUR env var to contain a number");
+
+    /// Whether requests without a proxy token are rejected. Read from the `REQUIRE_TOKEN` env variable.
+    pub static ref REQUIRE_TOKEN: bool = env::var("REQUIRE_TOKEN").unwrap_or(String::from("false"))
+        .parse::<bool>().expect("Expected REQUIRE_TOKEN env var to be either true or false");
+
+    /// How many unsigned requests per hour are allowed per ip, if request signing is enabled.
+    /// Read from the `ANONYMOUS_REQ_LIMIT_PER_HOUR` env variable. Unsigned requests are rejected if unset.
+    pub static ref ANONYMOUS_REQ_LIMIT_PER_HOUR: Option<u32> = env::var("ANONYMOUS_REQ_LIMIT_PER_HOUR").ok()
+        .map(|limit| limit.parse::<u32>().expect("Expected ANONYMOUS_REQ_LIMIT_PER_HOUR env var to contain a number"));
+}
+
+/// State of a proxy that is shared by all requests, and that can be carried over to a new proxy instance.
+///
+/// Cloning the state is cheap, clones refer to the same state.
+#[derive(Clone)]
+pub struct ProxyState {
+    /// Limits requests per IP.
+    ip_limiter: Arc<dyn RateLimit>,
+    /// Limits unsigned requests per IP, if request signing is enabled and unsigned requests are allowed.
+    anonymous_limiter: Option<Arc<IpRateLimiter>>,
+    /// Limits requests per client certificate, if clients present one.
+    identity_limiter: Arc<IdentityRateLimiter>,
+    cache: Arc<dyn Cache>,
+    config: Arc<ProxyConfig>,
+    hooks: Arc<[Arc<dyn ProxyHook>]>,
+}
+
+impl ProxyState {
+    /// Creates fresh state, with rate limits read from the environment and the process' response cache.
+    pub fn new() -> Self {
+        let config = config::ENV_CONFIG.clone();
+        let (ip_limiter, anonymous_limiter, identity_limiter) = Self::rate_limiters(&config);
+        ProxyState {
+            ip_limiter,
+            anonymous_limiter,
+            identity_limiter,
+            cache: cache::CACHE.clone(),
+            config,
+            hooks: Arc::new([]),
+        }
+    }
+
+    /// Creates fresh rate limiters for the limits in the config.
+    fn rate_limiters(config: &ProxyConfig) -> (Arc<dyn RateLimit>, Option<Arc<IpRateLimiter>>, Arc<IdentityRateLimiter>) {
+        let rate_limit_quota = Quota::per_hour(NonZeroU32::new(config.req_limit_per_hour()).expect("Expected req limit to not be null"));
+        let ip_limiter: IpRateLimiter = RateLimiter::keyed(rate_limit_quota);
+        let anonymous_limiter = config.anonymous_req_limit_per_hour().map(|limit| {
+            Arc::new(RateLimiter::keyed(Quota::per_hour(NonZeroU32::new(limit).expect("Expected anonymous req limit to not be null"))))
+        });
+        (Arc::new(ip_limiter), anonymous_limiter, Arc::new(RateLimiter::keyed(rate_limit_quota)))
+    }
+
+    /// Replaces the per-IP rate limiter, e.g. with a `test_util::FakeRateLimiter` in tests.
+    pub fn with_rate_limiter(self, limiter: impl RateLimit + 'static) -> Self {
+        ProxyState { ip_limiter: Arc::new(limiter), ..self }
+    }
+
+    /// Replaces the response cache, e.g. with a `test_util::FakeCache` in tests.
+    pub fn with_cache(self, cache: impl Cache + 'static) -> Self {
+        ProxyState { cache: Arc::new(cache), ..self }
+    }
+
+    /// Replaces the configuration read from the environment (see [`config`]). If the config sets rate limits or
+    /// how responses are cached, fresh rate limiters or a response cache of this proxy's own are set up for them,
+    /// so call [`with_rate_limiter`](Self::with_rate_limiter) & [`with_cache`](Self::with_cache) after this.
+    pub fn with_config(self, config: ProxyConfig) -> Self {
+        let mut state = self;
+        if config.sets_rate_limits() {
+            (state.ip_limiter, state.anonymous_limiter, state.identity_limiter) = Self::rate_limiters(&config);
+        }
+        if config.sets_cache() {
+            let cache = ResponseCache::new(config.cache_ttl(), config.cache_max_entries()).with_compression(*compression::CACHE_COMPRESSION);
+            state.cache = Arc::new(cache);
+        }
+        ProxyState { config: Arc::new(config), ..state }
+    }
+
+    /// Returns how requests are handled: where requests to the CF api go, with which keys, and which limits apply.
+    pub fn config(&self) -> &ProxyConfig {
+        &self.config
+    }
+
+    /// Registers a hook that's run for every request & response, after those registered before it (see
+    /// [`hooks`]).
+    pub fn with_hook(self, hook: impl ProxyHook + 'static) -> Self {
+        let mut hooks = self.hooks.to_vec();
+        hooks.push(Arc::new(hook));
+        ProxyState { hooks: hooks.into(), ..self }
+    }
+
+    /// Returns the response cache.
+    pub(crate) fn cache(&self) -> &Arc<dyn Cache> {
+        &self.cache
+    }
+
+    /// Returns statistics about the response cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Returns the number of IP addresses & client certificates the rate limiter keeps state for.
+    pub fn rate_limiter_keys(&self) -> usize {
+        self.ip_limiter.tracked_keys() + self.identity_limiter.len()
+    }
+}
+
+impl Default for ProxyState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Identity of the TLS certificate a client presented, see [`tls::client_identity`]. Requests carry it as an
+/// extension, and are rate limited by it instead of their IP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity(pub String);
+
+/// The proxy's request handling as a [`Service`] for the requests of one client, for serving them with a
+/// server of your own, or wrapping it in middleware. Requests are handled like by [`handle_request`].
+#[derive(Clone)]
+pub struct ProxyService {
+    state: ProxyState,
+    remote_addr: IpAddr,
+    identity: Option<ClientIdentity>,
+    routes: RouteSet,
+}
+
+impl ProxyService {
+    /// Creates a service for the requests of the client at `remote_addr`, serving all routes.
+    pub fn new(state: ProxyState, remote_addr: IpAddr) -> Self {
+        ProxyService { state, remote_addr, identity: None, routes: RouteSet::All }
+    }
+
+    /// Rate limits the client by the identity of the TLS certificate it presented instead of its IP.
+    pub fn identity(self, identity: Option<ClientIdentity>) -> Self {
+        ProxyService { identity, ..self }
+    }
+
+    /// Only serves `routes`, answering requests for other routes with `404 Not Found`.
+    pub fn routes(self, routes: RouteSet) -> Self {
+        ProxyService { routes, ..self }
+    }
+}
+
+impl Service<Request<Body>> for ProxyService {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        if let Some(identity) = &self.identity {
+            req.extensions_mut().insert(identity.clone());
+        }
+        req.extensions_mut().insert(self.routes);
+        Box::pin(handle_request(req, self.remote_addr, self.state.clone()))
+    }
+}
+
+/// Logs why a request is rejected and returns the response to send instead.
+fn reject(remote_addr: &IpAddr, status: StatusCode, message: &'static str) -> Result<Response<Body>, Infallible> {
+    println!("[{}{}] <!> {}", remote_addr, request_id::tag(), message);
+    metrics::METRICS.record_rejected_request();
+    Ok(error_response(status, message))
+}
+
+/// Authenticates & rate limits a request, then forwards it to the CF api.
+///
+/// Every response, including the proxy's own errors, carries CORS headers if CORS is enabled (see [`cors`]).
+/// Registered [`hooks`] see the request first & the response last. The bytes of both bodies are counted, see
+/// [`metering`], and the request gets an id, see [`request_id`]. Requests are counted here, once per client
+/// request, however many calls to upstreams they take (see [`metrics`]).
+pub async fn handle_request(req: Request<Body>, remote_addr: IpAddr, state: ProxyState) -> Result<Response<Body>, Infallible> {
+    let (mut req, received) = metering::meter_request(req);
+    let real_addr = get_real_ip_addr(&req, &remote_addr);
+    let path = req.uri().path().to_string();
+    let config = state.config.clone();
+    let request_id = request_id::assign(&mut req, &config);
+    let route = metrics::route_label_under(&path, config.base_path());
+    metrics::METRICS.record_route_request(&route);
+    let started = Instant::now();
+    let mut span = telemetry::start_request_span(&mut req, &metrics::endpoint_of(&path));
+    span.set_attribute("client.address", real_addr.to_string());
+    span.set_attribute("request.id", request_id.as_str());
+    let response = request_id::scope(request_id.clone(), hook_request(req, remote_addr, state)).await?;
+    span.set_attribute("http.response.status_code", response.status().as_u16());
+    if response.status().is_server_error() {
+        span.set_error(response.status().as_str());
+    }
+    drop(span);
+    let (latency, status) = (started.elapsed(), metrics::status_class(response.status()));
+    metrics::record_timing("request.duration", latency, &[("route", &route), ("status", status)]);
+    metrics::METRICS.record_latency(route, status, latency);
+    let response = request_id::echo(response, &request_id, &config);
+    Ok(metering::meter_response(response, received, move |bytes| {
+        metrics::METRICS.record_body_bytes(bytes.received, bytes.sent);
+        if *metering::LOG_BODY_BYTES {
+            println!("[{} {}] <-> {} received {} bytes, sent {} bytes", real_addr, request_id, path, bytes.received, bytes.sent);
+        }
+    }))
+}
+
+async fn hook_request(mut req: Request<Body>, remote_addr: IpAddr, state: ProxyState) -> Result<Response<Body>, Infallible> {
+    if state.hooks.is_empty() {
+        return serve_request(req, remote_addr, state).await;
+    }
+    let hooks = state.hooks.clone();
+    let real_addr = get_real_ip_addr(&req, &remote_addr);
+    let answered = hooks::on_request(&hooks, &mut req, real_addr);
+    let request = RequestSummary { remote_addr: real_addr, method: req.method().clone(), uri: req.uri().clone() };
+    let mut response = match answered {
+        Some(response) => response,
+        None => serve_request(req, remote_addr, state).await?,
+    };
+    hooks::on_response(&hooks, &request, &mut response);
+    Ok(response)
+}
+
+async fn serve_request(mut req: Request<Body>, remote_addr: IpAddr, state: ProxyState) -> Result<Response<Body>, Infallible> {
+    // Everything below sees paths relative to the base path
+    if !routes::strip_base_path(&mut req, state.config.base_path()) {
+        return reject(&get_real_ip_addr(&req, &remote_addr), StatusCode::NOT_FOUND, "Not found");
+    }
+
+    // Listeners may only serve some of the routes
+    let routes = req.extensions().get::<RouteSet>().copied().unwrap_or(RouteSet::All);
+    if !routes.serves(req.uri().path()) {
+        return reject(&get_real_ip_addr(&req, &remote_addr), StatusCode::NOT_FOUND, "Not found");
+    }
+
+    // Answer CORS preflights right away, they carry no credentials & Curseforge rejects them
+    let strict = state.config.strict_passthrough();
+    if let Some(policy) = cors::CORS_POLICY.as_ref().filter(|_| !strict && cors::is_preflight(&req)) {
+        let response = policy.preflight_response(&req);
+        println!("[{}{}] <-> {} preflight => {}", get_real_ip_addr(&req, &remote_addr), request_id::tag(), req.uri().path(), response.status().as_str());
+        return Ok(response);
+    }
+
+    let origin = req.headers().get(ORIGIN).cloned();
+    let mut response = route_request(req, remote_addr, state).await?;
+    if let (Some(policy), Some(origin)) = (cors::CORS_POLICY.as_ref(), origin) {
+        if !strict {
+            policy.apply(&origin, response.headers_mut());
+        }
+    }
+    Ok(response)
+}
+
+async fn route_request(mut req: Request<Body>, remote_addr: IpAddr, state: ProxyState) -> Result<Response<Body>, Infallible> {
+    let remote_addr = get_real_ip_addr(&req, &remote_addr);
+    if state.config.strict_passthrough() {
+        return pass_through(req, remote_addr, state).await;
+    }
+
+    // Shed load right away if the proxy is saturated
+    let _permit = match concurrency::IN_FLIGHT_LIMIT.try_acquire() {
+        Some(permit) => permit,
+        None => {
+            println!("[{}{}] <!> Request shed, {} requests in flight", remote_addr, request_id::tag(), concurrency::IN_FLIGHT_LIMIT.in_flight());
+            metrics::METRICS.record_shed_request();
+            let response = error_response(StatusCode::SERVICE_UNAVAILABLE, "Proxy is at capacity, try again later");
+            return Ok(with_retry_after(response, Duration::from_secs(1)));
+        }
+    };
+
+    // Check the bearer token if an allowlist is configured
+    if let Some(allowlist) = bearer::BEARER_ALLOWLIST.as_ref() {
+        if !allowlist.is_authorized(req.headers()) {
+            let mut response = reject(&remote_addr, StatusCode::UNAUTHORIZED, "Missing or invalid bearer token");
+            if let Ok(response) = response.as_mut() {
+                response.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            return response;
+        }
+    }
+
+    // Listeners that only serve local routes, like an admin port on localhost, answer them right away. Everywhere
+    // else they're answered like proxied requests are, below, once the client is authenticated & rate limited
+    let local = routes::answers_locally(req.uri().path(), &state.config);
+    if local && req.extensions().get::<RouteSet>() == Some(&RouteSet::Local) {
+        return Ok(answer_local(&req, &remote_addr, &state));
+    }
+
+    // Don't spend upstream quota on paths that aren't part of the CF api, downloads of its files or GraphQL queries
+    let download = downloads::parse_download_path(req.uri().path()).filter(|_| *downloads::DOWNLOAD_PROXY);
+    let legacy = legacy::translate(req.method(), req.uri()).filter(|_| *legacy::LEGACY_API);
+    let graphql = *graphql::GRAPHQL && req.uri().path() == graphql::GRAPHQL_PATH;
+    let allowed = match (&download, &legacy) {
+        _ if local || graphql => true,
+        (Some(_), _) => true,
+        (_, Some(translation)) => routes::is_allowed(translation.path.split('?').next().unwrap_or_default(), &state.config),
+        _ => routes::is_allowed(req.uri().path(), &state.config) || upstreams::route_for(req.uri().path(), &state.config).is_some(),
+    };
+    if !allowed {
+        return reject(&remote_addr, StatusCode::NOT_FOUND, "Not found");
+    }
+    let methods: &[&str] = match download {
+        _ if graphql => &["POST"],
+        Some(_) => &["GET", "HEAD"],
+        None => routes::allowed_methods(&classify::classify(req.uri().path())),
+    };
+    if !local && legacy.is_none() && !methods.contains(&req.method().as_str()) {
+        println!("[{}{}] <!> Method {} not allowed for {}", remote_addr, request_id::tag(), req.method(), req.uri().path());
+        metrics::METRICS.record_rejected_request();
+        return Ok(routes::method_not_allowed(methods));
+    }
+
+    // Read the body before anything else does, so oversized bodies are never read in full
+    req = match body_limit::read_limited(req, *body_limit::MAX_REQUEST_BODY_BYTES).await {
+        Ok(req) => req,
+        Err(BodyError::TooLarge) => return reject(&remote_addr, StatusCode::PAYLOAD_TOO_LARGE, "Request body too large"),
+        Err(BodyError::Unreadable) => return reject(&remote_addr, StatusCode::BAD_REQUEST, "Could not read request body"),
+    };
+
+    // Check the request signature if signing is enabled. Unsigned requests may still be let through as
+    // anonymous requests, which are subject to a lower rate limit
+    let mut anonymous = false;
+    if let Some(verifier) = signing::SIGNATURE_VERIFIER.as_ref() {
+        let (verified_req, verification) = match verifier.verify_request(req).await {
+            Ok(verified) => verified,
+            Err(_) => return reject(&remote_addr, StatusCode::BAD_REQUEST, "Could not read request body"),
+        };
+        req = verified_req;
+        match verification {
+            Verification::Signed => {}
+            Verification::Unsigned if state.anonymous_limiter.is_some() => anonymous = true,
+            Verification::Unsigned => return reject(&remote_addr, StatusCode::UNAUTHORIZED, "Missing request signature"),
+            Verification::Invalid(reason) => return reject(&remote_addr, StatusCode::UNAUTHORIZED, reason),
+        }
+    }
+
+    // Check the proxy token, if the client presented one
+    let token = match req.headers().get(&*tokens::TOKEN_HEADER) {
+        Some(token) => match token.to_str().ok().and_then(|token| Some((token, tokens::TOKEN_STORE.get(token)?))) {
+            Some(token) => Some(token),
+            None => return reject(&remote_addr, StatusCode::UNAUTHORIZED, "Invalid proxy token"),
+        },
+        None if state.config.require_token() => return reject(&remote_addr, StatusCode::UNAUTHORIZED, "Missing proxy token"),
+        None => None,
+    };
+
+    // Apply the first access rule that matches
+    let rule = rules::ACCESS_RULES.evaluate(&rules::RequestInfo {
+        ip: remote_addr,
+        token: token.as_ref().map(|(token, _)| *token),
+        path: req.uri().path(),
+        method: req.method(),
+    });
+    if let Some(Action::Deny) = rule.map(|rule| &rule.action) {
+        return reject(&remote_addr, StatusCode::FORBIDDEN, "Denied by access rules");
+    }
+
+    // Wait until the rate limiter allows this request - clients matching a limiting access rule are limited
+    // by the rule, anonymous clients by the anonymous quota of their IP, clients presenting a token by their
+    // token's tier or quota, everyone else by the anonymous tier or their client certificate or IP
+    let max_wait = state.config.rate_limit_max_wait();
+    let mut span = telemetry::span_for(&req, "rate limit", SpanKind::Internal);
+    let identity = req.extensions().get::<ClientIdentity>().map(|identity| identity.0.clone());
+    let client = identity.clone().unwrap_or_else(|| remote_addr.to_string());
+    // Which client waits, how its budget is checked, and how hitting the limit is logged, if it is
+    type Check<'a> = Box<dyn Fn() -> Result<(), Duration> + Send + Sync + 'a>;
+    let (waiter, check, logged): (String, Check<'_>, Option<String>) = if let Some(Action::Limit(tier)) = rule.map(|rule| &rule.action) {
+        let client = token.map(|(token, _)| token.to_string()).unwrap_or(client);
+        (client.clone(), Box::new(move || tier.check(&client)), None)
+    } else {
+        match (state.anonymous_limiter.as_ref(), token) {
+            (Some(anonymous_limiter), _) if anonymous => {
+                (remote_addr.to_string(), Box::new(move || RateLimit::check_key(&**anonymous_limiter, &remote_addr)), None)
+            }
+            (_, Some((token, limits))) => match tiers::TIERS.for_token(token) {
+                Some(tier) => {
+                    let token = token.to_string();
+                    (token.clone(), Box::new(move || tier.check(&token)), None)
+                }
+                None => (token.to_string(), Box::new(move || limiter::check_direct(&limits.limiter)), None),
+            },
+            _ => match (tiers::TIERS.get(tiers::ANONYMOUS_TIER), identity) {
+                (Some(tier), _) => (client.clone(), Box::new(move || tier.check(&client)), None),
+                (None, Some(identity)) => {
+                    let logged = format!(" by {}", identity);
+                    let identity_limiter = &state.identity_limiter;
+                    (identity.clone(), Box::new(move || limiter::check_identity(identity_limiter, &identity)), Some(logged))
+                }
+                (None, None) => (client, Box::new(|| state.ip_limiter.check_key(&remote_addr)), Some(String::new())),
+            },
+        }
+    };
+    let ready = limiter::until_ready_queued(&waiter, &check, max_wait).await;
+    if let (Ok(true), Some(logged)) = (&ready, &logged) {
+        println!("[{}{}] <!> Rate limit was hit{}", remote_addr, request_id::tag(), logged);
+    }
+    span.set_attribute("rate_limit.rejected", ready.is_err());
+    drop(span);
+    if let Err(wait) = ready {
+        metrics::METRICS.record_rate_limited_request();
+        let response = reject(&remote_addr, StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded");
+        return response.map(|response| with_retry_after(response, wait));
+    }
+
+    if local {
+        return Ok(answer_local(&req, &remote_addr, &state));
+    }
+
+    // The CF api has no use for the headers checked above
+    strip_proxy_headers(req.headers_mut());
+    if let Some(file) = download {
+        return Ok(downloads::download_file(req, file, &remote_addr, &state.cache, &state.config).await);
+    }
+    if graphql {
+        return Ok(graphql::handle_graphql(req, &remote_addr, &state.cache, &state.config).await);
+    }
+    if let Some(translation) = legacy {
+        return Ok(legacy::proxy_legacy(req, translation, &remote_addr, &state.cache, &state.config).await);
+    }
+    // Each lookup a batch is split into after the first uses up another request of the client's rate limit
+    let (req, charged) = batch::charge_chunks(req, &check, &state.config).await;
+    if let Err(wait) = charged {
+        println!("[{}{}] <!> Rate limit was hit splitting a batch lookup", remote_addr, request_id::tag());
+        metrics::METRICS.record_rate_limited_request();
+        return Ok(with_retry_after(error_response(StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded"), wait));
+    }
+    match paginate::first_page(req.uri()) {
+        // Each page after the first uses up another request of the client's rate limit
+        Some(first_page) => Ok(paginate::proxy_all_pages(req, first_page, &remote_addr, &state.cache, &state.config, &check).await),
+        None => Ok(proxy_request_with_cache(req, &remote_addr, &state.cache, &state.config).await.unwrap_or_else(ProxyError::into_response)),
+    }
+}
+
+/// Forwards a request in strict pass-through mode: nothing is checked but the per-IP rate limit, every path,
+/// method & body goes to the CF api as it is.
+async fn pass_through(req: Request<Body>, remote_addr: IpAddr, state: ProxyState) -> Result<Response<Body>, Infallible> {
+    let check = || state.ip_limiter.check_key(&remote_addr);
+    match limiter::until_ready_queued(&remote_addr.to_string(), check, state.config.rate_limit_max_wait()).await {
+        Ok(false) => {}
+        Ok(true) => println!("[{}{}] <!> Rate limit was hit", remote_addr, request_id::tag()),
+        Err(wait) => {
+            metrics::METRICS.record_rate_limited_request();
+            let response = reject(&remote_addr, StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded");
+            return response.map(|response| with_retry_after(response, wait));
+        }
+    }
+    Ok(proxy_request_with_cache(req, &remote_addr, &state.cache, &state.config).await.unwrap_or_else(ProxyError::into_response))
+}
+
+/// Answers a request for one of the proxy's own routes.
+fn answer_local(req: &Request<Body>, remote_addr: &IpAddr, state: &ProxyState) -> Response<Body> {
+    let response = routes::handle_local(req, state).unwrap_or_else(|| error_response(StatusCode::NOT_FOUND, "Not found"));
+    println!("[{}{}] <-> {} => {}", remote_addr, request_id::tag(), req.uri().path(), response.status().as_str());
+    response
+}
+
+/// Removes headers the proxy consumes itself from a request.
+fn strip_proxy_headers(headers: &mut HeaderMap) {
+    if bearer::BEARER_ALLOWLIST.is_some() {
+        headers.remove(AUTHORIZATION);
+    }
+    headers.remove(&*tokens::TOKEN_HEADER);
+    headers.remove(signing::TIMESTAMP_HEADER);
+    headers.remove(signing::SIGNATURE_HEADER);
+}
+
+/// Binds a listener at `addr`.
+fn bind(addr: &ListenAddr) -> io::Result<ClientIncoming> {
+    match addr {
+        #[cfg(unix)]
+        ListenAddr::Tcp(addr) if *listener::REUSE_PORT => ClientIncoming::bind_reusing_port(addr, *proxy_protocol::PROXY_PROTOCOL),
+        ListenAddr::Tcp(addr) => ClientIncoming::bind(addr, *proxy_protocol::PROXY_PROTOCOL).map_err(|e| {
+            let kind = std::error::Error::source(&e).and_then(|source| source.downcast_ref::<io::Error>())
+                .map_or(io::ErrorKind::AddrNotAvailable, io::Error::kind);
+            io::Error::new(kind, e)
+        }),
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => ClientIncoming::bind_unix(path.clone(), *listener::LISTEN_SOCKET_MODE, *listener::REUSE_PORT, *proxy_protocol::PROXY_PROTOCOL),
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "unix sockets are not supported on this platform")),
+    }
+}
+
+/// Joins listeners into one, failing if one of them failed or there are none.
+fn join_listeners(listeners: impl Iterator<Item = io::Result<ClientIncoming>>) -> io::Result<ClientIncoming> {
+    let mut joined: Option<ClientIncoming> = None;
+    for listener in listeners {
+        let listener = listener?;
+        joined = Some(match joined {
+            Some(joined) => joined.join(listener),
+            None => listener,
+        });
+    }
+    joined.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to listen at"))
+}
+
+/// A running proxy server.
+pub struct ProxyHandle {
+    /// Where the proxy listens, never empty.
+    listen_addrs: Vec<ListenAddr>,
+    state: ProxyState,
+    shutdown: oneshot::Sender<()>,
+    server: JoinHandle<Result<(), hyper::Error>>,
+}
+
+impl ProxyHandle {
+    /// Starts a proxy listening at `addr`, with fresh state.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn start(addr: SocketAddr) -> Result<Self, hyper::Error> {
+        Self::start_with_state(addr, ProxyState::new())
+    }
+
+    /// Starts a proxy listening at `addr`, continuing with the state of a previous proxy.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn start_with_state(addr: SocketAddr, state: ProxyState) -> Result<Self, hyper::Error> {
+        Ok(Self::serve(ClientIncoming::bind(&addr, *proxy_protocol::PROXY_PROTOCOL)?, state))
+    }
+
+    /// Starts a proxy listening at a unix socket at `path`, continuing with the state of a previous proxy.
+    /// The socket file is removed once the proxy shuts down.
+    ///
+    /// Must be called from within a tokio runtime.
+    #[cfg(unix)]
+    pub fn start_unix(path: PathBuf, state: ProxyState) -> io::Result<Self> {
+        Self::start_at(&ListenAddr::Unix(path), state)
+    }
+
+    /// Starts a proxy accepting connections on inherited listening sockets, like those passed by systemd (see
+    /// [`listener::systemd_listen_fds`]), continuing with the state of a previous proxy.
+    ///
+    /// Must be called from within a tokio runtime.
+    #[cfg(unix)]
+    pub fn start_from_fds(fds: &[std::os::unix::io::RawFd], state: ProxyState) -> io::Result<Self> {
+        let listeners = fds.iter().map(|fd| ClientIncoming::from_fd(*fd, *proxy_protocol::PROXY_PROTOCOL));
+        Ok(Self::serve(join_listeners(listeners)?, state))
+    }
+
+    /// Starts a proxy listening at `addr`, continuing with the state of a previous proxy.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn start_at(addr: &ListenAddr, state: ProxyState) -> io::Result<Self> {
+        Ok(Self::serve(bind(addr)?, state))
+    }
+
+    /// Starts a proxy listening at all of the `bindings` at once, each serving its own set of routes,
+    /// continuing with the state of a previous proxy.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn start_bindings(bindings: &[Binding], state: ProxyState) -> io::Result<Self> {
+        let listeners = bindings.iter().map(|binding| Ok(bind(&binding.addr)?.routes(binding.routes)));
+        Ok(Self::serve(join_listeners(listeners)?, state))
+    }
+
+    fn serve(incoming: ClientIncoming, state: ProxyState) -> Self {
+        let service_state = state.clone();
+        let service = make_service_fn(move |socket: &ClientStream| {
+
+            let identity = socket.client_identity().map(|identity| ClientIdentity(identity.to_string()));
+            let mut proxy = ProxyService::new(service_state.clone(), socket.remote_addr().ip())
+                .identity(identity)
+                .routes(socket.routes());
+            let activity = socket.activity();
+            let connection = metrics::METRICS.track_connection();
+            let mut requests = 0;
+
+            async move {
+
+                let service = service_fn(move |req: Request<Body>| {
+
+                    // Count the connection as active for as long as its service is alive
+                    let _connection = &connection;
+
+                    // Have HTTP/1.1 clients reconnect after the last request they may send on the connection
+                    requests += 1;
+                    let max_requests = *listener::MAX_REQUESTS_PER_CONNECTION;
+                    let last = max_requests > 0 && requests >= max_requests && req.version() < Version::HTTP_2;
+
+                    // The connection isn't idle while one of its requests is being handled
+                    let busy = activity.busy();
+                    let response = proxy.call(req);
+                    async move {
+                        let mut response = response.await;
+                        drop(busy);
+                        if let (true, Ok(response)) = (last, &mut response) {
+                            response.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+                        }
+                        response
+                    }
+                });
+
+                // Pass the request to the service handler
+                Ok::<_, Infallible>(service)
+            }
+        });
+
+        let incoming = incoming
+            .max_connections_per_ip(*listener::MAX_CONNECTIONS_PER_IP)
+            .idle_timeout(*listener::IDLE_TIMEOUT)
+            .tcp_keepalive(*listener::TCP_KEEPALIVE);
+        #[cfg(feature = "tls")]
+        let incoming = incoming.tls(tls::TLS_CERTIFICATE.clone());
+        let listen_addrs = incoming.local_addrs();
+        let server = Server::builder(incoming)
+            .http1_preserve_header_case(state.config.strict_passthrough())
+            .http1_header_read_timeout(*listener::HEADER_READ_TIMEOUT)
+            .http2_max_concurrent_streams(*listener::MAX_STREAMS_PER_CONNECTION)
+            .serve(service);
+        let (shutdown, shutdown_received) = oneshot::channel::<()>();
+        let server = server.with_graceful_shutdown(async {
+            shutdown_received.await.ok();
+        });
+
+        ProxyHandle { listen_addrs, state, shutdown, server: tokio::spawn(server) }
+    }
+
+    /// Returns the TCP address the proxy is listening at, the first one if there are several.
+    ///
+    /// Panics if it only listens at unix sockets, see [`ProxyHandle::listen_addrs`].
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listen_addrs.iter()
+            .find_map(|addr| match addr {
+                ListenAddr::Tcp(addr) => Some(*addr),
+                ListenAddr::Unix(_) => None,
+            })
+            .expect("Expected the proxy to listen at a TCP address")
+    }
+
+    /// Returns where the proxy is listening, the first address if there are several.
+    pub fn listen_addr(&self) -> &ListenAddr {
+        &self.listen_addrs[0]
+    }
+
+    /// Returns all addresses the proxy is listening at.
+    pub fn listen_addrs(&self) -> &[ListenAddr] {
+        &self.listen_addrs
+    }
+
+    /// Returns the state of the proxy.
+    pub fn state(&self) -> &ProxyState {
+        &self.state
+    }
+
+    /// Stops accepting connections and waits for in-flight requests to finish.
+    ///
+    /// Returns the proxy's state, which can be passed to [`ProxyHandle::start_with_state`] to start a new
+    /// proxy without losing it.
+    pub async fn shutdown(self) -> Result<ProxyState, hyper::Error> {
+        // The server only stops by receiving this signal, so it is still listening for it
+        self.shutdown.send(()).ok();
+        self.server.await.expect("Expected the server task to not panic")?;
+        Ok(self.state)
+    }
+}
+
+/// The whole proxy the binary runs - fetching secrets, obtaining certificates, checking keys, background tasks
+/// and listeners - for embedding it into another application instead of running the binary.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), String> {
+/// use cfproxy::config::ProxyConfig;
+/// use cfproxy::server::ProxyServer;
+///
+/// let config = ProxyConfig::from_env().with_api_keys(["key"])?;
+/// let bindings = vec!["127.0.0.1:3000".parse()?];
+/// ProxyServer::new(config).listen(bindings).run(async { tokio::signal::ctrl_c().await.ok(); }).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ProxyServer {
+    state: ProxyState,
+    bindings: Vec<Binding>,
+}
+
+impl ProxyServer {
+    /// Creates a server with fresh state, sending requests to the CF api in `config`.
+    pub fn new(config: ProxyConfig) -> Self {
+        ProxyServer { state: ProxyState::new().with_config(config), bindings: Vec::new() }
+    }
+
+    /// Continues with the state of a previous proxy, including its config.
+    pub fn with_state(self, state: ProxyState) -> Self {
+        ProxyServer { state, ..self }
+    }
+
+    /// Listens at `bindings`, unless systemd passed sockets to listen at (see [`listener::systemd_listen_fds`]).
+    pub fn listen(self, bindings: Vec<Binding>) -> Self {
+        ProxyServer { bindings, ..self }
+    }
+
+    /// Returns the state of the proxy.
+    pub fn state(&self) -> &ProxyState {
+        &self.state
+    }
+
+    /// Starts the proxy and runs it until `shutdown` resolves, then stops accepting connections and waits for
+    /// in-flight requests to finish.
+    ///
+    /// Fails if the proxy can't start (e.g. its secrets, certificate or listeners can't be set up, or keys are
+    /// rejected with `STARTUP_KEY_CHECK=fail`), or if the server fails while running.
+    pub async fn run(self, shutdown: impl Future<Output = ()>) -> Result<(), String> {
+        // Fetch secrets kept in secret stores
+        secrets::resolve_secrets().await?;
+
+        // Answer ACME challenges, and obtain a certificate unless there's one already
+        let mut tasks = Vec::new();
+        #[cfg(feature = "tls")]
+        if acme::ACME_DOMAINS.is_some() {
+            let addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], *acme::ACME_HTTP_PORT));
+            tasks.push(tokio::spawn(async move {
+                if let Err(e) = acme::serve_challenges(addr).await {
+                    eprintln!("<!> Could not answer ACME challenges at port {}: {}", addr.port(), e);
+                }
+            }));
+            acme::ensure_certificate().await.map_err(|e| format!("Could not obtain a certificate: {}", e))?;
+        }
+
+        // Start the uptime clock, and load keys, secrets, tokens, tiers & certificates now so broken config is
+        // noticed at startup
+        lazy_static::initialize(&diagnostics::STARTED_AT);
+        self.state.config().key_pool();
+        lazy_static::initialize(&signing::SIGNATURE_VERIFIER);
+        tokens::reload_tokens().map_err(|e| format!("Could not load the token store {}", e))?;
+        lazy_static::initialize(&tiers::TIERS);
+        lazy_static::initialize(&bearer::BEARER_ALLOWLIST);
+        #[cfg(feature = "tls")]
+        lazy_static::initialize(&tls::TLS_CERTIFICATE);
+
+        // Make sure Curseforge accepts the keys
+        keys::check_keys(self.state.config(), *keys::STARTUP_KEY_CHECK).await?;
+
+        // Carry over metrics from the previous run & keep persisting them
+        metrics::restore_snapshot();
+        tasks.push(tokio::spawn(metrics::persist_snapshots()));
+
+        // Pick up changes to the bearer token allowlist
+        tasks.push(tokio::spawn(bearer::watch_allowlist()));
+
+        // Send spans to the OpenTelemetry collector
+        tasks.push(tokio::spawn(telemetry::export_spans()));
+
+        // Push metrics to the StatsD agent
+        tasks.push(tokio::spawn(statsd::push_metrics(self.state.clone())));
+
+        // Renew & pick up renewed TLS certificates
+        #[cfg(feature = "tls")]
+        {
+            tasks.push(tokio::spawn(acme::renew_certificates()));
+            tasks.push(tokio::spawn(tls::watch_certificate()));
+        }
+
+        let state = self.state.clone();
+        let result = self.serve(shutdown).await;
+        for task in tasks {
+            task.abort();
+        }
+        metrics::save_snapshot();
+        statsd::push(&state).await;
+        telemetry::flush().await;
+        result
+    }
+
+    /// Starts the proxy on the sockets systemd passed with socket activation, or at the bindings otherwise, and
+    /// serves until `shutdown` resolves.
+    async fn serve(self, shutdown: impl Future<Output = ()>) -> Result<(), String> {
+        #[cfg(unix)]
+        let fds = listener::systemd_listen_fds();
+        #[cfg(unix)]
+        let handle = match fds.is_empty() {
+            false => ProxyHandle::start_from_fds(&fds, self.state)
+                .map_err(|e| format!("Could not accept connections on the sockets passed by systemd: {}", e))?,
+            true => ProxyHandle::start_bindings(&self.bindings, self.state)
+                .map_err(|e| format!("Could not listen at {}: {}", list(&self.bindings), e))?,
+        };
+        #[cfg(not(unix))]
+        let handle = ProxyHandle::start_bindings(&self.bindings, self.state)
+            .map_err(|e| format!("Could not listen at {}: {}", list(&self.bindings), e))?;
+        println!("<-> Server starting at {}", list(handle.listen_addrs()));
+
+        // Run until asked to shut down, then let in-flight requests finish
+        shutdown.await;
+        println!("<-> Shutting down, letting in-flight requests finish");
+        handle.shutdown().await.map(|_| ()).map_err(|e| format!("Server error: {}", e))
+    }
+}
+
+/// Lists addresses comma separated.
+fn list(addrs: &[impl ToString]) -> String {
+    addrs.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
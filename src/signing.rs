@@ -0,0 +1,149 @@
+//! HMAC signatures for requests made by first-party clients.
+//!
+//! If `SIGNING_SECRET` is set, clients are expected to sign every request with that shared secret, so third
+//! parties can't freeload on the deployment. A request is signed by sending
+//! - the current unix time in seconds in the `x-proxy-timestamp` header, and
+//! - the hex-encoded HMAC-SHA256 over `<timestamp>\n<path and query>\n<body>` in the `x-proxy-signature` header.
+//!
+//! The path is the one the client requests, including the `BASE_PATH` if the proxy is mounted under one - e.g.
+//! `/cfproxy/v1/games?index=50`.
+//!
+//! Signatures are only accepted once, and only while the timestamp is at most `SIGNATURE_MAX_AGE_SECS` off
+//! from the server's clock, so captured requests can't be replayed.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use hyper::{Body, Request};
+use hyper::body::Bytes;
+use lazy_static::lazy_static;
+use sha2::Sha256;
+use crate::routes::OriginalUri;
+use crate::secrets;
+
+lazy_static! {
+    /// Verifies signatures with the secret from the `SIGNING_SECRET` env variable (or the `SIGNING_SECRET_FILE` file).
+    /// `None` if the variable is unset, in which case requests are not checked.
+    pub static ref SIGNATURE_VERIFIER: Option<SignatureVerifier> = secrets::env_secret("SIGNING_SECRET")
+        .unwrap_or_else(|e| panic!("Expected the signing secret to be readable: {}", e))
+        .filter(|secret| !secret.is_empty())
+        .map(|secret| SignatureVerifier::new(
+            secret.into_bytes(),
+            env::var("SIGNATURE_MAX_AGE_SECS").unwrap_or(String::from("300"))
+                .parse::<u64>().expect("Expected SIGNATURE_MAX_AGE_SECS env var to contain a number"),
+        ));
+}
+
+/// Header containing the unix time at which the request was signed.
+pub const TIMESTAMP_HEADER: &str = "x-proxy-timestamp";
+
+/// Header containing the hex-encoded signature of the request.
+pub const SIGNATURE_HEADER: &str = "x-proxy-signature";
+
+/// Returns the current unix time in seconds.
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Returns the HMAC over the signed parts of a request.
+fn mac(secret: &[u8], timestamp: u64, path_and_query: &str, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}\n{}\n", timestamp, path_and_query).as_bytes());
+    mac.update(body);
+    mac
+}
+
+/// Computes the signature of a request, hex-encoded.
+pub fn sign(secret: &[u8], timestamp: u64, path_and_query: &str, body: &[u8]) -> String {
+    mac(secret, timestamp, path_and_query, body).finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses a hex string into bytes.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes().chunks(2)
+        .map(|byte| match byte {
+            [_, _] => std::str::from_utf8(byte).ok().and_then(|byte| u8::from_str_radix(byte, 16).ok()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The outcome of checking a request's signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// The request carries a valid signature.
+    Signed,
+    /// The request carries no signature at all.
+    Unsigned,
+    /// The request carries a signature, but it is not valid.
+    Invalid(&'static str),
+}
+
+/// Checks request signatures against a shared secret.
+#[derive(Debug)]
+pub struct SignatureVerifier {
+    secret: Vec<u8>,
+    max_age_secs: u64,
+    /// Signatures that were already accepted, along with the time they can be forgotten at.
+    seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl SignatureVerifier {
+    /// Creates a verifier accepting signatures whose timestamp is at most `max_age_secs` off.
+    pub fn new(secret: Vec<u8>, max_age_secs: u64) -> Self {
+        SignatureVerifier { secret, max_age_secs, seen: Mutex::new(HashMap::new()) }
+    }
+
+    /// Checks the signature of a request with the given parts.
+    ///
+    /// A valid signature is remembered, and rejected if it is presented again.
+    pub fn verify(&self, timestamp: &str, signature: &str, path_and_query: &str, body: &[u8]) -> Verification {
+        let timestamp = match timestamp.parse::<u64>() {
+            Ok(timestamp) => timestamp,
+            Err(_) => return Verification::Invalid("Malformed signature timestamp"),
+        };
+        let now = now();
+        if timestamp.saturating_add(self.max_age_secs) < now || timestamp > now.saturating_add(self.max_age_secs) {
+            return Verification::Invalid("Signature expired");
+        }
+        let signature = match decode_hex(signature) {
+            Some(signature) => signature,
+            None => return Verification::Invalid("Malformed signature"),
+        };
+
+        if mac(&self.secret, timestamp, path_and_query, body).verify_slice(&signature).is_err() {
+            return Verification::Invalid("Invalid signature");
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, forget_at| *forget_at > now);
+        if seen.insert(signature, timestamp.saturating_add(self.max_age_secs).saturating_add(1)).is_some() {
+            return Verification::Invalid("Signature was already used");
+        }
+        Verification::Signed
+    }
+
+    /// Checks the signature of a request.
+    ///
+    /// The body has to be read in order to verify it, so the request is handed back with the body buffered.
+    pub async fn verify_request(&self, req: Request<Body>) -> Result<(Request<Body>, Verification), hyper::Error> {
+        let (parts, body) = req.into_parts();
+        let body: Bytes = hyper::body::to_bytes(body).await?;
+
+        let verification = match (parts.headers.get(TIMESTAMP_HEADER), parts.headers.get(SIGNATURE_HEADER)) {
+            (None, None) => Verification::Unsigned,
+            (Some(timestamp), Some(signature)) => match (timestamp.to_str(), signature.to_str()) {
+                (Ok(timestamp), Ok(signature)) => {
+                    let uri = parts.extensions.get::<OriginalUri>().map_or(&parts.uri, |original| &original.0);
+                    let path_and_query = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+                    self.verify(timestamp, signature, path_and_query, &body)
+                }
+                _ => Verification::Invalid("Malformed signature headers"),
+            },
+            _ => Verification::Invalid("Expected both a signature and a timestamp"),
+        };
+        Ok((Request::from_parts(parts, Body::from(body)), verification))
+    }
+}
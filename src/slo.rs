@@ -0,0 +1,199 @@
+//! Availability & latency SLOs of proxied requests, tracked over a rolling window.
+//!
+//! A request counts against the availability SLO if it fails or Curseforge answers with a `5xx`, and against
+//! the latency SLO if it takes longer than `SLO_LATENCY_MS`. From the share of bad requests, the tracker
+//! derives
+//! - how much of the error budget (`1 - target`) of the last `SLO_WINDOW_SECS` is left, and
+//! - the burn rate over the last `SLO_BURN_WINDOW_SECS`: how many times faster than allowed the budget is used up.
+//!
+//! If `SLO_DEGRADED_MODE` is enabled, the proxy answers from the cache - including expired responses - while
+//! less than `SLO_DEGRADE_BELOW` of the availability budget is left.
+
+use std::collections::VecDeque;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+lazy_static! {
+    /// The SLO tracker of this process, configured from the `SLO_*` env variables.
+    pub static ref SLO: SloTracker = SloTracker::new(SloConfig {
+        availability_target: env::var("SLO_AVAILABILITY_TARGET").unwrap_or(String::from("0.99"))
+            .parse::<f64>().ok().filter(|target| (0.0..1.0).contains(target))
+            .expect("Expected SLO_AVAILABILITY_TARGET env var to contain a number between 0 and 1"),
+        latency_target: env::var("SLO_LATENCY_TARGET").unwrap_or(String::from("0.95"))
+            .parse::<f64>().ok().filter(|target| (0.0..1.0).contains(target))
+            .expect("Expected SLO_LATENCY_TARGET env var to contain a number between 0 and 1"),
+        latency_threshold: Duration::from_millis(env::var("SLO_LATENCY_MS").unwrap_or(String::from("1000"))
+            .parse::<u64>().expect("Expected SLO_LATENCY_MS env var to contain a number")),
+        window: Duration::from_secs(env::var("SLO_WINDOW_SECS").unwrap_or(String::from("3600"))
+            .parse::<u64>().expect("Expected SLO_WINDOW_SECS env var to contain a number")),
+        burn_window: Duration::from_secs(env::var("SLO_BURN_WINDOW_SECS").unwrap_or(String::from("300"))
+            .parse::<u64>().expect("Expected SLO_BURN_WINDOW_SECS env var to contain a number")),
+        degraded_mode: env::var("SLO_DEGRADED_MODE").unwrap_or(String::from("false"))
+            .parse::<bool>().expect("Expected SLO_DEGRADED_MODE env var to be either true or false"),
+        degrade_below: env::var("SLO_DEGRADE_BELOW").unwrap_or(String::from("0.1"))
+            .parse::<f64>().expect("Expected SLO_DEGRADE_BELOW env var to contain a number"),
+    });
+}
+
+/// Requests are counted in buckets of this length.
+const BUCKET: Duration = Duration::from_secs(10);
+
+/// The degraded mode doesn't kick in before this many requests were tracked in the window, so a few
+/// failures right after startup don't switch it on.
+const MIN_REQUESTS: u64 = 20;
+
+/// SLO targets & windows.
+#[derive(Debug, Clone, Copy)]
+pub struct SloConfig {
+    /// Share of requests that should succeed.
+    pub availability_target: f64,
+    /// Share of requests that should be faster than `latency_threshold`.
+    pub latency_target: f64,
+    pub latency_threshold: Duration,
+    /// Window the error budget is tracked over.
+    pub window: Duration,
+    /// Window the burn rate is calculated over.
+    pub burn_window: Duration,
+    /// Whether to answer from the cache while the availability budget is nearly used up.
+    pub degraded_mode: bool,
+    /// Share of the availability budget below which the degraded mode kicks in.
+    pub degrade_below: f64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Bucket {
+    index: u64,
+    requests: u64,
+    errors: u64,
+    slow: u64,
+}
+
+/// Request outcomes in buckets, oldest first.
+#[derive(Debug)]
+pub struct SloTracker {
+    config: SloConfig,
+    started: Instant,
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+/// The state of the SLOs.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SloReport {
+    /// Requests tracked in the window.
+    pub requests: u64,
+    /// Share of requests in the window that succeeded, `1` if there were none.
+    pub availability: f64,
+    /// Share of requests in the window that were fast enough, `1` if there were none.
+    pub latency: f64,
+    /// Share of the availability error budget that's left in the window, can be negative once it's blown.
+    pub availability_budget_remaining: f64,
+    /// Share of the latency error budget that's left in the window, can be negative once it's blown.
+    pub latency_budget_remaining: f64,
+    /// How many times faster than allowed the availability budget was used up over the burn window.
+    pub availability_burn_rate: f64,
+    /// How many times faster than allowed the latency budget was used up over the burn window.
+    pub latency_burn_rate: f64,
+    /// Whether the proxy answers from the cache to save the budget.
+    pub degraded: bool,
+}
+
+#[derive(Default)]
+struct Counts {
+    requests: u64,
+    errors: u64,
+    slow: u64,
+}
+
+fn bad_share(bad: u64, requests: u64) -> f64 {
+    match requests {
+        0 => 0.0,
+        requests => bad as f64 / requests as f64,
+    }
+}
+
+impl SloTracker {
+    /// Creates a tracker without any requests.
+    pub fn new(config: SloConfig) -> Self {
+        SloTracker { config, started: Instant::now(), buckets: Mutex::new(VecDeque::new()) }
+    }
+
+    /// Returns the targets & windows of the tracker.
+    pub fn config(&self) -> &SloConfig {
+        &self.config
+    }
+
+    fn bucket_index(&self, at: Instant) -> u64 {
+        at.saturating_duration_since(self.started).as_secs() / BUCKET.as_secs()
+    }
+
+    /// Records the outcome of a request that finished now.
+    pub fn record(&self, success: bool, latency: Duration) {
+        self.record_at(Instant::now(), success, latency)
+    }
+
+    /// Records the outcome of a request that finished at the given time.
+    pub fn record_at(&self, at: Instant, success: bool, latency: Duration) {
+        let index = self.bucket_index(at);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.back().map(|bucket| bucket.index != index).unwrap_or(true) {
+            buckets.push_back(Bucket { index, ..Bucket::default() });
+        }
+        let bucket = buckets.back_mut().unwrap();
+        bucket.requests += 1;
+        bucket.errors += !success as u64;
+        bucket.slow += (latency > self.config.latency_threshold) as u64;
+
+        // Forget buckets that left the window
+        let window_buckets = self.config.window.as_secs() / BUCKET.as_secs();
+        while buckets.front().map(|bucket| bucket.index + window_buckets <= index).unwrap_or(false) {
+            buckets.pop_front();
+        }
+    }
+
+    fn counts(&self, at: Instant, window: Duration) -> Counts {
+        let index = self.bucket_index(at);
+        let window_buckets = window.as_secs() / BUCKET.as_secs();
+        self.buckets.lock().unwrap().iter()
+            .filter(|bucket| bucket.index + window_buckets > index)
+            .fold(Counts::default(), |counts, bucket| Counts {
+                requests: counts.requests + bucket.requests,
+                errors: counts.errors + bucket.errors,
+                slow: counts.slow + bucket.slow,
+            })
+    }
+
+    /// Returns the state of the SLOs now.
+    pub fn report(&self) -> SloReport {
+        self.report_at(Instant::now())
+    }
+
+    /// Returns the state of the SLOs at the given time.
+    pub fn report_at(&self, at: Instant) -> SloReport {
+        let config = &self.config;
+        let window = self.counts(at, config.window);
+        let burn = self.counts(at, config.burn_window);
+        let availability_budget = 1.0 - config.availability_target;
+        let latency_budget = 1.0 - config.latency_target;
+
+        let availability_budget_remaining = 1.0 - bad_share(window.errors, window.requests) / availability_budget;
+        SloReport {
+            requests: window.requests,
+            availability: 1.0 - bad_share(window.errors, window.requests),
+            latency: 1.0 - bad_share(window.slow, window.requests),
+            availability_budget_remaining,
+            latency_budget_remaining: 1.0 - bad_share(window.slow, window.requests) / latency_budget,
+            availability_burn_rate: bad_share(burn.errors, burn.requests) / availability_budget,
+            latency_burn_rate: bad_share(burn.slow, burn.requests) / latency_budget,
+            degraded: config.degraded_mode && window.requests >= MIN_REQUESTS
+                && availability_budget_remaining < config.degrade_below,
+        }
+    }
+
+    /// Returns whether the proxy should answer from the cache to save the availability budget.
+    pub fn is_degraded(&self) -> bool {
+        self.config.degraded_mode && self.report().degraded
+    }
+}
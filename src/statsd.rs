@@ -0,0 +1,220 @@
+//! Pushing metrics to a StatsD agent.
+//!
+//! If `STATSD_HOST` is set, the proxy's counters and gauges (see [`metrics`](crate::metrics)), and those of
+//! its cache & rate limiter, are sent to the agent over UDP every `STATSD_INTERVAL_SECS` - counters as how much
+//! they increased since the last push - along with the timings of requests and of calls to Curseforge recorded in
+//! the meantime. With `STATSD_FLAVOR=dogstatsd`, metrics carry the `STATSD_TAGS` and their own tags (like the
+//! route of a request) the way Datadog's agent expects them.
+
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use lazy_static::lazy_static;
+use tokio::net::UdpSocket;
+use crate::cache::CacheStats;
+use crate::metrics::{self, MetricsSink, RunStats, Tags};
+use crate::server::ProxyState;
+
+lazy_static! {
+    /// The StatsD agent metrics are pushed to. Read from the `STATSD_HOST` env variable, metrics aren't pushed
+    /// if unset.
+    pub static ref STATSD_HOST: Option<String> = env::var("STATSD_HOST").ok()
+        .filter(|host| !host.is_empty());
+
+    /// The port of the StatsD agent. Read from the `STATSD_PORT` env variable.
+    pub static ref STATSD_PORT: u16 = env::var("STATSD_PORT").unwrap_or(String::from("8125"))
+        .parse::<u16>().expect("Expected STATSD_PORT env var to contain a port number");
+
+    /// What the names of metrics start with. Read from the `STATSD_PREFIX` env variable.
+    pub static ref STATSD_PREFIX: String = env::var("STATSD_PREFIX").unwrap_or(String::from("cfproxy"));
+
+    /// Which protocol the agent speaks. Read from the `STATSD_FLAVOR` env variable.
+    pub static ref STATSD_FLAVOR: Flavor = match env::var("STATSD_FLAVOR").unwrap_or(String::from("statsd")).as_str() {
+        "statsd" => Flavor::Statsd,
+        "dogstatsd" => Flavor::DogStatsd,
+        _ => panic!("Expected STATSD_FLAVOR env var to be one of statsd or dogstatsd"),
+    };
+
+    /// Tags every metric carries, like `env:production`. Read from the `STATSD_TAGS` env variable, as a comma
+    /// separated list.
+    pub static ref STATSD_TAGS: Vec<String> = env::var("STATSD_TAGS").unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(sanitize)
+        .collect();
+
+    /// How often metrics are pushed. Read from the `STATSD_INTERVAL_SECS` env variable.
+    pub static ref STATSD_INTERVAL: Duration = Duration::from_secs(
+        env::var("STATSD_INTERVAL_SECS").unwrap_or(String::from("10"))
+            .parse::<u64>().ok().filter(|secs| *secs > 0)
+            .expect("Expected STATSD_INTERVAL_SECS env var to contain a positive number")
+    );
+
+    /// Where metrics are collected until they're pushed, if they're pushed at all.
+    pub static ref SINK: Option<StatsdSink> = STATSD_HOST.as_ref()
+        .map(|_| StatsdSink::new(&STATSD_PREFIX, *STATSD_FLAVOR, STATSD_TAGS.clone()));
+
+    /// The counters as of the last push.
+    static ref LAST_PUSHED: Mutex<(RunStats, CacheStats)> = Mutex::new(Default::default());
+}
+
+/// How many metrics are kept until the next push at most, further ones are dropped.
+pub const MAX_QUEUED_METRICS: usize = 10_000;
+
+/// How large a single UDP packet sent to the agent may be, so it isn't fragmented.
+pub const MAX_PACKET_BYTES: usize = 1432;
+
+/// The protocol a StatsD agent speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flavor {
+    /// Plain StatsD, which has no tags.
+    Statsd,
+    /// Datadog's StatsD, with tags.
+    DogStatsd,
+}
+
+/// Collects metrics as StatsD lines, like `cfproxy.requests:5|c`.
+#[derive(Debug)]
+pub struct StatsdSink {
+    prefix: String,
+    flavor: Flavor,
+    tags: Vec<String>,
+    lines: Mutex<Vec<String>>,
+}
+
+impl StatsdSink {
+    pub fn new(prefix: &str, flavor: Flavor, tags: Vec<String>) -> Self {
+        let prefix = match prefix.trim_end_matches('.') {
+            "" => String::new(),
+            prefix => format!("{}.", prefix),
+        };
+        StatsdSink { prefix, flavor, tags, lines: Mutex::new(Vec::new()) }
+    }
+
+    /// Returns the metrics collected so far, and starts collecting anew.
+    pub fn take_lines(&self) -> Vec<String> {
+        std::mem::take(&mut *self.lines.lock().unwrap())
+    }
+
+    fn push(&self, name: &str, value: String, kind: &str, tags: &Tags<'_>) {
+        let mut line = format!("{}{}:{}|{}", self.prefix, name, value, kind);
+        if self.flavor == Flavor::DogStatsd {
+            let tags = self.tags.iter().cloned()
+                .chain(tags.iter().map(|(name, value)| format!("{}:{}", sanitize(name), sanitize(value))))
+                .collect::<Vec<_>>();
+            if !tags.is_empty() {
+                line.push_str("|#");
+                line.push_str(&tags.join(","));
+            }
+        }
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() < MAX_QUEUED_METRICS {
+            lines.push(line);
+        }
+    }
+}
+
+impl MetricsSink for StatsdSink {
+    fn count(&self, name: &str, value: u64, tags: &Tags<'_>) {
+        self.push(name, value.to_string(), "c", tags);
+    }
+
+    fn gauge(&self, name: &str, value: u64, tags: &Tags<'_>) {
+        self.push(name, value.to_string(), "g", tags);
+    }
+
+    fn timing(&self, name: &str, duration: Duration, tags: &Tags<'_>) {
+        self.push(name, format!("{:.3}", duration.as_secs_f64() * 1000.0), "ms", tags);
+    }
+}
+
+/// Replaces the characters that delimit lines, values & tags in the StatsD protocol.
+fn sanitize(tag: &str) -> String {
+    tag.replace(['\n', ',', '|', '#'], "_")
+}
+
+/// Joins lines into as few packets of at most [`MAX_PACKET_BYTES`] as possible. Lines longer than that are
+/// sent on their own.
+pub fn packets(lines: Vec<String>) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_BYTES {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(&line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+/// Pushes the current counters & gauges of the proxy with `state`, and the timings collected since the last push,
+/// to the agent.
+///
+/// Does nothing if `STATSD_HOST` is not set.
+pub async fn push(state: &ProxyState) {
+    let (sink, host) = match (SINK.as_ref(), STATSD_HOST.as_ref()) {
+        (Some(sink), Some(host)) => (sink, host),
+        _ => return,
+    };
+    {
+        let mut last = LAST_PUSHED.lock().unwrap();
+        metrics::METRICS.report_to(sink, &mut last.0);
+        state.cache_stats().report_to(sink, &mut last.1);
+        sink.gauge("rate_limiter.keys", state.rate_limiter_keys() as u64, &[]);
+    }
+    let lines = sink.take_lines();
+
+    let addr = match tokio::net::lookup_host((host.as_str(), *STATSD_PORT)).await.map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        Ok(None) => {
+            eprintln!("<!> Could not push metrics, {} has no address", host);
+            return;
+        }
+        Err(e) => {
+            eprintln!("<!> Could not push metrics, {} could not be resolved: {}", host, e);
+            return;
+        }
+    };
+    let local_addr = match addr {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], 0)),
+    };
+    let socket = match UdpSocket::bind(local_addr).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("<!> Could not push metrics to {}: {}", addr, e);
+            return;
+        }
+    };
+    for packet in packets(lines) {
+        if let Err(e) = socket.send_to(packet.as_bytes(), addr).await {
+            eprintln!("<!> Could not push metrics to {}: {}", addr, e);
+            return;
+        }
+    }
+}
+
+/// Keeps pushing metrics of the proxy with `state` to the agent, forever.
+///
+/// Returns immediately if `STATSD_HOST` is not set.
+pub async fn push_metrics(state: ProxyState) {
+    if STATSD_HOST.is_none() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(*STATSD_INTERVAL);
+    // The first tick completes immediately, skip it
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        push(&state).await;
+    }
+}
@@ -0,0 +1,435 @@
+//! OpenTelemetry tracing of the request pipeline.
+//!
+//! If an OTLP endpoint is configured through the standard `OTEL_EXPORTER_OTLP_ENDPOINT` (or
+//! `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`) env variable, every request gets a span, with child spans for the
+//! cache lookup, waiting for rate limiters and each call to an upstream. Spans are sent in batches to the
+//! collector - like Jaeger or Tempo - with OTLP over HTTP, as JSON.
+//!
+//! The span of a request is kept in its extensions as a [`SpanContext`], so anything handling the request can
+//! add children with [`span_for`].
+//!
+//! Traces are propagated with W3C trace context headers: a request with a `traceparent` continues the client's
+//! trace, and calls to upstreams carry the `traceparent` of their span along with the client's `tracestate`.
+
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, HeaderMap, Method, Request, Uri};
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use crate::{egress, pool};
+
+lazy_static! {
+    /// Where spans are sent. Read from the `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` env variable, or the
+    /// `OTEL_EXPORTER_OTLP_ENDPOINT` env variable with `/v1/traces` appended. Tracing is disabled if neither is
+    /// set, or `OTEL_SDK_DISABLED` is `true` or `OTEL_TRACES_EXPORTER` is `none`.
+    pub static ref OTLP_TRACES_ENDPOINT: Option<Uri> = {
+        let disabled = env::var("OTEL_SDK_DISABLED").map(|disabled| disabled.eq_ignore_ascii_case("true")).unwrap_or(false)
+            || env::var("OTEL_TRACES_EXPORTER").map(|exporter| exporter.split(',').all(|e| e.trim() == "none")).unwrap_or(false);
+        let protocol = env::var("OTEL_EXPORTER_OTLP_TRACES_PROTOCOL").or_else(|_| env::var("OTEL_EXPORTER_OTLP_PROTOCOL"));
+        if protocol.map(|protocol| protocol != "http/json").unwrap_or(false) {
+            panic!("Expected OTEL_EXPORTER_OTLP_PROTOCOL env var to be http/json");
+        }
+        let endpoint = env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").ok().filter(|endpoint| !endpoint.is_empty())
+            .or_else(|| env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().filter(|endpoint| !endpoint.is_empty())
+                .map(|endpoint| format!("{}/v1/traces", endpoint.trim_end_matches('/'))));
+        endpoint.filter(|_| !disabled)
+            .map(|endpoint| endpoint.parse::<Uri>().expect("Expected OTEL_EXPORTER_OTLP_ENDPOINT env var to be a url"))
+    };
+
+    /// Headers sent along with spans, like credentials of the collector. Read from the
+    /// `OTEL_EXPORTER_OTLP_TRACES_HEADERS` or `OTEL_EXPORTER_OTLP_HEADERS` env variable, as comma separated
+    /// `<name>=<value>` pairs with percent-encoded values.
+    pub static ref OTLP_HEADERS: Vec<(HeaderName, HeaderValue)> = env::var("OTEL_EXPORTER_OTLP_TRACES_HEADERS")
+        .or_else(|_| env::var("OTEL_EXPORTER_OTLP_HEADERS"))
+        .unwrap_or_default()
+        .split(',')
+        .filter(|header| !header.trim().is_empty())
+        .map(|header| {
+            let (name, value) = header.split_once('=').expect("Expected OTEL_EXPORTER_OTLP_HEADERS env var to contain <name>=<value> pairs");
+            let name = HeaderName::from_bytes(name.trim().as_bytes()).expect("Expected OTEL_EXPORTER_OTLP_HEADERS env var to contain valid header names");
+            let value = HeaderValue::from_str(egress::percent_decode(value.trim()).as_str()).expect("Expected OTEL_EXPORTER_OTLP_HEADERS env var to contain valid header values");
+            (name, value)
+        })
+        .collect();
+
+    /// The service spans are reported for. Read from the `OTEL_SERVICE_NAME` env variable.
+    pub static ref OTEL_SERVICE_NAME: String = env::var("OTEL_SERVICE_NAME").ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or(String::from("cfproxy"));
+
+    /// Which requests are traced. Read from the `OTEL_TRACES_SAMPLER` env variable - `always_on`, `always_off` or
+    /// `traceidratio` with the ratio in `OTEL_TRACES_SAMPLER_ARG`, optionally prefixed with `parentbased_`.
+    pub static ref SAMPLER: Sampler = {
+        let sampler = env::var("OTEL_TRACES_SAMPLER").unwrap_or(String::from("parentbased_always_on"));
+        let parent_based = sampler.starts_with("parentbased_");
+        let ratio = match sampler.trim_start_matches("parentbased_") {
+            "always_on" => 1.0,
+            "always_off" => 0.0,
+            "traceidratio" => env::var("OTEL_TRACES_SAMPLER_ARG").unwrap_or(String::from("1.0"))
+                .parse::<f64>().expect("Expected OTEL_TRACES_SAMPLER_ARG env var to contain a number"),
+            _ => panic!("Expected OTEL_TRACES_SAMPLER env var to be always_on, always_off or traceidratio"),
+        };
+        Sampler { ratio, parent_based }
+    };
+
+    /// How often batches of spans are sent. Read from the `OTEL_BSP_SCHEDULE_DELAY` env variable, in milliseconds.
+    pub static ref EXPORT_INTERVAL: Duration = Duration::from_millis(
+        env::var("OTEL_BSP_SCHEDULE_DELAY").unwrap_or(String::from("5000"))
+            .parse::<u64>().expect("Expected OTEL_BSP_SCHEDULE_DELAY env var to contain a number")
+    );
+
+    /// How many ended spans are kept until they're sent, further spans are dropped. Read from the
+    /// `OTEL_BSP_MAX_QUEUE_SIZE` env variable.
+    pub static ref MAX_QUEUED_SPANS: usize = env::var("OTEL_BSP_MAX_QUEUE_SIZE").unwrap_or(String::from("2048"))
+        .parse::<usize>().expect("Expected OTEL_BSP_MAX_QUEUE_SIZE env var to contain a number");
+
+    /// Ended spans that weren't sent yet.
+    static ref QUEUE: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+}
+
+/// The header W3C trace contexts are propagated in.
+pub const TRACEPARENT: &str = "traceparent";
+
+/// Spans dropped because the queue was full.
+static DROPPED_SPANS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns whether spans are recorded & exported at all.
+pub fn is_enabled() -> bool {
+    OTLP_TRACES_ENDPOINT.is_some()
+}
+
+/// Identifies a span, and the trace it's part of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    /// Whether the trace is recorded.
+    pub sampled: bool,
+}
+
+impl SpanContext {
+    /// Parses a W3C `traceparent` header value, like `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let mut fields = value.trim().split('-');
+        let version = u8::from_str_radix(fields.next().filter(|version| version.len() == 2)?, 16).ok()?;
+        let trace_id = parse_hex::<16>(fields.next()?)?;
+        let span_id = parse_hex::<8>(fields.next()?)?;
+        let flags = parse_hex::<1>(fields.next()?)?[0];
+        // Later versions may add fields, but this version has none
+        if version == 0xff || (version == 0 && fields.next().is_some()) {
+            return None;
+        }
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        Some(SpanContext { trace_id, span_id, sampled: flags & 1 == 1 })
+    }
+
+    /// Formats the context as a W3C `traceparent` header value.
+    pub fn to_traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", hex(&self.trace_id), hex(&self.span_id), self.sampled as u8)
+    }
+
+    /// Returns whether the context identifies a span - spans of requests without a context have none.
+    pub fn is_valid(&self) -> bool {
+        self.trace_id != [0; 16] && self.span_id != [0; 8]
+    }
+}
+
+/// Decides which traces are recorded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sampler {
+    /// The share of new traces that are recorded.
+    pub ratio: f64,
+    /// Whether traces continued from a client are recorded if & only if the client records them.
+    pub parent_based: bool,
+}
+
+impl Sampler {
+    /// Returns whether a trace is recorded - `parent` is whether the client records it, if the trace was
+    /// continued from a client.
+    pub fn sample(&self, parent: Option<bool>) -> bool {
+        match parent {
+            Some(sampled) if self.parent_based => sampled,
+            _ => rand::random::<f64>() < self.ratio,
+        }
+    }
+}
+
+/// What a span stands for, as in OTLP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// A span attribute's value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::String(value)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue::Int(value)
+    }
+}
+
+impl From<u16> for AttributeValue {
+    fn from(value: u16) -> Self {
+        AttributeValue::Int(value as i64)
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        AttributeValue::Bool(value)
+    }
+}
+
+/// A timed operation. The span ends, and is queued for export if its trace is sampled, when it's dropped.
+#[derive(Debug)]
+pub struct Span {
+    context: SpanContext,
+    parent: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    attributes: Vec<(&'static str, AttributeValue)>,
+    error: Option<String>,
+}
+
+impl Span {
+    /// Starts the first span of a new trace, which is sampled according to the [`SAMPLER`].
+    pub fn root(name: impl Into<String>, kind: SpanKind) -> Self {
+        let sampled = is_enabled() && SAMPLER.sample(None);
+        let context = SpanContext { trace_id: random_id(), span_id: random_id(), sampled };
+        Span::start(context, None, name.into(), kind)
+    }
+
+    /// Starts the first span of the proxy in a trace a client started, with the client's span as `parent`. The
+    /// span is sampled according to the [`SAMPLER`].
+    pub fn continue_trace(parent: &SpanContext, name: impl Into<String>, kind: SpanKind) -> Self {
+        let sampled = is_enabled() && SAMPLER.sample(Some(parent.sampled));
+        Span::child_of(&SpanContext { sampled, ..*parent }, name, kind)
+    }
+
+    /// Starts a span as a child of `parent`.
+    pub fn child_of(parent: &SpanContext, name: impl Into<String>, kind: SpanKind) -> Self {
+        let context = SpanContext { span_id: random_id(), ..*parent };
+        Span::start(context, Some(parent.span_id), name.into(), kind)
+    }
+
+    fn start(context: SpanContext, parent: Option<[u8; 8]>, name: String, kind: SpanKind) -> Self {
+        Span { context, parent, name, kind, start: SystemTime::now(), attributes: Vec::new(), error: None }
+    }
+
+    pub fn context(&self) -> SpanContext {
+        self.context
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.context.sampled
+    }
+
+    /// Sets an attribute, following the OpenTelemetry semantic conventions where there's one - like
+    /// `http.response.status_code`.
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<AttributeValue>) {
+        if !self.is_recording() {
+            return;
+        }
+        let value = value.into();
+        match self.attributes.iter_mut().find(|(existing, _)| *existing == key) {
+            Some((_, existing)) => *existing = value,
+            None => self.attributes.push((key, value)),
+        }
+    }
+
+    /// Marks the operation as failed.
+    pub fn set_error(&mut self, message: impl Into<String>) {
+        if self.is_recording() {
+            self.error = Some(message.into());
+        }
+    }
+
+    /// Converts the span to its OTLP JSON form.
+    fn to_otlp(&self, end: SystemTime) -> Value {
+        let mut span = json!({
+            "traceId": hex(&self.context.trace_id),
+            "spanId": hex(&self.context.span_id),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": unix_nanos(self.start).to_string(),
+            "endTimeUnixNano": unix_nanos(end).to_string(),
+            "attributes": self.attributes.iter().map(|(key, value)| attribute(key, value)).collect::<Vec<_>>(),
+            "status": match &self.error {
+                Some(message) => json!({ "code": 2, "message": message }),
+                None => json!({}),
+            },
+        });
+        if let Some(parent) = self.parent {
+            span["parentSpanId"] = Value::String(hex(&parent));
+        }
+        span
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if !self.is_recording() {
+            return;
+        }
+        let span = self.to_otlp(SystemTime::now());
+        let mut queue = QUEUE.lock().unwrap();
+        if queue.len() >= *MAX_QUEUED_SPANS {
+            DROPPED_SPANS.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        queue.push(span);
+    }
+}
+
+/// Starts a span as a child of the request's span, or one that isn't recorded if the request has none.
+pub fn span_for<B>(req: &Request<B>, name: impl Into<String>, kind: SpanKind) -> Span {
+    match req.extensions().get::<SpanContext>() {
+        Some(parent) => Span::child_of(parent, name, kind),
+        None => Span::start(SpanContext { trace_id: [0; 16], span_id: [0; 8], sampled: false }, None, name.into(), kind),
+    }
+}
+
+/// Starts the span of a request the proxy received - continuing the trace in its `traceparent`, if it has one -
+/// and keeps it in the request so the request's handlers can add children.
+pub fn start_request_span<B>(req: &mut Request<B>, route: &str) -> Span {
+    let name = format!("{} {}", req.method(), route);
+    let parent = req.headers().get(TRACEPARENT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(SpanContext::from_traceparent);
+    let mut span = match parent {
+        Some(parent) => Span::continue_trace(&parent, name, SpanKind::Server),
+        None => Span::root(name, SpanKind::Server),
+    };
+    span.set_attribute("http.request.method", req.method().as_str());
+    span.set_attribute("http.route", route);
+    span.set_attribute("url.path", req.uri().path());
+    req.extensions_mut().insert(span.context());
+    span
+}
+
+/// Makes `span` the parent of the upstream's spans, by setting the request's `traceparent` - the client's
+/// `tracestate` is passed on as it is. Requests are left alone while tracing is disabled, so clients' trace
+/// contexts pass through the proxy unchanged.
+pub fn propagate(span: &Span, headers: &mut HeaderMap) {
+    if !is_enabled() || !span.context.is_valid() {
+        return;
+    }
+    // Trace contexts only consist of hex digits & dashes
+    headers.insert(TRACEPARENT, HeaderValue::from_str(&span.context.to_traceparent()).unwrap());
+}
+
+/// Sends the queued spans to the collector every [`EXPORT_INTERVAL`].
+pub async fn export_spans() {
+    if !is_enabled() {
+        return;
+    }
+    let mut interval = tokio::time::interval(*EXPORT_INTERVAL);
+    loop {
+        interval.tick().await;
+        flush().await;
+    }
+}
+
+/// Sends all queued spans to the collector right away.
+pub async fn flush() {
+    let endpoint = match &*OTLP_TRACES_ENDPOINT {
+        Some(endpoint) => endpoint,
+        None => return,
+    };
+    let spans = std::mem::take(&mut *QUEUE.lock().unwrap());
+    let dropped = DROPPED_SPANS.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        eprintln!("<!> Dropped {} spans, the export queue was full", dropped);
+    }
+    if spans.is_empty() {
+        return;
+    }
+
+    let count = spans.len();
+    let body = json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [attribute("service.name", &AttributeValue::String(OTEL_SERVICE_NAME.clone()))] },
+            "scopeSpans": [{
+                "scope": { "name": "cfproxy", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    });
+    let mut req = Request::new(Body::from(body.to_string()));
+    *req.method_mut() = Method::POST;
+    *req.uri_mut() = endpoint.clone();
+    req.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    for (name, value) in OTLP_HEADERS.iter() {
+        req.headers_mut().insert(name.clone(), value.clone());
+    }
+    match tokio::time::timeout(Duration::from_secs(10), pool::CLIENT.request(req)).await {
+        Ok(Ok(resp)) if resp.status().is_success() => {}
+        Ok(Ok(resp)) => eprintln!("<!> Could not export {} spans, collector answered {}", count, resp.status().as_str()),
+        Ok(Err(err)) => eprintln!("<!> Could not export {} spans: {}", count, err),
+        Err(_) => eprintln!("<!> Could not export {} spans, collector did not answer in time", count),
+    }
+}
+
+fn attribute(key: &str, value: &AttributeValue) -> Value {
+    let value = match value {
+        AttributeValue::String(value) => json!({ "stringValue": value }),
+        AttributeValue::Int(value) => json!({ "intValue": value.to_string() }),
+        AttributeValue::Bool(value) => json!({ "boolValue": value }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+/// Returns a random id that isn't all zeros, which is invalid.
+fn random_id<const N: usize>() -> [u8; N] {
+    loop {
+        let id: [u8; N] = std::array::from_fn(|_| rand::random());
+        if id.iter().any(|byte| *byte != 0) {
+            return id;
+        }
+    }
+}
+
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
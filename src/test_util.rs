@@ -0,0 +1,149 @@
+//! Deterministic fakes of the rate limiter & cache, for tests of code that embeds the proxy.
+//!
+//! Only available with the `test-util` feature. The fakes don't depend on the wall clock: the rate limiter
+//! runs on a clock that only moves when the test advances it, and cached responses never expire unless the
+//! test expires them. Both are cheap to clone, clones share their state - keep a clone to inspect the fake
+//! after handing it to [`ProxyState`](crate::server::ProxyState).
+//!
+//! ```
+//! use std::time::Duration;
+//! use cfproxy::server::ProxyState;
+//! use cfproxy::test_util::{FakeCache, FakeRateLimiter};
+//!
+//! let limiter = FakeRateLimiter::per_hour(60);
+//! let cache = FakeCache::new();
+//! let state = ProxyState::new().with_rate_limiter(limiter.clone()).with_cache(cache.clone());
+//! // ... start a proxy with the state, send requests ...
+//! limiter.advance(Duration::from_secs(60));
+//! ```
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use governor::clock::{Clock, FakeRelativeClock};
+use governor::middleware::NoOpMiddleware;
+use governor::nanos::Nanos;
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::{Quota, RateLimiter};
+use crate::cache::{Cache, CacheStats, CachedResponse};
+use crate::limiter::RateLimit;
+
+/// A per-IP rate limiter on a fake clock, see [`FakeRateLimiter::advance`].
+#[derive(Clone)]
+pub struct FakeRateLimiter {
+    clock: FakeRelativeClock,
+    limiter: Arc<RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, FakeRelativeClock, NoOpMiddleware<Nanos>>>,
+    checks: Arc<AtomicU64>,
+}
+
+impl FakeRateLimiter {
+    /// Creates a limiter allowing requests at the rate & burst of the quota.
+    pub fn new(quota: Quota) -> Self {
+        let clock = FakeRelativeClock::default();
+        let limiter = Arc::new(RateLimiter::dashmap_with_clock(quota, &clock));
+        FakeRateLimiter { clock, limiter, checks: Arc::new(AtomicU64::new(0)) }
+    }
+
+    /// Creates a limiter allowing `limit` requests per hour, like `REQ_LIMIT_PER_HOUR`.
+    pub fn per_hour(limit: u32) -> Self {
+        Self::new(Quota::per_hour(NonZeroU32::new(limit).expect("Expected limit to not be null")))
+    }
+
+    /// Moves the limiter's clock forward, replenishing budget.
+    pub fn advance(&self, by: Duration) {
+        self.clock.advance(by);
+    }
+
+    /// Returns how many requests were checked against the limiter, allowed or not.
+    pub fn checks(&self) -> u64 {
+        self.checks.load(Ordering::Relaxed)
+    }
+}
+
+impl RateLimit for FakeRateLimiter {
+    fn check_key(&self, key: &IpAddr) -> Result<(), Duration> {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        self.limiter.check_key(key).map_err(|not_until| not_until.wait_time_from(self.clock.now()))
+    }
+
+    fn tracked_keys(&self) -> usize {
+        self.limiter.len()
+    }
+}
+
+#[derive(Debug, Default)]
+struct FakeCacheState {
+    /// Cached responses, and whether they are still fresh.
+    entries: HashMap<String, (CachedResponse, bool)>,
+    hits: u64,
+    misses: u64,
+}
+
+/// A response cache without eviction, whose responses stay fresh until [`FakeCache::expire`] is called.
+#[derive(Debug, Clone, Default)]
+pub struct FakeCache {
+    state: Arc<Mutex<FakeCacheState>>,
+}
+
+impl FakeCache {
+    /// Creates an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks the response cached under the key as expired, so only [`Cache::get_stale`] returns it.
+    pub fn expire(&self, key: &str) {
+        if let Some((_, fresh)) = self.state.lock().unwrap().entries.get_mut(key) {
+            *fresh = false;
+        }
+    }
+
+    /// Returns the keys responses are cached under, sorted.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.state.lock().unwrap().entries.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    fn lookup(&self, key: &str, stale: bool) -> Option<CachedResponse> {
+        let mut state = self.state.lock().unwrap();
+        let cached = state.entries.get(key)
+            .filter(|(_, fresh)| *fresh || stale)
+            .map(|(response, _)| response.clone());
+        match cached {
+            Some(_) => state.hits += 1,
+            None => state.misses += 1,
+        }
+        cached
+    }
+}
+
+impl Cache for FakeCache {
+    fn is_enabled(&self) -> bool {
+        true
+    }
+
+    fn get(&self, key: &str) -> Option<CachedResponse> {
+        self.lookup(key, false)
+    }
+
+    fn get_stale(&self, key: &str) -> Option<CachedResponse> {
+        self.lookup(key, true)
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.state.lock().unwrap().entries.get(key).map(|(_, fresh)| *fresh).unwrap_or(false)
+    }
+
+    fn insert(&self, key: String, response: CachedResponse) {
+        self.state.lock().unwrap().entries.insert(key, (response, true));
+    }
+
+    fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats { entries: state.entries.len(), hits: state.hits, misses: state.misses, ..CacheStats::default() }
+    }
+}
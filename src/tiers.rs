@@ -0,0 +1,163 @@
+//! Named rate limit tiers that client tokens are mapped to.
+//!
+//! Tiers are defined in the `RATE_LIMIT_TIERS` env variable as a comma separated list of `<name>: <rate>`,
+//! where a rate is either `unlimited` or `<requests>/<s|min|h>`:
+//!
+//! ```text
+//! RATE_LIMIT_TIERS="anonymous: 2/s, partner: 20/s, internal: unlimited"
+//! ```
+//!
+//! Tokens (see [`crate::tokens`]) are mapped to tiers in the `TOKEN_TIERS` env variable, as a comma separated
+//! list of `<token>: <tier>`. A token ending in `*` matches every token starting with it:
+//!
+//! ```text
+//! TOKEN_TIERS="partner_*: partner, 3f9b0c7e2a4d41d6a1c5b8e0f2d7a934: internal"
+//! ```
+//!
+//! A token mapped to a tier is limited by the tier's rate instead of the quota in the token store. Every token
+//! gets its own budget of the tier's rate. If a tier named `anonymous` is defined, it applies to clients
+//! without a token, per IP.
+
+use std::collections::HashMap;
+use std::env;
+use std::num::NonZeroU32;
+use std::time::Duration;
+use governor::{Quota, RateLimiter};
+use governor::clock::{Clock, DefaultClock};
+use governor::state::keyed::DefaultKeyedStateStore;
+use lazy_static::lazy_static;
+use crate::limiter;
+
+lazy_static! {
+    /// The tiers configured in `RATE_LIMIT_TIERS` and `TOKEN_TIERS`.
+    pub static ref TIERS: Tiers = Tiers::parse(
+        &env::var("RATE_LIMIT_TIERS").unwrap_or_default(),
+        &env::var("TOKEN_TIERS").unwrap_or_default(),
+    ).expect("Expected RATE_LIMIT_TIERS and TOKEN_TIERS env vars to contain valid tiers");
+}
+
+/// Name of the tier that applies to clients without a token, if defined.
+pub const ANONYMOUS_TIER: &str = "anonymous";
+
+/// How many requests a tier allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rate {
+    /// Requests are never limited.
+    Unlimited,
+    /// Requests are limited to the quota.
+    Limited(Quota),
+}
+
+/// Parses a rate like `20/s`, `300/min`, `1000/h` or `unlimited`.
+pub fn parse_rate(rate: &str) -> Result<Rate, String> {
+    let rate = rate.trim();
+    if rate.eq_ignore_ascii_case("unlimited") {
+        return Ok(Rate::Unlimited);
+    }
+    let (requests, unit) = rate.split_once('/').ok_or_else(|| format!("`{}`: expected `<requests>/<unit>` or `unlimited`", rate))?;
+    let requests = requests.trim().parse::<NonZeroU32>()
+        .map_err(|_| format!("`{}`: expected a positive number of requests", rate))?;
+    match unit.trim() {
+        "s" | "sec" => Ok(Rate::Limited(Quota::per_second(requests))),
+        "m" | "min" => Ok(Rate::Limited(Quota::per_minute(requests))),
+        "h" | "hour" => Ok(Rate::Limited(Quota::per_hour(requests))),
+        unit => Err(format!("`{}`: unknown unit `{}`, expected one of s, min, h", rate, unit)),
+    }
+}
+
+/// A named rate limit tier.
+#[derive(Debug)]
+pub struct Tier {
+    name: String,
+    rate: Rate,
+    /// Limits requests per client, `None` if the tier is unlimited.
+    limiter: Option<RateLimiter<String, DefaultKeyedStateStore<String>, DefaultClock>>,
+}
+
+impl Tier {
+    pub(crate) fn new(name: String, rate: Rate) -> Self {
+        let limiter = match rate {
+            Rate::Unlimited => None,
+            Rate::Limited(quota) => Some(RateLimiter::keyed(quota)),
+        };
+        Tier { name, rate, limiter }
+    }
+
+    /// Returns the name of the tier.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the rate of the tier.
+    pub fn rate(&self) -> Rate {
+        self.rate
+    }
+
+    /// Waits until the tier's rate allows another request by the given client, or returns how long the client
+    /// should wait if that's longer than `max_wait` or the client can't queue up (see [`limiter::until_ready_queued`]).
+    pub async fn until_ready(&self, client: &str, max_wait: Option<Duration>) -> Result<(), Duration> {
+        if self.limiter.is_some() {
+            limiter::until_ready_queued(client, || self.check(client), max_wait).await?;
+        }
+        Ok(())
+    }
+
+    /// Takes one request of the client off the tier's budget, or returns how long to wait until it has budget again.
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        match &self.limiter {
+            Some(limiter) => limiter.check_key(&client.to_string()).map_err(|not_until| not_until.wait_time_from(DefaultClock::default().now())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// All configured tiers, and which tokens map to them.
+#[derive(Debug, Default)]
+pub struct Tiers {
+    tiers: HashMap<String, Tier>,
+    /// Token patterns with the name of the tier they map to.
+    tokens: Vec<(String, String)>,
+}
+
+/// Splits a comma separated list of `<key>: <value>` pairs.
+fn parse_pairs(list: &str) -> Result<Vec<(&str, &str)>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| entry.split_once(':')
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .ok_or_else(|| format!("`{}`: expected `<name>: <value>`", entry)))
+        .collect()
+}
+
+impl Tiers {
+    /// Parses tiers and token mappings, in the format of the `RATE_LIMIT_TIERS` and `TOKEN_TIERS` env variables.
+    pub fn parse(tiers: &str, tokens: &str) -> Result<Self, String> {
+        let tiers = parse_pairs(tiers)?.into_iter()
+            .map(|(name, rate)| Ok((name.to_string(), Tier::new(name.to_string(), parse_rate(rate)?))))
+            .collect::<Result<HashMap<_, _>, String>>()?;
+        let tokens = parse_pairs(tokens)?.into_iter()
+            .map(|(token, tier)| match tiers.contains_key(tier) {
+                true => Ok((token.to_string(), tier.to_string())),
+                false => Err(format!("`{}`: unknown tier `{}`", token, tier)),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Tiers { tiers, tokens })
+    }
+
+    /// Returns the tier with the given name.
+    pub fn get(&self, name: &str) -> Option<&Tier> {
+        self.tiers.get(name)
+    }
+
+    /// Returns the tier a token is mapped to.
+    ///
+    /// An exact mapping wins over prefixes, and longer prefixes win over shorter ones.
+    pub fn for_token(&self, token: &str) -> Option<&Tier> {
+        let exact = self.tokens.iter().find(|(pattern, _)| pattern == token);
+        let prefix = || self.tokens.iter()
+            .filter(|(pattern, _)| pattern.strip_suffix('*').map(|prefix| token.starts_with(prefix)).unwrap_or(false))
+            .max_by_key(|(pattern, _)| pattern.len());
+        exact.or_else(prefix).and_then(|(_, tier)| self.tiers.get(tier))
+    }
+}
@@ -0,0 +1,328 @@
+//! Terminating TLS for clients.
+//!
+//! If `TLS_CERT_FILE` and `TLS_KEY_FILE` are set, the proxy serves HTTPS instead of plain HTTP. Both files are
+//! PEM: the certificate file holds the certificate followed by its intermediates, the key file its private key.
+//! They are watched for changes, so renewed certificates are picked up without restarting the proxy -
+//! connections that are already open keep their certificate.
+//!
+//! Instead of certificate files, the proxy can obtain certificates itself, see [`acme`](crate::acme).
+//!
+//! With `TLS_CLIENT_CA_FILE`, clients have to present a certificate signed by one of the CAs in that file.
+//! Clients are then rate limited by the identity of their certificate instead of their IP (see
+//! [`client_identity`]).
+//!
+//! Clients can speak HTTP/2 or HTTP/1.1, whichever they prefer - it is negotiated with ALPN.
+//!
+//! TLS is terminated with native-tls (OpenSSL), not rustls as was asked for - see `BACKLOG.md`.
+
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, SystemTime};
+use lazy_static::lazy_static;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::ssl::{select_next_proto, AlpnError, ErrorCode, Ssl, SslAcceptor, SslMethod, SslStream, SslVerifyMode};
+use openssl::x509::{X509Ref, X509};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use crate::acme;
+
+lazy_static! {
+    /// The certificate read from the files in the `TLS_CERT_FILE` & `TLS_KEY_FILE` env variables, or the one
+    /// obtained with [ACME](crate::acme). `None` if neither is configured, in which case plain HTTP is served.
+    pub static ref TLS_CERTIFICATE: Option<Arc<ReloadingCertificate>> = {
+        let files = match (env::var("TLS_CERT_FILE").ok().filter(|path| !path.is_empty()), env::var("TLS_KEY_FILE").ok().filter(|path| !path.is_empty())) {
+            _ if acme::ACME_DOMAINS.is_some() => Some((acme::cert_path(&acme::ACME_DIR), acme::key_path(&acme::ACME_DIR))),
+            (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+            (None, None) => None,
+            _ => panic!("Expected both TLS_CERT_FILE & TLS_KEY_FILE env vars to be set, or neither"),
+        };
+        let client_ca = env::var("TLS_CLIENT_CA_FILE").ok().filter(|path| !path.is_empty()).map(PathBuf::from);
+        if files.is_none() && client_ca.is_some() {
+            panic!("Expected TLS_CERT_FILE & TLS_KEY_FILE env vars to be set along with TLS_CLIENT_CA_FILE");
+        }
+        files.map(|(cert, key)| Arc::new(ReloadingCertificate::load(cert, key, client_ca)
+            .unwrap_or_else(|e| panic!("Expected TLS_CERT_FILE, TLS_KEY_FILE & TLS_CLIENT_CA_FILE to point to PEM certificates & a key: {}", e))))
+    };
+}
+
+/// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Protocols offered to clients with ALPN, most preferred first.
+pub(crate) const ALPN_PROTOCOLS: &[u8] = b"\x02h2\x08http/1.1";
+
+fn invalid_data(err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// Builds an acceptor from a PEM certificate chain and private key. With `client_ca`, clients need to present
+/// a certificate signed by one of the PEM certificates in it.
+pub fn acceptor_from_pem(cert: &[u8], key: &[u8], client_ca: Option<&[u8]>) -> io::Result<SslAcceptor> {
+    let mut chain = X509::stack_from_pem(cert).map_err(invalid_data)?.into_iter();
+    let cert = chain.next().ok_or_else(|| invalid_data("no certificate found"))?;
+    let key = PKey::private_key_from_pem(key).map_err(invalid_data)?;
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).map_err(invalid_data)?;
+    builder.set_certificate(&cert).map_err(invalid_data)?;
+    for intermediate in chain {
+        builder.add_extra_chain_cert(intermediate).map_err(invalid_data)?;
+    }
+    builder.set_private_key(&key).map_err(invalid_data)?;
+    builder.check_private_key().map_err(invalid_data)?;
+    builder.set_alpn_select_callback(|_, client| select_next_proto(ALPN_PROTOCOLS, client).ok_or(AlpnError::NOACK));
+    if let Some(client_ca) = client_ca {
+        let cas = X509::stack_from_pem(client_ca).map_err(invalid_data)?;
+        if cas.is_empty() {
+            return Err(invalid_data("no client CA certificate found"));
+        }
+        for ca in cas {
+            builder.add_client_ca(&ca).map_err(invalid_data)?;
+            builder.cert_store_mut().add_cert(ca).map_err(invalid_data)?;
+        }
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    Ok(builder.build())
+}
+
+/// Returns the identity of a client certificate: its common name, or its first DNS name or email address if
+/// it has no common name.
+pub fn client_identity(cert: &X509Ref) -> Option<String> {
+    let common_name = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|name| name.to_string());
+    common_name.or_else(|| {
+        cert.subject_alt_names()?.iter()
+            .find_map(|name| name.dnsname().or_else(|| name.email()).map(String::from))
+    })
+}
+
+/// A certificate backed by a certificate & key file, and optionally a file of client CAs.
+pub struct ReloadingCertificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    client_ca_path: Option<PathBuf>,
+    acceptor: RwLock<Arc<SslAcceptor>>,
+    /// Modification times of the files when they were last read.
+    modified: Mutex<Option<Vec<SystemTime>>>,
+}
+
+impl ReloadingCertificate {
+    /// Reads the certificate from the given files.
+    pub fn load(cert_path: PathBuf, key_path: PathBuf, client_ca_path: Option<PathBuf>) -> io::Result<Self> {
+        let certificate = ReloadingCertificate {
+            acceptor: RwLock::new(Arc::new(Self::read(&cert_path, &key_path, client_ca_path.as_deref())?)),
+            cert_path,
+            key_path,
+            client_ca_path,
+            modified: Mutex::new(None),
+        };
+        *certificate.modified.lock().unwrap() = certificate.modified_times();
+        Ok(certificate)
+    }
+
+    fn read(cert_path: &Path, key_path: &Path, client_ca_path: Option<&Path>) -> io::Result<SslAcceptor> {
+        let client_ca = client_ca_path.map(fs::read).transpose()?;
+        acceptor_from_pem(&fs::read(cert_path)?, &fs::read(key_path)?, client_ca.as_deref())
+    }
+
+    /// Returns the file the certificate is read from.
+    pub fn path(&self) -> &Path {
+        &self.cert_path
+    }
+
+    /// Returns whether clients have to present a certificate.
+    pub fn requires_client_certificate(&self) -> bool {
+        self.client_ca_path.is_some()
+    }
+
+    /// Returns the acceptor for new connections.
+    pub fn acceptor(&self) -> Arc<SslAcceptor> {
+        Arc::clone(&self.acceptor.read().unwrap())
+    }
+
+    fn modified_times(&self) -> Option<Vec<SystemTime>> {
+        [Some(&self.cert_path), Some(&self.key_path), self.client_ca_path.as_ref()].iter()
+            .flatten()
+            .map(|path| fs::metadata(path).ok()?.modified().ok())
+            .collect()
+    }
+
+    /// Re-reads the files if one of them changed since they were last read. Returns whether they were re-read.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let modified = self.modified_times();
+        if modified.is_some() && modified == *self.modified.lock().unwrap() {
+            return Ok(false);
+        }
+        let acceptor = Self::read(&self.cert_path, &self.key_path, self.client_ca_path.as_deref())?;
+        *self.acceptor.write().unwrap() = Arc::new(acceptor);
+        *self.modified.lock().unwrap() = modified;
+        Ok(true)
+    }
+}
+
+/// Keeps reloading [`TLS_CERTIFICATE`] whenever its files change, forever.
+///
+/// Returns immediately if TLS is not enabled. If the files can't be read, the previously loaded certificate
+/// stays in effect.
+pub async fn watch_certificate() {
+    let certificate = match TLS_CERTIFICATE.as_ref() {
+        Some(certificate) => certificate,
+        None => return,
+    };
+
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        match certificate.reload_if_changed() {
+            Ok(true) => println!("<-> Reloaded TLS certificate from {}", certificate.path().display()),
+            Ok(false) => {}
+            Err(e) => eprintln!("<!> Could not reload TLS certificate from {}: {}", certificate.path().display(), e),
+        }
+    }
+}
+
+/// Presents an async stream as the blocking stream openssl expects, reporting pending io as `WouldBlock`.
+#[derive(Debug)]
+struct BlockingAdapter<S> {
+    stream: S,
+    /// Waker of the task polling the stream, while openssl reads or writes.
+    waker: Option<Waker>,
+}
+
+impl<S: Unpin> BlockingAdapter<S> {
+    fn poll_io<R>(&mut self, poll: impl FnOnce(Pin<&mut S>, &mut Context<'_>) -> Poll<io::Result<R>>) -> io::Result<R> {
+        let waker = self.waker.clone().expect("Expected the stream to be polled from a task");
+        match poll(Pin::new(&mut self.stream), &mut Context::from_waker(&waker)) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> Read for BlockingAdapter<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        self.poll_io(|stream, cx| stream.poll_read(cx, &mut buf))?;
+        Ok(buf.filled().len())
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Write for BlockingAdapter<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.poll_io(|stream, cx| stream.poll_write(cx, buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.poll_io(|stream, cx| stream.poll_flush(cx))
+    }
+}
+
+/// A TLS connection of a client, or to an upstream.
+#[derive(Debug)]
+pub struct TlsStream<S>(SslStream<BlockingAdapter<S>>);
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TlsStream<S> {
+    /// Does the server side of the handshake of a connection.
+    pub async fn accept(acceptor: &SslAcceptor, stream: S) -> io::Result<Self> {
+        let ssl = Ssl::new(acceptor.context()).map_err(invalid_data)?;
+        let stream = SslStream::new(ssl, BlockingAdapter { stream, waker: None }).map_err(invalid_data)?;
+        Handshake(Some(TlsStream(stream)), SslStream::accept).await
+    }
+
+    /// Does the client side of the handshake of a connection, with `ssl` configured for the server.
+    pub async fn connect(ssl: Ssl, stream: S) -> io::Result<Self> {
+        let stream = SslStream::new(ssl, BlockingAdapter { stream, waker: None }).map_err(invalid_data)?;
+        Handshake(Some(TlsStream(stream)), SslStream::connect).await
+    }
+
+    /// Returns the underlying connection.
+    pub fn get_ref(&self) -> &S {
+        &self.0.get_ref().stream
+    }
+
+    /// Returns the protocol negotiated with ALPN, if any.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.0.ssl().selected_alpn_protocol()
+    }
+
+    /// Returns the identity of the client's certificate, if it presented one.
+    pub fn client_identity(&self) -> Option<String> {
+        client_identity(&*self.0.ssl().peer_certificate()?)
+    }
+
+    /// Runs an openssl operation with the task's waker, so pending io wakes the task.
+    fn with_waker<R>(&mut self, cx: &mut Context<'_>, operation: impl FnOnce(&mut SslStream<BlockingAdapter<S>>) -> Result<R, openssl::ssl::Error>) -> Poll<io::Result<R>> {
+        self.0.get_mut().waker = Some(cx.waker().clone());
+        let result = operation(&mut self.0);
+        self.0.get_mut().waker = None;
+        match result {
+            Ok(result) => Poll::Ready(Ok(result)),
+            Err(e) if e.code() == ErrorCode::WANT_READ || e.code() == ErrorCode::WANT_WRITE => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e.into_io_error().unwrap_or_else(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e)))),
+        }
+    }
+}
+
+/// The side of a handshake, [`SslStream::accept`] or [`SslStream::connect`].
+type HandshakeStep<S> = fn(&mut SslStream<BlockingAdapter<S>>) -> Result<(), openssl::ssl::Error>;
+
+/// Resolves once the handshake of a connection is done.
+struct Handshake<S>(Option<TlsStream<S>>, HandshakeStep<S>);
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for Handshake<S> {
+    type Output = io::Result<TlsStream<S>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut stream = self.0.take().expect("Expected the handshake to not be polled after it's done");
+        let step = self.1;
+        match stream.with_waker(cx, step) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(stream)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => {
+                self.0 = Some(stream);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let read = self.with_waker(cx, |stream| match stream.ssl_read(buf.initialize_unfilled()) {
+            // The other side closed the connection
+            Err(e) if e.code() == ErrorCode::ZERO_RETURN || (e.code() == ErrorCode::SYSCALL && e.io_error().is_none()) => Ok(0),
+            read => read,
+        });
+        match read {
+            Poll::Ready(Ok(read)) => {
+                buf.advance(read);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.with_waker(cx, |stream| stream.ssl_write(buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Send the close notification, then close the connection whether or not the client answers it
+        if self.with_waker(cx, |stream| stream.shutdown()).is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut self.0.get_mut().stream).poll_shutdown(cx)
+    }
+}
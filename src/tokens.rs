@@ -0,0 +1,157 @@
+//! Client tokens that can be handed out to users of the proxy.
+//!
+//! Rate limiting by IP breaks down for users behind CGNAT, where lots of clients share an address. Instead,
+//! clients can present a token in the `x-proxy-token` header (configurable with `TOKEN_HEADER`). Tokens are
+//! validated against a token store file (set with `TOKEN_STORE_FILE`), and each token gets its own rate limit.
+//!
+//! The token store contains one token per line, followed by its quota in requests per hour:
+//!
+//! ```text
+//! # token                          requests per hour
+//! 3f9b0c7e2a4d41d6a1c5b8e0f2d7a934 3600
+//! ```
+//!
+//! New tokens are issued with `cfproxy issue-token <requests per hour>`, which appends them to the store. The
+//! store is read again on `SIGHUP`, see [`reload_tokens`].
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use governor::{Quota, RateLimiter};
+use governor::clock::DefaultClock;
+use governor::state::{InMemoryState, NotKeyed};
+use hyper::header::HeaderName;
+use lazy_static::lazy_static;
+use rand::Rng;
+
+lazy_static! {
+    /// The header clients present their token in. Read from the `TOKEN_HEADER` env variable.
+    pub static ref TOKEN_HEADER: HeaderName = HeaderName::from_bytes(
+        env::var("TOKEN_HEADER").unwrap_or(String::from("x-proxy-token")).as_bytes()
+    ).expect("Expected TOKEN_HEADER env var to contain a valid header name");
+
+    /// Path of the token store. Read from the `TOKEN_STORE_FILE` env variable.
+    pub static ref TOKEN_STORE_FILE: Option<String> = env::var("TOKEN_STORE_FILE").ok()
+        .filter(|path| !path.is_empty());
+
+    /// The tokens accepted by this proxy, loaded from the token store on first use & by [`reload_tokens`].
+    /// Empty if `TOKEN_STORE_FILE` is not set, or the store couldn't be read on first use.
+    pub static ref TOKEN_STORE: TokenStore = load_configured().unwrap_or_else(|e| {
+        eprintln!("<!> Could not load the token store {} - no tokens are accepted", e);
+        TokenStore::default()
+    });
+}
+
+/// Reads the token store at `TOKEN_STORE_FILE`, or returns an empty one if it's not set.
+fn load_configured() -> Result<TokenStore, String> {
+    match TOKEN_STORE_FILE.as_ref() {
+        Some(path) => TokenStore::load(Path::new(path)).map_err(|e| format!("{}: {}", path, e)),
+        None => Ok(TokenStore::default()),
+    }
+}
+
+/// Reads the token store at `TOKEN_STORE_FILE` again, and switches [`TOKEN_STORE`] to its tokens.
+///
+/// Tokens whose quota didn't change keep their rate limit state. Returns how many tokens are accepted now, or
+/// why the store couldn't be read, in which case the old tokens are kept.
+pub fn reload_tokens() -> Result<usize, String> {
+    let store = load_configured()?;
+    Ok(TOKEN_STORE.replace_tokens(store))
+}
+
+/// A token that was issued to a client, along with its rate limit.
+#[derive(Debug)]
+pub struct Token {
+    /// How many requests per hour this token may make.
+    pub requests_per_hour: NonZeroU32,
+    /// Rate limiter for requests made with this token.
+    pub limiter: RateLimiter<NotKeyed, InMemoryState, DefaultClock>,
+}
+
+impl Token {
+    fn new(requests_per_hour: NonZeroU32) -> Self {
+        Token {
+            requests_per_hour,
+            limiter: RateLimiter::direct(Quota::per_hour(requests_per_hour)),
+        }
+    }
+}
+
+/// The set of valid client tokens.
+#[derive(Debug, Default)]
+pub struct TokenStore {
+    tokens: RwLock<HashMap<String, Arc<Token>>>,
+}
+
+impl TokenStore {
+    /// Parses a token store from its textual representation.
+    ///
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut tokens = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (token, quota) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(token), Some(quota), None) => (token, quota),
+                _ => return Err(format!("line {}: expected `<token> <requests per hour>`", i + 1)),
+            };
+            let quota = quota.parse::<NonZeroU32>()
+                .map_err(|_| format!("line {}: expected a positive number of requests per hour", i + 1))?;
+            tokens.insert(token.to_string(), Arc::new(Token::new(quota)));
+        }
+        Ok(TokenStore { tokens: RwLock::new(tokens) })
+    }
+
+    /// Reads the token store from the given file.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Looks up a token presented by a client. Returns `None` if the token is not valid.
+    pub fn get(&self, token: &str) -> Option<Arc<Token>> {
+        self.tokens.read().unwrap().get(token).cloned()
+    }
+
+    /// Returns how many tokens are configured.
+    pub fn len(&self) -> usize {
+        self.tokens.read().unwrap().len()
+    }
+
+    /// Returns whether no tokens are configured.
+    pub fn is_empty(&self) -> bool {
+        self.tokens.read().unwrap().is_empty()
+    }
+
+    /// Switches to the tokens of `other`. Tokens whose quota didn't change keep their rate limit state.
+    ///
+    /// Returns how many tokens there are now.
+    pub fn replace_tokens(&self, other: TokenStore) -> usize {
+        let mut tokens = self.tokens.write().unwrap();
+        let mut replaced = other.tokens.into_inner().unwrap();
+        for (token, limits) in replaced.iter_mut() {
+            if let Some(old) = tokens.get(token).filter(|old| old.requests_per_hour == limits.requests_per_hour) {
+                *limits = old.clone();
+            }
+        }
+        *tokens = replaced;
+        tokens.len()
+    }
+}
+
+/// Generates a new token with the given quota and appends it to the token store at `path`.
+///
+/// Returns the new token. Running proxies pick it up after a restart or `SIGHUP`.
+pub fn issue_token(path: &Path, requests_per_hour: NonZeroU32) -> io::Result<String> {
+    let token: String = (0..16).map(|_| format!("{:02x}", rand::thread_rng().gen::<u8>())).collect();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{} {}", token, requests_per_hour)?;
+    Ok(token)
+}
@@ -0,0 +1,104 @@
+//! Routing of path prefixes to upstreams other than the CF api.
+//!
+//! Everything is forwarded to the CF api, unless its path starts with the prefix of one of the
+//! `UPSTREAM_ROUTES`: those requests are forwarded to the route's upstream, without the prefix. Routes are
+//! comma separated `<prefix>=<base url>`, optionally followed by `|<header>: <value>` to authenticate with
+//! the upstream - e.g. `/example=https://api.example.com|x-api-key: secret`. The longest matching prefix wins.
+//!
+//! `MODRINTH_PROXY` adds a route from `/modrinth` to Modrinth's public api (`MODRINTH_API_URL`). Routed
+//! requests share the rate limits & cache with requests for the CF api, but the CF api's circuit breaker &
+//! key pool don't apply. Nothing is routed with `STRICT_PASSTHROUGH`.
+
+use std::env;
+use hyper::header::{HeaderName, HeaderValue};
+use lazy_static::lazy_static;
+use crate::config::ProxyConfig;
+use crate::Upstream;
+
+lazy_static! {
+    /// Whether requests under `/modrinth` are forwarded to Modrinth's api. Read from the `MODRINTH_PROXY` env variable.
+    pub static ref MODRINTH_PROXY: bool = env::var("MODRINTH_PROXY").unwrap_or(String::from("false"))
+        .parse::<bool>().expect("Expected MODRINTH_PROXY env var to be either true or false");
+
+    /// The routes of this process, read from the `UPSTREAM_ROUTES` env variable, along with Modrinth's.
+    pub static ref UPSTREAM_ROUTES: Vec<UpstreamRoute> = {
+        let mut routes = parse_routes(&env::var("UPSTREAM_ROUTES").unwrap_or_default())
+            .unwrap_or_else(|err| panic!("Expected UPSTREAM_ROUTES env var to contain comma separated `<prefix>=<url>` routes: {}", err));
+        if *MODRINTH_PROXY {
+            let url = env::var("MODRINTH_API_URL").unwrap_or(String::from("https://api.modrinth.com"));
+            let upstream = Upstream::try_parse(&url).unwrap_or_else(|err| panic!("Expected MODRINTH_API_URL env var to contain a url: {}", err));
+            routes.push(UpstreamRoute { prefix: String::from(MODRINTH_PREFIX), upstream, auth: None });
+        }
+        routes
+    };
+}
+
+/// Path prefix of requests that are forwarded to Modrinth's api, if [`MODRINTH_PROXY`] is enabled.
+pub const MODRINTH_PREFIX: &str = "/modrinth";
+
+/// A path prefix forwarded to another upstream.
+#[derive(Debug)]
+pub struct UpstreamRoute {
+    /// The prefix, without a trailing `/`.
+    pub prefix: String,
+    pub(crate) upstream: Upstream,
+    /// Header authenticating requests with the upstream.
+    pub auth: Option<(HeaderName, HeaderValue)>,
+}
+
+impl UpstreamRoute {
+    /// Returns the host & port of the upstream.
+    pub fn authority(&self) -> &str {
+        self.upstream.authority.as_str()
+    }
+
+    /// Returns the path & query to request from the upstream, or `None` if the path isn't under the prefix.
+    pub fn strip_prefix<'a>(&self, path_and_query: &'a str) -> Option<&'a str> {
+        let rest = path_and_query.strip_prefix(&self.prefix)?;
+        match rest.starts_with('/') {
+            true => Some(rest),
+            false => None,
+        }
+    }
+}
+
+/// Parses routes given as comma separated `<prefix>=<url>`, each optionally followed by `|<header>: <value>`.
+pub fn parse_routes(routes: &str) -> Result<Vec<UpstreamRoute>, String> {
+    routes.split(',')
+        .map(str::trim)
+        .filter(|route| !route.is_empty())
+        .map(|route| {
+            let (route, auth) = match route.split_once('|') {
+                Some((route, auth)) => (route, Some(auth)),
+                None => (route, None),
+            };
+            let (prefix, url) = route.split_once('=').ok_or_else(|| format!("`{}`: expected `<prefix>=<url>`", route))?;
+            let prefix = prefix.trim().trim_end_matches('/');
+            if !prefix.starts_with('/') || prefix.len() < 2 {
+                return Err(format!("`{}`: the prefix has to start with `/`", route));
+            }
+            let upstream = Upstream::try_parse(url.trim()).map_err(|err| format!("`{}`: {}", route, err))?;
+            let auth = match auth {
+                Some(auth) => {
+                    let (name, value) = auth.split_once(':').ok_or_else(|| format!("`{}`: expected `<header>: <value>`", auth))?;
+                    let name = name.trim().parse::<HeaderName>().map_err(|e| format!("`{}`: {}", auth, e))?;
+                    let value = value.trim().parse::<HeaderValue>().map_err(|e| format!("`{}`: {}", auth, e))?;
+                    Some((name, value))
+                }
+                None => None,
+            };
+            Ok(UpstreamRoute { prefix: prefix.to_string(), upstream, auth })
+        })
+        .collect()
+}
+
+/// Returns the route of the path, `None` if it's forwarded to the CF api - as every path is by a proxy in
+/// strict pass-through mode (see [`ProxyConfig::strict_passthrough`]).
+pub fn route_for(path: &str, config: &ProxyConfig) -> Option<&'static UpstreamRoute> {
+    if config.strict_passthrough() {
+        return None;
+    }
+    UPSTREAM_ROUTES.iter()
+        .filter(|route| route.strip_prefix(path).is_some())
+        .max_by_key(|route| route.prefix.len())
+}
@@ -0,0 +1,60 @@
+// Lowers the file descriptor limit of the whole process, so it's the only test in its binary
+#[cfg(all(test, unix))]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::time::Duration;
+    use cfproxy::listener::ListenAddr;
+    use cfproxy::server::{ProxyHandle, ProxyState};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    fn set_fd_limit(limit: libc::rlim_t) -> libc::rlim_t {
+        let mut rlimit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        // SAFETY: rlimit is a valid, exclusively borrowed struct for both calls
+        unsafe {
+            assert_eq!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit), 0);
+            let previous = rlimit.rlim_cur;
+            rlimit.rlim_cur = limit;
+            assert_eq!(libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit), 0);
+            previous
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_serving_after_accept_errors() {
+        env::set_var("CF_API_KEY", "key");
+        let dir = env::temp_dir().join(format!("cfproxy-accept-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cfproxy.sock");
+        let handle = ProxyHandle::start_at(&ListenAddr::Unix(path.clone()), ProxyState::new()).expect("Expected the proxy to start");
+
+        // Use up all file descriptors but the one the client connects with, so the proxy can't accept it
+        let open = fs::read_dir("/proc/self/fd").map(|fds| fds.count()).unwrap_or(64);
+        let previous = set_fd_limit((open + 64) as libc::rlim_t);
+        let mut files = Vec::new();
+        while let Ok(file) = File::open("/dev/null") {
+            files.push(file);
+        }
+        files.pop();
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(files);
+        set_fd_limit(previous);
+
+        stream.write_all(b"GET /_routes HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response)).await
+            .expect("Expected the connection to be accepted after the error").unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        // ...and so are later connections
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream.write_all(b"GET /_routes HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        handle.shutdown().await.unwrap();
+    }
+}
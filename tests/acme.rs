@@ -0,0 +1,141 @@
+mod common;
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use cfproxy::acme::{base64url, challenge_response, expires_within, obtain_certificate};
+    use hyper::{Body, Request, Response, StatusCode};
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509NameBuilder, X509};
+    use serde_json::{json, Value};
+    use crate::common::start_upstream_async;
+
+    fn certificate(days: u32) -> Vec<u8> {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "example.com").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(days).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        cert.build().to_pem().unwrap()
+    }
+
+    #[test]
+    fn encodes_base64url() {
+        assert_eq!(base64url(b""), "");
+        assert_eq!(base64url(b"f"), "Zg");
+        assert_eq!(base64url(b"fo"), "Zm8");
+        assert_eq!(base64url(b"foo"), "Zm9v");
+        assert_eq!(base64url(&[0xfb, 0xff, 0xfe]), "-__-");
+    }
+
+    #[test]
+    fn renews_expiring_certificates() {
+        assert!(expires_within(&certificate(10), 30));
+        assert!(!expires_within(&certificate(90), 30));
+        assert!(expires_within(b"", 30));
+    }
+
+    #[test]
+    fn redirects_to_https() {
+        let req = Request::get("/v1/games?index=1").header("host", "cf.example.com:80").body(Body::empty()).unwrap();
+        let response = challenge_response(&req);
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()["location"], "https://cf.example.com/v1/games?index=1");
+
+        let req = Request::get("/.well-known/acme-challenge/unknown").body(Body::empty()).unwrap();
+        assert_eq!(challenge_response(&req).status(), StatusCode::NOT_FOUND);
+    }
+
+    #[derive(Default)]
+    struct Ca {
+        base: String,
+        challenged: bool,
+        finalized: bool,
+        key_authorization: Option<String>,
+        certificate: Vec<u8>,
+    }
+
+    async fn answer(ca: Arc<Mutex<Ca>>, req: Request<Body>) -> ((), Response<Body>) {
+        let path = req.uri().path().to_string();
+        let method = req.method().clone();
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(req.into_body()).await.unwrap()).unwrap_or(Value::Null);
+        if method == "POST" {
+            assert!(body["protected"].is_string() && body["payload"].is_string() && body["signature"].is_string());
+        }
+        // The proxy answers the challenge while it's pending
+        let key_authorization = match path.as_str() {
+            "/challenges/1" => {
+                let req = Request::get("/.well-known/acme-challenge/token").body(Body::empty()).unwrap();
+                Some(hyper::body::to_bytes(challenge_response(&req).into_body()).await.unwrap())
+            }
+            _ => None,
+        };
+        let mut ca = ca.lock().unwrap();
+        let base = ca.base.clone();
+        let response = Response::builder().header("replay-nonce", "nonce");
+        let json = |value: Value| Body::from(value.to_string());
+        ((), match path.as_str() {
+            "/directory" => response.body(json(json!({
+                "newNonce": format!("{}/nonce", base),
+                "newAccount": format!("{}/account", base),
+                "newOrder": format!("{}/order", base),
+            }))),
+            "/nonce" => response.body(Body::empty()),
+            "/account" => response.status(201).header("location", format!("{}/accounts/1", base)).body(json(json!({ "status": "valid" }))),
+            "/order" => response.status(201).header("location", format!("{}/orders/1", base)).body(json(json!({
+                "status": "pending",
+                "authorizations": [format!("{}/authz/1", base)],
+                "finalize": format!("{}/finalize/1", base),
+            }))),
+            "/authz/1" => response.body(json(json!({
+                "status": if ca.challenged { "valid" } else { "pending" },
+                "identifier": { "type": "dns", "value": "example.com" },
+                "challenges": [{ "type": "http-01", "url": format!("{}/challenges/1", base), "token": "token" }],
+            }))),
+            "/challenges/1" => {
+                ca.key_authorization = key_authorization.map(|body| String::from_utf8(body.to_vec()).unwrap());
+                ca.challenged = true;
+                response.body(json(json!({ "status": "processing" })))
+            }
+            "/finalize/1" => {
+                ca.finalized = true;
+                response.body(json(json!({ "status": "processing" })))
+            }
+            "/orders/1" => response.body(json(json!({
+                "status": if ca.finalized { "valid" } else { "processing" },
+                "certificate": format!("{}/certificates/1", base),
+            }))),
+            "/certificates/1" => response.body(Body::from(ca.certificate.clone())),
+            _ => response.status(404).body(Body::empty()),
+        }.unwrap())
+    }
+
+    #[tokio::test]
+    async fn obtains_certificates() {
+        let ca = Arc::new(Mutex::new(Ca { certificate: certificate(90), ..Ca::default() }));
+        let service_ca = Arc::clone(&ca);
+        let (server, _) = start_upstream_async(move |req| answer(Arc::clone(&service_ca), req));
+        let base = format!("http://{}", server);
+        ca.lock().unwrap().base = base.clone();
+
+        let dir = env::temp_dir().join(format!("cfproxy-acme-{}", std::process::id()));
+        obtain_certificate(&format!("{}/directory", base), &[String::from("example.com")], &dir).await.unwrap();
+
+        let ca = ca.lock().unwrap();
+        assert!(ca.key_authorization.as_deref().unwrap().starts_with("token."));
+        assert_eq!(fs::read(dir.join("cert.pem")).unwrap(), ca.certificate);
+        assert!(PKey::private_key_from_pem(&fs::read(dir.join("key.pem")).unwrap()).is_ok());
+        assert!(dir.join("account.pem").exists());
+    }
+}
@@ -0,0 +1,101 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::env;
+    use std::net::{IpAddr, Ipv4Addr};
+    use cfproxy::batch::{batch_field, chunk_count, merge_bodies, split_body};
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::{proxy_request_to_cf, ProxyError};
+    use cfproxy::server::{ProxyService, ProxyState};
+    use cfproxy::test_util::FakeRateLimiter;
+    use hyper::body::Bytes;
+    use hyper::service::{make_service_fn, service_fn, Service};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use serde_json::{json, Value};
+    use crate::common::start_upstream;
+
+    #[test]
+    fn splits_oversized_lookups() {
+        assert_eq!(batch_field("/v1/mods"), Some("modIds"));
+        assert_eq!(batch_field("/v1/fingerprints/432"), Some("fingerprints"));
+        assert_eq!(batch_field("/v1/mods/search"), None);
+
+        let body = json!({ "modIds": [1, 2, 3, 4, 5], "filterPcOnly": true }).to_string();
+        let chunks: Vec<Value> = split_body(body.as_bytes(), "modIds", 2).unwrap().iter()
+            .map(|chunk| serde_json::from_slice(chunk).unwrap())
+            .collect();
+        assert_eq!(chunks, vec![
+            json!({ "modIds": [1, 2], "filterPcOnly": true }),
+            json!({ "modIds": [3, 4], "filterPcOnly": true }),
+            json!({ "modIds": [5], "filterPcOnly": true }),
+        ]);
+        assert_eq!(split_body(body.as_bytes(), "modIds", 5), None);
+        assert_eq!(split_body(b"not json", "modIds", 2), None);
+
+        assert_eq!(chunk_count(body.as_bytes(), "modIds", 2), 3);
+        assert_eq!(chunk_count(body.as_bytes(), "modIds", 5), 1);
+        assert_eq!(chunk_count(b"not json", "modIds", 2), 1);
+    }
+
+    #[test]
+    fn merges_fingerprint_matches() {
+        let bodies: Vec<Bytes> = [
+            json!({ "data": { "isCacheBuilt": true, "exactFingerprints": [1], "partialMatchFingerprints": { "a": [1] } } }),
+            json!({ "data": { "isCacheBuilt": false, "exactFingerprints": [2], "partialMatchFingerprints": { "b": [2] } } }),
+        ].iter().map(|body| Bytes::from(body.to_string())).collect();
+        let merged: Value = serde_json::from_slice(&merge_bodies(&bodies).unwrap()).unwrap();
+        assert_eq!(merged, json!({ "data": { "isCacheBuilt": false, "exactFingerprints": [1, 2], "partialMatchFingerprints": { "a": [1], "b": [2] } } }));
+    }
+
+    #[tokio::test]
+    async fn merges_responses_of_split_lookups() {
+        let upstream = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let body: Value = serde_json::from_slice(&hyper::body::to_bytes(req.into_body()).await.unwrap()).unwrap();
+                let ids = body["modIds"].as_array().unwrap();
+                assert!(ids.len() <= 2);
+                let mods: Vec<Value> = ids.iter().map(|id| json!({ "id": id })).collect();
+                Ok::<_, Infallible>(Response::new(Body::from(json!({ "data": mods }).to_string())))
+            }))
+        }));
+        env::set_var("CF_API_URL", format!("http://{}", upstream.local_addr()));
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("BATCH_CHUNK_SIZE", "2");
+        tokio::spawn(upstream);
+
+        let req = Request::post("/v1/mods").body(Body::from(r#"{"modIds":[1,2,3,4,5]}"#)).unwrap();
+        let response = proxy_request_to_cf(req, &IpAddr::V4(Ipv4Addr::LOCALHOST)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        let ids: Vec<&Value> = body["data"].as_array().unwrap().iter().map(|m| &m["id"]).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn answers_failed_lookups_of_chunks_with_502() {
+        let panicked = tokio::spawn(async { panic!("lookup panicked") }).await.expect_err("Expected the lookup to panic");
+        let err = ProxyError::ChunkFailed(panicked);
+        assert!(std::error::Error::source(&err).is_some());
+        assert_eq!(err.into_response().status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn charges_each_lookup() {
+        let (upstream, lookups) = start_upstream(|_| ());
+        env::set_var("BATCH_CHUNK_SIZE", "2");
+        let config = ProxyConfig::from_env().with_api_url(&format!("http://{}", upstream)).unwrap().with_api_keys(["key"]).unwrap();
+        let limiter = FakeRateLimiter::per_hour(5);
+        let mut service = ProxyService::new(ProxyState::new().with_config(config).with_rate_limiter(limiter.clone()), [127, 0, 0, 1].into());
+
+        let lookup = || Request::post("/v1/mods").body(Body::from(r#"{"modIds":[1,2,3,4,5]}"#)).unwrap();
+        assert_eq!(service.call(lookup()).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(limiter.checks(), 3);
+        assert_eq!(lookups.lock().unwrap().len(), 3);
+
+        // Two requests are left, not enough for the three lookups - none of them is sent
+        assert_eq!(service.call(lookup()).await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(lookups.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,41 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use hyper::HeaderMap;
+    use hyper::header::{HeaderValue, AUTHORIZATION};
+    use cfproxy::bearer::{bearer_token, parse_tokens, BearerAllowlist};
+
+    fn headers_with_auth(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn parses_tokens_and_headers() {
+        let tokens = parse_tokens("# comment\nabc\n\n  def  \n");
+        assert_eq!(tokens.len(), 2);
+        assert!(tokens.contains("abc") && tokens.contains("def"));
+
+        assert_eq!(bearer_token(&headers_with_auth("Bearer abc")), Some("abc"));
+        assert_eq!(bearer_token(&headers_with_auth("bearer abc")), Some("abc"));
+        assert_eq!(bearer_token(&headers_with_auth("Basic abc")), None);
+        assert_eq!(bearer_token(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn reloads_changed_file() {
+        let path = env::temp_dir().join(format!("cfproxy-bearer-{}.txt", std::process::id()));
+        fs::write(&path, "abc\n").unwrap();
+        let allowlist = BearerAllowlist::load(path.clone()).expect("Expected allowlist to load");
+        assert!(allowlist.is_authorized(&headers_with_auth("Bearer abc")));
+        assert!(!allowlist.reload_if_changed().unwrap());
+
+        fs::write(&path, "def\n").unwrap();
+        allowlist.reload().unwrap();
+        fs::remove_file(&path).ok();
+        assert!(!allowlist.is_authorized(&headers_with_auth("Bearer abc")));
+        assert!(allowlist.is_authorized(&headers_with_auth("Bearer def")));
+    }
+}
@@ -0,0 +1,42 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use cfproxy::listener::{Binding, ListenAddr};
+    use cfproxy::routes::RouteSet;
+    use cfproxy::server::{ProxyHandle, ProxyState};
+    use hyper::{Client, StatusCode};
+
+    #[test]
+    fn parses_bindings() {
+        let bindings = Binding::parse_list("[::]:3000, local@127.0.0.1:3001,proxy@unix:/run/cfproxy.sock").unwrap();
+        assert_eq!(bindings, vec![
+            Binding { addr: ListenAddr::Tcp("[::]:3000".parse().unwrap()), routes: RouteSet::All },
+            Binding { addr: ListenAddr::Tcp(([127, 0, 0, 1], 3001).into()), routes: RouteSet::Local },
+            Binding { addr: ListenAddr::Unix("/run/cfproxy.sock".into()), routes: RouteSet::Proxy },
+        ]);
+        assert_eq!(bindings[1].to_string(), "local@127.0.0.1:3001");
+        assert!(Binding::parse_list("admin@127.0.0.1:3001").is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_route_sets_per_binding() {
+        env::set_var("CF_API_KEY", "key");
+        let bindings = Binding::parse_list("proxy@127.0.0.1:0,local@127.0.0.1:0").unwrap();
+        let handle = ProxyHandle::start_bindings(&bindings, ProxyState::new()).expect("Expected the proxy to start");
+        let (public, admin) = match handle.listen_addrs() {
+            [ListenAddr::Tcp(public), ListenAddr::Tcp(admin)] => (*public, *admin),
+            addrs => panic!("Expected two TCP addresses, got {:?}", addrs),
+        };
+
+        let client = Client::new();
+        let status = |addr, path| {
+            let request = client.get(format!("http://{}{}", addr, path).parse().unwrap());
+            async move { request.await.unwrap().status() }
+        };
+        assert_eq!(status(admin, "/_routes").await, StatusCode::OK);
+        assert_eq!(status(public, "/_routes").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(admin, "/v1/games").await, StatusCode::NOT_FOUND);
+
+        handle.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,28 @@
+#[cfg(test)]
+mod tests {
+    use cfproxy::body_limit::{read_limited, BodyError};
+    use hyper::{Body, Request};
+
+    #[tokio::test]
+    async fn rejects_oversized_bodies() {
+        let req = Request::post("/v1/fingerprints").body(Body::from(vec![b'1'; 16])).unwrap();
+        let req = read_limited(req, 16).await.expect("Expected a body at the limit to be accepted");
+        assert_eq!(hyper::body::to_bytes(req.into_body()).await.unwrap().len(), 16);
+
+        // Declared too large
+        let req = Request::post("/v1/fingerprints").header("content-length", "17").body(Body::from(vec![b'1'; 17])).unwrap();
+        assert_eq!(read_limited(req, 16).await.unwrap_err(), BodyError::TooLarge);
+
+        // Streamed without a declared length
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..4 {
+                if sender.send_data(vec![b'1'; 8].into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let req = Request::post("/v1/fingerprints").body(body).unwrap();
+        assert_eq!(read_limited(req, 16).await.unwrap_err(), BodyError::TooLarge);
+    }
+}
@@ -0,0 +1,65 @@
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use cfproxy::breaker::{BreakerState, CircuitBreaker};
+
+    #[test]
+    fn trips_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+        let start = Instant::now();
+        breaker.record_at(start, false);
+        breaker.record_at(start, false);
+        breaker.record_at(start, true);
+        assert_eq!(breaker.state(), BreakerState::Closed { failures: 0 });
+
+        for _ in 0..3 {
+            breaker.record_at(start, false);
+        }
+        assert_eq!(breaker.allow_at(start + Duration::from_secs(10)), Err(Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn half_opens_for_a_single_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        let start = Instant::now();
+        breaker.record_at(start, false);
+
+        // One probe goes through, everyone else keeps failing fast
+        let probe = start + Duration::from_secs(30);
+        assert_eq!(breaker.allow_at(probe), Ok(()));
+        assert!(breaker.allow_at(probe).is_err());
+
+        // A failed probe opens the breaker again, a successful one closes it
+        breaker.record_at(probe, false);
+        assert!(breaker.allow_at(probe + Duration::from_secs(29)).is_err());
+        assert_eq!(breaker.allow_at(probe + Duration::from_secs(30)), Ok(()));
+        breaker.record_at(probe + Duration::from_secs(30), true);
+        assert_eq!(breaker.state(), BreakerState::Closed { failures: 0 });
+    }
+
+    #[test]
+    fn checks_without_taking_the_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        let start = Instant::now();
+        breaker.record_at(start, false);
+        assert_eq!(breaker.check_at(start + Duration::from_secs(10)), Err(Duration::from_secs(20)));
+
+        // Requests that give up between checking & being let through leave the probe to the next one
+        let probe = start + Duration::from_secs(30);
+        assert_eq!(breaker.check_at(probe), Ok(()));
+        assert_eq!(breaker.check_at(probe), Ok(()));
+        assert!(matches!(breaker.state(), BreakerState::Open { .. }));
+        assert_eq!(breaker.allow_at(probe), Ok(()));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen { probe_since: probe });
+        assert!(breaker.check_at(probe).is_err());
+    }
+
+    #[test]
+    fn never_trips_without_threshold() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(30));
+        for _ in 0..10 {
+            breaker.record(false);
+        }
+        assert_eq!(breaker.allow(), Ok(()));
+    }
+}
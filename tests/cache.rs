@@ -0,0 +1,93 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use cfproxy::cache::{cache_key, is_cacheable, Cache, CachedResponse, ResponseCache};
+    use hyper::header::{HeaderValue, CACHE_CONTROL};
+    use hyper::{Body, HeaderMap, Method, Request, StatusCode};
+
+    fn response(body: &'static str) -> CachedResponse {
+        CachedResponse::new(StatusCode::OK, HeaderMap::new(), body.into())
+    }
+
+    #[test]
+    fn keys_get_requests_by_path_and_query() {
+        let get = Request::get("/v1/mods/search?gameId=432").body(Body::empty()).unwrap();
+        assert_eq!(cache_key(&get).as_deref(), Some("/v1/mods/search?gameId=432"));
+        let post = Request::builder().method(Method::POST).uri("/v1/mods").body(Body::empty()).unwrap();
+        assert_eq!(cache_key(&post), None);
+    }
+
+    #[test]
+    fn respects_cache_control() {
+        let mut headers = HeaderMap::new();
+        assert!(is_cacheable(StatusCode::OK, &headers));
+        assert!(!is_cacheable(StatusCode::NOT_FOUND, &headers));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0, Private"));
+        assert!(!is_cacheable(StatusCode::OK, &headers));
+    }
+
+    #[test]
+    fn expires_entries() {
+        let cache = ResponseCache::new(Duration::from_millis(50), 10);
+        cache.insert("/a".to_string(), response("a"));
+        assert_eq!(cache.get("/a").unwrap().body, "a");
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.get("/a").is_none());
+        assert_eq!((cache.stats().hits, cache.stats().misses), (1, 1));
+    }
+
+    #[test]
+    fn evicts_oldest_entry_when_full() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 2);
+        cache.insert("/a".to_string(), response("a"));
+        cache.insert("/b".to_string(), response("b"));
+        cache.insert("/c".to_string(), response("c"));
+        assert!(!cache.contains("/a"));
+        assert!(cache.contains("/b") && cache.contains("/c"));
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_entry_when_full() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 2);
+        cache.insert("/a".to_string(), response("a"));
+        cache.insert("/b".to_string(), response("b"));
+        assert!(cache.get("/a").is_some());
+        cache.insert("/c".to_string(), response("c"));
+        assert!(!cache.contains("/b"));
+        assert!(cache.contains("/a") && cache.contains("/c"));
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn counts_stale_hits_evictions_and_bytes() {
+        let cache = ResponseCache::new(Duration::from_millis(50), 2);
+        cache.insert("/a".to_string(), response("a"));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get_stale("/a").unwrap().body, "a");
+        assert!(cache.get_stale("/b").is_none());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.stale_hits, stats.misses, stats.evictions), (0, 1, 1, 0));
+
+        // The least recently used response goes first, the expired one served before the others were cached
+        cache.insert("/b".to_string(), response("b"));
+        cache.insert("/c".to_string(), response("c"));
+        assert_eq!(cache.stats().evictions, 1);
+        cache.insert("/d".to_string(), response("d"));
+        assert_eq!(cache.stats().evictions, 2);
+
+        let bytes = cache.stats().bytes;
+        assert_eq!(bytes, 2 * ("/c".len() + response("c").estimated_size()));
+        cache.insert("/d".to_string(), response("dddd"));
+        assert_eq!(cache.stats().bytes, bytes + 3);
+        assert_eq!(cache.stats().evictions, 2);
+    }
+
+    #[test]
+    fn disabled_without_ttl() {
+        let cache = ResponseCache::new(Duration::ZERO, 10);
+        cache.insert("/a".to_string(), response("a"));
+        assert!(!cache.is_enabled());
+        assert!(cache.get("/a").is_none());
+    }
+}
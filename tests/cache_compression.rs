@@ -0,0 +1,115 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use cfproxy::cache::{Cache, CachedResponse, ResponseCache};
+    use cfproxy::compression::{accepts_gzip, gunzip, gzip, Coding};
+    use hyper::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, VARY};
+    use hyper::{HeaderMap, StatusCode};
+
+    fn json(mods: usize) -> String {
+        let mods = (0..mods)
+            .map(|id| format!("{{\"id\":{},\"gameId\":432,\"name\":\"Mod {}\",\"summary\":\"A mod\",\"downloadCount\":{}}}", id, id, id * 31))
+            .collect::<Vec<_>>();
+        format!("{{\"data\":[{}]}}", mods.join(","))
+    }
+
+    fn accepting(encoding: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(encoding));
+        headers
+    }
+
+    #[test]
+    fn round_trips_gzip() {
+        let body = json(500);
+        let compressed = gzip(body.as_bytes());
+        assert!(compressed.len() * 5 < body.len());
+        assert_eq!(gunzip(&compressed, usize::MAX).unwrap(), body.as_bytes());
+        assert_eq!(gunzip(&gzip(b""), usize::MAX).unwrap(), b"");
+        let random = (0..10_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect::<Vec<_>>();
+        assert_eq!(gunzip(&gzip(&random), usize::MAX).unwrap(), random);
+
+        assert!(gunzip(&compressed, 1000).is_err());
+        let mut corrupted = compressed.clone();
+        let last = corrupted.len() - 5;
+        corrupted[last] ^= 1;
+        assert!(gunzip(&corrupted, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn round_trips_brotli_and_zstd() {
+        let body = json(500);
+        for coding in [Coding::Brotli, Coding::Zstd] {
+            let compressed = coding.compress(body.as_bytes());
+            assert!(compressed.len() * 5 < body.len(), "{:?}", coding);
+            assert_eq!(coding.decompress(&compressed, usize::MAX).unwrap(), body.as_bytes());
+            assert!(coding.decompress(&compressed, 1000).is_err());
+            assert!(coding.decompress(b"not compressed at all", usize::MAX).is_err());
+        }
+    }
+
+    #[test]
+    fn decompresses_streams_of_other_compressors() {
+        // As compressed by `gzip -9`, with dynamic Huffman codes
+        let stream = [0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x05, 0xc1, 0x31, 0x0a, 0x00, 0x30,
+            0x00, 0x02, 0xb1, 0xb7, 0x3a, 0x1c, 0xd8, 0x45, 0xa1, 0xf5, 0xff, 0x34, 0x91, 0x88, 0xe1, 0x56, 0x8f, 0xa0,
+            0x82, 0xc7, 0x56, 0x4b, 0x19, 0x35, 0x77, 0x87, 0x68, 0x1f, 0x07, 0xe7, 0x96, 0x00, 0x28, 0x00, 0x00, 0x00];
+        assert_eq!(gunzip(&stream, usize::MAX).unwrap(), b"aaenheeroaseneaoeehtettohaanteohertienat");
+    }
+
+    #[test]
+    fn parses_accept_encoding() {
+        assert!(accepts_gzip(&accepting("gzip, deflate, br")));
+        assert!(accepts_gzip(&accepting("br;q=1.0, GZIP;q=0.5")));
+        assert!(accepts_gzip(&accepting("*")));
+        assert!(!accepts_gzip(&accepting("gzip;q=0, *")));
+        assert!(!accepts_gzip(&accepting("br, identity")));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn stores_bodies_compressed() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 10).with_compression(Some(Coding::Gzip));
+        let body = json(200);
+        cache.insert("/v1/mods".to_string(), CachedResponse::new(StatusCode::OK, HeaderMap::new(), body.clone().into()));
+        cache.insert("/v1/games".to_string(), CachedResponse::new(StatusCode::OK, HeaderMap::new(), "{}".into()));
+
+        let cached = cache.get("/v1/mods").unwrap();
+        assert!(cached.coding() == Some(Coding::Gzip) && cached.body.len() < body.len() / 5);
+        let response = cached.to_response_for(&accepting("gzip"));
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "Accept-Encoding");
+
+        let response = cached.to_response_for(&HeaderMap::new());
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(response.headers()[VARY], "Accept-Encoding");
+        let decompressed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(decompressed, body.as_bytes());
+
+        // Too small to be worth it
+        assert_eq!(cache.get("/v1/games").unwrap().coding(), None);
+    }
+
+    #[tokio::test]
+    async fn recompresses_for_clients_accepting_another_coding() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 10).with_compression(Some(Coding::Zstd));
+        let body = json(200);
+        cache.insert("/v1/mods".to_string(), CachedResponse::new(StatusCode::OK, HeaderMap::new(), body.clone().into()));
+        let cached = cache.get("/v1/mods").unwrap();
+        assert_eq!(cached.coding(), Some(Coding::Zstd));
+
+        let response = cached.to_response_for(&accepting("zstd, br"));
+        assert_eq!(response.headers()[CONTENT_ENCODING], "zstd");
+
+        for (accepted, coding) in [("gzip, br", Coding::Brotli), ("gzip", Coding::Gzip)] {
+            let response = cached.to_response_for(&accepting(accepted));
+            assert_eq!(response.headers()[CONTENT_ENCODING], coding.name());
+            let compressed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(coding.decompress(&compressed, usize::MAX).unwrap(), body.as_bytes());
+        }
+
+        let response = cached.to_response_for(&accepting("identity"));
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), body.as_bytes());
+    }
+}
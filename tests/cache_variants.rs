@@ -0,0 +1,83 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use cfproxy::cache::{is_cacheable, Cache, CachedResponse};
+    use cfproxy::compression::gzip;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::test_util::FakeCache;
+    use hyper::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, VARY};
+    use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+    use crate::common::{start_upstream_with, Recorded};
+
+    const BODY: &str = "{\"data\":[{\"id\":238222,\"name\":\"Just Enough Items\"}]}";
+
+    /// Starts an upstream that gzips its response if asked to, & varies it on `X-Game-Version`. Records the
+    /// `Accept-Encoding` of every request.
+    fn start_upstream() -> (String, Recorded<String>) {
+        let encoding = |req: &Request<Body>| req.headers().get(ACCEPT_ENCODING).map(|value| value.to_str().unwrap().to_string()).unwrap_or_default();
+        let (addr, requests) = start_upstream_with(false, encoding, move |req| {
+            let response = Response::builder().header(VARY, "Accept-Encoding, X-Game-Version");
+            let response = match encoding(req).as_str() {
+                "gzip" => response.header(CONTENT_ENCODING, "gzip").body(Body::from(gzip(BODY.as_bytes()))),
+                _ => response.body(Body::from(BODY)),
+            };
+            response.unwrap()
+        });
+        (format!("http://{}", addr), requests)
+    }
+
+    async fn get(cache: &Arc<dyn Cache>, config: &Arc<ProxyConfig>, encoding: Option<&str>, version: &str) -> Response<Body> {
+        let mut req = Request::get("/v1/mods/search?gameId=432").header("X-Game-Version", version);
+        if let Some(encoding) = encoding {
+            req = req.header(ACCEPT_ENCODING, encoding);
+        }
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        cfproxy::proxy_request_with_cache(req.body(Body::empty()).unwrap(), &ip, cache, config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_variants_only_to_matching_clients() {
+        let (url, requests) = start_upstream();
+        let config = Arc::new(ProxyConfig::from_env().with_api_url(&url).unwrap().with_api_keys(["key"]).unwrap());
+        let cache: Arc<dyn Cache> = Arc::new(FakeCache::new());
+
+        // Curseforge is only asked for encodings the cache can serve to everyone
+        let response = get(&cache, &config, Some("br, gzip;q=0.8"), "1.20.1").await;
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(*requests.lock().unwrap(), vec!["gzip"]);
+
+        // The gzipped response is decompressed for clients that don't accept gzip
+        let response = get(&cache, &config, None, "1.20.1").await;
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), BODY);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // Clients with another value of a header named in Vary don't get it
+        let response = get(&cache, &config, Some("identity"), "1.19.2").await;
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), BODY);
+        assert_eq!(*requests.lock().unwrap(), vec!["gzip", "identity"]);
+    }
+
+    #[test]
+    fn only_serves_encodings_clients_accept() {
+        let mut headers = HeaderMap::new();
+        // A coding the cache can't decompress for other clients
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("deflate"));
+        let cached = CachedResponse::new(StatusCode::OK, headers, "compressed".into());
+
+        let mut request_headers = HeaderMap::new();
+        assert!(!cached.matches(&request_headers));
+        request_headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, deflate"));
+        assert!(cached.matches(&request_headers));
+    }
+
+    #[test]
+    fn does_not_cache_responses_varying_on_everything() {
+        let mut headers = HeaderMap::new();
+        headers.insert(VARY, HeaderValue::from_static("Accept-Encoding, *"));
+        assert!(!is_cacheable(StatusCode::OK, &headers));
+    }
+}
@@ -0,0 +1,61 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use cfproxy::checksum::{to_hex, with_checksum_trailer, with_sha1_check, Sha1, CHECKSUM_HEADER};
+    use hyper::body::HttpBody;
+    use hyper::{Body, Response};
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[tokio::test]
+    async fn appends_checksum_trailer() {
+        let logged = Arc::new(Mutex::new(None));
+        let logged_by_callback = Arc::clone(&logged);
+        let response = with_checksum_trailer(Response::new(Body::from("hello")), move |checksum| {
+            *logged_by_callback.lock().unwrap() = Some(checksum.to_string());
+        });
+        assert_eq!(response.headers()["trailer"], CHECKSUM_HEADER);
+
+        let mut body = response.into_body();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        let trailers = body.trailers().await.unwrap().expect("Expected trailers");
+
+        assert_eq!(data, b"hello");
+        assert_eq!(trailers[CHECKSUM_HEADER], HELLO_SHA256);
+        assert_eq!(logged.lock().unwrap().as_deref(), Some(HELLO_SHA256));
+    }
+
+    fn sha1(chunks: &[&[u8]]) -> String {
+        let mut hasher = Sha1::new();
+        for chunk in chunks {
+            hasher.update(chunk);
+        }
+        to_hex(&hasher.finalize())
+    }
+
+    #[test]
+    fn hashes_sha1() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(sha1(&[]), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1(&[b"abc"]), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(sha1(&[&data]), "c9c960a0b925474fab83942cc27d504fc24ac37b");
+        assert_eq!(sha1(&[&data[..7], &data[7..64], &data[64..130], &data[130..]]), "c9c960a0b925474fab83942cc27d504fc24ac37b");
+    }
+
+    #[tokio::test]
+    async fn aborts_bodies_not_matching_sha1() {
+        let matching = with_sha1_check(Response::new(Body::from("abc")), "A9993E364706816ABA3E25717850C26C9CD0D89D", |_| panic!("Expected the hash to match"));
+        assert_eq!(hyper::body::to_bytes(matching.into_body()).await.unwrap(), "abc");
+
+        let mismatched = Arc::new(Mutex::new(None));
+        let mismatched_by_callback = Arc::clone(&mismatched);
+        let corrupted = with_sha1_check(Response::new(Body::from("abd")), "a9993e364706816aba3e25717850c26c9cd0d89d", move |actual| {
+            *mismatched_by_callback.lock().unwrap() = Some(actual.to_string());
+        });
+        assert!(hyper::body::to_bytes(corrupted.into_body()).await.is_err());
+        assert!(mismatched.lock().unwrap().is_some());
+    }
+}
@@ -0,0 +1,29 @@
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use cfproxy::cidr::Cidr;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let range: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains(&ip("10.1.255.3")));
+        assert!(!range.contains(&ip("10.2.0.1")));
+        assert!(range.contains(&ip("::ffff:10.1.0.1")), "Expected IPv4-mapped addresses to match IPv4 ranges");
+        assert!(!range.contains(&ip("fd00::1")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(&ip("fd12:3456::1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(&ip("203.0.113.7")));
+        assert!("203.0.113.7".parse::<Cidr>().unwrap().contains(&ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+}
@@ -0,0 +1,28 @@
+#[cfg(test)]
+mod tests {
+    use cfproxy::classify::classify;
+
+    #[test]
+    fn matches_route_templates() {
+        assert_eq!(classify("/v1/mods/238222/files/3573562").template, "/v1/mods/{id}/files/{fileId}");
+        assert_eq!(classify("/v1/mods/238222/files/3573562/download-url").template, "/v1/mods/{id}/files/{fileId}/download-url");
+        assert_eq!(classify("/v1/minecraft/version/1.18.2").template, "/v1/minecraft/version/{version}");
+        assert_eq!(classify("/v1/games/").template, "/v1/games");
+        assert!(classify("/v1/games/432").known);
+    }
+
+    #[test]
+    fn prefers_fixed_segments() {
+        assert_eq!(classify("/v1/mods/search").template, "/v1/mods/search");
+        assert_eq!(classify("/v1/fingerprints/fuzzy").template, "/v1/fingerprints/fuzzy");
+        assert_eq!(classify("/v1/fingerprints/432").template, "/v1/fingerprints/{gameId}");
+    }
+
+    #[test]
+    fn falls_back_to_grouping_ids() {
+        let route = classify("/v1/unknown/123/things");
+        assert_eq!(route.template, "/v1/unknown/{id}/things");
+        assert!(!route.known);
+        assert_eq!(classify("/").template, "/");
+    }
+}
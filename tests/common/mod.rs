@@ -0,0 +1,75 @@
+//! Helpers shared by the integration tests, declared as `mod common;` by the tests that use them.
+#![cfg(feature = "test-util")]
+// Each test binary only uses some of the helpers
+#![allow(dead_code)]
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+
+/// What a fake upstream recorded of the requests it got.
+pub type Recorded<T> = Arc<Mutex<Vec<T>>>;
+
+/// Starts a fake CF api that answers every request with `{"data":[]}`, and records what `record` takes from
+/// each request.
+pub fn start_upstream<T: Send + 'static>(record: impl Fn(&Request<Body>) -> T + Send + Sync + 'static) -> (SocketAddr, Recorded<T>) {
+    start_upstream_with(false, record, |_| Response::new(Body::from("{\"data\":[]}")))
+}
+
+/// Starts a fake upstream that answers requests with `respond`, and records what `record` takes from each
+/// request. With `http2_only`, it only speaks HTTP/2.
+pub fn start_upstream_with<T: Send + 'static>(
+    http2_only: bool,
+    record: impl Fn(&Request<Body>) -> T + Send + Sync + 'static,
+    respond: impl Fn(&Request<Body>) -> Response<Body> + Send + Sync + 'static,
+) -> (SocketAddr, Recorded<T>) {
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    let handler = Arc::new((recorded.clone(), record, respond));
+    let make_svc = make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let (recorded, record, respond) = &*handler;
+                recorded.lock().unwrap().push(record(&req));
+                let response = respond(&req);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).http2_only(http2_only).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    (addr, recorded)
+}
+
+/// Starts a fake upstream for requests that can't be answered right away, e.g. because their body is read.
+/// `respond` resolves to what's recorded of the request along with the response.
+pub fn start_upstream_async<T, F>(respond: impl Fn(Request<Body>) -> F + Send + Sync + 'static) -> (SocketAddr, Recorded<T>)
+where
+    T: Send + 'static,
+    F: Future<Output = (T, Response<Body>)> + Send + 'static,
+{
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    let handler = Arc::new((recorded.clone(), respond));
+    let make_svc = make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let handler = handler.clone();
+                async move {
+                    let (recorded, respond) = &*handler;
+                    let (record, response) = respond(req).await;
+                    recorded.lock().unwrap().push(record);
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    (addr, recorded)
+}
@@ -0,0 +1,50 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use cfproxy::concurrency::{AdaptiveLimit, ConcurrencyLimit};
+
+    #[test]
+    fn hands_out_permits_up_to_the_limit() {
+        let limit = ConcurrencyLimit::new(2);
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        assert_eq!(limit.in_flight(), 2);
+
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+    }
+
+    #[test]
+    fn zero_is_unlimited() {
+        let limit = ConcurrencyLimit::new(0);
+        let permits: Vec<_> = (0..100).map(|_| limit.try_acquire().unwrap()).collect();
+        assert_eq!(limit.in_flight(), permits.len());
+    }
+
+    #[test]
+    fn adaptive_limit_follows_latency() {
+        let limit = AdaptiveLimit::new(10, Duration::from_millis(100));
+        assert_eq!(limit.limit(), 10);
+
+        // Slow calls & timeouts shrink the limit by a tenth, down to 1
+        limit.record(Duration::from_millis(500), false);
+        assert_eq!(limit.limit(), 9);
+        for _ in 0..50 {
+            limit.record(Duration::from_millis(10), true);
+        }
+        assert_eq!(limit.limit(), 1);
+        let _permit = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+
+        // Fast calls grow it back, by about one per round of calls
+        for _ in 0..4 {
+            limit.record(Duration::from_millis(10), false);
+        }
+        assert_eq!(limit.limit(), 3);
+        for _ in 0..1000 {
+            limit.record(Duration::from_millis(10), false);
+        }
+        assert_eq!(limit.limit(), 10);
+    }
+}
@@ -0,0 +1,66 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::num::NonZeroU32;
+    use std::time::Duration;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::server::{ProxyService, ProxyState};
+    use hyper::service::Service;
+    use hyper::{Body, Request, StatusCode};
+    use crate::common::start_upstream;
+
+    #[tokio::test]
+    async fn runs_proxies_with_their_own_config() {
+        let api_key = |req: &Request<Body>| req.headers().get("x-api-key").map(|key| key.to_str().unwrap().to_string()).unwrap_or_default();
+        let (first, first_keys) = start_upstream(api_key);
+        let (second, second_keys) = start_upstream(api_key);
+        let config = |addr: SocketAddr, key: &str| ProxyConfig::from_env()
+            .with_api_url(&format!("http://{}", addr)).unwrap()
+            .with_api_keys([key]).unwrap();
+        let mut first_proxy = ProxyService::new(ProxyState::new().with_config(config(first, "first-key")), [127, 0, 0, 1].into());
+        let mut second_proxy = ProxyService::new(ProxyState::new().with_config(config(second, "second-key")), [127, 0, 0, 1].into());
+
+        for proxy in [&mut first_proxy, &mut second_proxy] {
+            let resp = proxy.call(Request::get("/v1/games").body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(resp.status(), 200);
+        }
+        assert_eq!(*first_keys.lock().unwrap(), vec!["first-key"]);
+        assert_eq!(*second_keys.lock().unwrap(), vec!["second-key"]);
+
+        assert!(ProxyConfig::from_env().with_api_url("not a url").is_err());
+        assert!(ProxyConfig::from_env().with_api_keys([]).is_err());
+    }
+
+    #[tokio::test]
+    async fn limits_proxies_by_their_own_config() {
+        let (upstream, paths) = start_upstream(|req| req.uri().path().to_string());
+        let api = || ProxyConfig::from_env()
+            .with_api_url(&format!("http://{}", upstream)).unwrap()
+            .with_api_keys(["key"]).unwrap();
+        let limited = api()
+            .with_allowed_paths(["/v1/games"])
+            .with_req_limit_per_hour(NonZeroU32::new(1).unwrap())
+            .with_rate_limit_max_wait(Some(Duration::ZERO));
+        let strict = api().with_strict_passthrough(true).with_base_path("/cfproxy");
+        let mut limited = ProxyService::new(ProxyState::new().with_config(limited), [127, 0, 0, 1].into());
+        let mut strict = ProxyService::new(ProxyState::new().with_config(strict), [127, 0, 0, 1].into());
+        let get = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+
+        // Only the limited proxy checks paths & rate limits, and echoes request ids
+        assert_eq!(limited.call(get("/v1/mods/1")).await.unwrap().status(), StatusCode::NOT_FOUND);
+        let resp = limited.call(get("/v1/games")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().contains_key("x-request-id"));
+        assert_eq!(limited.call(get("/v1/games")).await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // The strict proxy forwards everything under its base path, and nothing else
+        let resp = strict.call(get("/cfproxy/wp-login.php")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key("x-request-id"));
+        assert_eq!(strict.call(get("/cfproxy/v1/games")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(strict.call(get("/v1/games")).await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert_eq!(*paths.lock().unwrap(), vec!["/v1/games", "/wp-login.php", "/v1/games"]);
+    }
+}
@@ -0,0 +1,49 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::time::Duration;
+    use cfproxy::server::ProxyHandle;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    /// Reads a response with a `content-length`, returning its head.
+    async fn read_response(stream: &mut TcpStream) -> String {
+        let mut read = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let text = String::from_utf8_lossy(&read).to_lowercase();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head.lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map(|length| length.trim().parse::<usize>().unwrap())
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    return head.to_string();
+                }
+            }
+            let len = stream.read(&mut buf).await.unwrap();
+            assert!(len > 0, "Expected a response");
+            read.extend_from_slice(&buf[..len]);
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_connections_after_max_requests() {
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("MAX_REQUESTS_PER_CONNECTION", "2");
+        env::set_var("TCP_KEEPALIVE_SECS", "30");
+        let handle = ProxyHandle::start(([127, 0, 0, 1], 0).into()).expect("Expected the proxy to start");
+
+        let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+        stream.write_all(b"GET /_routes HTTP/1.1\r\nhost: localhost\r\n\r\n").await.unwrap();
+        let first = read_response(&mut stream).await;
+        assert!(first.starts_with("http/1.1 200") && !first.contains("connection: close"), "{}", first);
+
+        stream.write_all(b"GET /_routes HTTP/1.1\r\nhost: localhost\r\n\r\n").await.unwrap();
+        let second = read_response(&mut stream).await;
+        assert!(second.contains("connection: close"), "{}", second);
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut rest)).await
+            .expect("Expected the connection to be closed").unwrap();
+    }
+}
@@ -0,0 +1,33 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::time::Duration;
+    use cfproxy::server::ProxyHandle;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    async fn is_closed(stream: &mut TcpStream) -> bool {
+        let mut buf = [0; 1];
+        matches!(tokio::time::timeout(Duration::from_millis(200), stream.read(&mut buf)).await, Ok(Ok(0)) | Ok(Err(_)))
+    }
+
+    #[tokio::test]
+    async fn closes_connections_over_the_cap() {
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("MAX_CONNECTIONS_PER_IP", "2");
+        let handle = ProxyHandle::start(([127, 0, 0, 1], 0).into()).expect("Expected the proxy to start");
+
+        let mut first = TcpStream::connect(handle.local_addr()).await.unwrap();
+        let mut second = TcpStream::connect(handle.local_addr()).await.unwrap();
+        let mut third = TcpStream::connect(handle.local_addr()).await.unwrap();
+        assert!(!is_closed(&mut first).await);
+        assert!(!is_closed(&mut second).await);
+        assert!(is_closed(&mut third).await);
+
+        // A closed connection frees its slot
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut fourth = TcpStream::connect(handle.local_addr()).await.unwrap();
+        assert!(!is_closed(&mut fourth).await);
+    }
+}
@@ -0,0 +1,69 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use cfproxy::cors::CorsPolicy;
+    use cfproxy::server::ProxyHandle;
+    use hyper::header::HeaderValue;
+    use hyper::{Body, Client, HeaderMap, Method, Request, StatusCode};
+
+    fn policy(origins: &[&str]) -> CorsPolicy {
+        let methods = vec!["GET".to_string(), "POST".to_string()];
+        let headers = vec!["content-type".to_string()];
+        CorsPolicy::new(origins.iter().map(|origin| origin.to_string()).collect(), &methods, &headers).unwrap()
+    }
+
+    #[test]
+    fn echoes_allowed_origins() {
+        let policy = policy(&["https://tools.example"]);
+        let mut headers = HeaderMap::new();
+        policy.apply(&HeaderValue::from_static("https://tools.example"), &mut headers);
+        assert_eq!(headers["access-control-allow-origin"], "https://tools.example");
+        assert_eq!(headers["access-control-allow-methods"], "GET, POST");
+        assert_eq!(headers["access-control-allow-headers"], "content-type");
+        assert_eq!(headers["vary"], "origin");
+
+        let mut headers = HeaderMap::new();
+        policy.apply(&HeaderValue::from_static("https://evil.example"), &mut headers);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn wildcard_allows_any_origin() {
+        let policy = policy(&["*"]);
+        let mut headers = HeaderMap::new();
+        policy.apply(&HeaderValue::from_static("https://tools.example"), &mut headers);
+        assert_eq!(headers["access-control-allow-origin"], "*");
+        assert!(!headers.contains_key("vary"));
+    }
+
+    #[tokio::test]
+    async fn answers_preflights_locally() {
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("CORS_ALLOWED_ORIGINS", "https://tools.example");
+        env::set_var("REQUIRE_TOKEN", "true");
+        let handle = ProxyHandle::start(([127, 0, 0, 1], 0).into()).expect("Expected the proxy to start");
+
+        // Answered without a token, and without asking Curseforge
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri(format!("http://{}/v1/mods/search", handle.local_addr()))
+            .header("origin", "https://tools.example")
+            .header("access-control-request-method", "GET")
+            .body(Body::empty())
+            .unwrap();
+        let response = Client::new().request(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()["access-control-allow-origin"], "https://tools.example");
+        assert_eq!(response.headers()["access-control-max-age"], "600");
+
+        // Other responses carry CORS headers too
+        let req = Request::get(format!("http://{}/v1/mods/search", handle.local_addr()))
+            .header("origin", "https://tools.example")
+            .body(Body::empty())
+            .unwrap();
+        let response = Client::new().request(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["access-control-allow-origin"], "https://tools.example");
+        handle.shutdown().await.expect("Expected the proxy to shut down");
+    }
+}
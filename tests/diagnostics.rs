@@ -0,0 +1,29 @@
+#[cfg(test)]
+mod tests {
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::diagnostics::{DiagnosticReport, CONFIG_VARS};
+    use cfproxy::server::ProxyState;
+
+    #[test]
+    fn reports_keys_of_the_proxy() {
+        let config = ProxyConfig::from_env().with_api_keys(["key-a", "key-b"]).unwrap();
+        let report = DiagnosticReport::collect(&ProxyState::new().with_config(config));
+        assert_eq!((report.healthy_api_keys, report.api_keys), (2, 2));
+    }
+
+    #[test]
+    fn lists_the_documented_vars() {
+        let documented = include_str!("../README.md").lines()
+            .filter(|line| line.starts_with("| `"))
+            .flat_map(|line| line.split('|').nth(1).unwrap().split(','))
+            .map(|name| name.trim().trim_matches('`'))
+            .filter(|name| name.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+            .collect::<Vec<_>>();
+        for name in &documented {
+            assert!(CONFIG_VARS.contains(name), "{} is missing from CONFIG_VARS", name);
+        }
+        for name in CONFIG_VARS {
+            assert!(documented.contains(name), "{} is missing from the README", name);
+        }
+    }
+}
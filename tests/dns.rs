@@ -0,0 +1,86 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use cfproxy::dns;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UdpSocket};
+
+    /// Answers `A` queries with `ip`, valid for a second, & every other query with no records. With `truncated`,
+    /// the answer is marked as truncated & holds no records instead.
+    fn answer(query: &[u8], ip: [u8; 4], truncated: bool) -> Vec<u8> {
+        let is_a = query[query.len() - 4..query.len() - 2] == [0, 1] && !truncated;
+        let mut response = query[..2].to_vec();
+        response.extend_from_slice(&[0x81 | (truncated as u8) << 1, 0x80, 0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+        response.extend_from_slice(&query[12..]);
+        if is_a {
+            response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 1, 0, 4]);
+            response.extend_from_slice(&ip);
+        }
+        response
+    }
+
+    /// Starts a fake DNS server that answers every `A` query with `1.2.3.4`. Hosts starting with `large` only get
+    /// a truncated answer over UDP, and `5.6.7.8` over TCP. Counts the queries it gets over UDP.
+    async fn start_dns_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counted = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+                counted.fetch_add(1, Ordering::SeqCst);
+                let query = &buf[..len];
+                let truncated = query[13..].starts_with(b"large");
+                socket.send_to(&answer(query, [1, 2, 3, 4], truncated), client).await.unwrap();
+            }
+        });
+        let listener = TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    // Messages over TCP are prefixed with their length
+                    while let Ok(len) = stream.read_u16().await {
+                        let mut query = vec![0; len as usize];
+                        stream.read_exact(&mut query).await.unwrap();
+                        let response = answer(&query, [5, 6, 7, 8], false);
+                        stream.write_u16(response.len() as u16).await.unwrap();
+                        stream.write_all(&response).await.unwrap();
+                    }
+                });
+            }
+        });
+        (addr, queries)
+    }
+
+    #[tokio::test]
+    async fn resolves_with_dns_servers_and_caches_answers() {
+        let (server, queries) = start_dns_server().await;
+        env::set_var("DNS_SERVERS", server.to_string());
+        env::set_var("DNS_OVERRIDES", "pinned.test=10.0.0.1, pinned.test=10.0.0.2");
+
+        let expected = vec![IpAddr::from([1, 2, 3, 4])];
+        assert_eq!(dns::resolve("api.example.test").await.unwrap(), expected);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // Cached until the TTL runs out
+        assert_eq!(dns::resolve("API.example.test.").await.unwrap(), expected);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(dns::resolve("api.example.test").await.unwrap(), expected);
+        assert_eq!(queries.load(Ordering::SeqCst), 4);
+
+        assert_eq!(dns::resolve("pinned.test").await.unwrap(), vec![IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2])]);
+        assert_eq!(dns::resolve("[::1]").await.unwrap(), vec![IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])]);
+        assert_eq!(queries.load(Ordering::SeqCst), 4);
+
+        // Truncated answers are asked for again over TCP
+        assert_eq!(dns::resolve("large.example.test").await.unwrap(), vec![IpAddr::from([5, 6, 7, 8])]);
+    }
+}
@@ -0,0 +1,57 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use cfproxy::server::{ProxyHandle, ProxyState};
+    use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+    use crate::common::start_upstream_with;
+
+    const JAR_SHA1: &str = "01c56e3ae46c962debe4976038d5ba38d1e61ef7";
+
+    #[tokio::test]
+    async fn streams_files_from_the_cdn() {
+        let (cdn, _) = start_upstream_with(false, |_| (), |req| match req.uri().path() {
+            "/files/2/a%20b.jar" => Response::builder().status(StatusCode::FOUND).header("location", "/mirror/a.jar").body(Body::empty()).unwrap(),
+            "/mirror/a.jar" => {
+                let range = req.headers().get("range").cloned().unwrap();
+                Response::builder().header("x-range", range).body(Body::from("jar bytes")).unwrap()
+            }
+            _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap(),
+        });
+        let (api, _) = start_upstream_with(false, |_| (), move |req| match req.uri().path() {
+            "/v1/mods/1/files/2" | "/v1/mods/1/files/3" | "/v1/mods/1/files/4" => Response::new(Body::from(format!(r#"{{"data":{{"hashes":[{{"value":"{}","algo":1}}]}}}}"#, JAR_SHA1))),
+            "/v1/mods/1/files/6" => Response::new(Body::from(r#"{"data":{"hashes":[{"value":"a9993e364706816aba3e25717850c26c9cd0d89d","algo":1}]}}"#)),
+            "/v1/mods/1/files/2/download-url" | "/v1/mods/1/files/6/download-url" => Response::new(Body::from(format!(r#"{{"data":"http://{}/files/2/a b.jar"}}"#, cdn))),
+            "/v1/mods/1/files/3/download-url" => Response::new(Body::from(r#"{"data":"http://example.com/files/3/a.jar"}"#)),
+            "/v1/mods/1/files/4/download-url" => Response::new(Body::from(r#"{"data":null}"#)),
+            _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap(),
+        });
+        env::set_var("CF_API_URL", format!("http://{}", api));
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("DOWNLOAD_PROXY", "true");
+        env::set_var("DOWNLOAD_HOSTS", "127.0.0.1");
+
+        let handle = ProxyHandle::start_with_state(([127, 0, 0, 1], 0).into(), ProxyState::new()).expect("Expected the proxy to start");
+        let download = |file: u32| {
+            let uri: Uri = format!("http://{}/download/1/{}", handle.local_addr(), file).parse().unwrap();
+            Client::new().request(Request::get(uri).header("range", "bytes=0-").body(Body::empty()).unwrap())
+        };
+
+        let response = download(2).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-range"], "bytes=0-");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "jar bytes");
+
+        assert_eq!(download(3).await.unwrap().status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(download(4).await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert_eq!(download(5).await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        // The CDN's file doesn't match the hash of file 6
+        let response = download(6).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+        handle.shutdown().await.expect("Expected the proxy to shut down");
+    }
+}
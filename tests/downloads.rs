@@ -0,0 +1,44 @@
+#[cfg(test)]
+mod tests {
+    use cfproxy::downloads::{parse_download_path, rewrite_download_urls, FileRef};
+    use serde_json::{json, Value};
+
+    const BASE: &str = "https://proxy.example.com/download";
+
+    #[test]
+    fn rewrites_nested_download_urls() {
+        let body = json!({
+            "data": [{
+                "id": 238222,
+                "latestFiles": [
+                    { "id": 3456, "modId": 238222, "downloadUrl": "https://edge.forgecdn.net/files/3456/1/jei.jar" },
+                    { "id": 3457, "modId": 238222, "downloadUrl": null },
+                ],
+            }],
+            "pagination": { "index": 0 },
+        });
+        let rewritten = rewrite_download_urls(body.to_string().as_bytes(), BASE).unwrap();
+        let rewritten: Value = serde_json::from_slice(&rewritten).unwrap();
+
+        let files = &rewritten["data"][0]["latestFiles"];
+        assert_eq!(files[0]["downloadUrl"], "https://proxy.example.com/download/238222/3456");
+        assert_eq!(files[1]["downloadUrl"], Value::Null);
+        assert_eq!(rewritten["pagination"], body["pagination"]);
+    }
+
+    #[test]
+    fn leaves_bodies_without_files_alone() {
+        assert_eq!(rewrite_download_urls(br#"{"data":[{"id":1,"name":"Minecraft"}]}"#, BASE), None);
+        assert_eq!(rewrite_download_urls(b"not json", BASE), None);
+    }
+
+    #[test]
+    fn parses_download_paths() {
+        assert_eq!(parse_download_path("/download/238222/3456"), Some(FileRef { mod_id: 238222, file_id: 3456 }));
+        assert_eq!(parse_download_path("/download/238222/3456/"), Some(FileRef { mod_id: 238222, file_id: 3456 }));
+        assert_eq!(parse_download_path("/download/238222"), None);
+        assert_eq!(parse_download_path("/download/238222/3456/extra"), None);
+        assert_eq!(parse_download_path("/download/jei/3456"), None);
+        assert_eq!(parse_download_path("/v1/mods/238222"), None);
+    }
+}
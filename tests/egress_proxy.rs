@@ -0,0 +1,156 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::egress::HostPattern;
+    use cfproxy::server::{ProxyService, ProxyState};
+    use hyper::service::Service;
+    use hyper::{Body, Request, StatusCode};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::common::start_upstream;
+
+    /// Starts a fake HTTP proxy that records the `CONNECT` requests it gets, & tunnels them unless `refuse` is set.
+    async fn start_proxy(refuse: bool) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut client, _) = listener.accept().await.unwrap();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        head.push(client.read_u8().await.unwrap());
+                    }
+                    let head = String::from_utf8(head).unwrap();
+                    recorded.lock().unwrap().push(head.trim().to_string());
+                    if refuse {
+                        client.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await.unwrap();
+                        return;
+                    }
+                    let target = head.split_whitespace().nth(1).unwrap().to_string();
+                    let mut upstream = TcpStream::connect(target).await.unwrap();
+                    client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.unwrap();
+                    tokio::io::copy_bidirectional(&mut client, &mut upstream).await.ok();
+                });
+            }
+        });
+        (addr, requests)
+    }
+
+    /// Starts a fake SOCKS5 proxy that records the destinations it's asked for (with the user, if any), &
+    /// connects all of them to `upstream`.
+    async fn start_socks_proxy(upstream: SocketAddr) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut client, _) = listener.accept().await.unwrap();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let mut greeting = [0; 2];
+                    client.read_exact(&mut greeting).await.unwrap();
+                    let mut methods = vec![0; greeting[1] as usize];
+                    client.read_exact(&mut methods).await.unwrap();
+                    let mut user = String::new();
+                    if methods.contains(&2) {
+                        client.write_all(&[5, 2]).await.unwrap();
+                        let mut auth = vec![0; 2];
+                        client.read_exact(&mut auth).await.unwrap();
+                        let mut name = vec![0; auth[1] as usize];
+                        client.read_exact(&mut name).await.unwrap();
+                        let mut password = vec![0; client.read_u8().await.unwrap() as usize];
+                        client.read_exact(&mut password).await.unwrap();
+                        user = format!("{}:{}@", String::from_utf8(name).unwrap(), String::from_utf8(password).unwrap());
+                        client.write_all(&[1, 0]).await.unwrap();
+                    } else {
+                        client.write_all(&[5, 0]).await.unwrap();
+                    }
+
+                    let mut request = [0; 4];
+                    client.read_exact(&mut request).await.unwrap();
+                    let host = match request[3] {
+                        1 => {
+                            let mut ip = [0; 4];
+                            client.read_exact(&mut ip).await.unwrap();
+                            std::net::Ipv4Addr::from(ip).to_string()
+                        }
+                        _ => {
+                            let mut domain = vec![0; client.read_u8().await.unwrap() as usize];
+                            client.read_exact(&mut domain).await.unwrap();
+                            String::from_utf8(domain).unwrap()
+                        }
+                    };
+                    let port = client.read_u16().await.unwrap();
+                    recorded.lock().unwrap().push(format!("{}{}:{}", user, host, port));
+                    let mut upstream = TcpStream::connect(upstream).await.unwrap();
+                    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+                    tokio::io::copy_bidirectional(&mut client, &mut upstream).await.ok();
+                });
+            }
+        });
+        (addr, requests)
+    }
+
+    async fn get(api_url: &str) -> StatusCode {
+        let config = ProxyConfig::from_env().with_api_url(api_url).unwrap().with_api_keys(["key"]).unwrap();
+        let mut service = ProxyService::new(ProxyState::new().with_config(config), [127, 0, 0, 1].into());
+        service.call(Request::get("/v1/games").body(Body::empty()).unwrap()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn tunnels_upstream_connections_through_proxies() {
+        let (proxy, requests) = start_proxy(false).await;
+        let (refusing_proxy, refused) = start_proxy(true).await;
+        env::set_var("HTTP_PROXY", format!("http://user:p%40ss@{}", proxy));
+        env::set_var("NO_PROXY", "example.com, localhost");
+        let upstream = start_upstream(|_| ()).0;
+        let (socks_proxy, socks_requests) = start_socks_proxy(upstream).await;
+        env::set_var("UPSTREAM_PROXIES", format!("127.0.0.2=http://{0},127.0.0.3=socks5://{1},upstream.test=socks5h://user:pw@{1}",
+            refusing_proxy, socks_proxy));
+
+        assert_eq!(get(&format!("http://{}", upstream)).await, StatusCode::OK);
+        assert_eq!(*requests.lock().unwrap(), vec![format!(
+            "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\nProxy-Authorization: Basic dXNlcjpwQHNz", upstream)]);
+
+        // Hosts in NO_PROXY are connected to directly
+        assert_eq!(get(&format!("http://localhost:{}", upstream.port())).await, StatusCode::OK);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // UPSTREAM_PROXIES picks the proxy per upstream host
+        assert_ne!(get(&format!("http://127.0.0.2:{}", upstream.port())).await, StatusCode::OK);
+        assert!(!refused.lock().unwrap().is_empty());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // SOCKS5 proxies are asked for the upstream's address, or with socks5h, its host name
+        assert_eq!(get(&format!("http://127.0.0.3:{}", upstream.port())).await, StatusCode::OK);
+        assert_eq!(get(&format!("http://upstream.test:{}", upstream.port())).await, StatusCode::OK);
+        assert_eq!(*socks_requests.lock().unwrap(), vec![
+            format!("127.0.0.3:{}", upstream.port()),
+            format!("user:pw@upstream.test:{}", upstream.port()),
+        ]);
+    }
+
+    #[test]
+    fn matches_hosts_like_no_proxy() {
+        let domain = HostPattern::new(".Example.com:443");
+        assert!(domain.matches("example.com"));
+        assert!(domain.matches("api.example.com"));
+        assert!(!domain.matches("badexample.com"));
+
+        let addresses = HostPattern::new("10.0.0.0/8");
+        assert!(addresses.matches("10.1.2.3"));
+        assert!(!addresses.matches("192.168.0.1"));
+        assert!(HostPattern::new("[::1]:8080").matches("::1"));
+        assert!(HostPattern::new("*").matches("anything"));
+    }
+}
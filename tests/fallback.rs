@@ -0,0 +1,64 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::{IpAddr, Ipv4Addr, TcpListener};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use cfproxy::breaker::{BreakerState, BREAKER};
+    use cfproxy::cache::{Cache, ResponseCache};
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::metrics::METRICS;
+    use cfproxy::{proxy_request_to_cf, proxy_request_with_cache, ProxyError};
+    use hyper::{Body, Request, Response, StatusCode};
+    use crate::common::{start_upstream, start_upstream_with};
+
+    #[tokio::test]
+    async fn forwards_to_fallback_while_cf_is_unreachable_or_the_breaker_is_open() {
+        // Nothing listens at the primary's address anymore
+        let primary = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (fallback_addr, _) = start_upstream_with(false, |_| (), |req| {
+            let host = req.headers()["host"].to_str().unwrap();
+            let api_key = req.headers().get("x-api-key").map_or("none", |key| key.to_str().unwrap());
+            Response::new(Body::from(format!("{} {}", host, api_key)))
+        });
+        env::set_var("CF_API_URL", format!("http://{}", primary));
+        env::set_var("FALLBACK_API_URL", format!("http://{}", fallback_addr));
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("UPSTREAM_RETRIES", "0");
+
+        // The fallback holds its own key, it isn't given ours
+        let (quota, fallback_requests) = (METRICS.snapshot().quota.used, METRICS.run_stats().fallback_requests);
+        let req = Request::get("/v1/games").body(Body::empty()).unwrap();
+        let response = proxy_request_to_cf(req, &IpAddr::V4(Ipv4Addr::LOCALHOST)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, format!("{} none", fallback_addr));
+        // Nor does it use up the quota of our key
+        assert_eq!(METRICS.snapshot().quota.used, quota);
+        assert_eq!(METRICS.run_stats().upstream_requests, 0);
+        assert_eq!(METRICS.run_stats().fallback_requests, fallback_requests + 1);
+
+        // Large bodies of requests that aren't retried aren't kept around for the fallback
+        let req = Request::post("/v1/mods").body(Body::from(vec![b' '; 100 * 1024])).unwrap();
+        let err = proxy_request_to_cf(req, &IpAddr::V4(Ipv4Addr::LOCALHOST)).await.expect_err("Expected Curseforge not to be reachable");
+        assert!(matches!(err, ProxyError::Upstream(_)), "{:?}", err);
+        assert_eq!(err.into_response().status(), StatusCode::BAD_GATEWAY);
+
+        // While the breaker is open, requests go to the fallback without trying Curseforge
+        let (reachable, calls) = start_upstream(|_| ());
+        let config = Arc::new(ProxyConfig::from_env().with_api_url(&format!("http://{}", reachable)).unwrap());
+        while !matches!(BREAKER.state(), BreakerState::Open { .. }) {
+            BREAKER.record(false);
+        }
+
+        let cache: Arc<dyn Cache> = Arc::new(ResponseCache::new(Duration::ZERO, 0));
+        let req = Request::get("/v1/games/432").body(Body::empty()).unwrap();
+        let response = proxy_request_with_cache(req, &IpAddr::V4(Ipv4Addr::LOCALHOST), &cache, &config).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, format!("{} none", fallback_addr));
+        assert!(calls.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,75 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use cfproxy::forwarding::{add_headers, parse_headers, scrub_client_headers, stamp_via, strip_hop_by_hop};
+    use hyper::header::HeaderValue;
+    use hyper::HeaderMap;
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", HeaderValue::from_static("keep-alive, x-session"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("te", HeaderValue::from_static("trailers"));
+        headers.insert("transfer-encoding", HeaderValue::from_static("chunked"));
+        headers.insert("x-session", HeaderValue::from_static("abc"));
+        headers.insert("accept", HeaderValue::from_static("application/json"));
+        strip_hop_by_hop(&mut headers);
+
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["accept"], "application/json");
+    }
+
+    #[test]
+    fn scrubs_client_credentials() {
+        env::set_var("FORWARD_CLIENT_HEADERS", "x-forwarded-host");
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer secret"));
+        headers.insert("cookie", HeaderValue::from_static("session=abc"));
+        headers.insert("x-api-key", HeaderValue::from_static("client-key"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("10.0.0.1"));
+        headers.insert("fly-client-ip", HeaderValue::from_static("10.0.0.1"));
+        headers.insert("x-forwarded-host", HeaderValue::from_static("proxy.example"));
+        headers.insert("accept", HeaderValue::from_static("application/json"));
+        scrub_client_headers(&mut headers);
+
+        let mut left: Vec<_> = headers.keys().map(|name| name.as_str()).collect();
+        left.sort_unstable();
+        assert_eq!(left, vec!["accept", "x-forwarded-host"]);
+    }
+
+    #[test]
+    fn appends_via() {
+        let mut headers = HeaderMap::new();
+        headers.insert("via", HeaderValue::from_static("1.1 cdn"));
+        stamp_via(&mut headers);
+
+        let via: Vec<_> = headers.get_all("via").iter().collect();
+        assert_eq!(via, vec!["1.1 cdn", concat!("1.1 cfproxy/", env!("CARGO_PKG_VERSION"))]);
+    }
+
+    #[test]
+    fn parses_extra_headers() {
+        let headers = parse_headers("Cache-Control: public, max-age=300\n\n  X-Partner: a:b  ").unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers["cache-control"], "public, max-age=300");
+        assert_eq!(headers["x-partner"], "a:b");
+
+        assert!(parse_headers("").unwrap().is_empty());
+        assert!(parse_headers("no separator").is_err());
+        assert!(parse_headers("bad name: value").is_err());
+    }
+
+    #[test]
+    fn extra_headers_replace_existing_ones() {
+        let mut headers = HeaderMap::new();
+        headers.insert("cache-control", HeaderValue::from_static("no-store"));
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        add_headers(&mut headers, &parse_headers("Cache-Control: public, max-age=300\nX-Partner: 1\nX-Partner: 2").unwrap());
+
+        assert_eq!(headers["cache-control"], "public, max-age=300");
+        assert_eq!(headers["content-type"], "application/json");
+        let partner: Vec<_> = headers.get_all("x-partner").iter().collect();
+        assert_eq!(partner, vec!["1", "2"]);
+    }
+}
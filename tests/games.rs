@@ -3,7 +3,6 @@ mod tests {
     use std::net::{IpAddr, Ipv4Addr};
     use cfproxy::proxy_request_to_cf;
     use hyper::{Request, Body, StatusCode};
-    use tokio;
     use dotenv::dotenv;
 
     #[tokio::test]
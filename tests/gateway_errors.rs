@@ -0,0 +1,62 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::Write;
+    use std::net::{IpAddr, Ipv4Addr, TcpListener};
+    use std::sync::Arc;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::{forward_request, proxy_request_to_cf, ProxyError};
+    use hyper::{Body, Request, Response, StatusCode};
+
+    async fn error_of(response: Response<Body>) -> String {
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        body["error"]["message"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn answers_upstream_failures_with_502() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        env::set_var("CF_API_URL", format!("https://{}", addr));
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("UPSTREAM_RETRIES", "0");
+        env::set_var("CIRCUIT_BREAKER_THRESHOLD", "0");
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        // Nothing listens yet
+        let req = Request::get("/v1/games").body(Body::empty()).unwrap();
+        let err = proxy_request_to_cf(req, &ip).await.expect_err("Expected Curseforge not to be reachable");
+        assert!(matches!(err, ProxyError::Upstream(_)), "{:?}", err);
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(error_of(response).await, "Curseforge could not be reached");
+
+        // Plain HTTP where TLS is expected
+        let listener = TcpListener::bind(addr).unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n");
+            }
+        });
+        let req = Request::get("/v1/games").body(Body::empty()).unwrap();
+        let response = proxy_request_to_cf(req, &ip).await.expect_err("Expected the TLS handshake to fail").into_response();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(error_of(response).await, "TLS handshake with Curseforge failed");
+    }
+
+    #[tokio::test]
+    async fn returns_failures_as_errors() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("UPSTREAM_RETRIES", "0");
+        env::set_var("CIRCUIT_BREAKER_THRESHOLD", "0");
+        let config = Arc::new(ProxyConfig::from_env().with_api_url(&format!("http://{}", addr)).unwrap());
+
+        let req = Request::get("/v1/games").body(Body::empty()).unwrap();
+        match forward_request(req, &config).await {
+            Err(err @ ProxyError::Upstream(_)) => assert!(std::error::Error::source(&err).is_some()),
+            other => panic!("Expected an upstream error, got {:?}", other),
+        }
+    }
+}
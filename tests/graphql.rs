@@ -0,0 +1,63 @@
+#[cfg(test)]
+mod tests {
+    use cfproxy::graphql::{call_for, parse_query, project, Call, MAX_DEPTH, MAX_MOD_IDS};
+    use serde_json::{json, Map};
+
+    #[test]
+    fn parses_queries() {
+        let variables = json!({ "id": 238222 }).as_object().cloned().unwrap();
+        let fields = parse_query(r#"
+            query Lookup($id: Int!) {
+                jei: mod(id: $id) { name latestFiles { id } } # the mod
+                search(gameId: 432, searchFilter: "just enough", sortField: Popularity) { id }
+            }
+        "#, &variables).unwrap();
+
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].key(), "jei");
+        assert_eq!(fields[0].argument("id"), Some(&json!(238222)));
+        assert_eq!(fields[0].selection.len(), 2);
+        assert_eq!(fields[0].selection[1].selection[0].name, "id");
+        assert_eq!(call_for(&fields[0]), Ok(Call::Get(String::from("/v1/mods/238222"))));
+        assert_eq!(call_for(&fields[1]), Ok(Call::Get(String::from("/v1/mods/search?gameId=432&searchFilter=just%20enough&sortField=Popularity"))));
+    }
+
+    #[test]
+    fn rejects_unsupported_queries() {
+        assert!(parse_query("mutation { mod(id: 1) { id } }", &Map::new()).is_err());
+        assert!(parse_query("{ mod(id: 1) { id }", &Map::new()).is_err());
+        assert!(call_for(&parse_query("{ addon { id } }", &Map::new()).unwrap()[0]).is_err());
+        assert!(call_for(&parse_query("{ files { id } }", &Map::new()).unwrap()[0]).is_err());
+
+        let ids = (0..=MAX_MOD_IDS).map(|id| id.to_string()).collect::<Vec<_>>().join(", ");
+        assert!(call_for(&parse_query(&format!("{{ mods(ids: [{}]) {{ id }} }}", ids), &Map::new()).unwrap()[0]).is_err());
+    }
+
+    #[test]
+    fn rejects_deeply_nested_queries() {
+        let deep = format!("{}{}", "{ a ".repeat(MAX_DEPTH + 1), "}".repeat(MAX_DEPTH + 1));
+        assert_eq!(parse_query(&deep, &Map::new()), Err(String::from("query is nested too deeply")));
+        let deep = format!("{{ mods(ids: {}{}) }}", "[".repeat(100_000), "]".repeat(100_000));
+        assert_eq!(parse_query(&deep, &Map::new()), Err(String::from("query is nested too deeply")));
+        let nested = format!("{}{}", "{ a ".repeat(MAX_DEPTH), "}".repeat(MAX_DEPTH));
+        assert!(parse_query(&nested, &Map::new()).is_ok());
+    }
+
+    #[test]
+    fn projects_selected_fields() {
+        let fields = parse_query("{ mod(id: 1) { id title: name missing latestFiles { fileName } } }", &Map::new()).unwrap();
+        let value = json!({
+            "id": 1,
+            "name": "JEI",
+            "summary": "View items and recipes",
+            "latestFiles": [{ "id": 2, "fileName": "jei.jar" }],
+        });
+
+        assert_eq!(project(&value, &fields[0].selection), json!({
+            "id": 1,
+            "title": "JEI",
+            "missing": null,
+            "latestFiles": [{ "fileName": "jei.jar" }],
+        }));
+    }
+}
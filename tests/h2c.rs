@@ -0,0 +1,47 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::server::{ProxyHandle, ProxyState};
+    use hyper::{Body, Client, Response, Version};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use crate::common::start_upstream_with;
+
+    #[tokio::test]
+    async fn speaks_http2_over_cleartext() {
+        let (upstream, requests) = start_upstream_with(
+            true,
+            |req| (req.version(), req.headers().clone()),
+            |_| Response::new(Body::from("{\"data\":[]}")),
+        );
+        env::set_var("UPSTREAM_H2C", "true");
+        let config = ProxyConfig::from_env().with_api_url(&format!("http://{}", upstream)).unwrap().with_api_keys(["key"]).unwrap();
+        let handle = ProxyHandle::start_with_state(([127, 0, 0, 1], 0).into(), ProxyState::new().with_config(config))
+            .expect("Expected the proxy to start");
+
+        let client = Client::builder().http2_only(true).build_http::<Body>();
+        let resp = client.get(format!("http://{}/v1/games", handle.local_addr()).parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.version(), Version::HTTP_2);
+        assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "{\"data\":[]}");
+        assert_eq!(requests.lock().unwrap()[0].0, Version::HTTP_2);
+
+        // Upgrades to h2c are answered with HTTP/1.1
+        let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+        stream.write_all(b"GET /v1/games HTTP/1.1\r\nHost: localhost\r\nConnection: close, Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        // The upgrade is about the client's connection, so it isn't forwarded
+        let (version, headers) = requests.lock().unwrap()[1].clone();
+        assert_eq!(version, Version::HTTP_2);
+        assert!(headers.get("upgrade").is_none());
+        assert!(headers.get("http2-settings").is_none());
+
+        handle.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::IpAddr;
+    use std::sync::{Arc, Mutex};
+    use cfproxy::hooks::{ErrorInfo, ProxyHook, RequestSummary};
+    use cfproxy::server::{ProxyService, ProxyState};
+    use hyper::header::HeaderValue;
+    use hyper::service::Service;
+    use hyper::{Body, Request, Response, StatusCode};
+
+    /// Blocks requests for `/blocked`, tags responses & records the proxy's errors.
+    struct TestHook {
+        errors: Arc<Mutex<Vec<(String, ErrorInfo)>>>,
+    }
+
+    impl ProxyHook for TestHook {
+        fn on_request(&self, req: &mut Request<Body>, _remote_addr: IpAddr) -> Option<Response<Body>> {
+            match req.uri().path() {
+                "/blocked" => Some(Response::builder().status(StatusCode::IM_A_TEAPOT).body(Body::empty()).unwrap()),
+                _ => None,
+            }
+        }
+
+        fn on_response(&self, _request: &RequestSummary, response: &mut Response<Body>) {
+            response.headers_mut().insert("x-hooked", HeaderValue::from_static("yes"));
+        }
+
+        fn on_error(&self, request: &RequestSummary, error: &ErrorInfo, _response: &mut Response<Body>) {
+            self.errors.lock().unwrap().push((request.uri.path().to_string(), error.clone()));
+        }
+    }
+
+    async fn get(service: &mut ProxyService, path: &str) -> Response<Body> {
+        service.call(Request::get(path).body(Body::empty()).unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn runs_hooks() {
+        env::set_var("CF_API_KEY", "key");
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let state = ProxyState::new().with_hook(TestHook { errors: errors.clone() });
+        let mut service = ProxyService::new(state, [127, 0, 0, 1].into());
+
+        let resp = get(&mut service, "/_routes").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-hooked"], "yes");
+
+        // Hooks can answer requests themselves
+        let resp = get(&mut service, "/blocked").await;
+        assert_eq!(resp.status(), StatusCode::IM_A_TEAPOT);
+
+        // The proxy's own errors go to on_error instead of on_response
+        let resp = get(&mut service, "/v2/nothing-here").await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(resp.headers().get("x-hooked").is_none());
+        let error = ErrorInfo { status: StatusCode::NOT_FOUND, message: String::from("Not found") };
+        assert_eq!(*errors.lock().unwrap(), vec![(String::from("/v2/nothing-here"), error)]);
+    }
+}
@@ -0,0 +1,83 @@
+mod common;
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::pool::UpstreamConnector;
+    use cfproxy::server::{ProxyHandle, ProxyState};
+    use hyper::{Body, Client, Version};
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509NameBuilder, X509};
+    use crate::common::start_upstream;
+
+    /// Returns a self-signed certificate & its PEM key for `localhost`.
+    fn self_signed() -> (X509, Vec<u8>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (cert.build(), key.private_key_to_pem_pkcs8().unwrap())
+    }
+
+    #[tokio::test]
+    async fn negotiates_http2_with_alpn() {
+        let (upstream, versions) = start_upstream(|req| req.version());
+        let (cert, key) = self_signed();
+        let dir = env::temp_dir().join(format!("cfproxy-http2-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cert.pem"), cert.to_pem().unwrap()).unwrap();
+        fs::write(dir.join("key.pem"), key).unwrap();
+        env::set_var("TLS_CERT_FILE", dir.join("cert.pem"));
+        env::set_var("TLS_KEY_FILE", dir.join("key.pem"));
+        let config = ProxyConfig::from_env().with_api_url(&format!("http://{}", upstream)).unwrap().with_api_keys(["key"]).unwrap();
+        let handle = ProxyHandle::start_with_state(([127, 0, 0, 1], 0).into(), ProxyState::new().with_config(config))
+            .expect("Expected the proxy to start");
+        let url = format!("https://localhost:{}/v1/games", handle.local_addr().port());
+
+        // Clients offering HTTP/2 get HTTP/2, & their requests reach the upstream over its HTTP/1.1
+        let client = Client::builder().build::<_, Body>(UpstreamConnector::with_root_certificates(true, std::slice::from_ref(&cert)).unwrap());
+        let resp = client.get(url.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.version(), Version::HTTP_2);
+        assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "{\"data\":[]}");
+        assert_eq!(*versions.lock().unwrap(), vec![Version::HTTP_11]);
+
+        // Clients that only speak HTTP/1.1 keep getting HTTP/1.1
+        let client = Client::builder().build::<_, Body>(UpstreamConnector::with_root_certificates(false, &[cert]).unwrap());
+        let resp = client.get(url.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.version(), Version::HTTP_11);
+
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_untrusted_upstreams() {
+        let (cert, key) = self_signed();
+        let acceptor = cfproxy::tls::acceptor_from_pem(&cert.to_pem().unwrap(), &key, None).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                cfproxy::tls::TlsStream::accept(&acceptor, stream).await.ok();
+            }
+        });
+
+        let client = Client::builder().build::<_, Body>(UpstreamConnector::new(true).unwrap());
+        let err = client.get(format!("https://localhost:{}/", port).parse().unwrap()).await.unwrap_err();
+        assert!(err.is_connect());
+    }
+}
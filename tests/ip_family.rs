@@ -0,0 +1,18 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::IpAddr;
+    use cfproxy::dns;
+
+    #[tokio::test]
+    async fn connects_over_one_ip_family_only() {
+        env::set_var("UPSTREAM_IP_FAMILY", "ipv4");
+        env::set_var("DNS_OVERRIDES", "dual.test=::1,dual.test=127.0.0.1,v6.test=::1");
+
+        assert_eq!(dns::resolve("dual.test").await.unwrap(), vec![IpAddr::from([127, 0, 0, 1])]);
+        assert_eq!(dns::resolve("127.0.0.1").await.unwrap(), vec![IpAddr::from([127, 0, 0, 1])]);
+        assert_eq!(dns::resolve("v6.test").await.unwrap_err().to_string(), "v6.test has no IPv4 addresses");
+        assert!(dns::resolve("[::1]").await.is_err());
+        assert!("ipv5".parse::<dns::IpFamily>().is_err());
+    }
+}
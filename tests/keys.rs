@@ -0,0 +1,84 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::time::Duration;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::keys::{check_keys, KeyCheck, KeyPool, Rotation, KEY_POOL};
+    use hyper::{Body, Response, StatusCode};
+    use crate::common::start_upstream_with;
+
+    #[test]
+    fn rotates_round_robin() {
+        let pool = KeyPool::new(["a", "b", "c"], Rotation::RoundRobin, Duration::from_secs(60)).unwrap();
+        let picked: Vec<usize> = (0..6).map(|_| pool.pick().index).collect();
+        assert_eq!(picked, vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn sidelines_rejected_keys() {
+        let pool = KeyPool::new(["a", "b"], Rotation::LeastUsed, Duration::from_secs(60)).unwrap();
+        let first = pool.pick();
+        pool.report(&first, StatusCode::OK);
+        assert_eq!(pool.healthy(), 2);
+
+        pool.report(&first, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(pool.healthy(), 1);
+        for _ in 0..3 {
+            assert_ne!(pool.pick().index, first.index);
+        }
+
+        // With every key sidelined, keys are still handed out
+        let second = pool.pick();
+        pool.report(&second, StatusCode::FORBIDDEN);
+        assert_eq!(pool.healthy(), 0);
+        assert_eq!(pool.pick().index, first.index);
+    }
+
+    #[test]
+    fn rejects_empty_pools() {
+        assert!(KeyPool::new([], Rotation::RoundRobin, Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn replaces_keys_keeping_state() {
+        let pool = KeyPool::new(["a", "b"], Rotation::RoundRobin, Duration::from_secs(60)).unwrap();
+        let a = pool.pick();
+        pool.report(&a, StatusCode::FORBIDDEN);
+
+        assert!(pool.replace_keys(["c", "a"]));
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.healthy(), 1, "Expected key `a` to stay sidelined");
+        for _ in 0..3 {
+            assert_eq!(pool.pick().key, "c");
+        }
+
+        // Reports for keys picked before the reload still find them
+        pool.report(&a, StatusCode::FORBIDDEN);
+        assert_eq!(pool.healthy(), 1);
+
+        assert!(!pool.replace_keys([]));
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn checks_keys_on_startup() {
+        // Upstream that only accepts the key `good`
+        let (upstream, _) = start_upstream_with(false, |_| (), |req| {
+            let status = match req.headers()["x-api-key"] == "good" {
+                true => StatusCode::OK,
+                false => StatusCode::FORBIDDEN,
+            };
+            Response::builder().status(status).body(Body::empty()).unwrap()
+        });
+        env::set_var("CF_API_URL", format!("http://{}", upstream));
+        env::set_var("CF_API_KEYS", "good,bad");
+
+        assert_eq!(check_keys(&ProxyConfig::from_env(), KeyCheck::Off).await, Ok(()));
+        assert_eq!(KEY_POOL.healthy(), 2);
+        assert_eq!(check_keys(&ProxyConfig::from_env(), KeyCheck::Warn).await, Ok(()));
+        assert_eq!(KEY_POOL.healthy(), 1);
+        assert!(check_keys(&ProxyConfig::from_env(), KeyCheck::Fail).await.unwrap_err().contains("#2"));
+    }
+}
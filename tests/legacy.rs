@@ -0,0 +1,106 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::env;
+    use std::time::Duration;
+    use cfproxy::cache::ResponseCache;
+    use cfproxy::compression::{gzip, Coding};
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::legacy::{legacy_mod, translate, Shape};
+    use cfproxy::server::{ProxyHandle, ProxyService, ProxyState};
+    use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+    use hyper::service::{make_service_fn, service_fn, Service};
+    use hyper::{Body, Client, Method, Request, Response, Server, StatusCode, Uri};
+    use serde_json::{json, Value};
+    use crate::common::start_upstream_with;
+
+    #[test]
+    fn translates_legacy_routes() {
+        let translated = |method: Method, uri: &str| translate(&method, &uri.parse().unwrap()).map(|translation| (translation.path, translation.shape));
+        assert_eq!(translated(Method::GET, "/api/v2/addon/238222"), Some((String::from("/v1/mods/238222"), Shape::Addon)));
+        assert_eq!(translated(Method::GET, "/api/v2/addon/238222/file/3456/download-url"), Some((String::from("/v1/mods/238222/files/3456/download-url"), Shape::DownloadUrl)));
+        assert_eq!(translated(Method::GET, "/api/v2/addon/search?gameId=432&sectionId=6&searchFilter=jei&unknown=1"), Some((String::from("/v1/mods/search?gameId=432&classId=6&searchFilter=jei"), Shape::Addons)));
+        assert_eq!(translated(Method::POST, "/api/v2/fingerprint"), Some((String::from("/v1/fingerprints"), Shape::Fingerprints)));
+        assert_eq!(translated(Method::GET, "/api/v2/addon/jei"), None);
+        assert_eq!(translated(Method::DELETE, "/api/v2/addon/238222"), None);
+        assert_eq!(translated(Method::GET, "/v1/mods/238222"), None);
+    }
+
+    #[test]
+    fn adds_legacy_fields() {
+        let reshaped = legacy_mod(json!({
+            "id": 238222,
+            "links": { "websiteUrl": "https://www.curseforge.com/minecraft/mc-mods/jei" },
+            "logo": { "id": 1, "title": "logo", "thumbnailUrl": "thumb", "url": "url" },
+            "latestFiles": [{ "id": 3456, "gameVersions": ["1.18.2"] }],
+        }));
+        assert_eq!(reshaped["websiteUrl"], "https://www.curseforge.com/minecraft/mc-mods/jei");
+        assert_eq!(reshaped["attachments"][0]["isDefault"], true);
+        assert_eq!(reshaped["latestFiles"][0]["gameVersion"], json!(["1.18.2"]));
+        assert_eq!(reshaped["links"]["websiteUrl"], "https://www.curseforge.com/minecraft/mc-mods/jei");
+    }
+
+    #[tokio::test]
+    async fn answers_like_the_legacy_api() {
+        let upstream = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let body = match req.uri().path() {
+                    "/v1/mods" => {
+                        let lookup: Value = serde_json::from_slice(&hyper::body::to_bytes(req.into_body()).await.unwrap()).unwrap();
+                        let mods: Vec<Value> = lookup["modIds"].as_array().unwrap().iter().map(|id| json!({ "id": id })).collect();
+                        json!({ "data": mods })
+                    }
+                    path => json!({ "data": format!("https://edge.forgecdn.net{}", path) }),
+                };
+                Ok::<_, Infallible>(Response::new(Body::from(body.to_string())))
+            }))
+        }));
+        env::set_var("CF_API_URL", format!("http://{}", upstream.local_addr()));
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("LEGACY_API", "true");
+        tokio::spawn(upstream);
+
+        let handle = ProxyHandle::start_with_state(([127, 0, 0, 1], 0).into(), ProxyState::new()).expect("Expected the proxy to start");
+        let uri: Uri = format!("http://{}/api/v2/addon", handle.local_addr()).parse().unwrap();
+        let response = Client::new().request(Request::post(uri).body(Body::from("[1, 2]")).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body[1]["id"], 2);
+        assert_eq!(body[1]["attachments"], json!([]));
+
+        let uri: Uri = format!("http://{}/api/v2/addon/1/file/2/download-url", handle.local_addr()).parse().unwrap();
+        let response = Client::new().get(uri).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "text/plain; charset=utf-8");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "https://edge.forgecdn.net/v1/mods/1/files/2/download-url");
+        handle.shutdown().await.expect("Expected the proxy to shut down");
+    }
+
+    #[tokio::test]
+    async fn answers_gzip_accepting_clients() {
+        // An upstream that compresses whenever it may
+        let (upstream, encodings) = start_upstream_with(false, |req| req.headers().get(ACCEPT_ENCODING).cloned(), |req| {
+            let body = json!({ "data": { "id": 238222, "links": {}, "latestFiles": [] } }).to_string();
+            match req.headers().get(ACCEPT_ENCODING).is_some_and(|encoding| encoding.to_str().unwrap().contains("gzip")) {
+                true => Response::builder().header(CONTENT_ENCODING, "gzip").body(Body::from(gzip(body.as_bytes()))).unwrap(),
+                false => Response::new(Body::from(body)),
+            }
+        });
+        env::set_var("LEGACY_API", "true");
+        let config = ProxyConfig::from_env().with_api_url(&format!("http://{}", upstream)).unwrap().with_api_keys(["key"]).unwrap();
+        let cache = ResponseCache::new(Duration::from_secs(60), 10).with_compression(Some(Coding::Gzip));
+        let mut service = ProxyService::new(ProxyState::new().with_config(config).with_cache(cache), [127, 0, 0, 1].into());
+
+        // The first response comes from Curseforge, the second from the compressed cache
+        for _ in 0..2 {
+            let request = Request::get("/api/v2/addon/238222").header(ACCEPT_ENCODING, "gzip").body(Body::empty()).unwrap();
+            let response = service.call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: Value = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+            assert_eq!(body["id"], 238222);
+        }
+        assert_eq!(encodings.lock().unwrap().len(), 1);
+    }
+}
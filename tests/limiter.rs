@@ -0,0 +1,55 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+    use cfproxy::limiter::{self, RateLimit, WaitQueue};
+    use cfproxy::server::{ProxyHandle, ProxyState};
+    use cfproxy::test_util::FakeRateLimiter;
+    use hyper::{Client, StatusCode, Uri};
+    use crate::common::start_upstream;
+
+    #[tokio::test]
+    async fn gives_up_on_waits_longer_than_the_max() {
+        let limiter = FakeRateLimiter::per_hour(1);
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert_eq!(limiter::until_ready(|| limiter.check_key(&ip), Some(Duration::from_secs(60))).await, Ok(false));
+        assert_eq!(limiter::until_ready(|| limiter.check_key(&ip), Some(Duration::from_secs(60))).await, Err(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn bounds_waiting_requests() {
+        let queue = WaitQueue::new(2, 3);
+        let first = queue.enter("a").unwrap();
+        let _second = queue.enter("a").unwrap();
+        assert!(queue.enter("a").is_none());
+
+        // The total bound applies across clients
+        let _third = queue.enter("b").unwrap();
+        assert!(queue.enter("c").is_none());
+        assert_eq!(queue.len(), 3);
+
+        drop(first);
+        assert!(queue.enter("a").is_some());
+    }
+
+    #[tokio::test]
+    async fn rejects_with_retry_after() {
+        let (upstream, _) = start_upstream(|_| ());
+        env::set_var("CF_API_URL", format!("http://{}", upstream));
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("RATE_LIMIT_MAX_WAIT_SECS", "60");
+
+        let state = ProxyState::new().with_rate_limiter(FakeRateLimiter::per_hour(1));
+        let handle = ProxyHandle::start_with_state(([127, 0, 0, 1], 0).into(), state).expect("Expected the proxy to start");
+        let uri: Uri = format!("http://{}/v1/games", handle.local_addr()).parse().unwrap();
+        assert_eq!(Client::new().get(uri.clone()).await.unwrap().status(), StatusCode::OK);
+
+        let response = Client::new().get(uri).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "3600");
+        handle.shutdown().await.expect("Expected the proxy to shut down");
+    }
+}
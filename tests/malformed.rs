@@ -0,0 +1,16 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::{IpAddr, Ipv4Addr};
+    use cfproxy::{proxy_request_to_cf, ProxyError};
+    use hyper::{Body, Method, Request, StatusCode};
+
+    #[tokio::test]
+    async fn rejects_requests_without_path() {
+        env::set_var("CF_API_KEY", "test");
+        let req = Request::builder().method(Method::CONNECT).uri("api.curseforge.com:443").body(Body::empty()).unwrap();
+        let err = proxy_request_to_cf(req, &IpAddr::V4(Ipv4Addr::LOCALHOST)).await.expect_err("Expected the request to be rejected");
+        assert!(matches!(err, ProxyError::MissingPath(_)), "{:?}", err);
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,41 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use cfproxy::metering::{self, ByteCounts};
+    use cfproxy::metrics::METRICS;
+    use cfproxy::server::{ProxyService, ProxyState};
+    use hyper::body::Bytes;
+    use hyper::service::Service;
+    use hyper::{Body, Request, Response};
+
+    #[tokio::test]
+    async fn counts_streamed_bodies() {
+        let (mut sender, request_body) = Body::channel();
+        let (req, received) = metering::meter_request(Request::post("/").body(request_body).unwrap());
+        tokio::spawn(async move {
+            sender.send_data(Bytes::from("abc")).await.unwrap();
+            sender.send_data(Bytes::from("de")).await.unwrap();
+        });
+        assert_eq!(hyper::body::to_bytes(req.into_body()).await.unwrap(), "abcde");
+
+        let (mut sender, response_body) = Body::channel();
+        let counts = Arc::new(Mutex::new(None));
+        let recorded = counts.clone();
+        let response = metering::meter_response(Response::new(response_body), received, move |bytes| {
+            *recorded.lock().unwrap() = Some(bytes);
+        });
+        tokio::spawn(async move {
+            sender.send_data(Bytes::from(vec![0; 1000])).await.unwrap();
+        });
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap().len(), 1000);
+        assert_eq!(*counts.lock().unwrap(), Some(ByteCounts { received: 5, sent: 1000 }));
+    }
+
+    #[tokio::test]
+    async fn records_bytes_of_proxied_requests() {
+        let mut service = ProxyService::new(ProxyState::new(), [127, 0, 0, 1].into());
+        let response = service.call(Request::get("/_routes").body(Body::empty()).unwrap()).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(METRICS.run_stats().bytes_sent >= body.len() as u64);
+    }
+}
@@ -0,0 +1,146 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::time::Duration;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::metrics::{self, endpoint_of, route_label, status_class, LatencyHistogram, Metrics, UNKNOWN_ROUTE};
+    use cfproxy::server::{ProxyService, ProxyState};
+    use hyper::service::Service;
+    use hyper::{Body, Request, Response, StatusCode};
+    use serde_json::json;
+    use crate::common::start_upstream_with;
+
+    #[test]
+    fn endpoints_group_ids() {
+        assert_eq!(endpoint_of("/v1/mods/238222/files/3573562"), "/v1/mods/{id}/files/{fileId}");
+        assert_eq!(endpoint_of("/v1/games"), "/v1/games");
+        assert_eq!(endpoint_of("/"), "/");
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let metrics = Metrics::default();
+        metrics.record_request("/v1/mods/1");
+        metrics.record_request("/v1/mods/2");
+        metrics.record_request("/wp-login.php");
+        metrics.record_request("/.env");
+        metrics.record_upstream_request();
+
+        let path = env::temp_dir().join(format!("cfproxy-metrics-{}.json", std::process::id()));
+        metrics.save_to(&path).expect("Expected snapshot to be written");
+
+        let restored = Metrics::default();
+        restored.load_from(&path).expect("Expected snapshot to be read");
+        std::fs::remove_file(&path).ok();
+
+        let snapshot = restored.snapshot();
+        assert_eq!(snapshot, metrics.snapshot());
+        assert_eq!(snapshot.total_requests, 4);
+        assert_eq!(snapshot.endpoints["/v1/mods/{id}"], 2);
+        assert_eq!(snapshot.endpoints[UNKNOWN_ROUTE], 2);
+        assert_eq!(snapshot.endpoints.len(), 2);
+        assert_eq!(snapshot.quota.used, 1);
+    }
+
+    #[test]
+    fn merges_snapshots_of_instances_sharing_the_file() {
+        let path = env::temp_dir().join(format!("cfproxy-metrics-handover-{}.json", std::process::id()));
+        std::fs::remove_file(&path).ok();
+
+        // The old instance has saved before the new one starts
+        let old = Metrics::default();
+        old.record_request("/v1/mods/1");
+        old.record_upstream_request();
+        old.save_to(&path).expect("Expected snapshot to be written");
+        let new = Metrics::default();
+        new.load_from(&path).expect("Expected snapshot to be read");
+
+        // Both handle requests during the handover, then the old one saves on shutdown & the new one later
+        old.record_request("/v1/mods/2");
+        new.record_request("/v1/games");
+        new.record_request("/v1/games");
+        new.record_upstream_request();
+        old.save_to(&path).expect("Expected snapshot to be written");
+        new.save_to(&path).expect("Expected snapshot to be written");
+        assert_eq!(new.snapshot().total_requests, 4);
+
+        let restored = Metrics::default();
+        restored.load_from(&path).expect("Expected snapshot to be read");
+        std::fs::remove_file(&path).ok();
+        let snapshot = restored.snapshot();
+        assert_eq!(snapshot, new.snapshot());
+        assert_eq!(snapshot.total_requests, 4);
+        assert_eq!(snapshot.endpoints["/v1/mods/{id}"], 2);
+        assert_eq!(snapshot.endpoints["/v1/games"], 2);
+        assert_eq!(snapshot.quota.used, 2);
+        assert!(!path.with_extension("lock").exists());
+    }
+
+    #[test]
+    fn labels_routes_by_template() {
+        assert_eq!(route_label("/v1/mods/238222"), "/v1/mods/{id}");
+        assert_eq!(route_label("/v1/mods/238222/files/3573562"), "/v1/mods/{id}/files/{fileId}");
+        assert_eq!(route_label("/_routes"), "/_routes");
+        assert_eq!(route_label("/v1/unknown/123"), UNKNOWN_ROUTE);
+        assert_eq!(route_label("/wp-login.php"), UNKNOWN_ROUTE);
+        assert_eq!((status_class(StatusCode::OK), status_class(StatusCode::BAD_GATEWAY)), ("2xx", "5xx"));
+    }
+
+    #[test]
+    fn histograms_estimate_quantiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.quantile_ms(0.5), None);
+        for ms in [1, 2, 3, 40, 40, 40, 40, 40, 90, 20_000] {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!((histogram.count, histogram.sum_ms, histogram.max_ms), (10, 20_296, 20_000));
+        assert_eq!(histogram.buckets.iter().sum::<u64>(), 10);
+        assert_eq!(histogram.quantile_ms(0.3), Some(5));
+        assert_eq!(histogram.quantile_ms(0.5), Some(50));
+        assert_eq!(histogram.quantile_ms(0.9), Some(100));
+        assert_eq!(histogram.quantile_ms(0.99), Some(20_000));
+    }
+
+    #[tokio::test]
+    async fn records_latencies_per_route_and_status() {
+        let metrics = Metrics::default();
+        metrics.record_latency(route_label("/v1/mods/1"), "2xx", Duration::from_millis(3));
+        metrics.record_latency(route_label("/v1/mods/2"), "2xx", Duration::from_millis(30));
+        metrics.record_latency(route_label("/v1/mods/3"), "5xx", Duration::from_millis(300));
+        let latencies = metrics.latencies();
+        assert_eq!(latencies.iter().map(|latency| (latency.route.as_str(), latency.status, latency.histogram.count)).collect::<Vec<_>>(),
+            [("/v1/mods/{id}", "2xx", 2), ("/v1/mods/{id}", "5xx", 1)]);
+
+        let mut service = ProxyService::new(ProxyState::new(), [127, 0, 0, 1].into());
+        let response = service.call(Request::get("/_routes").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let latencies = metrics::METRICS.latencies();
+        assert!(latencies.iter().any(|latency| latency.route == "/_routes" && latency.status == "2xx" && latency.histogram.count == 1), "{:?}", latencies);
+    }
+
+    #[tokio::test]
+    async fn counts_client_requests_once() {
+        let (upstream, calls) = start_upstream_with(false, |_| (), |req| {
+            let index: u64 = req.uri().query().unwrap().split('&')
+                .find_map(|param| param.strip_prefix("index="))
+                .map(|index| index.parse().unwrap())
+                .unwrap_or(0);
+            let page = json!({ "data": [index], "pagination": { "index": index, "pageSize": 50, "resultCount": 1, "totalCount": 150 } });
+            Response::new(Body::from(page.to_string()))
+        });
+        let config = ProxyConfig::from_env().with_api_url(&format!("http://{}", upstream)).unwrap().with_api_keys(["key"]).unwrap();
+        let mut service = ProxyService::new(ProxyState::new().with_config(config), [127, 0, 0, 1].into());
+
+        // All pages are fetched for one request
+        let request = Request::get("/v1/mods/search?gameId=432&x-proxy-paginate=all").body(Body::empty()).unwrap();
+        assert_eq!(service.call(request).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(calls.lock().unwrap().len(), 3);
+        assert_eq!(service.call(Request::get("/_status").body(Body::empty()).unwrap()).await.unwrap().status(), StatusCode::OK);
+
+        let snapshot = metrics::METRICS.snapshot();
+        assert_eq!(snapshot.endpoints["/v1/mods/search"], 1);
+        assert_eq!(snapshot.endpoints["/_status"], 1);
+    }
+}
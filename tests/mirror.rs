@@ -0,0 +1,106 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::env;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use cfproxy::mirror::s3::S3Mirror;
+    use cfproxy::mirror::{key, DiskMirror, MirrorStore};
+    use cfproxy::secrets::aws::Credentials;
+    use hyper::body::Bytes;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+    #[test]
+    fn keys_by_file_and_hash() {
+        assert_eq!(key(3456, "A94A8FE5CCB19BA61C4C0873D391E987982FBBD3"), Some(String::from("3456-a94a8fe5ccb19ba61c4c0873d391e987982fbbd3")));
+        assert_eq!(key(3456, "../../etc/passwd"), None);
+        assert_eq!(key(3456, ""), None);
+    }
+
+    #[tokio::test]
+    async fn serves_stored_files() {
+        let dir = env::temp_dir().join(format!("cfproxy-mirror-{}", std::process::id()));
+        let mirror = DiskMirror::new(dir.clone()).unwrap();
+        assert!(mirror.open("1-ab").await.is_none());
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data("jar ".into()).await.unwrap();
+            sender.send_data("bytes".into()).await.unwrap();
+        });
+        let stored = mirror.store("1-ab", Response::new(body));
+        assert_eq!(hyper::body::to_bytes(stored.into_body()).await.unwrap(), "jar bytes");
+
+        let mirrored = mirror.open("1-ab").await.expect("Expected the file to be mirrored");
+        assert_eq!(mirrored.headers()["content-length"], "9");
+        assert_eq!(hyper::body::to_bytes(mirrored.into_body()).await.unwrap(), "jar bytes");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Starts a fake S3 store that keeps the objects it's sent, as long as the request is signed.
+    fn start_s3() -> (String, Arc<Mutex<HashMap<String, Bytes>>>) {
+        let objects = Arc::new(Mutex::new(HashMap::new()));
+        let stored = objects.clone();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+            let objects = stored.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let objects = objects.clone();
+                    async move {
+                        let signed = req.headers().get("authorization")
+                            .is_some_and(|auth| auth.to_str().unwrap().starts_with("AWS4-HMAC-SHA256 Credential=id/"));
+                        let path = req.uri().path().to_string();
+                        let mut response = Response::new(Body::empty());
+                        match (signed, req.method().clone()) {
+                            (false, _) => *response.status_mut() = StatusCode::FORBIDDEN,
+                            (true, Method::PUT) => {
+                                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                                objects.lock().unwrap().insert(path, body);
+                            }
+                            (true, _) => match objects.lock().unwrap().get(&path) {
+                                Some(body) => *response.body_mut() = Body::from(body.clone()),
+                                None => *response.status_mut() = StatusCode::NOT_FOUND,
+                            },
+                        }
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        }));
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (url, objects)
+    }
+
+    #[tokio::test]
+    async fn serves_files_stored_in_s3() {
+        let (url, objects) = start_s3();
+        let credentials = Credentials { access_key_id: String::from("id"), secret_access_key: String::from("secret"), session_token: None };
+        let mirror = S3Mirror::new("mods", "eu-west-1", Some(&url), credentials).unwrap();
+        assert!(mirror.open("1-ab").await.is_none());
+
+        let response = Response::builder().header("content-length", 9).body(Body::from("jar bytes")).unwrap();
+        let stored = mirror.store("1-ab", response);
+        assert_eq!(hyper::body::to_bytes(stored.into_body()).await.unwrap(), "jar bytes");
+        // The upload finishes after the client got the last byte
+        for _ in 0..50 {
+            if !objects.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(objects.lock().unwrap().get("/mods/1-ab").map(Bytes::as_ref), Some(&b"jar bytes"[..]));
+
+        let mirrored = mirror.open("1-ab").await.expect("Expected the file to be mirrored");
+        assert_eq!(mirrored.headers()["content-length"], "9");
+        assert_eq!(hyper::body::to_bytes(mirrored.into_body()).await.unwrap(), "jar bytes");
+
+        // Files of unknown length can't be uploaded
+        let stored = mirror.store("2-cd", Response::new(Body::from("jar")));
+        assert_eq!(hyper::body::to_bytes(stored.into_body()).await.unwrap(), "jar");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!objects.lock().unwrap().contains_key("/mods/2-cd"));
+    }
+}
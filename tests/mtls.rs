@@ -0,0 +1,104 @@
+mod common;
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use cfproxy::server::{ProxyHandle, ProxyState};
+    use cfproxy::tls::client_identity;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkcs12::Pkcs12;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509NameBuilder, X509};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use crate::common::start_upstream;
+
+    /// Returns a certificate for `common_name` with its key, signed by `issuer` or self-signed.
+    fn certificate(common_name: Option<&str>, issuer: Option<&(X509, PKey<Private>)>, ca: bool) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        if let Some(common_name) = common_name {
+            name.append_entry_by_text("CN", common_name).unwrap();
+        }
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(issuer.map(|(issuer, _)| issuer.subject_name()).unwrap_or(&name)).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        if ca {
+            cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+        }
+        if common_name.is_none() {
+            let san = SubjectAlternativeName::new().dns("service.internal").build(&cert.x509v3_context(None, None)).unwrap();
+            cert.append_extension(san).unwrap();
+        }
+        cert.sign(issuer.map(|(_, key)| key).unwrap_or(&key), MessageDigest::sha256()).unwrap();
+        (cert.build(), key)
+    }
+
+    fn identity((cert, key): &(X509, PKey<Private>)) -> native_tls::Identity {
+        let pkcs12 = Pkcs12::builder().build("", "client", key, cert).unwrap();
+        native_tls::Identity::from_pkcs12(&pkcs12.to_der().unwrap(), "").unwrap()
+    }
+
+    /// Sends a request, returning the response or `None` if the proxy didn't answer.
+    async fn request(addr: std::net::SocketAddr, identity: Option<native_tls::Identity>) -> Option<String> {
+        let mut connector = native_tls::TlsConnector::builder();
+        connector.danger_accept_invalid_certs(true);
+        if let Some(identity) = identity {
+            connector.identity(identity);
+        }
+        let connector = tokio_native_tls::TlsConnector::from(connector.build().unwrap());
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector.connect("localhost", stream).await.ok()?;
+        stream.write_all(b"GET /v1/games HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.ok()?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.ok()?;
+        Some(String::from_utf8_lossy(&response).to_string()).filter(|response| response.starts_with("HTTP/1.1"))
+    }
+
+    #[test]
+    fn identifies_clients() {
+        let ca = certificate(Some("ca"), None, true);
+        assert_eq!(client_identity(&certificate(Some("launcher"), Some(&ca), false).0), Some(String::from("launcher")));
+        assert_eq!(client_identity(&certificate(None, Some(&ca), false).0), Some(String::from("service.internal")));
+    }
+
+    #[tokio::test]
+    async fn requires_client_certificates() {
+        let (upstream, _) = start_upstream(|_| ());
+        env::set_var("CF_API_URL", format!("http://{}", upstream));
+
+        let ca = certificate(Some("ca"), None, true);
+        let server = certificate(Some("localhost"), None, false);
+        let dir = env::temp_dir().join(format!("cfproxy-mtls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("ca.pem"), ca.0.to_pem().unwrap()).unwrap();
+        fs::write(dir.join("cert.pem"), server.0.to_pem().unwrap()).unwrap();
+        fs::write(dir.join("key.pem"), server.1.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("TLS_CERT_FILE", dir.join("cert.pem"));
+        env::set_var("TLS_KEY_FILE", dir.join("key.pem"));
+        env::set_var("TLS_CLIENT_CA_FILE", dir.join("ca.pem"));
+        let handle = ProxyHandle::start_with_state(([127, 0, 0, 1], 0).into(), ProxyState::new()).expect("Expected the proxy to start");
+
+        // Clients without a certificate of the CA are turned away
+        assert!(request(handle.local_addr(), None).await.is_none());
+        let stranger = certificate(Some("stranger"), None, false);
+        assert!(request(handle.local_addr(), Some(identity(&stranger))).await.is_none());
+
+        // Clients are rate limited by their certificate, not their IP
+        let alice = certificate(Some("alice"), Some(&ca), false);
+        let bob = certificate(Some("bob"), Some(&ca), false);
+        assert!(request(handle.local_addr(), Some(identity(&alice))).await.unwrap().starts_with("HTTP/1.1 200"));
+        assert!(request(handle.local_addr(), Some(identity(&bob))).await.unwrap().starts_with("HTTP/1.1 200"));
+        assert_eq!(handle.state().rate_limiter_keys(), 2);
+    }
+}
@@ -0,0 +1,96 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::env;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::paginate::{combine_pages, first_page, max_pages};
+    use cfproxy::server::{ProxyHandle, ProxyService, ProxyState};
+    use cfproxy::test_util::FakeRateLimiter;
+    use hyper::service::{make_service_fn, service_fn, Service};
+    use hyper::{Body, Client, Request, Response, Server, StatusCode, Uri};
+    use serde_json::{json, Value};
+    use crate::common::start_upstream_with;
+
+    #[test]
+    fn requests_largest_pages() {
+        let uri: Uri = "/v1/mods/search?gameId=432&x-proxy-paginate=all".parse().unwrap();
+        assert_eq!(first_page(&uri).as_deref(), Some("/v1/mods/search?gameId=432&pageSize=50"));
+        let uri: Uri = "/v1/mods/1/files?pageSize=1&x-proxy-paginate=all".parse().unwrap();
+        assert_eq!(first_page(&uri).as_deref(), Some("/v1/mods/1/files?pageSize=50"));
+        assert_eq!(first_page(&"/v1/mods/search?gameId=432".parse().unwrap()), None);
+        assert_eq!(first_page(&"/v1/games?x-proxy-paginate=all".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn combines_pages_up_to_the_cap() {
+        let pages = [
+            json!({ "data": [1, 2], "pagination": { "index": 0, "pageSize": 2, "resultCount": 2, "totalCount": 5 } }),
+            json!({ "data": [3, 4], "pagination": { "index": 2, "pageSize": 2, "resultCount": 2, "totalCount": 5 } }),
+        ];
+        assert_eq!(combine_pages(&pages, 3), Some(json!({
+            "data": [1, 2, 3],
+            "pagination": { "index": 0, "pageSize": 3, "resultCount": 3, "totalCount": 5 },
+        })));
+        assert_eq!(combine_pages(&[json!({ "error": "nope" })], 3), None);
+    }
+
+    #[tokio::test]
+    async fn follows_pagination() {
+        let upstream = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let index: u64 = req.uri().query().unwrap().split('&')
+                    .find_map(|param| param.strip_prefix("index="))
+                    .map(|index| index.parse().unwrap())
+                    .unwrap_or(0);
+                let data: Vec<u64> = (index..(index + 50).min(120)).collect();
+                let page = json!({ "data": data, "pagination": { "index": index, "pageSize": 50, "resultCount": data.len(), "totalCount": 120 } });
+                Ok::<_, Infallible>(Response::new(Body::from(page.to_string())))
+            }))
+        }));
+        env::set_var("CF_API_URL", format!("http://{}", upstream.local_addr()));
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("PAGINATE_MAX_RESULTS", "500");
+        tokio::spawn(upstream);
+
+        let handle = ProxyHandle::start_with_state(([127, 0, 0, 1], 0).into(), ProxyState::new()).expect("Expected the proxy to start");
+        let uri: Uri = format!("http://{}/v1/mods/search?gameId=432&x-proxy-paginate=all", handle.local_addr()).parse().unwrap();
+        let response = Client::new().get(uri).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["data"].as_array().unwrap().len(), 120);
+        assert_eq!(body["data"][119], 119);
+        assert_eq!(body["pagination"]["totalCount"], 120);
+        handle.shutdown().await.expect("Expected the proxy to shut down");
+    }
+
+    #[tokio::test]
+    async fn bounds_the_pages_and_charges_each() {
+        // An upstream that pages one result at a time, however many were asked for
+        let (upstream, queries) = start_upstream_with(false, |req| req.uri().query().unwrap_or_default().to_string(), |req| {
+            let index: u64 = req.uri().query().unwrap().split('&')
+                .find_map(|param| param.strip_prefix("index="))
+                .map(|index| index.parse().unwrap())
+                .unwrap_or(0);
+            let page = json!({ "data": [index], "pagination": { "index": index, "pageSize": 1, "resultCount": 1, "totalCount": 10_000 } });
+            Response::new(Body::from(page.to_string()))
+        });
+        env::set_var("PAGINATE_MAX_RESULTS", "500");
+        let config = ProxyConfig::from_env().with_api_url(&format!("http://{}", upstream)).unwrap().with_api_keys(["key"]).unwrap();
+        let limiter = FakeRateLimiter::per_hour(1000);
+        let state = ProxyState::new().with_config(config).with_rate_limiter(limiter.clone());
+        let mut service = ProxyService::new(state, [127, 0, 0, 1].into());
+
+        let request = Request::get("/v1/mods/search?gameId=432&pageSize=1&x-proxy-paginate=all").body(Body::empty()).unwrap();
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["data"].as_array().unwrap().len(), max_pages());
+
+        let queries = queries.lock().unwrap();
+        assert_eq!(queries.len(), max_pages());
+        assert!(queries.iter().all(|query| query.contains("pageSize=50")), "{:?}", queries);
+        assert_eq!(limiter.checks(), max_pages() as u64);
+    }
+}
@@ -0,0 +1,163 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::env;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use cfproxy::cache::Cache;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::prefetch::next_page;
+    use cfproxy::server::{ProxyService, ProxyState};
+    use cfproxy::test_util::FakeCache;
+    use hyper::service::{make_service_fn, service_fn, Service};
+    use hyper::header::{CONTENT_TYPE, SET_COOKIE};
+    use hyper::{Body, Request, Response, Server, StatusCode, Uri};
+    use serde_json::{json, Value};
+    use crate::common::start_upstream_with;
+
+    fn page(index: u64, page_size: u64, total_count: u64) -> String {
+        format!(r#"{{"data":[],"pagination":{{"index":{},"pageSize":{},"resultCount":{},"totalCount":{}}}}}"#,
+            index, page_size, page_size, total_count)
+    }
+
+    /// Sets the env variables all tests of this file rely on - they're read once per process, by whichever
+    /// test comes first.
+    fn configure() {
+        env::set_var("CACHE_TTL_SECS", "60");
+        env::set_var("PREFETCH_NEXT_PAGE", "true");
+        env::set_var("DOWNLOAD_URL_BASE", "https://proxy.example.com/download");
+    }
+
+    #[test]
+    fn moves_index_by_page_size() {
+        let uri: Uri = "/v1/mods/search?gameId=432&index=20&pageSize=20&sortOrder=desc".parse().unwrap();
+        assert_eq!(next_page(&uri, page(20, 20, 100).as_bytes()).as_deref(),
+            Some("/v1/mods/search?gameId=432&index=40&pageSize=20&sortOrder=desc"));
+
+        let uri: Uri = "/v1/mods/search?gameId=432".parse().unwrap();
+        assert_eq!(next_page(&uri, page(0, 50, 100).as_bytes()).as_deref(), Some("/v1/mods/search?gameId=432&index=50"));
+    }
+
+    #[test]
+    fn stops_at_last_page() {
+        let uri: Uri = "/v1/mods/search?gameId=432&index=80&pageSize=20".parse().unwrap();
+        assert_eq!(next_page(&uri, page(80, 20, 100).as_bytes()), None);
+        assert_eq!(next_page(&uri, br#"{"data":{"id":1}}"#), None);
+    }
+
+    #[tokio::test]
+    async fn serves_prefetched_page_from_cache() {
+        // Upstream that records which pages it was asked for
+        let requested = Arc::new(Mutex::new(Vec::<String>::new()));
+        let recorder = Arc::clone(&requested);
+        let upstream = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+            let recorder = Arc::clone(&recorder);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let query = req.uri().query().unwrap_or("").to_string();
+                    recorder.lock().unwrap().push(query.clone());
+                    let index = query.split('&').find_map(|p| p.strip_prefix("index=")).unwrap_or("0").parse().unwrap();
+                    async move { Ok::<_, Infallible>(Response::new(Body::from(page(index, 10, 100)))) }
+                }))
+            }
+        }));
+        env::set_var("CF_API_URL", format!("http://{}", upstream.local_addr()));
+        env::set_var("CF_API_KEY", "key");
+        configure();
+        tokio::spawn(upstream);
+
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let first = Request::get("/v1/mods/search?gameId=432&index=0&pageSize=10").body(Body::empty()).unwrap();
+        let first = cfproxy::proxy_request_to_cf(first, &ip).await.unwrap();
+        assert_eq!(hyper::body::to_bytes(first.into_body()).await.unwrap(), page(0, 10, 100));
+
+        // Wait for the prefetch to land in the cache
+        let next_key = "/v1/mods/search?gameId=432&index=10&pageSize=10";
+        for _ in 0..100 {
+            if cfproxy::cache::CACHE.contains(next_key) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let second = Request::get(next_key).body(Body::empty()).unwrap();
+        let second = cfproxy::proxy_request_to_cf(second, &ip).await.unwrap();
+        assert_eq!(hyper::body::to_bytes(second.into_body()).await.unwrap(), page(10, 10, 100));
+
+        // The second page was only requested once, by the prefetch - which stops there, as it doesn't prefetch itself
+        assert_eq!(*requested.lock().unwrap(), vec![
+            "gameId=432&index=0&pageSize=10".to_string(),
+            "gameId=432&index=10&pageSize=10".to_string(),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn paginating_requests_each_page_once() {
+        let (upstream, queries) = start_upstream_with(false, |req| req.uri().query().unwrap_or_default().to_string(), |req| {
+            let index = req.uri().query().unwrap().split('&').find_map(|p| p.strip_prefix("index=")).unwrap_or("0").parse().unwrap();
+            Response::new(Body::from(page(index, 50, 150)))
+        });
+        configure();
+        let config = ProxyConfig::from_env().with_api_url(&format!("http://{}", upstream)).unwrap().with_api_keys(["key"]).unwrap();
+        let state = ProxyState::new().with_config(config).with_cache(FakeCache::new());
+        let mut service = ProxyService::new(state, [127, 0, 0, 1].into());
+
+        let request = Request::get("/v1/mods/search?gameId=1&x-proxy-paginate=all").body(Body::empty()).unwrap();
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Give prefetches that shouldn't have been started the time to reach the upstream
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*queries.lock().unwrap(), vec![
+            "gameId=1&pageSize=50".to_string(),
+            "gameId=1&pageSize=50&index=50".to_string(),
+            "gameId=1&pageSize=50&index=100".to_string(),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn prepares_prefetched_pages_like_others() {
+        let (upstream, queries) = start_upstream_with(false, |req| req.uri().query().unwrap_or_default().to_string(), |req| {
+            let index: u64 = req.uri().query().unwrap().split('&').find_map(|p| p.strip_prefix("index=")).unwrap_or("0").parse().unwrap();
+            let page = json!({
+                "data": [{ "id": index + 1, "modId": 7, "downloadUrl": "https://edge.forgecdn.net/files/1/1/mod.jar" }],
+                "pagination": { "index": index, "pageSize": 1, "resultCount": 1, "totalCount": 2 },
+            });
+            Response::builder()
+                .header(CONTENT_TYPE, "application/json; charset=utf-8")
+                .header(SET_COOKIE, "session=upstream")
+                .header("cf-ray", "1234-FRA")
+                .body(Body::from(page.to_string()))
+                .unwrap()
+        });
+        configure();
+        let config = ProxyConfig::from_env().with_api_url(&format!("http://{}", upstream)).unwrap().with_api_keys(["key"]).unwrap();
+        let cache = FakeCache::new();
+        let state = ProxyState::new().with_config(config).with_cache(cache.clone());
+        let mut service = ProxyService::new(state, [127, 0, 0, 1].into());
+
+        let first = Request::get("/v1/mods/7/files?gameId=2&index=0&pageSize=1").body(Body::empty()).unwrap();
+        assert_eq!(service.call(first).await.unwrap().status(), StatusCode::OK);
+        let next_key = "/v1/mods/7/files?gameId=2&index=1&pageSize=1";
+        for _ in 0..100 {
+            if cache.contains(next_key) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let second = Request::get(next_key).body(Body::empty()).unwrap();
+        let second = service.call(second).await.unwrap();
+        assert_eq!(second.status(), StatusCode::OK);
+        assert!(second.headers().get(SET_COOKIE).is_none());
+        assert!(second.headers().get("cf-ray").is_none());
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(second.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["data"][0]["downloadUrl"], "https://proxy.example.com/download/7/2");
+
+        // The second page came from the cache
+        assert_eq!(queries.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,27 @@
+#[cfg(test)]
+mod tests {
+    use cfproxy::profile::{env_or, Profile};
+
+    #[test]
+    fn parses_profiles() {
+        assert_eq!("public-community".parse::<Profile>(), Ok(Profile::PublicCommunity));
+        assert_eq!("single-user".parse::<Profile>(), Ok(Profile::SingleUser));
+        assert!("huge".parse::<Profile>().is_err());
+    }
+
+    #[test]
+    fn presets_set_defaults() {
+        assert_eq!(Profile::PublicCommunity.default_for("CACHE_TTL_SECS"), Some("300"));
+        assert_eq!(Profile::SingleUser.default_for("PREFETCH_NEXT_PAGE"), Some("false"));
+        assert_eq!(Profile::PrivateTeam.default_for("PORT"), None);
+    }
+
+    #[test]
+    fn explicit_settings_win() {
+        std::env::set_var("LIMITS_PROFILE", "public-community");
+        std::env::set_var("CACHE_MAX_ENTRIES", "5");
+        assert_eq!(env_or("CACHE_MAX_ENTRIES", "10000"), "5");
+        assert_eq!(env_or("CACHE_TTL_SECS", "0"), "300");
+        assert_eq!(env_or("KEY_SIDELINE_SECS", "300"), "300");
+    }
+}
@@ -0,0 +1,71 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::SocketAddr;
+    use cfproxy::proxy_protocol::{parse_header, ProxyHeader};
+    use cfproxy::server::ProxyHandle;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        header.extend_from_slice(&[0x20 | command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn parses_v1() {
+        let header = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(parse_header(header), Ok(Some(ProxyHeader { source: Some("203.0.113.7:51234".parse().unwrap()), len: 43 })));
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 443\r\n";
+        assert_eq!(parse_header(header).unwrap().unwrap().source, Some("[2001:db8::1]:51234".parse().unwrap()));
+        assert_eq!(parse_header(b"PROXY UNKNOWN\r\n"), Ok(Some(ProxyHeader { source: None, len: 15 })));
+
+        assert_eq!(parse_header(b"PROXY TCP4 203.0"), Ok(None));
+        assert!(parse_header(b"PROXY TCP4 nonsense\r\n").is_err());
+        assert!(parse_header(b"GET / HTTP/1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn parses_v2() {
+        let ipv4 = v2(1, 0x11, &[203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x01, 0xbb]);
+        assert_eq!(parse_header(&ipv4), Ok(Some(ProxyHeader { source: Some("203.0.113.7:51234".parse().unwrap()), len: 28 })));
+
+        let mut addresses = [0u8; 36];
+        addresses[..16].copy_from_slice(&"2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        addresses[32..34].copy_from_slice(&51234u16.to_be_bytes());
+        let source: SocketAddr = "[2001:db8::1]:51234".parse().unwrap();
+        assert_eq!(parse_header(&v2(1, 0x21, &addresses)).unwrap().unwrap().source, Some(source));
+
+        // Health checks of the load balancer itself
+        assert_eq!(parse_header(&v2(0, 0x00, &[])), Ok(Some(ProxyHeader { source: None, len: 16 })));
+
+        assert_eq!(parse_header(&ipv4[..20]), Ok(None));
+        assert_eq!(parse_header(&ipv4[..5]), Ok(None));
+        assert!(parse_header(&v2(1, 0x11, &[203, 0, 113])).is_err());
+    }
+
+    #[tokio::test]
+    async fn uses_client_address_from_header() {
+        env::set_var("CF_API_KEY", "test");
+        env::set_var("PROXY_PROTOCOL", "true");
+        env::set_var("ACCESS_RULES", "deny from 203.0.113.7");
+        let handle = ProxyHandle::start(([127, 0, 0, 1], 0).into()).expect("Expected the proxy to start");
+
+        let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+        stream.write_all(b"PROXY TCP4 203.0.113.7 127.0.0.1 51234 3000\r\nGET /v1/games HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 403"), "unexpected response: {}", response);
+
+        // Connections without a header are closed
+        let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+        stream.write_all(b"GET /v1/games HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "");
+        handle.shutdown().await.expect("Expected the proxy to shut down");
+    }
+}
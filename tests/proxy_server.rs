@@ -0,0 +1,43 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::TcpListener;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::listener::{Binding, ListenAddr};
+    use cfproxy::routes::RouteSet;
+    use cfproxy::server::ProxyServer;
+    use hyper::Client;
+    use tokio::sync::oneshot;
+
+    fn binding(listener: &TcpListener) -> Binding {
+        Binding { addr: ListenAddr::Tcp(listener.local_addr().unwrap()), routes: RouteSet::All }
+    }
+
+    #[tokio::test]
+    async fn runs_until_shut_down() {
+        env::set_var("STARTUP_KEY_CHECK", "off");
+        let config = || ProxyConfig::from_env().with_api_keys(["key"]).unwrap();
+
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let err = ProxyServer::new(config()).listen(vec![binding(&taken)]).run(async {}).await.unwrap_err();
+        assert!(err.starts_with("Could not listen at"), "{}", err);
+
+        let free = binding(&TcpListener::bind("127.0.0.1:0").unwrap());
+        let addr = free.addr.clone();
+        let (shutdown, shutdown_received) = oneshot::channel::<()>();
+        let server = tokio::spawn(ProxyServer::new(config()).listen(vec![free]).run(async {
+            shutdown_received.await.ok();
+        }));
+
+        let url = format!("http://{}/_routes", addr);
+        let mut resp = Client::new().get(url.parse().unwrap()).await;
+        while resp.is_err() {
+            tokio::task::yield_now().await;
+            resp = Client::new().get(url.parse().unwrap()).await;
+        }
+        assert_eq!(resp.unwrap().status(), 200);
+
+        shutdown.send(()).unwrap();
+        assert_eq!(server.await.unwrap(), Ok(()));
+    }
+}
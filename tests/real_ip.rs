@@ -0,0 +1,43 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::IpAddr;
+    use cfproxy::get_real_ip_addr;
+    use hyper::header::HeaderValue;
+    use hyper::{Body, Request};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn uses_rightmost_untrusted_forwarded_hop() {
+        env::set_var("TRUSTED_PROXIES", "172.16.0.0/12, fdaa::/16, 10.0.0.0/8");
+        env::set_var("REAL_IP_HEADER", "X-Forwarded-For");
+
+        // The client claims to be 1.2.3.4, the edge saw 198.51.100.7, an internal balancer forwarded it
+        let req = Request::get("/v1/games")
+            .header("X-Forwarded-For", "1.2.3.4, 198.51.100.7")
+            .header("X-Forwarded-For", "10.1.2.3")
+            .body(Body::empty()).unwrap();
+        assert_eq!(get_real_ip_addr(&req, &ip("172.19.0.2")), ip("198.51.100.7"));
+        assert_eq!(get_real_ip_addr(&req, &ip("fdaa:0:1::3")), ip("198.51.100.7"));
+
+        // Untrusted connections can't pick their address
+        assert_eq!(get_real_ip_addr(&req, &ip("203.0.113.9")), ip("203.0.113.9"));
+
+        // Only trusted hops, or garbage
+        let req = Request::get("/").header("X-Forwarded-For", "10.0.0.1, 10.0.0.2").body(Body::empty()).unwrap();
+        assert_eq!(get_real_ip_addr(&req, &ip("172.19.0.2")), ip("10.0.0.1"));
+        let req = Request::get("/").header("X-Forwarded-For", "unknown").body(Body::empty()).unwrap();
+        assert_eq!(get_real_ip_addr(&req, &ip("172.19.0.2")), ip("172.19.0.2"));
+        let req = Request::get("/").header("X-Forwarded-For", HeaderValue::from_bytes(b"1.2.3.4\xff").unwrap()).body(Body::empty()).unwrap();
+        assert_eq!(get_real_ip_addr(&req, &ip("172.19.0.2")), ip("172.19.0.2"));
+
+        // Garbage left of the client doesn't drop the hops right of it
+        let req = Request::get("/").header("X-Forwarded-For", "garbage, 1.2.3.4, 198.51.100.7").body(Body::empty()).unwrap();
+        assert_eq!(get_real_ip_addr(&req, &ip("172.19.0.2")), ip("198.51.100.7"));
+        let req = Request::get("/").header("X-Forwarded-For", "1.2.3.4, garbage, 10.0.0.1").body(Body::empty()).unwrap();
+        assert_eq!(get_real_ip_addr(&req, &ip("172.19.0.2")), ip("10.0.0.1"));
+    }
+}
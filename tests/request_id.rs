@@ -0,0 +1,56 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::request_id::{self, RequestId};
+    use cfproxy::server::{ProxyService, ProxyState};
+    use hyper::service::Service;
+    use hyper::{Body, Request};
+    use crate::common::start_upstream;
+
+    #[tokio::test]
+    async fn adopts_or_generates_ids_and_passes_them_on() {
+        let (upstream, ids) = start_upstream(|req| req.headers().get("x-request-id").map(|id| id.to_str().unwrap().to_string()).unwrap_or_default());
+        let config = ProxyConfig::from_env().with_api_url(&format!("http://{}", upstream)).unwrap().with_api_keys(["key"]).unwrap();
+        let mut service = ProxyService::new(ProxyState::new().with_config(config), [127, 0, 0, 1].into());
+        let mut get = |id: Option<&str>| {
+            let mut req = Request::get("/v1/games");
+            if let Some(id) = id {
+                req = req.header("X-Request-Id", id);
+            }
+            service.call(req.body(Body::empty()).unwrap())
+        };
+
+        let response = get(Some("client-4f2a:1")).await.unwrap();
+        assert_eq!(response.headers()["x-request-id"], "client-4f2a:1");
+
+        let response = get(None).await.unwrap();
+        let generated = response.headers()["x-request-id"].to_str().unwrap().to_string();
+        assert!(generated.len() == 32 && generated.bytes().all(|byte| byte.is_ascii_hexdigit()));
+
+        // Ids that would make a mess of log lines are replaced
+        let response = get(Some("two words")).await.unwrap();
+        let replaced = response.headers()["x-request-id"].to_str().unwrap().to_string();
+        assert_ne!(replaced, "two words");
+
+        assert_eq!(*ids.lock().unwrap(), vec!["client-4f2a:1".to_string(), generated, replaced]);
+    }
+
+    #[tokio::test]
+    async fn tags_log_lines_of_the_current_request() {
+        assert_eq!(request_id::tag(), "");
+        let id = RequestId::generate();
+        let (tag, prefix) = request_id::scope(id.clone(), async { (request_id::tag(), request_id::prefix()) }).await;
+        assert_eq!(tag, format!(" {}", id));
+        assert_eq!(prefix, format!("[{}] ", id));
+    }
+
+    #[tokio::test]
+    async fn passes_ids_on_to_spawned_tasks() {
+        let id = RequestId::generate();
+        let spawned = request_id::scope(id.clone(), async { request_id::spawn(async { request_id::current() }).await }).await;
+        assert_eq!(spawned.unwrap(), Some(id));
+        assert_eq!(request_id::spawn(async { request_id::current() }).await.unwrap(), None);
+    }
+}
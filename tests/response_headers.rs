@@ -0,0 +1,23 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use cfproxy::response_headers::RESPONSE_HEADER_POLICY;
+    use hyper::header::HeaderValue;
+    use hyper::HeaderMap;
+
+    #[test]
+    fn strips_defaults_and_overrides() {
+        env::set_var("RESPONSE_HEADERS_STRIP", "X-Internal-*");
+        env::set_var("RESPONSE_HEADERS_KEEP", "x-ratelimit-remaining");
+
+        let mut headers = HeaderMap::new();
+        for name in ["content-type", "set-cookie", "cf-ray", "server", "x-powered-by", "x-ratelimit-limit", "x-ratelimit-remaining", "x-internal-node"] {
+            headers.insert(name, HeaderValue::from_static("value"));
+        }
+        RESPONSE_HEADER_POLICY.apply(&mut headers);
+
+        let mut forwarded: Vec<&str> = headers.keys().map(|name| name.as_str()).collect();
+        forwarded.sort_unstable();
+        assert_eq!(forwarded, vec!["content-type", "x-ratelimit-remaining"]);
+    }
+}
@@ -0,0 +1,34 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::{IpAddr, Ipv4Addr};
+    use cfproxy::metrics::METRICS;
+    use cfproxy::proxy_request_to_cf;
+    use hyper::{Body, Request, StatusCode};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn retries_dropped_connections() {
+        // Upstream that drops the first connection without answering, and answers the second
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        env::set_var("CF_API_URL", format!("http://{}", listener.local_addr().unwrap()));
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("RETRY_BACKOFF_MS", "1");
+        tokio::spawn(async move {
+            let (mut dropped, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = dropped.read(&mut buf).await.unwrap();
+            drop(dropped);
+
+            let (mut answered, _) = listener.accept().await.unwrap();
+            let _ = answered.read(&mut buf).await.unwrap();
+            answered.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}").await.unwrap();
+        });
+
+        let req = Request::get("/v1/games").body(Body::empty()).unwrap();
+        let response = proxy_request_to_cf(req, &IpAddr::V4(Ipv4Addr::LOCALHOST)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(METRICS.run_stats().upstream_retries, 1);
+    }
+}
@@ -0,0 +1,47 @@
+#[cfg(all(test, unix))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use cfproxy::listener::{Binding, ListenAddr};
+    use cfproxy::routes::RouteSet;
+    use cfproxy::server::{ProxyHandle, ProxyState};
+    use hyper::{Client, StatusCode};
+
+    fn binding(addr: ListenAddr) -> [Binding; 1] {
+        [Binding { addr, routes: RouteSet::All }]
+    }
+
+    #[tokio::test]
+    async fn hands_over_tcp_addresses() {
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("REUSE_PORT", "true");
+        let old = ProxyHandle::start_bindings(&binding(ListenAddr::Tcp(([127, 0, 0, 1], 0).into())), ProxyState::new()).unwrap();
+        let addr = old.local_addr();
+
+        // A new instance binds the same address while the old one is still running, then the old one drains
+        let new = ProxyHandle::start_bindings(&binding(ListenAddr::Tcp(addr)), ProxyState::new()).expect("Expected the address to be shared");
+        old.shutdown().await.unwrap();
+
+        let resp = Client::new().get(format!("http://{}/_routes", addr).parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        new.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn takes_over_unix_sockets() {
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("REUSE_PORT", "true");
+        let dir = env::temp_dir().join(format!("cfproxy-reuse-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cfproxy.sock");
+
+        let old = ProxyHandle::start_bindings(&binding(ListenAddr::Unix(path.clone())), ProxyState::new()).unwrap();
+        let new = ProxyHandle::start_bindings(&binding(ListenAddr::Unix(path.clone())), ProxyState::new()).expect("Expected the socket to be taken over");
+
+        // The old instance leaves the new one's socket in place
+        old.shutdown().await.unwrap();
+        assert!(path.exists());
+        new.shutdown().await.unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,130 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use cfproxy::cache::{Cache, CachedResponse, ResponseCache};
+    use cfproxy::classify::classify;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::routes::{allowed_methods, handle_local, is_allowed, normalize_base_path, strip_base_path, OriginalUri, RouteTable};
+    use cfproxy::server::ProxyState;
+    use hyper::{Body, HeaderMap, Method, Request, StatusCode};
+
+    #[tokio::test]
+    async fn lists_routes() {
+        let req = Request::get("/_routes").body(Body::empty()).unwrap();
+        let response = handle_local(&req, &ProxyState::new()).expect("Expected /_routes to be a local route");
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let table: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(table["local"].as_array().unwrap().iter().any(|route| route["path"] == "/_routes"));
+        let proxied = table["proxied"].as_array().unwrap();
+        assert!(proxied.iter().any(|route| route["pattern"] == "/v1/mods/{id}/files/{fileId}"));
+        assert_eq!(proxied.last().unwrap()["pattern"], "/v1/**");
+    }
+
+    #[test]
+    fn leaves_other_paths_to_the_proxy() {
+        let req = Request::get("/v1/games").body(Body::empty()).unwrap();
+        assert!(handle_local(&req, &ProxyState::new()).is_none());
+
+        let req = Request::builder().method(Method::POST).uri("/_routes").body(Body::empty()).unwrap();
+        let response = handle_local(&req, &ProxyState::new()).unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()["allow"], "GET");
+    }
+
+    #[test]
+    fn only_allows_api_paths_by_default() {
+        let config = ProxyConfig::from_env();
+        assert!(is_allowed("/v1/mods/238222", &config));
+        assert!(is_allowed("/v1", &config));
+        assert!(!is_allowed("/wp-login.php", &config));
+        assert!(!is_allowed("/.env", &config));
+        assert!(!is_allowed("/v1.php", &config));
+
+        let config = ProxyConfig::from_env().with_allowed_paths(["/v1/games", "/v1/mods/*"]);
+        assert!(is_allowed("/v1/games", &config));
+        assert!(is_allowed("/v1/mods/238222", &config));
+        assert!(!is_allowed("/v1/mods/238222/files", &config));
+    }
+
+    #[tokio::test]
+    async fn suggests_poll_intervals() {
+        let req = Request::get("/_hints").body(Body::empty()).unwrap();
+        let response = handle_local(&req, &ProxyState::new()).expect("Expected /_hints to be a local route");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let hints: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        // 21600 requests per hour by default, one every 1/6 s
+        assert_eq!(hints["requests_per_hour"], 21600);
+        assert_eq!(hints["min_poll_interval_secs"], 1);
+        assert_eq!(hints["degraded"], false);
+        let route = hints["routes"].as_array().unwrap().iter().find(|route| route["template"] == "/v1/mods/{id}").unwrap();
+        assert_eq!(route["suggested_poll_interval_secs"], 1);
+    }
+
+    #[tokio::test]
+    async fn reports_status() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 10);
+        cache.insert("/v1/games".to_string(), CachedResponse::new(StatusCode::OK, HeaderMap::new(), "{}".into()));
+        cache.get("/v1/games");
+        let req = Request::get("/_status").body(Body::empty()).unwrap();
+        let state = ProxyState::new().with_cache(cache).with_config(ProxyConfig::from_env().with_api_keys(["key"]).unwrap());
+        let response = handle_local(&req, &state).expect("Expected /_status to be a local route");
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert!(status["uptime_secs"].is_u64());
+        assert!(status["run"]["requests"].is_u64());
+        assert_eq!(status["in_flight"]["upstream_calls"], 0);
+        assert_eq!((&status["cache"]["entries"], &status["cache"]["hits"]), (&1.into(), &1.into()));
+        assert_eq!(status["rate_limiter"]["keys"], 0);
+        assert_eq!(status["upstream"]["circuit_breaker"], "closed");
+        assert_eq!((&status["upstream"]["healthy_api_keys"], &status["upstream"]["api_keys"]), (&1.into(), &1.into()));
+        assert!(status["latencies"].is_array());
+
+        let req = Request::get("/status").body(Body::empty()).unwrap();
+        let response = handle_local(&req, &state).expect("Expected /status to be a local route");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let alias: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(alias["upstream"], status["upstream"]);
+    }
+
+    #[test]
+    fn restricts_methods_per_route() {
+        assert_eq!(allowed_methods(&classify("/v1/mods/238222")), &["GET", "HEAD"]);
+        assert_eq!(allowed_methods(&classify("/v1/fingerprints/432")), &["POST"]);
+        assert_eq!(allowed_methods(&classify("/v1/mods")), &["POST"]);
+        assert_eq!(allowed_methods(&classify("/v1/not-yet-known")), &["GET", "HEAD", "POST"]);
+    }
+
+    #[test]
+    fn strips_base_path() {
+        assert_eq!(normalize_base_path(" cfproxy/ "), "/cfproxy");
+        assert_eq!(normalize_base_path("/"), "");
+
+        let stripped = |uri: &str| {
+            let mut req = Request::get(uri).body(Body::empty()).unwrap();
+            strip_base_path(&mut req, "/cfproxy").then(|| req.uri().to_string())
+        };
+        assert_eq!(stripped("/cfproxy/v1/games?index=1").as_deref(), Some("/v1/games?index=1"));
+        assert_eq!(stripped("/cfproxy?index=1").as_deref(), Some("/?index=1"));
+        assert_eq!(stripped("/cfproxyx/v1/games"), None);
+        assert_eq!(stripped("/v1/games"), None);
+
+        let mut req = Request::get("/cfproxy/v1/games").body(Body::empty()).unwrap();
+        assert!(strip_base_path(&mut req, "/cfproxy"));
+        assert_eq!(req.extensions().get::<OriginalUri>().unwrap().0, "/cfproxy/v1/games");
+    }
+
+    #[test]
+    fn lists_routes_under_base_path() {
+        let state = ProxyState::new().with_config(ProxyConfig::from_env().with_base_path("/cfproxy/"));
+        let table = RouteTable::collect(&state);
+        assert_eq!(table.base_path, "/cfproxy");
+        assert!(table.local.iter().any(|route| route.path == "/cfproxy/_routes"));
+        assert!(table.proxied.iter().any(|route| route.pattern == "/cfproxy/v1/mods/{id}"));
+        assert!(table.proxied.iter().all(|route| route.pattern.starts_with("/cfproxy/")));
+    }
+}
@@ -0,0 +1,45 @@
+#[cfg(test)]
+mod tests {
+    use cfproxy::rules::{glob_matches, Action, RequestInfo, Rules};
+    use hyper::Method;
+
+    fn request<'a>(ip: &str, token: Option<&'a str>, path: &'a str, method: &'a Method) -> RequestInfo<'a> {
+        RequestInfo { ip: ip.parse().unwrap(), token, path, method }
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_matches("/v1/**", "/v1/mods/123/files"));
+        assert!(glob_matches("/v1/mods/*/files", "/v1/mods/123/files"));
+        assert!(glob_matches("/v1/mods/search*", "/v1/mods/search"));
+        assert!(!glob_matches("/v1/mods/*", "/v1/mods/123/files"));
+        assert!(!glob_matches("/v1/**", "/v2/games"));
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let rules = Rules::parse("
+            # Block a network, except for a partner
+            allow token partner_* from 203.0.113.0/24
+            deny from 203.0.113.0/24
+            limit 1/s path /v1/mods/search method GET,HEAD; deny method POST
+        ").unwrap();
+        let (get, post) = (Method::GET, Method::POST);
+
+        let rule = |req| rules.evaluate(&req).map(|rule| &rule.action);
+        assert!(matches!(rule(request("203.0.113.9", Some("partner_a"), "/v1/games", &post)), Some(Action::Allow)));
+        assert!(matches!(rule(request("203.0.113.9", None, "/v1/games", &get)), Some(Action::Deny)));
+        assert!(matches!(rule(request("198.51.100.1", None, "/v1/mods/search", &get)), Some(Action::Limit(_))));
+        assert!(matches!(rule(request("198.51.100.1", None, "/v1/fingerprints", &post)), Some(Action::Deny)));
+        assert!(rule(request("198.51.100.1", None, "/v1/games", &get)).is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert!(Rules::parse("permit from 10.0.0.0/8").is_err());
+        assert!(Rules::parse("deny from").is_err());
+        assert!(Rules::parse("limit fast").is_err());
+        assert!(Rules::parse("deny color blue").is_err());
+        assert!(Rules::parse("").unwrap().is_empty());
+    }
+}
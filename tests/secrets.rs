@@ -0,0 +1,79 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use cfproxy::secrets::aws::{amz_date, canonical_request, signature};
+    use cfproxy::secrets::{env_secret, parse_reference, resolve_secrets};
+    use hyper::{Body, Response, StatusCode};
+    use crate::common::start_upstream_with;
+
+    #[test]
+    fn reads_secrets_from_files() {
+        let path = env::temp_dir().join(format!("cfproxy-secret-{}", std::process::id()));
+        fs::write(&path, "file-secret\n").unwrap();
+        env::set_var("TEST_SECRET_FILE", &path);
+        assert_eq!(env_secret("TEST_SECRET"), Ok(Some(String::from("file-secret"))));
+
+        // The variable itself wins over the file
+        env::set_var("TEST_SECRET", "env-secret");
+        assert_eq!(env_secret("TEST_SECRET"), Ok(Some(String::from("env-secret"))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reports_unreadable_files() {
+        assert_eq!(env_secret("UNSET_SECRET"), Ok(None));
+        env::set_var("MISSING_SECRET_FILE", "/nonexistent/secret");
+        assert!(env_secret("MISSING_SECRET").unwrap_err().contains("/nonexistent/secret"));
+    }
+
+    #[test]
+    fn parses_references() {
+        assert_eq!(parse_reference("vault:secret/data/cfproxy#key"), Some(("vault", "secret/data/cfproxy#key")));
+        assert_eq!(parse_reference("aws-sm:cfproxy"), Some(("aws-sm", "cfproxy")));
+        assert_eq!(parse_reference("$2a$10$plain:secret"), None);
+    }
+
+    #[test]
+    fn signs_like_aws() {
+        // Example from the AWS Signature Version 4 documentation
+        let (canonical, signed_headers) = canonical_request("GET", "/", "Action=ListUsers&Version=2010-05-08", &[
+            ("content-type", "application/x-www-form-urlencoded; charset=utf-8"),
+            ("host", "iam.amazonaws.com"),
+            ("x-amz-date", "20150830T123600Z"),
+        ], b"");
+        assert_eq!(signed_headers, "content-type;host;x-amz-date");
+        assert_eq!(
+            signature("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20150830T123600Z", "us-east-1", "iam", &canonical),
+            "5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7",
+        );
+        assert_eq!(amz_date(1440938160), "20150830T123600Z");
+        assert_eq!(amz_date(951782400), "20000229T000000Z");
+    }
+
+    #[tokio::test]
+    async fn fetches_secrets_from_vault() {
+        // Vault serving a KV v2 secret to the right token only
+        let (vault, _) = start_upstream_with(false, |_| (), |req| {
+            let authorized = req.headers().get("x-vault-token").map(|token| token == "root").unwrap_or(false);
+            match (authorized, req.uri().path()) {
+                (true, "/v1/secret/data/cfproxy") => Response::new(Body::from(r#"{"data":{"data":{"cf_api_key":"from-vault"},"metadata":{}}}"#)),
+                _ => Response::builder().status(StatusCode::FORBIDDEN).body(Body::empty()).unwrap(),
+            }
+        });
+        env::set_var("VAULT_ADDR", format!("http://{}", vault));
+        env::set_var("VAULT_TOKEN", "root");
+        env::set_var("CF_API_KEY", "vault:secret/data/cfproxy#cf_api_key");
+
+        assert!(env_secret("CF_API_KEY").is_err(), "Expected references to not be returned before they are fetched");
+        resolve_secrets().await.unwrap();
+        assert_eq!(env_secret("CF_API_KEY"), Ok(Some(String::from("from-vault"))));
+
+        env::set_var("CF_API_KEY", "vault:secret/data/cfproxy#missing");
+        assert!(resolve_secrets().await.unwrap_err().contains("missing"));
+        env::set_var("VAULT_TOKEN", "wrong");
+        assert!(resolve_secrets().await.unwrap_err().contains("403"));
+    }
+}
@@ -0,0 +1,50 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use cfproxy::routes::RouteSet;
+    use cfproxy::server::{ProxyHandle, ProxyService, ProxyState};
+    use cfproxy::test_util::FakeRateLimiter;
+    use hyper::service::Service;
+    use hyper::{Body, Client, Request, Uri};
+
+    #[tokio::test]
+    async fn restart_keeps_state() {
+        env::set_var("CF_API_KEY", "test");
+        let handle = ProxyHandle::start(([127, 0, 0, 1], 0).into()).expect("Expected the proxy to start");
+        let uri: Uri = format!("http://{}/v1/games", handle.local_addr()).parse().unwrap();
+        Client::new().get(uri).await.expect("Expected a response");
+        assert_eq!(handle.state().rate_limiter_keys(), 1);
+
+        let state = handle.shutdown().await.expect("Expected the proxy to shut down");
+        let handle = ProxyHandle::start_with_state(([127, 0, 0, 1], 0).into(), state).expect("Expected the proxy to restart");
+        assert_eq!(handle.state().rate_limiter_keys(), 1);
+        handle.shutdown().await.expect("Expected the proxy to shut down");
+    }
+
+    #[tokio::test]
+    async fn serves_requests_without_a_listener() {
+        env::set_var("CF_API_KEY", "test");
+        let mut service = ProxyService::new(ProxyState::new(), [127, 0, 0, 1].into());
+        let resp = service.call(Request::get("/_routes").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+
+        let mut service = service.routes(RouteSet::Proxy);
+        let resp = service.call(Request::get("/_routes").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_local_routes_except_on_local_listeners() {
+        env::set_var("CF_API_KEY", "test");
+        let limiter = FakeRateLimiter::per_hour(60);
+        let mut service = ProxyService::new(ProxyState::new().with_rate_limiter(limiter.clone()), [127, 0, 0, 1].into());
+        let resp = service.call(Request::get("/_status").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(limiter.checks(), 1);
+
+        let mut service = service.routes(RouteSet::Local);
+        let resp = service.call(Request::get("/_status").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(limiter.checks(), 1);
+    }
+}
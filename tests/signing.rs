@@ -0,0 +1,75 @@
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use cfproxy::routes::strip_base_path;
+    use cfproxy::signing::{sign, SignatureVerifier, Verification};
+    use hyper::{Body, Request};
+
+    const SECRET: &[u8] = b"secret";
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn accepts_valid_signature_once() {
+        let verifier = SignatureVerifier::new(SECRET.to_vec(), 300);
+        let timestamp = now();
+        let signature = sign(SECRET, timestamp, "/v1/mods/search?gameId=432", b"");
+        let timestamp = timestamp.to_string();
+
+        assert_eq!(verifier.verify(&timestamp, &signature, "/v1/mods/search?gameId=432", b""), Verification::Signed);
+        assert!(matches!(verifier.verify(&timestamp, &signature, "/v1/mods/search?gameId=432", b""), Verification::Invalid(_)));
+    }
+
+    #[test]
+    fn rejects_tampered_requests() {
+        let verifier = SignatureVerifier::new(SECRET.to_vec(), 300);
+        let timestamp = now();
+        let signature = sign(SECRET, timestamp, "/v1/fingerprints", b"{\"fingerprints\":[1]}");
+        let timestamp = timestamp.to_string();
+
+        assert!(matches!(verifier.verify(&timestamp, &signature, "/v1/fingerprints", b"{\"fingerprints\":[2]}"), Verification::Invalid(_)));
+        assert!(matches!(verifier.verify(&timestamp, &signature, "/v1/mods", b"{\"fingerprints\":[1]}"), Verification::Invalid(_)));
+        assert!(matches!(verifier.verify(&timestamp, "zz", "/v1/fingerprints", b"{\"fingerprints\":[1]}"), Verification::Invalid(_)));
+    }
+
+    #[test]
+    fn rejects_expired_signature() {
+        let verifier = SignatureVerifier::new(SECRET.to_vec(), 300);
+        let timestamp = now() - 301;
+        let signature = sign(SECRET, timestamp, "/v1/games", b"");
+
+        assert!(matches!(verifier.verify(&timestamp.to_string(), &signature, "/v1/games", b""), Verification::Invalid(_)));
+    }
+
+    #[test]
+    fn rejects_timestamps_far_in_the_future() {
+        let verifier = SignatureVerifier::new(SECRET.to_vec(), 300);
+        let signature = sign(SECRET, u64::MAX, "/v1/games", b"");
+
+        assert_eq!(verifier.verify(&u64::MAX.to_string(), &signature, "/v1/games", b""), Verification::Invalid("Signature expired"));
+    }
+
+    #[tokio::test]
+    async fn verifies_the_path_including_the_base_path() {
+        let verifier = SignatureVerifier::new(SECRET.to_vec(), 300);
+        let signed = |path: &str| {
+            let timestamp = now();
+            Request::get("/cfproxy/v1/games?index=50")
+                .header("x-proxy-timestamp", timestamp.to_string())
+                .header("x-proxy-signature", sign(SECRET, timestamp, path, b""))
+                .body(Body::empty()).unwrap()
+        };
+
+        let mut req = signed("/cfproxy/v1/games?index=50");
+        assert!(strip_base_path(&mut req, "/cfproxy"));
+        let (_, verification) = verifier.verify_request(req).await.unwrap();
+        assert_eq!(verification, Verification::Signed);
+
+        let mut req = signed("/v1/games?index=50");
+        assert!(strip_base_path(&mut req, "/cfproxy"));
+        let (_, verification) = verifier.verify_request(req).await.unwrap();
+        assert!(matches!(verification, Verification::Invalid(_)));
+    }
+}
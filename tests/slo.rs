@@ -0,0 +1,53 @@
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use cfproxy::slo::{SloConfig, SloTracker};
+
+    fn tracker(degraded_mode: bool) -> SloTracker {
+        SloTracker::new(SloConfig {
+            availability_target: 0.9,
+            latency_target: 0.5,
+            latency_threshold: Duration::from_millis(100),
+            window: Duration::from_secs(600),
+            burn_window: Duration::from_secs(60),
+            degraded_mode,
+            degrade_below: 0.1,
+        })
+    }
+
+    #[test]
+    fn tracks_error_budgets() {
+        let slo = tracker(false);
+        let now = Instant::now();
+        for i in 0..20 {
+            slo.record_at(now, i % 20 != 0, Duration::from_millis(if i % 4 == 0 { 200 } else { 10 }));
+        }
+        let report = slo.report_at(now);
+        assert_eq!(report.requests, 20);
+        assert!((report.availability - 0.95).abs() < 1e-9);
+        assert!((report.availability_budget_remaining - 0.5).abs() < 1e-9);
+        assert!((report.availability_burn_rate - 0.5).abs() < 1e-9);
+        assert!((report.latency - 0.75).abs() < 1e-9);
+        assert!((report.latency_budget_remaining - 0.5).abs() < 1e-9);
+        assert!(!report.degraded);
+    }
+
+    #[test]
+    fn forgets_requests_outside_the_window() {
+        let slo = tracker(true);
+        let now = Instant::now();
+        for _ in 0..20 {
+            slo.record_at(now, false, Duration::ZERO);
+        }
+        assert!(slo.report_at(now).degraded);
+
+        // Out of the burn window, but still in the budget window
+        let later = now + Duration::from_secs(120);
+        assert_eq!(slo.report_at(later).availability_burn_rate, 0.0);
+        assert!(slo.report_at(later).degraded);
+
+        let much_later = now + Duration::from_secs(700);
+        assert_eq!(slo.report_at(much_later).requests, 0);
+        assert!(!slo.report_at(much_later).degraded);
+    }
+}
@@ -0,0 +1,78 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+    use hyper::{HeaderMap, StatusCode};
+    use cfproxy::cache::{Cache, CachedResponse, ResponseCache};
+    use cfproxy::metrics::{self, MetricsSink};
+    use cfproxy::server::ProxyState;
+    use cfproxy::statsd::{self, Flavor, StatsdSink, MAX_PACKET_BYTES};
+
+    #[test]
+    fn formats_metrics() {
+        let sink = StatsdSink::new("cfproxy", Flavor::Statsd, vec![String::from("env:test")]);
+        sink.count("requests", 3, &[]);
+        sink.gauge("connections.active", 2, &[("listener", "main")]);
+        sink.timing("request.duration", Duration::from_micros(12_500), &[]);
+        assert_eq!(sink.take_lines(), ["cfproxy.requests:3|c", "cfproxy.connections.active:2|g", "cfproxy.request.duration:12.500|ms"]);
+        assert!(sink.take_lines().is_empty());
+
+        let sink = StatsdSink::new("", Flavor::DogStatsd, vec![String::from("env:test")]);
+        sink.timing("request.duration", Duration::from_millis(3), &[("route", "/v1/mods/{id}"), ("status", "2xx")]);
+        sink.count("requests", 1, &[("bad", "a,b|c")]);
+        assert_eq!(sink.take_lines(), [
+            "request.duration:3.000|ms|#env:test,route:/v1/mods/{id},status:2xx",
+            "requests:1|c|#env:test,bad:a_b_c",
+        ]);
+    }
+
+    #[test]
+    fn splits_lines_into_packets() {
+        let line = "x".repeat(500);
+        let packets = statsd::packets(vec![line.clone(), line.clone(), line.clone(), String::from("y")]);
+        assert_eq!(packets, [format!("{}\n{}", line, line), format!("{}\ny", line)]);
+        assert!(packets.iter().all(|packet| packet.len() <= MAX_PACKET_BYTES));
+        assert!(statsd::packets(Vec::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn pushes_counters_and_timings() {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        agent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        env::set_var("STATSD_HOST", "127.0.0.1");
+        env::set_var("STATSD_PORT", agent.local_addr().unwrap().port().to_string());
+        env::set_var("STATSD_FLAVOR", "dogstatsd");
+        env::set_var("STATSD_TAGS", "env:test");
+
+        let cache = ResponseCache::new(Duration::from_secs(60), 10);
+        cache.insert("/v1/mods/1".to_string(), CachedResponse::new(StatusCode::OK, HeaderMap::new(), "{}".into()));
+        cache.get("/v1/mods/1");
+        let state = ProxyState::new().with_cache(cache);
+
+        metrics::METRICS.record_request("/v1/mods/1");
+        metrics::METRICS.record_request("/v1/mods/2");
+        metrics::record_timing("request.duration", Duration::from_millis(5), &[("route", "/v1/mods/{id}")]);
+        statsd::push(&state).await;
+
+        let mut buf = [0; 2048];
+        let len = agent.recv(&mut buf).unwrap();
+        let packet = String::from_utf8(buf[..len].to_vec()).unwrap();
+        let lines = packet.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"cfproxy.request.duration:5.000|ms|#env:test,route:/v1/mods/{id}"), "{}", packet);
+        assert!(lines.contains(&"cfproxy.requests:2|c|#env:test"), "{}", packet);
+        assert!(lines.contains(&"cfproxy.connections.active:0|g|#env:test"), "{}", packet);
+        assert!(lines.contains(&"cfproxy.cache.hits:1|c|#env:test"), "{}", packet);
+        assert!(lines.contains(&"cfproxy.cache.entries:1|g|#env:test"), "{}", packet);
+        assert!(lines.contains(&"cfproxy.rate_limiter.keys:0|g|#env:test"), "{}", packet);
+
+        // Counters are pushed as how much they increased since the last push
+        metrics::METRICS.record_request("/v1/mods/3");
+        statsd::push(&state).await;
+        let len = agent.recv(&mut buf).unwrap();
+        let packet = String::from_utf8(buf[..len].to_vec()).unwrap();
+        assert!(packet.lines().any(|line| line == "cfproxy.requests:1|c|#env:test"), "{}", packet);
+        assert!(packet.lines().any(|line| line == "cfproxy.cache.hits:0|c|#env:test"), "{}", packet);
+        assert!(!packet.contains("request.duration"), "{}", packet);
+    }
+}
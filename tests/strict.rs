@@ -0,0 +1,160 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::server::{ProxyHandle, ProxyService, ProxyState};
+    use hyper::service::Service;
+    use hyper::{Body, Request, StatusCode};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::common::start_upstream;
+
+    const UPSTREAM_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\n\
+        Date: Tue, 01 Feb 2022 20:15:44 GMT\r\n\
+        Content-Type: application/json; charset=utf-8\r\n\
+        Set-Cookie: __cf_bm=abc; path=/\r\n\
+        X-Upstream-Thing: Value\r\n\
+        Content-Length: 17\r\n\
+        \r\n\
+        {\"data\":[1,2,3]}\n";
+
+    /// Reads a full HTTP/1.1 message with a `Content-Length` (or none) from the stream.
+    async fn read_message(stream: &mut TcpStream) -> Vec<u8> {
+        let mut message = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            message.extend_from_slice(&buf[..n]);
+            if let Some(end) = message.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&message[..end]).to_lowercase();
+                let length = head.lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .map(|length| length.trim().parse::<usize>().unwrap())
+                    .unwrap_or(0);
+                if message.len() >= end + 4 + length {
+                    return message;
+                }
+            }
+            if n == 0 {
+                return message;
+            }
+        }
+    }
+
+    /// Sets the env variables every test of strict pass-through mode expects.
+    fn enable_strict_passthrough() {
+        env::set_var("STRICT_PASSTHROUGH", "true");
+        env::set_var("ALLOWED_PATHS", "/v1/**,/_status,/api/**,/graphql,/example/**");
+        // Translating & routing would change what's forwarded, so they're off however they're configured
+        env::set_var("LEGACY_API", "true");
+        env::set_var("GRAPHQL", "true");
+        env::set_var("UPSTREAM_ROUTES", "/example=http://127.0.0.1:1");
+        // Nor are requests checked, however small the body limit
+        env::set_var("MAX_REQUEST_BODY_BYTES", "16");
+    }
+
+    #[tokio::test]
+    async fn passes_through_byte_identical() {
+        // Upstream that captures the raw request and replies with a canned response
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        env::set_var("CF_API_URL", format!("http://{}", upstream.local_addr().unwrap()));
+        env::set_var("CF_API_KEY", "key");
+        enable_strict_passthrough();
+        let capture = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let request = read_message(&mut stream).await;
+            stream.write_all(UPSTREAM_RESPONSE).await.unwrap();
+            request
+        });
+
+        let proxy = ProxyHandle::start(([127, 0, 0, 1], 0).into()).expect("Expected the proxy to start");
+        let mut client = TcpStream::connect(proxy.local_addr()).await.unwrap();
+        client.write_all(b"POST /v1/fingerprints?x=1 HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Content-Type: application/json\r\n\
+            X-Custom-Header: Some Value\r\n\
+            Content-Length: 22\r\n\
+            \r\n\
+            {\"fingerprints\":[123]}").await.unwrap();
+        let response = read_message(&mut client).await;
+        let request = capture.await.unwrap();
+
+        assert_eq!(String::from_utf8(response).unwrap(), String::from_utf8(UPSTREAM_RESPONSE.to_vec()).unwrap());
+        let request = String::from_utf8(request).unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        let mut lines: Vec<&str> = head.lines().collect();
+        lines.sort_unstable();
+        assert_eq!(lines, vec![
+            "Content-Length: 22",
+            "Content-Type: application/json",
+            &*format!("Host: {}", env::var("CF_API_URL").unwrap().trim_start_matches("http://")),
+            "POST /v1/fingerprints?x=1 HTTP/1.1",
+            "X-Custom-Header: Some Value",
+            "x-api-key: key",
+        ]);
+        assert_eq!(body, "{\"fingerprints\":[123]}");
+
+        proxy.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn forwards_local_routes() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ProxyConfig::from_env()
+            .with_api_url(&format!("http://{}", upstream.local_addr().unwrap())).unwrap()
+            .with_api_keys(["key"]).unwrap();
+        enable_strict_passthrough();
+        let capture = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let request = read_message(&mut stream).await;
+            stream.write_all(UPSTREAM_RESPONSE).await.unwrap();
+            request
+        });
+
+        let proxy = ProxyHandle::start_with_state(([127, 0, 0, 1], 0).into(), ProxyState::new().with_config(config))
+            .expect("Expected the proxy to start");
+        let mut client = TcpStream::connect(proxy.local_addr()).await.unwrap();
+        client.write_all(b"GET /_status HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let response = read_message(&mut client).await;
+        let request = String::from_utf8(capture.await.unwrap()).unwrap();
+
+        assert!(request.starts_with("GET /_status HTTP/1.1\r\n"), "{}", request);
+        assert_eq!(String::from_utf8(response).unwrap(), String::from_utf8(UPSTREAM_RESPONSE.to_vec()).unwrap());
+
+        proxy.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn forwards_translated_and_routed_paths() {
+        let (upstream, requests) = start_upstream(|req| format!("{} {}", req.method(), req.uri()));
+        let config = ProxyConfig::from_env().with_api_url(&format!("http://{}", upstream)).unwrap().with_api_keys(["key"]).unwrap();
+        enable_strict_passthrough();
+        let mut service = ProxyService::new(ProxyState::new().with_config(config), [127, 0, 0, 1].into());
+
+        for (method, path) in [("GET", "/api/v2/addon/1"), ("POST", "/graphql"), ("GET", "/example/thing")] {
+            let request = Request::builder().method(method).uri(path).body(Body::from("{}")).unwrap();
+            let response = service.call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+            assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "{\"data\":[]}");
+        }
+        assert_eq!(*requests.lock().unwrap(), vec!["GET /api/v2/addon/1", "POST /graphql", "GET /example/thing"]);
+    }
+
+    #[tokio::test]
+    async fn forwards_requests_the_proxy_would_reject() {
+        let (upstream, requests) = start_upstream(|req| format!("{} {}", req.method(), req.uri()));
+        let config = ProxyConfig::from_env().with_api_url(&format!("http://{}", upstream)).unwrap().with_api_keys(["key"]).unwrap();
+        enable_strict_passthrough();
+        let mut service = ProxyService::new(ProxyState::new().with_config(config), [127, 0, 0, 1].into());
+
+        let large_body = format!("{{\"fingerprints\":[{}]}}", vec!["123"; 100].join(","));
+        for (method, path, body) in [("OPTIONS", "/v1/mods/1", String::new()), ("GET", "/not/allowed", String::new()), ("POST", "/v1/fingerprints", large_body)] {
+            let request = Request::builder().method(method).uri(path).body(Body::from(body)).unwrap();
+            let response = service.call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{} {}", method, path);
+        }
+        assert_eq!(*requests.lock().unwrap(), vec!["OPTIONS /v1/mods/1", "GET /not/allowed", "POST /v1/fingerprints"]);
+    }
+}
@@ -0,0 +1,65 @@
+#[cfg(all(test, unix))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::io::IntoRawFd;
+    use cfproxy::listener::{systemd_listen_fds, ListenAddr};
+    use cfproxy::server::{ProxyHandle, ProxyState};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::{TcpStream, UnixStream};
+
+    async fn get_routes(mut stream: impl AsyncRead + AsyncWrite + Unpin) -> String {
+        stream.write_all(b"GET /_routes HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn reads_listen_fds() {
+        env::set_var("LISTEN_PID", std::process::id().to_string());
+        env::set_var("LISTEN_FDS", "2");
+        assert_eq!(systemd_listen_fds(), vec![3, 4]);
+
+        // The sockets are taken, so they're not handed out again
+        assert!(env::var("LISTEN_FDS").is_err());
+        assert!(systemd_listen_fds().is_empty());
+
+        // Sockets meant for another process aren't taken
+        env::set_var("LISTEN_PID", "1");
+        env::set_var("LISTEN_FDS", "1");
+        assert!(systemd_listen_fds().is_empty());
+    }
+
+    #[tokio::test]
+    async fn accepts_on_inherited_tcp_sockets() {
+        env::set_var("CF_API_KEY", "key");
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = ProxyHandle::start_from_fds(&[listener.into_raw_fd()], ProxyState::new()).expect("Expected the proxy to start");
+        assert_eq!(handle.listen_addr(), &ListenAddr::Tcp(addr));
+
+        let response = get_routes(TcpStream::connect(addr).await.unwrap()).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn accepts_on_inherited_unix_sockets() {
+        env::set_var("CF_API_KEY", "key");
+        let dir = env::temp_dir().join(format!("cfproxy-systemd-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cfproxy.sock");
+        fs::remove_file(&path).ok();
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let handle = ProxyHandle::start_from_fds(&[listener.into_raw_fd()], ProxyState::new()).expect("Expected the proxy to start");
+        assert_eq!(handle.listen_addr(), &ListenAddr::Unix(path.clone()));
+
+        let response = get_routes(UnixStream::connect(&path).await.unwrap()).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        // The socket file belongs to whoever bound it
+        handle.shutdown().await.unwrap();
+        assert!(path.exists());
+    }
+}
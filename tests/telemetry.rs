@@ -0,0 +1,113 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::env;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use cfproxy::cache::ResponseCache;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::server::{ProxyService, ProxyState};
+    use cfproxy::telemetry::{self, Sampler, SpanContext};
+    use hyper::service::{make_service_fn, service_fn, Service};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use serde_json::Value;
+    use crate::common::start_upstream;
+
+    /// Requests a collector got, as `(path, authorization, body)`.
+    type Exports = Arc<Mutex<Vec<(String, String, Value)>>>;
+
+    /// Starts a fake OTLP collector that records the requests it gets.
+    fn start_collector() -> (SocketAddr, Exports) {
+        let exports = Arc::new(Mutex::new(Vec::new()));
+        let recorded = exports.clone();
+        let make_svc = make_service_fn(move |_| {
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let recorded = recorded.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let authorization = req.headers()["authorization"].to_str().unwrap().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        recorded.lock().unwrap().push((path, authorization, serde_json::from_slice(&body).unwrap()));
+                        Ok::<_, Infallible>(Response::new(Body::from("{}")))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, exports)
+    }
+
+    #[tokio::test]
+    async fn exports_spans_of_the_request_pipeline() {
+        let (collector, exports) = start_collector();
+        env::set_var("OTEL_EXPORTER_OTLP_ENDPOINT", format!("http://{}/", collector));
+        env::set_var("OTEL_EXPORTER_OTLP_HEADERS", "Authorization=Bearer%20secret");
+        env::set_var("OTEL_SERVICE_NAME", "cfproxy-test");
+
+        let upstream = start_upstream(|_| ()).0;
+        let config = ProxyConfig::from_env().with_api_url(&format!("http://{}", upstream)).unwrap().with_api_keys(["key"]).unwrap();
+        let state = ProxyState::new().with_config(config).with_cache(ResponseCache::new(Duration::from_secs(60), 100));
+        let mut service = ProxyService::new(state, [127, 0, 0, 1].into());
+        let response = service.call(Request::get("/v1/mods/238222").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        telemetry::flush().await;
+
+        let exports = exports.lock().unwrap();
+        assert_eq!(exports.len(), 1);
+        let (path, authorization, body) = &exports[0];
+        assert_eq!((path.as_str(), authorization.as_str()), ("/v1/traces", "Bearer secret"));
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(resource["resource"]["attributes"][0]["value"]["stringValue"], "cfproxy-test");
+
+        let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+        let span = |name: &str| spans.iter().find(|span| span["name"] == name).unwrap_or_else(|| panic!("no {} span in {:?}", name, spans));
+        let root = span("GET /v1/mods/{id}");
+        assert_eq!(root["kind"], 2);
+        assert!(root.get("parentSpanId").is_none());
+        let status = root["attributes"].as_array().unwrap().iter().find(|attribute| attribute["key"] == "http.response.status_code").unwrap();
+        assert_eq!(status["value"]["intValue"], "200");
+
+        for name in ["rate limit", "cache lookup", "upstream GET"] {
+            assert_eq!(span(name)["traceId"], root["traceId"]);
+            assert_eq!(span(name)["parentSpanId"], root["spanId"]);
+        }
+        assert_eq!(span("upstream GET")["kind"], 3);
+    }
+
+    #[test]
+    fn parses_traceparent() {
+        let context = SpanContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(context.trace_id[..2], [0x4b, 0xf9]);
+        assert_eq!(context.span_id[7], 0xb7);
+        assert!(context.sampled);
+        assert_eq!(context.to_traceparent(), "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+
+        // Later versions may append fields
+        assert!(SpanContext::from_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra").is_some());
+        for invalid in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert_eq!(SpanContext::from_traceparent(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn samples_like_the_parent() {
+        let parent_based = Sampler { ratio: 0.0, parent_based: true };
+        assert!(parent_based.sample(Some(true)));
+        assert!(!parent_based.sample(Some(false)));
+        assert!(!parent_based.sample(None));
+        assert!(Sampler { ratio: 1.0, parent_based: false }.sample(Some(false)));
+    }
+}
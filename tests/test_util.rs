@@ -0,0 +1,67 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+    use cfproxy::cache::{Cache, CachedResponse};
+    use cfproxy::limiter::RateLimit;
+    use cfproxy::server::{ProxyHandle, ProxyState};
+    use cfproxy::test_util::{FakeCache, FakeRateLimiter};
+    use hyper::{Client, HeaderMap, StatusCode, Uri};
+    use crate::common::start_upstream;
+
+    #[test]
+    fn fake_limiter_only_replenishes_when_advanced() {
+        let limiter = FakeRateLimiter::per_hour(1);
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(limiter.check_key(&ip).is_ok());
+        assert_eq!(limiter.check_key(&ip), Err(Duration::from_secs(3600)));
+
+        limiter.advance(Duration::from_secs(1800));
+        assert_eq!(limiter.check_key(&ip), Err(Duration::from_secs(1800)));
+        limiter.advance(Duration::from_secs(1800));
+        assert!(limiter.check_key(&ip).is_ok());
+        assert_eq!((limiter.checks(), limiter.tracked_keys()), (4, 1));
+    }
+
+    #[test]
+    fn fake_cache_keeps_responses_until_expired() {
+        let cache = FakeCache::new();
+        cache.insert("/a".to_string(), CachedResponse::new(StatusCode::OK, HeaderMap::new(), "a".into()));
+        assert_eq!(cache.get("/a").unwrap().body, "a");
+
+        cache.expire("/a");
+        assert!(cache.get("/a").is_none());
+        assert!(!cache.contains("/a"));
+        assert_eq!(cache.get_stale("/a").unwrap().body, "a");
+        assert_eq!((cache.stats().hits, cache.stats().misses), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn proxy_uses_injected_fakes() {
+        let (upstream, upstream_requests) = start_upstream(|_| ());
+        env::set_var("CF_API_URL", format!("http://{}", upstream));
+        env::set_var("CF_API_KEY", "key");
+
+        let limiter = FakeRateLimiter::per_hour(60);
+        let cache = FakeCache::new();
+        let state = ProxyState::new().with_rate_limiter(limiter.clone()).with_cache(cache.clone());
+        let handle = ProxyHandle::start_with_state(([127, 0, 0, 1], 0).into(), state).expect("Expected the proxy to start");
+
+        // The second request is answered from the fake cache
+        let uri: Uri = format!("http://{}/v1/games", handle.local_addr()).parse().unwrap();
+        for _ in 0..2 {
+            let response = Client::new().get(uri.clone()).await.expect("Expected a response");
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        assert_eq!(limiter.checks(), 2);
+        assert_eq!(handle.state().rate_limiter_keys(), 1);
+        assert_eq!(cache.keys(), vec!["/v1/games".to_string()]);
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(upstream_requests.lock().unwrap().len(), 1);
+        handle.shutdown().await.expect("Expected the proxy to shut down");
+    }
+}
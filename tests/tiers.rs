@@ -0,0 +1,31 @@
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+    use governor::Quota;
+    use cfproxy::tiers::{parse_rate, Rate, Tiers};
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("2/s"), Ok(Rate::Limited(Quota::per_second(NonZeroU32::new(2).unwrap()))));
+        assert_eq!(parse_rate(" 300/min "), Ok(Rate::Limited(Quota::per_minute(NonZeroU32::new(300).unwrap()))));
+        assert_eq!(parse_rate("1000/h"), Ok(Rate::Limited(Quota::per_hour(NonZeroU32::new(1000).unwrap()))));
+        assert_eq!(parse_rate("unlimited"), Ok(Rate::Unlimited));
+        assert!(parse_rate("0/s").is_err());
+        assert!(parse_rate("5/day").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn maps_tokens_to_tiers() {
+        let tiers = Tiers::parse(
+            "anonymous: 2/s, partner: 20/s, internal: unlimited",
+            "p_*: partner, p_internal_*: internal, p_exact: anonymous",
+        ).expect("Expected valid tiers");
+
+        assert_eq!(tiers.for_token("p_abc").map(|tier| tier.name()), Some("partner"));
+        assert_eq!(tiers.for_token("p_internal_abc").map(|tier| tier.name()), Some("internal"));
+        assert_eq!(tiers.for_token("p_exact").map(|tier| tier.name()), Some("anonymous"));
+        assert!(tiers.for_token("other").is_none());
+        assert!(Tiers::parse("partner: 20/s", "abc: unknown").is_err());
+    }
+}
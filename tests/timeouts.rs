@@ -0,0 +1,46 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::time::Duration;
+    use cfproxy::server::ProxyHandle;
+    use hyper::{Body, Response};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use crate::common::start_upstream_async;
+
+    /// Reads until the connection is closed, or gives up after `wait`. Returns what was read, if it closed.
+    async fn read_until_closed(stream: &mut TcpStream, wait: Duration) -> Option<String> {
+        let mut read = Vec::new();
+        match tokio::time::timeout(wait, stream.read_to_end(&mut read)).await {
+            Ok(_) => Some(String::from_utf8_lossy(&read).into_owned()),
+            Err(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_slow_and_idle_connections() {
+        // Upstream that takes longer than the timeouts to answer
+        let (upstream, _) = start_upstream_async(|_| async {
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            ((), Response::new(Body::from("{}")))
+        });
+        env::set_var("CF_API_URL", format!("http://{}", upstream));
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("HEADER_READ_TIMEOUT_SECS", "1");
+        env::set_var("IDLE_TIMEOUT_SECS", "1");
+        let handle = ProxyHandle::start(([127, 0, 0, 1], 0).into()).expect("Expected the proxy to start");
+
+        // Headers that never complete
+        let mut slow = TcpStream::connect(handle.local_addr()).await.unwrap();
+        slow.write_all(b"GET /v1/games HTTP/1.1\r\n").await.unwrap();
+        assert!(read_until_closed(&mut slow, Duration::from_secs(3)).await.is_some());
+
+        // A request that's being handled keeps the connection open, until it's idle afterwards
+        let mut idle = TcpStream::connect(handle.local_addr()).await.unwrap();
+        idle.write_all(b"GET /v1/games HTTP/1.1\r\nhost: localhost\r\n\r\n").await.unwrap();
+        let read = read_until_closed(&mut idle, Duration::from_secs(5)).await.expect("Expected the connection to close");
+        assert!(read.starts_with("HTTP/1.1 200"), "{}", read);
+    }
+}
@@ -0,0 +1,95 @@
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use cfproxy::server::ProxyHandle;
+    use cfproxy::tls::{acceptor_from_pem, ReloadingCertificate};
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509NameBuilder, X509};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    /// Returns a self-signed PEM certificate & key for `localhost`.
+    fn self_signed() -> (Vec<u8>, Vec<u8>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (cert.build().to_pem().unwrap(), key.private_key_to_pem_pkcs8().unwrap())
+    }
+
+    fn write_certificate(name: &str) -> (PathBuf, PathBuf) {
+        let dir = env::temp_dir().join(format!("cfproxy-tls-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = self_signed();
+        fs::write(dir.join("cert.pem"), cert).unwrap();
+        fs::write(dir.join("key.pem"), key).unwrap();
+        (dir.join("cert.pem"), dir.join("key.pem"))
+    }
+
+    #[test]
+    fn rejects_invalid_certificates() {
+        let (cert, key) = self_signed();
+        assert!(acceptor_from_pem(&cert, &key, None).is_ok());
+        assert!(acceptor_from_pem(b"not a certificate", &key, None).is_err());
+        assert!(acceptor_from_pem(&cert, b"not a key", None).is_err());
+        assert!(acceptor_from_pem(&cert, &key, Some(b"not a certificate")).is_err());
+
+        // The key has to belong to the certificate
+        let (_, other_key) = self_signed();
+        assert!(acceptor_from_pem(&cert, &other_key, None).is_err());
+    }
+
+    #[test]
+    fn reloads_changed_certificates() {
+        let (cert_path, key_path) = write_certificate("reload");
+        let certificate = ReloadingCertificate::load(cert_path.clone(), key_path.clone(), None).unwrap();
+        assert!(!certificate.reload_if_changed().unwrap());
+
+        // Broken files leave the previous certificate in place
+        fs::write(&cert_path, "not a certificate").unwrap();
+        assert!(certificate.reload_if_changed().is_err());
+
+        let (cert, key) = self_signed();
+        fs::write(&cert_path, cert).unwrap();
+        fs::write(&key_path, key).unwrap();
+        assert!(certificate.reload_if_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn serves_https() {
+        let (cert, key) = write_certificate("serve");
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("TLS_CERT_FILE", cert);
+        env::set_var("TLS_KEY_FILE", key);
+        let handle = ProxyHandle::start(([127, 0, 0, 1], 0).into()).expect("Expected the proxy to start");
+
+        let connector = native_tls::TlsConnector::builder().danger_accept_invalid_certs(true).build().unwrap();
+        let connector = tokio_native_tls::TlsConnector::from(connector);
+        let stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+        let mut stream = connector.connect("localhost", stream).await.expect("Expected the TLS handshake to succeed");
+        stream.write_all(b"GET /_routes HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 200"));
+
+        // Plain HTTP isn't served
+        let mut plain = TcpStream::connect(handle.local_addr()).await.unwrap();
+        plain.write_all(b"GET /_routes HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        let _ = plain.read_to_end(&mut response).await;
+        assert!(!String::from_utf8_lossy(&response).starts_with("HTTP/1.1"));
+    }
+}
@@ -0,0 +1,46 @@
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use cfproxy::tokens::{reload_tokens, TokenStore, TOKEN_STORE};
+
+    #[test]
+    fn parses_token_store() {
+        let store = TokenStore::parse("# comment\n\nabc 3600\n  def 10  \n").expect("Expected a valid token store");
+        assert_eq!(store.get("abc").expect("Expected token abc").requests_per_hour.get(), 3600);
+        assert_eq!(store.get("def").expect("Expected token def").requests_per_hour.get(), 10);
+        assert!(store.get("ghi").is_none());
+        assert!(store.get("# comment").is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(TokenStore::parse("abc").is_err());
+        assert!(TokenStore::parse("abc 0").is_err());
+        assert!(TokenStore::parse("abc 10 extra").is_err());
+    }
+
+    #[test]
+    fn reloads_token_store() {
+        let path = env::temp_dir().join(format!("cfproxy-tokens-{}", std::process::id()));
+        fs::write(&path, "abc 3600\ndef 10\n").unwrap();
+        env::set_var("TOKEN_STORE_FILE", &path);
+        assert_eq!(reload_tokens(), Ok(2));
+        let abc = TOKEN_STORE.get("abc").expect("Expected token abc");
+
+        // Tokens whose quota didn't change keep their limiter
+        fs::write(&path, "abc 3600\ndef 20\nghi 5\n").unwrap();
+        assert_eq!(reload_tokens(), Ok(3));
+        assert!(Arc::ptr_eq(&abc, &TOKEN_STORE.get("abc").unwrap()));
+        assert_eq!(TOKEN_STORE.get("def").unwrap().requests_per_hour.get(), 20);
+
+        // A broken store leaves the old tokens in place
+        fs::write(&path, "abc\n").unwrap();
+        assert!(reload_tokens().unwrap_err().contains("line 1"));
+        assert_eq!(TOKEN_STORE.len(), 3);
+        fs::remove_file(&path).unwrap();
+        assert!(reload_tokens().is_err());
+        assert!(TOKEN_STORE.get("ghi").is_some());
+    }
+}
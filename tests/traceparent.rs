@@ -0,0 +1,65 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::SocketAddr;
+    use cfproxy::config::ProxyConfig;
+    use cfproxy::server::{ProxyService, ProxyState};
+    use cfproxy::telemetry;
+    use hyper::service::Service;
+    use hyper::{Body, Request, Response};
+    use serde_json::Value;
+    use crate::common::{start_upstream_async, Recorded};
+
+    /// Starts a server that records the trace context headers of the requests it gets, & their JSON bodies, as
+    /// `(traceparent, tracestate, body)`.
+    fn start_server() -> (SocketAddr, Recorded<(String, String, Value)>) {
+        start_upstream_async(|req: Request<Body>| async move {
+            let header = |name: &str| req.headers().get(name).map(|value| value.to_str().unwrap().to_string()).unwrap_or_default();
+            let (traceparent, tracestate) = (header("traceparent"), header("tracestate"));
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            ((traceparent, tracestate, body), Response::new(Body::from("{\"data\":[]}")))
+        })
+    }
+
+    #[tokio::test]
+    async fn continues_and_propagates_client_traces() {
+        let (collector, exports) = start_server();
+        env::set_var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", format!("http://{}/v1/traces", collector));
+        let (upstream, requests) = start_server();
+        let config = ProxyConfig::from_env().with_api_url(&format!("http://{}", upstream)).unwrap().with_api_keys(["key"]).unwrap();
+        let mut service = ProxyService::new(ProxyState::new().with_config(config), [127, 0, 0, 1].into());
+
+        let req = Request::get("/v1/games")
+            .header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+            .header("tracestate", "vendor=abc")
+            .body(Body::empty()).unwrap();
+        service.call(req).await.unwrap();
+        telemetry::flush().await;
+
+        let spans = exports.lock().unwrap()[0].2["resourceSpans"][0]["scopeSpans"][0]["spans"].clone();
+        let spans = spans.as_array().unwrap();
+        let span = |name: &str| spans.iter().find(|span| span["name"] == name).unwrap().clone();
+        let (root, upstream_call) = (span("GET /v1/games"), span("upstream GET"));
+        assert_eq!(root["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(root["parentSpanId"], "00f067aa0ba902b7");
+
+        // Curseforge sees the proxy's span as the parent
+        let (traceparent, tracestate, _) = requests.lock().unwrap()[0].clone();
+        assert_eq!(traceparent, format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", upstream_call["spanId"].as_str().unwrap()));
+        assert_eq!(tracestate, "vendor=abc");
+
+        // Traces the client doesn't record aren't recorded either, but still propagated
+        let req = Request::get("/v1/games")
+            .header("traceparent", "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00")
+            .body(Body::empty()).unwrap();
+        service.call(req).await.unwrap();
+        telemetry::flush().await;
+        assert_eq!(exports.lock().unwrap().len(), 1);
+        let (traceparent, _, _) = requests.lock().unwrap()[1].clone();
+        assert!(traceparent.starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+        assert!(traceparent.ends_with("-00") && !traceparent.contains("b7ad6b7169203331"));
+    }
+}
@@ -0,0 +1,45 @@
+#[cfg(all(test, unix))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use cfproxy::listener::ListenAddr;
+    use cfproxy::server::{ProxyHandle, ProxyState};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn parses_listen_addrs() {
+        assert_eq!("unix:/run/cfproxy.sock".parse::<ListenAddr>().unwrap(), ListenAddr::Unix("/run/cfproxy.sock".into()));
+        assert_eq!("127.0.0.1:3000".parse::<ListenAddr>().unwrap(), ListenAddr::Tcp(([127, 0, 0, 1], 3000).into()));
+        assert_eq!("[::]:3000".parse::<ListenAddr>().unwrap().to_string(), "[::]:3000");
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert!("localhost".parse::<ListenAddr>().is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_at_unix_sockets() {
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("LISTEN_SOCKET_MODE", "600");
+        let dir = env::temp_dir().join(format!("cfproxy-unix-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cfproxy.sock");
+
+        // A socket left behind by a previous run is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let handle = ProxyHandle::start_at(&ListenAddr::Unix(path.clone()), ProxyState::new()).expect("Expected the proxy to start");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // ...but not one that's still in use
+        assert!(ProxyHandle::start_at(&ListenAddr::Unix(path.clone()), ProxyState::new()).is_err());
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream.write_all(b"GET /_routes HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        handle.shutdown().await.unwrap();
+        assert!(!path.exists());
+    }
+}
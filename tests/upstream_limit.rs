@@ -0,0 +1,30 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::{IpAddr, Ipv4Addr};
+    use cfproxy::{proxy_request_to_cf, ProxyError};
+    use hyper::{Body, Request, StatusCode};
+    use crate::common::start_upstream;
+
+    #[tokio::test]
+    async fn sheds_requests_over_the_upstream_limit() {
+        let (upstream, _) = start_upstream(|_| ());
+        env::set_var("CF_API_URL", format!("http://{}", upstream));
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("UPSTREAM_REQ_LIMIT_PER_SEC", "1");
+        env::set_var("RATE_LIMIT_MAX_WAIT_SECS", "0");
+
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let req = Request::get("/v1/games").body(Body::empty()).unwrap();
+        assert_eq!(proxy_request_to_cf(req, &ip).await.unwrap().status(), StatusCode::OK);
+
+        let req = Request::get("/v1/games").body(Body::empty()).unwrap();
+        let err = proxy_request_to_cf(req, &ip).await.expect_err("Expected the request to be shed");
+        assert!(matches!(err, ProxyError::Throttled(_)), "{:?}", err);
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "1");
+    }
+}
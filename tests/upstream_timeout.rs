@@ -0,0 +1,36 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+    use cfproxy::{proxy_request_to_cf, ProxyError};
+    use hyper::{Body, Request, Response, StatusCode};
+    use crate::common::start_upstream_async;
+
+    #[tokio::test]
+    async fn answers_hanging_upstream_with_504() {
+        // Upstream that takes far longer than the timeout
+        let (upstream, _) = start_upstream_async(|_| async {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            ((), Response::new(Body::from("{}")))
+        });
+        env::set_var("CF_API_URL", format!("http://{}", upstream));
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("UPSTREAM_TIMEOUT_SECS", "1");
+
+        let req = Request::get("/v1/games").body(Body::empty()).unwrap();
+        let err = proxy_request_to_cf(req, &IpAddr::V4(Ipv4Addr::LOCALHOST)).await.expect_err("Expected the request to time out");
+        assert!(matches!(err, ProxyError::Timeout), "{:?}", err);
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(response.headers()["content-type"], "application/json");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["status"], 504);
+        assert_eq!(body["error"]["code"], "gateway_timeout");
+        assert_eq!(body["source"], "proxy");
+    }
+}
@@ -0,0 +1,66 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use cfproxy::metrics;
+    use cfproxy::server::{ProxyHandle, ProxyState};
+    use cfproxy::upstreams::parse_routes;
+    use hyper::{Body, Client, Response, StatusCode, Uri};
+    use crate::common::start_upstream_with;
+
+    #[test]
+    fn parses_routes() {
+        let routes = parse_routes(" /example/=https://api.example.com|Authorization: Bearer secret, /other=http://127.0.0.1:8080").unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].prefix, "/example");
+        assert_eq!(routes[0].authority(), "api.example.com");
+        assert_eq!(routes[0].auth.as_ref().map(|(name, value)| (name.as_str(), value.to_str().unwrap())), Some(("authorization", "Bearer secret")));
+        assert_eq!(routes[1].strip_prefix("/other/v1/things?x=1"), Some("/v1/things?x=1"));
+        assert_eq!(routes[1].strip_prefix("/otherwise/v1"), None);
+
+        assert!(parse_routes("").unwrap().is_empty());
+        assert!(parse_routes("example=https://api.example.com").is_err());
+        assert!(parse_routes("/example=api").is_err());
+        assert!(parse_routes("/example=https://api.example.com|no header").is_err());
+    }
+
+    /// An upstream answering with its name, the path & whether it got an api key.
+    fn upstream(name: &'static str) -> String {
+        let (addr, _) = start_upstream_with(false, |_| (), move |req| {
+            Response::new(Body::from(format!("{} {} {}", name, req.uri(), req.headers().contains_key("x-api-key"))))
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn forwards_prefixed_requests() {
+        env::set_var("CF_API_URL", upstream("curseforge"));
+        env::set_var("MODRINTH_API_URL", upstream("modrinth"));
+        env::set_var("UPSTREAM_ROUTES", format!("/keyed={}|x-api-key: other-key", upstream("keyed")));
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("MODRINTH_PROXY", "true");
+
+        let handle = ProxyHandle::start_with_state(([127, 0, 0, 1], 0).into(), ProxyState::new()).expect("Expected the proxy to start");
+        let get = |path: &str| {
+            let uri: Uri = format!("http://{}{}", handle.local_addr(), path).parse().unwrap();
+            async move {
+                let response = Client::new().get(uri).await.unwrap();
+                let status = response.status();
+                (status, String::from_utf8(hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap())
+            }
+        };
+
+        assert_eq!(get("/modrinth/v2/project/jei?loaders=forge").await, (StatusCode::OK, String::from("modrinth /v2/project/jei?loaders=forge false")));
+        assert_eq!(get("/v1/games").await, (StatusCode::OK, String::from("curseforge /v1/games true")));
+        assert_eq!(get("/keyed/v1/things").await, (StatusCode::OK, String::from("keyed /v1/things true")));
+        assert_eq!(get("/modrinthx/v2/project/jei").await.0, StatusCode::NOT_FOUND);
+
+        // Only the call to Curseforge used up quota of its key
+        assert_eq!(metrics::METRICS.run_stats().upstream_requests, 1);
+        assert_eq!(metrics::METRICS.snapshot().quota.used, 1);
+        let routed = metrics::METRICS.routed_requests();
+        assert_eq!((routed.len(), routed.values().sum::<u64>()), (2, 2), "{:?}", routed);
+        handle.shutdown().await.expect("Expected the proxy to shut down");
+    }
+}
@@ -0,0 +1,24 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::{IpAddr, Ipv4Addr};
+    use hyper::header::USER_AGENT;
+    use hyper::{Body, Request};
+    use crate::common::start_upstream;
+
+    #[tokio::test]
+    async fn replaces_client_user_agent() {
+        // Upstream that records the user agents it was sent
+        let (upstream, agents) = start_upstream(|req| req.headers().get(USER_AGENT).and_then(|agent| agent.to_str().ok()).unwrap_or("").to_string());
+        env::set_var("CF_API_URL", format!("http://{}", upstream));
+        env::set_var("CF_API_KEY", "key");
+        env::set_var("UPSTREAM_USER_AGENT", "my-deployment/1.0 (+https://example.com)");
+
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let req = Request::get("/v1/games").header(USER_AGENT, "SomeLauncher/2.3").body(Body::empty()).unwrap();
+        cfproxy::proxy_request_to_cf(req, &ip).await.unwrap();
+        assert_eq!(*agents.lock().unwrap(), vec!["my-deployment/1.0 (+https://example.com)".to_string()]);
+    }
+}